@@ -0,0 +1,179 @@
+//! A Lua state running on its own thread, driven through an async mailbox
+//!
+//! [`Lua`] is not `Sync`, so a state cannot be shared between the tasks of a multi-threaded
+//! runtime. A [`LuaActor`] owns the state on a dedicated thread instead, and its handles, that
+//! are `Clone + Send + Sync`, send it the calls to make. The calls run concurrently on the actor
+//! thread: while one waits for an async function, the others can make progress.
+
+use std::{future::Future, pin::Pin, rc::Rc, thread};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    executor::LocalPool,
+    task::LocalSpawnExt,
+    StreamExt,
+};
+use rlua::{Context, Error, FromLuaMulti, Lua, Result, ToLuaMulti};
+
+use crate::AsyncLua;
+
+type Job = Box<dyn Send + FnOnce(Rc<AsyncLua>) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// A handle to a Lua state running on its own thread, see the [module documentation](self)
+///
+/// The thread stops once all the handles are dropped and the calls in flight are done.
+#[derive(Clone)]
+pub struct LuaActor {
+    mailbox: mpsc::UnboundedSender<Job>,
+}
+
+impl LuaActor {
+    /// Start an actor with a new Lua state, see [`Lua::new`]
+    pub fn new() -> Result<LuaActor> {
+        LuaActor::from_lua(Lua::new())
+    }
+
+    /// Start an actor owning `lua`
+    pub fn from_lua(lua: Lua) -> Result<LuaActor> {
+        let (mailbox, mut jobs) = mpsc::unbounded::<Job>();
+        thread::Builder::new()
+            .name("rlua-async actor".to_string())
+            .spawn(move || {
+                let lua = Rc::new(AsyncLua::from_lua(lua));
+                let mut pool = LocalPool::new();
+                let spawner = pool.spawner();
+                pool.run_until(async {
+                    while let Some(job) = jobs.next().await {
+                        if spawner.spawn_local(job(lua.clone())).is_err() {
+                            break;
+                        }
+                    }
+                });
+                pool.run();
+            })
+            .map_err(Error::external)?;
+        Ok(LuaActor { mailbox })
+    }
+
+    /// Send `job` to the actor thread, and wait for the result it sends back
+    async fn run<T, F, Fut>(&self, job: F) -> Result<T>
+    where
+        T: 'static + Send,
+        F: 'static + Send + FnOnce(Rc<AsyncLua>) -> Fut,
+        Fut: 'static + Future<Output = Result<T>>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |lua| {
+            Box::pin(async move {
+                let _ = tx.send(job(lua).await);
+            })
+        });
+        let stopped = || Error::RuntimeError("the Lua actor stopped".to_string());
+        self.mailbox.unbounded_send(job).map_err(|_| stopped())?;
+        rx.await.map_err(|_| stopped())?
+    }
+
+    /// Call the global function `name` in an async-compliant way
+    pub async fn call<Arg, Ret>(&self, name: &str, args: Arg) -> Result<Ret>
+    where
+        Arg: 'static + Send + for<'lua> ToLuaMulti<'lua>,
+        Ret: 'static + Send + for<'lua> FromLuaMulti<'lua>,
+    {
+        let name = name.to_string();
+        self.run(move |lua| async move {
+            lua.call_async(move |ctx| ctx.globals().get(name), args)
+                .await
+        })
+        .await
+    }
+
+    /// Execute `source` in an async-compliant way, see [`AsyncLua::exec_async`]
+    pub async fn exec<S: Into<Vec<u8>>>(&self, source: S) -> Result<()> {
+        let source = source.into();
+        self.run(move |lua| async move { lua.exec_async(&source).await })
+            .await
+    }
+
+    /// Evaluate `source` in an async-compliant way, see [`AsyncLua::eval_async`]
+    pub async fn eval<S, Ret>(&self, source: S) -> Result<Ret>
+    where
+        S: Into<Vec<u8>>,
+        Ret: 'static + Send + for<'lua> FromLuaMulti<'lua>,
+    {
+        let source = source.into();
+        self.run(move |lua| async move { lua.eval_async(&source).await })
+            .await
+    }
+
+    /// Run `f` in the context of the Lua state, eg. to register async functions
+    pub async fn with<R, F>(&self, f: F) -> Result<R>
+    where
+        R: 'static + Send,
+        F: 'static + Send + FnOnce(Context) -> Result<R>,
+    {
+        self.run(move |lua| async move { lua.context(f) }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{executor, future};
+
+    use crate::ContextExt;
+
+    #[test]
+    fn actor_calls() {
+        let actor = LuaActor::new().unwrap();
+        executor::block_on(async {
+            actor
+                .with(|ctx| {
+                    ctx.set_async_fn("sleep", |_, ms: u64| {
+                        futures_timer::Delay::new(Duration::from_millis(ms))
+                    })
+                })
+                .await
+                .expect("failed to register");
+            actor
+                .exec(
+                    r#"
+                        done = {}
+                        function slow_double(a, ms)
+                            sleep(ms)
+                            table.insert(done, a)
+                            return a * 2
+                        end
+                    "#,
+                )
+                .await
+                .expect("failed to exec");
+
+            // The calls run concurrently, so the slow one doesn't hold the other back
+            let (slow, fast) = future::join(
+                actor.call::<_, usize>("slow_double", (1, 50)),
+                actor.call::<_, usize>("slow_double", (20, 10)),
+            )
+            .await;
+            assert_eq!((slow.unwrap(), fast.unwrap()), (2, 40));
+            let done: String = actor
+                .eval(r#"table.concat(done, ",")"#)
+                .await
+                .expect("failed to eval");
+            assert_eq!(done, "20,1");
+
+            // The handles can be used from any thread
+            let other = actor.clone();
+            let res =
+                std::thread::spawn(move || executor::block_on(other.eval::<_, usize>("1 + 2")))
+                    .join()
+                    .unwrap();
+            assert_eq!(res.expect("failed to eval"), 3);
+
+            let err = actor.call::<_, ()>("missing", ()).await.unwrap_err();
+            assert!(err.to_string().contains("nil"), "{}", err);
+        });
+    }
+}
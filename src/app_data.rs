@@ -0,0 +1,96 @@
+//! Typed application state attached to a Lua state
+//!
+//! See [`ContextExt::set_app_data`](crate::ContextExt::set_app_data). The data is stored behind
+//! an [`Arc`], so that async callbacks can clone it into the futures they return.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+use rlua::{AnyUserData, Context, Result, UserData};
+
+static APP_DATA_REGISTRY_KEY: &str = "rlua-async app data";
+
+#[derive(Default)]
+struct AppData(HashMap<TypeId, Arc<dyn Any + Send + Sync>>);
+
+impl UserData for AppData {}
+
+fn app_data(ctx: Context) -> Result<AnyUserData> {
+    if let Some(ud) = ctx.named_registry_value::<_, Option<AnyUserData>>(APP_DATA_REGISTRY_KEY)? {
+        return Ok(ud);
+    }
+    let ud = ctx.create_userdata(AppData::default())?;
+    ctx.set_named_registry_value(APP_DATA_REGISTRY_KEY, ud.clone())?;
+    Ok(ud)
+}
+
+fn downcast<T: 'static + Send + Sync>(data: Arc<dyn Any + Send + Sync>) -> Arc<T> {
+    data.downcast()
+        .unwrap_or_else(|_| unreachable!("app data stored under the wrong type id"))
+}
+
+pub(crate) fn set<T: 'static + Send + Sync>(ctx: Context, data: T) -> Result<Option<Arc<T>>> {
+    let ud = app_data(ctx)?;
+    let mut app_data = ud.borrow_mut::<AppData>()?;
+    let old = app_data.0.insert(TypeId::of::<T>(), Arc::new(data));
+    Ok(old.map(downcast))
+}
+
+pub(crate) fn get<T: 'static + Send + Sync>(ctx: Context) -> Result<Option<Arc<T>>> {
+    let ud = app_data(ctx)?;
+    let app_data = ud.borrow::<AppData>()?;
+    Ok(app_data.0.get(&TypeId::of::<T>()).cloned().map(downcast))
+}
+
+pub(crate) fn remove<T: 'static + Send + Sync>(ctx: Context) -> Result<Option<Arc<T>>> {
+    let ud = app_data(ctx)?;
+    let mut app_data = ud.borrow_mut::<AppData>()?;
+    Ok(app_data.0.remove(&TypeId::of::<T>()).map(downcast))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor;
+    use rlua::{Function, Lua};
+
+    use crate::{ContextExt, FunctionExt};
+
+    struct Counter(AtomicUsize);
+
+    #[test]
+    fn app_data_from_async_fn() {
+        Lua::new().context(|lua| {
+            assert!(lua.get_app_data::<Counter>().unwrap().is_none());
+            lua.set_app_data(Counter(AtomicUsize::new(40))).unwrap();
+
+            let f = lua
+                .create_async_function(|ctx, ()| {
+                    let counter = ctx.get_app_data::<Counter>();
+                    async move {
+                        let counter = counter?.expect("counter is not set");
+                        Ok(counter.0.fetch_add(1, Ordering::SeqCst) + 1)
+                    }
+                })
+                .unwrap();
+            lua.globals().set("incr", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"function() incr() return incr() end"#)
+                    .eval::<Function>()
+                    .unwrap()
+                    .call_async::<_, usize>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 42);
+
+            let counter = lua.remove_app_data::<Counter>().unwrap().unwrap();
+            assert_eq!(counter.0.load(Ordering::SeqCst), 42);
+            assert!(lua.get_app_data::<Counter>().unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,97 @@
+function(new_semaphore)
+    local lib = {}
+
+    -- Call `f` like `pcall` does, but letting it wait for async functions: the `pcall` of rlua
+    -- cannot yield, so `f` runs in its own coroutine, whose yields are passed on
+    local function protect(f, ...)
+        local co = coroutine.create(f)
+        local function step(ok, ...)
+            if not ok or coroutine.status(co) == "dead" then
+                return ok, ...
+            end
+            return step(coroutine.resume(co, coroutine.yield(...)))
+        end
+        return step(coroutine.resume(co, ...))
+    end
+
+    -- Release `permit` once the protected call that returned `ok, ...` is done, and return its
+    -- results or raise its error
+    local function release(permit, ok, ...)
+        permit:release()
+        if not ok then
+            error((...), 0)
+        end
+        return ...
+    end
+
+    function lib.mutex()
+        local semaphore = new_semaphore(1)
+        local mutex = {}
+
+        function mutex:lock(f, ...)
+            local guard = semaphore:acquire()
+            if f == nil then
+                return guard
+            end
+            return release(guard, protect(f, ...))
+        end
+
+        return mutex
+    end
+
+    function lib.semaphore(permits)
+        local inner = new_semaphore(permits)
+        -- The permits taken by `acquire`, given back by `release`
+        local held = {}
+        local semaphore = {}
+
+        function semaphore:acquire()
+            -- The permit is only put in `held` once acquired, as it may change meanwhile
+            local permit = inner:acquire()
+            held[#held + 1] = permit
+        end
+
+        function semaphore:release()
+            local permit = table.remove(held)
+            if permit == nil then
+                error("cannot release a semaphore that has no permit acquired", 2)
+            end
+            permit:release()
+        end
+
+        function semaphore:with(f, ...)
+            return release(inner:acquire(), protect(f, ...))
+        end
+
+        return semaphore
+    end
+
+    -- The number of read locks a read-write lock can give at once
+    local max_readers = 0x7fffffff
+
+    function lib.rwlock()
+        -- The readers take one permit, and the writers all of them
+        local inner = new_semaphore(max_readers)
+        local rwlock = {}
+
+        local function lock(permits, f, ...)
+            local guard = inner:acquire(permits)
+            if f == nil then
+                return guard
+            end
+            return release(guard, protect(f, ...))
+        end
+
+        function rwlock:read(f, ...)
+            return lock(1, f, ...)
+        end
+
+        function rwlock:write(f, ...)
+            return lock(max_readers, f, ...)
+        end
+
+        return rwlock
+    end
+
+    return lib
+end
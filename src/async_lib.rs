@@ -0,0 +1,1222 @@
+//! The `async` Lua library, for scripts to wait and run concurrent tasks
+//!
+//! [`install`] puts the library in the `async` table of the globals. Its functions are async
+//! functions, so the scripts using them must run in async calls, eg. with
+//! [`FunctionExt::call_async`](crate::FunctionExt::call_async). The timers go through the
+//! [`Spawner`] of the Lua state, that must then be selected before.
+//!
+//! This module is only available with the `async-lib` feature, enabled by default.
+
+use std::{
+    sync::{Arc, Mutex},
+    task::{self, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use rlua::{
+    Context, Error, Function, MultiValue, RegistryKey, Result, Table, ToLuaMulti, UserData,
+    UserDataMethods, Value,
+};
+
+use crate::{
+    channel, global_table, make_async,
+    plain::PlainValue,
+    semaphore::{Permit, Semaphore},
+    spawn,
+    spawner::Spawner,
+    time::Millis,
+    userdata, ContextExt, TaskHandle, UserDataMethodsExt,
+};
+
+static ASYNC_LIB: &[u8] = include_bytes!("async-lib.lua");
+
+/// The task userdata returned by `async.spawn`
+struct LuaTask(TaskHandle);
+
+impl UserData for LuaTask {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        userdata::add_polled_method(methods, "join", |ctx, this, _| {
+            let handle = this.0.clone();
+            spawn::poller(ctx, move |ctx, fut_ctx| handle.poll_values(ctx, fut_ctx))
+        });
+        methods.add_method("cancel", |_, this, ()| {
+            this.0.cancel();
+            Ok(())
+        });
+        methods.add_method("is_finished", |_, this, ()| Ok(this.0.is_finished()));
+    }
+}
+
+/// The promise userdata returned by `async.start`
+struct LuaPromise(TaskHandle);
+
+impl UserData for LuaPromise {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        userdata::add_polled_method(methods, "await", |ctx, this, _| {
+            let handle = this.0.clone();
+            spawn::poller(ctx, move |ctx, fut_ctx| handle.poll_values(ctx, fut_ctx))
+        });
+        methods.add_method("status", |_, this, ()| {
+            Ok(match this.0.succeeded() {
+                None => "pending",
+                Some(true) => "fulfilled",
+                Some(false) => "rejected",
+            })
+        });
+        methods.add_method("cancel", |_, this, ()| {
+            this.0.cancel();
+            Ok(())
+        });
+    }
+}
+
+/// The semaphore behind the locks of the Lua library, with its number of permits
+struct LuaSemaphore(Semaphore, usize);
+
+impl UserData for LuaSemaphore {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("acquire", |_, this, n: Option<usize>| {
+            let n = n.unwrap_or(1);
+            let acquire = if n <= this.1 {
+                Ok(this.0.acquire_many(n))
+            } else {
+                Err(Error::RuntimeError(format!(
+                    "cannot acquire {} permits of a semaphore that has {}",
+                    n, this.1
+                )))
+            };
+            async move { Ok(LuaPermit(Mutex::new(Some(acquire?.await)))) }
+        });
+    }
+}
+
+/// A permit of a [`LuaSemaphore`], given back by its `release` method or when it is collected
+struct LuaPermit(Mutex<Option<Permit>>);
+
+impl UserData for LuaPermit {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("release", |_, this, ()| {
+            this.0.lock().unwrap().take();
+            Ok(())
+        });
+    }
+}
+
+#[derive(Default)]
+struct WaitGroupState {
+    count: usize,
+    wakers: Vec<Waker>,
+}
+
+/// The wait groups of `async.waitgroup`
+#[derive(Default)]
+struct WaitGroup(Arc<Mutex<WaitGroupState>>);
+
+impl UserData for WaitGroup {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("add", |_, this, n: Option<usize>| {
+            this.0.lock().unwrap().count += n.unwrap_or(1);
+            Ok(())
+        });
+        methods.add_method("done", |_, this, ()| {
+            let mut state = this.0.lock().unwrap();
+            if state.count == 0 {
+                return Err(Error::RuntimeError(
+                    "a wait group is done more times than work was added to it".to_string(),
+                ));
+            }
+            state.count -= 1;
+            if state.count == 0 {
+                state.wakers.drain(..).for_each(Waker::wake);
+            }
+            Ok(())
+        });
+        methods.add_method("count", |_, this, ()| Ok(this.0.lock().unwrap().count));
+        methods.add_async_method("wait", |_, this, ()| {
+            let state = this.0.clone();
+            future::poll_fn(move |fut_ctx| {
+                let mut state = state.lock().unwrap();
+                if state.count == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                if !state.wakers.iter().any(|w| w.will_wake(fut_ctx.waker())) {
+                    state.wakers.push(fut_ctx.waker().clone());
+                }
+                Poll::Pending
+            })
+        });
+    }
+}
+
+/// The intervals of `async.interval`
+struct Interval {
+    period: Duration,
+    /// The deadline of the next tick
+    next: Mutex<Instant>,
+    spawner: Arc<dyn Spawner>,
+}
+
+impl Interval {
+    /// Take the deadline of the next tick, skipping the ticks that are already missed
+    fn next_deadline(&self) -> Result<Instant> {
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let deadline = *next;
+        let mut following = deadline.checked_add(self.period);
+        if following.is_some_and(|following| following <= now) {
+            let late = (now - deadline).as_nanos() % self.period.as_nanos();
+            following = now.checked_add(self.period - Duration::from_nanos(late as u64));
+        }
+        *next = following.ok_or_else(out_of_range)?;
+        Ok(deadline)
+    }
+}
+
+/// The error of the intervals whose next tick cannot be represented as an [`Instant`]
+fn out_of_range() -> Error {
+    Error::RuntimeError("interval period out of range".to_string())
+}
+
+impl UserData for Interval {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("tick", |_, this, ()| {
+            let sleep = this.next_deadline().map(|deadline| {
+                this.spawner
+                    .sleep(deadline.saturating_duration_since(Instant::now()))
+            });
+            async move {
+                sleep?.await;
+                Ok(())
+            }
+        });
+    }
+}
+
+/// The task of `op`, spawning it if it is a function
+fn task_of<'lua>(ctx: Context<'lua>, op: Value<'lua>) -> Result<TaskHandle> {
+    match op {
+        Value::Function(f) => ctx.spawn(f, ()),
+        Value::UserData(ud) if ud.is::<LuaTask>() => Ok(ud.borrow::<LuaTask>()?.0.clone()),
+        Value::UserData(ud) if ud.is::<LuaPromise>() => Ok(ud.borrow::<LuaPromise>()?.0.clone()),
+        _ => Err(Error::RuntimeError(
+            "expected functions, tasks or promises".to_string(),
+        )),
+    }
+}
+
+/// The tasks of the sequence `ops`, see [`task_of`]
+fn tasks_of<'lua>(ctx: Context<'lua>, ops: Table<'lua>) -> Result<Vec<TaskHandle>> {
+    ops.sequence_values::<Value>()
+        .map(|op| task_of(ctx, op?))
+        .collect()
+}
+
+/// The tasks of the table `ops`, see [`task_of`], with their keys kept in the registry
+fn keyed_tasks_of<'lua>(
+    ctx: Context<'lua>,
+    ops: Table<'lua>,
+) -> Result<(Vec<RegistryKey>, Vec<TaskHandle>)> {
+    let (mut keys, mut tasks) = (Vec::new(), Vec::new());
+    for pair in ops.pairs::<Value, Value>() {
+        let (key, op) = pair?;
+        tasks.push(task_of(ctx, op)?);
+        keys.push(ctx.create_registry_value(key)?);
+    }
+    Ok((keys, tasks))
+}
+
+/// Poll `tasks` until they are all done, returning their return values, or until one fails,
+/// cancelling the others
+fn poll_all<'lua>(
+    ctx: Context<'lua>,
+    tasks: &[TaskHandle],
+    fut_ctx: &mut task::Context,
+) -> Poll<Result<Vec<MultiValue<'lua>>>> {
+    let mut results = Vec::with_capacity(tasks.len());
+    let mut pending = false;
+    for task in tasks {
+        match task.poll_values(ctx, fut_ctx) {
+            Poll::Pending => pending = true,
+            Poll::Ready(Ok(values)) => results.push(values),
+            Poll::Ready(Err(e)) => {
+                tasks.iter().for_each(TaskHandle::cancel);
+                return Poll::Ready(Err(e));
+            }
+        }
+    }
+    if pending {
+        return Poll::Pending;
+    }
+    Poll::Ready(Ok(results))
+}
+
+/// Poll `tasks` until one is done, returning its index and result, and cancelling the others
+fn poll_any<'lua>(
+    ctx: Context<'lua>,
+    tasks: &[TaskHandle],
+    fut_ctx: &mut task::Context,
+) -> Poll<(usize, Result<MultiValue<'lua>>)> {
+    for (i, task) in tasks.iter().enumerate() {
+        if let Poll::Ready(res) = task.poll_values(ctx, fut_ctx) {
+            tasks.iter().for_each(TaskHandle::cancel);
+            return Poll::Ready((i, res));
+        }
+    }
+    Poll::Pending
+}
+
+/// The first of `values`, as the result of an op in the tables of `async.join_all` and
+/// `async.all`
+fn first(values: MultiValue) -> Value {
+    values.into_iter().next().unwrap_or(Value::Nil)
+}
+
+/// `async.join_all`
+fn join_all<'lua>(ctx: Context<'lua>, ops: Table<'lua>) -> Result<Function<'lua>> {
+    let tasks = tasks_of(ctx, ops)?;
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        poll_all(ctx, &tasks, fut_ctx).map(|res| {
+            let results = res?.into_iter().map(first).enumerate();
+            let table = ctx.create_table_from(results.map(|(i, v)| (i + 1, v)))?;
+            Ok(MultiValue::from_vec(vec![Value::Table(table)]))
+        })
+    })
+}
+
+/// `async.all`
+fn all<'lua>(ctx: Context<'lua>, ops: Table<'lua>) -> Result<Function<'lua>> {
+    let (keys, tasks) = keyed_tasks_of(ctx, ops)?;
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        poll_all(ctx, &tasks, fut_ctx).map(|res| {
+            let table = ctx.create_table()?;
+            for (key, values) in keys.iter().zip(res?) {
+                table.set(ctx.registry_value::<Value>(key)?, first(values))?;
+            }
+            Ok(MultiValue::from_vec(vec![Value::Table(table)]))
+        })
+    })
+}
+
+/// `async.select`
+fn select<'lua>(ctx: Context<'lua>, ops: Table<'lua>) -> Result<Function<'lua>> {
+    let tasks = tasks_of(ctx, ops)?;
+    if tasks.is_empty() {
+        return Err(Error::RuntimeError(
+            "cannot select among no functions or tasks".to_string(),
+        ));
+    }
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        poll_any(ctx, &tasks, fut_ctx)
+            .map(|(i, res)| res.and_then(|values| (i + 1, values).to_lua_multi(ctx)))
+    })
+}
+
+/// `async.any`
+fn any<'lua>(ctx: Context<'lua>, ops: Table<'lua>) -> Result<Function<'lua>> {
+    let (keys, tasks) = keyed_tasks_of(ctx, ops)?;
+    if tasks.is_empty() {
+        return Err(Error::RuntimeError(
+            "cannot wait for any of no promises".to_string(),
+        ));
+    }
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        poll_any(ctx, &tasks, fut_ctx).map(|(i, res)| {
+            let key = ctx.registry_value::<Value>(&keys[i])?;
+            (key, res?).to_lua_multi(ctx)
+        })
+    })
+}
+
+/// `async.timeout`
+fn timeout<'lua>(
+    ctx: Context<'lua>,
+    (ms, f, args): (Millis, Function<'lua>, MultiValue<'lua>),
+) -> Result<Function<'lua>> {
+    let mut sleep = ctx.spawner()?.sleep(ms.0);
+    let task = ctx.spawn(f, args)?;
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        if let Poll::Ready(res) = task.poll_values(ctx, fut_ctx) {
+            return Poll::Ready(res);
+        }
+        match sleep.as_mut().poll(fut_ctx) {
+            Poll::Ready(()) => {
+                task.cancel();
+                Poll::Ready((Value::Nil, "timeout").to_lua_multi(ctx))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    })
+}
+
+/// Install the `async` library in the globals, creating the `async` table if need be
+///
+/// The installed functions are:
+///  * `async.sleep(ms)`, that waits for `ms` milliseconds. If the call running it is dropped or
+///    cancelled meanwhile, the timer is dropped with it.
+///  * `async.spawn(f, ...)`, that starts calling `f` with the other arguments concurrently with
+///    the caller, see [`ContextExt::spawn`], and returns its task. `task:join()` waits for the
+///    call to be done and returns its return values, or raises its error. The tasks run while
+///    the async calls of the Lua state wait, and the ones left once these are done must be
+///    driven by the embedder with [`ContextExt::run_spawned`]. `task:cancel()` stops the call,
+///    whose futures are dropped the next time the tasks are driven, and `task:is_finished()`
+///    tells whether it is done.
+///  * `async.start(f, ...)`, that starts calling `f` with the other arguments like `async.spawn`,
+///    and returns a promise of its results, eg. to call async functions before their results
+///    are needed. `p:await()` waits for the call and returns its return values, or raises its
+///    error, as many times as needed. `p:status()` is `"pending"` until the call is done, then
+///    `"fulfilled"` or `"rejected"`, and `p:cancel()` cancels the call, rejecting the promise.
+///    Like tasks, promises can be passed to `async.join_all` and `async.select`.
+///  * `async.all(promises)`, that waits for all the promises of the table `promises`, and
+///    returns the table of their first return values, at the same keys. If one is rejected, the
+///    others are cancelled and its error is raised.
+///  * `async.any(promises)`, that waits for the first of the promises of the table `promises`
+///    to be settled, and cancels the others. It returns the key of the first one followed by
+///    its return values, or raises its error. Like `async.join_all` and `async.select`, both
+///    also take functions, that they start, and tasks.
+///  * `async.join_all(ops)`, that runs the functions or tasks of the sequence `ops`
+///    concurrently, and returns the table of their first return values once they are all done.
+///    If one fails, the others are cancelled and its error is raised.
+///  * `async.select(ops)`, also named `async.race`, that runs the functions or tasks of the
+///    sequence `ops` concurrently until the first one is done. The others are then cancelled,
+///    and it returns the index of the first one in `ops` followed by its return values, or
+///    raises its error.
+///  * `async.timeout(ms, f, ...)`, that calls `f` with the other arguments, and returns its
+///    return values if it is done within `ms` milliseconds. Otherwise the call is cancelled,
+///    and it returns `nil, "timeout"`.
+///  * `async.channel(capacity)`, that creates a [`channel::bounded`] channel of
+///    [`PlainValue`]s, and returns its sender and receiver. `tx:send(value)` waits for room in
+///    the channel, and `rx:recv()` for a value. Rust code can create such channels too, and pass
+///    their ends to Lua.
+///  * `async.oneshot()`, that creates a [`channel::oneshot`] channel for a single value, and
+///    returns its sender and receiver. `tx:send(value)` sends the value, and `rx:recv()` waits
+///    for it, failing if the sender is closed without sending one.
+///  * `async.broadcast(capacity)`, that creates a [`channel::broadcast`] channel of
+///    [`PlainValue`]s keeping the last `capacity` ones, and returns its sender. `tx:send(value)`
+///    sends a value to all the receivers created by `tx:subscribe()`, and `rx:recv()` waits for
+///    the next one, returning `nil, "lagged", n` if the receiver missed `n` values.
+///  * `async.watch(value)`, that creates a [`channel::Watch`] cell holding the [`PlainValue`]
+///    `value`. `w:get()` returns the current value, `w:set(value)` replaces it, and `w:changed()`
+///    waits for it to be set again and returns the new value. Rust code can create such cells
+///    too, eg. to pass configuration updates to Lua.
+///  * `async.event()`, that creates a [`channel::Event`], a flag to signal something to tasks.
+///    `e:wait()` waits for it to be set, `e:set()` sets it, `e:reset()` unsets it and
+///    `e:is_set()` tells whether it is set. Rust code can create events too, and set them.
+///  * `async.interval(ms)`, that creates a ticker with a period of `ms` milliseconds, whose
+///    `i:tick()` waits for the next period. The ticks are due every `ms` milliseconds from the
+///    creation of the ticker, whatever the time spent between them, so that periodic tasks do
+///    not drift as with `async.sleep` loops; the ticks that are already past due when the
+///    previous one returns are skipped.
+///  * `async.waitgroup()`, that creates a wait group, to wait for several tasks without joining
+///    them one by one. `wg:add(n)` adds `n` (1 by default) to its count, `wg:done()` subtracts 1,
+///    failing if the count is already 0, `wg:count()` returns it and `wg:wait()` waits for it to
+///    be 0.
+///  * `async.mutex()`, that creates a mutex for the Lua tasks. `m:lock()` waits for the lock,
+///    the tasks getting it in the order they asked for it, and returns a guard whose
+///    `guard:release()` unlocks the mutex (as does the collection of the guard).
+///    `m:lock(f, ...)` calls `f` with the other arguments under the lock instead, and returns
+///    its return values, unlocking the mutex even if it fails.
+///  * `async.semaphore(permits)`, that creates a semaphore with `permits` permits, eg. to limit
+///    the number of concurrent requests. `s:acquire()` waits for a permit, the tasks getting
+///    them in the order they asked for them, and `s:release()` gives one back. `s:with(f, ...)`
+///    calls `f` with the other arguments with a permit, and returns its return values, giving
+///    the permit back even if it fails.
+///  * `async.rwlock()`, that creates a read-write lock. `rw:read(f, ...)` calls `f` with the
+///    other arguments under a read lock, that many tasks can hold at once, and `rw:write(f, ...)`
+///    under the write lock, that only one task can hold, with no read lock held. They return the
+///    return values of `f`, and unlock even if it fails. The tasks get the locks in the order
+///    they asked for them, so that the writers are not starved by a stream of readers. Without
+///    `f`, they return a guard, as `m:lock()` does.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let lib = global_table(ctx, "async")?;
+    let spawner = ctx.spawner()?;
+    let sleeper = spawner.clone();
+    lib.set(
+        "sleep",
+        ctx.create_named_async_function("async.sleep", move |_, ms: Millis| sleeper.sleep(ms.0))?,
+    )?;
+    lib.set(
+        "spawn",
+        ctx.create_function(|ctx, (f, args): (Function, MultiValue)| {
+            ctx.spawn(f, args).map(LuaTask)
+        })?,
+    )?;
+    lib.set(
+        "start",
+        ctx.create_function(|ctx, (f, args): (Function, MultiValue)| {
+            ctx.spawn(f, args).map(LuaPromise)
+        })?,
+    )?;
+    lib.set(
+        "join_all",
+        make_async(ctx, "async.join_all", ctx.create_function(join_all)?)?,
+    )?;
+    let select = make_async(ctx, "async.select", ctx.create_function(select)?)?;
+    lib.set("select", select.clone())?;
+    lib.set("race", select)?;
+    lib.set(
+        "all",
+        make_async(ctx, "async.all", ctx.create_function(all)?)?,
+    )?;
+    lib.set(
+        "any",
+        make_async(ctx, "async.any", ctx.create_function(any)?)?,
+    )?;
+    lib.set(
+        "timeout",
+        make_async(ctx, "async.timeout", ctx.create_function(timeout)?)?,
+    )?;
+    lib.set(
+        "channel",
+        ctx.create_function(|_, capacity: usize| {
+            if capacity == 0 {
+                return Err(Error::RuntimeError(
+                    "a channel needs room for at least one value".to_string(),
+                ));
+            }
+            Ok(channel::bounded::<PlainValue>(capacity))
+        })?,
+    )?;
+    lib.set(
+        "oneshot",
+        ctx.create_function(|_, ()| Ok(channel::oneshot()))?,
+    )?;
+    lib.set(
+        "broadcast",
+        ctx.create_function(|_, capacity: usize| {
+            if capacity == 0 {
+                return Err(Error::RuntimeError(
+                    "a channel needs room for at least one value".to_string(),
+                ));
+            }
+            Ok(channel::broadcast::<PlainValue>(capacity))
+        })?,
+    )?;
+    lib.set(
+        "watch",
+        ctx.create_function(|_, value: Value| {
+            Ok(channel::Watch::new(PlainValue::from_lua_value(value)?))
+        })?,
+    )?;
+
+    lib.set(
+        "interval",
+        ctx.create_function(move |_, period: Millis| {
+            if period.0 == Duration::from_secs(0) {
+                return Err(Error::RuntimeError(
+                    "an interval needs a positive period".to_string(),
+                ));
+            }
+            let first = Instant::now()
+                .checked_add(period.0)
+                .ok_or_else(out_of_range)?;
+            Ok(Interval {
+                period: period.0,
+                next: Mutex::new(first),
+                spawner: spawner.clone(),
+            })
+        })?,
+    )?;
+    lib.set(
+        "event",
+        ctx.create_function(|_, ()| Ok(channel::Event::new()))?,
+    )?;
+    lib.set(
+        "waitgroup",
+        ctx.create_function(|_, ()| Ok(WaitGroup::default()))?,
+    )?;
+
+    let new_semaphore = ctx.create_function(|_, permits: usize| {
+        if permits == 0 {
+            return Err(Error::RuntimeError(
+                "a semaphore needs at least one permit".to_string(),
+            ));
+        }
+        Ok(LuaSemaphore(Semaphore::new(permits), permits))
+    })?;
+    let lua_lib = ctx
+        .load(ASYNC_LIB)
+        .set_name(b"rlua-async async library")?
+        .eval::<Function<'lua>>()?
+        .call::<_, Table<'lua>>(new_semaphore)?;
+    for pair in lua_lib.pairs::<Value, Value>() {
+        let (name, f) = pair?;
+        lib.set(name, f)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use futures::{channel::oneshot, executor};
+    use rlua::Lua;
+
+    use crate::{ChunkExt, FunctionExt};
+
+    #[test]
+    fn async_sleep() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+
+            let start = Instant::now();
+            executor::block_on(lua.load("async.sleep(20)").exec_async(lua))
+                .expect("failed to sleep");
+            assert!(start.elapsed() >= Duration::from_millis(20));
+
+            let start = Instant::now();
+            let f = lua.load("async.sleep(60000)").into_function().unwrap();
+            let res = executor::block_on(async {
+                f.async_call::<()>(lua)
+                    .timeout(Duration::from_millis(10))
+                    .await
+            });
+            assert!(res.is_err());
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            let err = executor::block_on(lua.load("async.sleep(-1)").exec_async(lua)).unwrap_err();
+            assert!(err.to_string().contains("non-negative"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn async_spawn() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r##"
+                        local done = {}
+                        local function after(ms, a, b)
+                            async.sleep(ms)
+                            table.insert(done, a)
+                            return a, nil, b
+                        end
+                        local slow = async.spawn(after, 30, 1, "slow")
+                        local fast = async.spawn(after, 10, 2, "fast")
+                        local failing = async.spawn(function() error("task failed") end)
+                        local cancelled = async.spawn(after, 10, 3)
+                        cancelled:cancel()
+
+                        local a, none, b = slow:join()
+                        assert(a == 1 and none == nil and b == "slow")
+                        assert(fast:is_finished() and select("#", fast:join()) == 3)
+                        local ok, err = pcall(failing.join, failing)
+                        assert(not ok and tostring(err):find("task failed"), err)
+                        assert(not pcall(cancelled.join, cancelled))
+                        return table.concat(done, ",")
+                    "##,
+                )
+                .into_function()
+                .unwrap();
+            let done = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(done.expect("failed to join"), "2,1");
+        });
+    }
+
+    #[test]
+    fn async_start() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r##"
+                        local function after(ms, ...)
+                            async.sleep(ms)
+                            return ...
+                        end
+                        local pending = {
+                            slow = async.start(after, 30, "slow"),
+                            fast = async.start(after, 10, "fast", nil),
+                        }
+                        local failing = async.start(error, "call failed")
+                        local cancelled = async.start(after, 10)
+                        cancelled:cancel()
+                        assert(pending.slow:status() == "pending")
+
+                        local function await_all(promises)
+                            return promises.slow:await() .. "," .. promises.fast:await()
+                        end
+                        assert(await_all(pending) == "slow,fast")
+                        assert(pending.fast:status() == "fulfilled")
+                        assert(select("#", pending.fast:await()) == 2)
+                        assert(failing:status() == "rejected")
+                        local ok, err = pcall(failing.await, failing)
+                        assert(not ok and tostring(err):find("call failed"), err)
+                        assert(cancelled:status() == "rejected")
+
+                        local i, which = async.select{async.start(after, 60000), pending.fast}
+                        return i .. ":" .. which
+                    "##,
+                )
+                .into_function()
+                .unwrap();
+            let res = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(res.expect("failed to await"), "2:fast");
+        });
+    }
+
+    #[test]
+    fn async_join_all() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local done = {}
+                        local function after(ms, a)
+                            return function()
+                                async.sleep(ms)
+                                table.insert(done, a)
+                                return a
+                            end
+                        end
+                        local started = async.spawn(after(20, 3))
+                        local res = async.join_all{after(30, 1), after(10, 2), started}
+                        assert(#res == 3 and res[1] == 1 and res[2] == 2 and res[3] == 3)
+
+                        local slow = async.spawn(after(60000, 4))
+                        local ok, err = pcall(async.join_all, {
+                            slow,
+                            function() error("op failed") end,
+                        })
+                        assert(not ok and tostring(err):find("op failed"), err)
+                        assert(not pcall(slow.join, slow))
+                        return table.concat(done, ",")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let done = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(done.expect("failed to join"), "2,3,1");
+        });
+    }
+
+    #[test]
+    fn async_select() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local function after(ms, a)
+                            return function()
+                                async.sleep(ms)
+                                return a, nil
+                            end
+                        end
+                        local slow = async.spawn(after(60000, 1))
+                        local i, a, none = async.select{slow, after(10, 2)}
+                        assert(i == 2 and a == 2 and none == nil)
+                        assert(not pcall(slow.join, slow))
+
+                        local ok, err = pcall(async.race, {
+                            after(60000, 3),
+                            function() error("op failed") end,
+                        })
+                        assert(not ok and tostring(err):find("op failed"), err)
+                        assert(not pcall(async.race, {}))
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let start = Instant::now();
+            executor::block_on(async { f.async_call::<()>(lua).await }).expect("failed to select");
+            assert!(start.elapsed() < Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    fn async_all_any() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r##"
+                        local function after(ms, ...)
+                            async.sleep(ms)
+                            return ...
+                        end
+                        local res = async.all{
+                            user = async.start(after, 20, "alice"),
+                            posts = async.start(after, 10, 3, "ignored"),
+                            function() return "started" end,
+                        }
+                        assert(res.user == "alice" and res.posts == 3 and res[1] == "started")
+
+                        local slow = async.start(after, 60000)
+                        local ok, err = pcall(async.all, {
+                            slow = slow,
+                            failing = async.start(error, "call failed"),
+                        })
+                        assert(not ok and tostring(err):find("call failed"), err)
+                        assert(not pcall(slow.await, slow) and slow:status() == "rejected")
+
+                        slow = async.start(after, 60000, "slow")
+                        local key, a, b = async.any{
+                            slow = slow,
+                            fast = async.start(after, 10, "fast", 2),
+                        }
+                        assert(not pcall(slow.await, slow))
+                        assert(not pcall(async.any, {}))
+                        return key .. ":" .. a .. ":" .. b
+                    "##,
+                )
+                .into_function()
+                .unwrap();
+            let start = Instant::now();
+            let res = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(res.expect("failed to await"), "fast:fast:2");
+            assert!(start.elapsed() < Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    fn async_timeout() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local function after(ms, a)
+                            async.sleep(ms)
+                            done = a
+                            return a
+                        end
+                        assert(async.timeout(1000, after, 10, 1) == 1)
+                        local res, err = async.timeout(10, after, 60000, 2)
+                        assert(res == nil and err == "timeout")
+                        local ok, err = pcall(async.timeout, 1000, error, "call failed")
+                        assert(not ok and tostring(err):find("call failed"), err)
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let start = Instant::now();
+            executor::block_on(async { f.async_call::<()>(lua).await })
+                .expect("failed to time out");
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert_eq!(lua.globals().get::<_, u8>("done").unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn async_channel() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local tx, rx = async.channel(1)
+                        local log = {}
+                        local producer = async.spawn(function()
+                            for i = 1, 3 do
+                                tx:send(i)
+                                table.insert(log, "sent " .. i)
+                            end
+                            tx:close()
+                        end)
+                        while true do
+                            local v = rx:recv()
+                            if v == nil then break end
+                            table.insert(log, "got " .. v)
+                        end
+                        producer:join()
+                        assert(not pcall(tx.send, tx, 4))
+                        assert(not pcall(async.channel, 0))
+
+                        local sum = 0
+                        for v in from_rust.recv, from_rust do sum = sum + v end
+                        return sum, table.concat(log, ",")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+
+            let (tx, rx) = channel::bounded(2);
+            lua.globals().set("from_rust", rx).unwrap();
+            let producer = std::thread::spawn(move || {
+                executor::block_on(async {
+                    for i in 1..=10 {
+                        tx.send(PlainValue::Integer(i)).await.unwrap();
+                    }
+                })
+            });
+            let (sum, log) = executor::block_on(async { f.async_call::<(i64, String)>(lua).await })
+                .expect("failed to use the channels");
+            producer.join().unwrap();
+            assert_eq!(sum, 55);
+            assert_eq!(log, "sent 1,got 1,sent 2,got 2,sent 3,got 3");
+        });
+    }
+
+    #[test]
+    fn async_oneshot() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local tx, rx = async.oneshot()
+                        async.spawn(function()
+                            async.sleep(10)
+                            tx:send({answer = 42})
+                        end)
+                        assert(rx:recv().answer == 42)
+                        assert(not pcall(tx.send, tx, 1))
+                        assert(not pcall(rx.recv, rx))
+
+                        local tx, rx = async.oneshot()
+                        tx:close()
+                        local ok, err = pcall(rx.recv, rx)
+                        assert(not ok and tostring(err):find("dropped"), err)
+
+                        reply:send(rust_request:recv() * 2)
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+
+            let (request, rust_request) = oneshot::channel();
+            let (reply, rust_reply) = oneshot::channel();
+            let globals = lua.globals();
+            globals
+                .set("rust_request", channel::OneshotReceiver::from(rust_request))
+                .unwrap();
+            globals
+                .set("reply", channel::OneshotSender::from(reply))
+                .unwrap();
+            request.send(PlainValue::Integer(21)).unwrap();
+            executor::block_on(async { f.async_call::<()>(lua).await }).expect("failed to call");
+            assert_eq!(
+                executor::block_on(rust_reply).unwrap(),
+                PlainValue::Integer(42)
+            );
+        });
+    }
+
+    #[test]
+    fn async_broadcast() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local log = {}
+                        local function handler(name, rx)
+                            return async.spawn(function()
+                                while true do
+                                    local event, err, n = rx:recv()
+                                    if err == "closed" then return end
+                                    table.insert(log, name .. " " .. (event or err .. n))
+                                end
+                            end)
+                        end
+                        local a = handler("a", events:subscribe())
+                        local b = handler("b", events:subscribe())
+                        local late = events:subscribe()
+                        for i = 1, 3 do
+                            assert(events:send(i) == 3)
+                            async.sleep(1)
+                        end
+                        events:close()
+                        async.join_all{a, b}
+                        local none, err, n = late:recv()
+                        assert(none == nil and err == "lagged" and n == 1)
+                        assert(late:recv() == 2)
+                        return table.concat(log, ",")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+
+            let events = channel::broadcast::<PlainValue>(2);
+            lua.globals().set("events", events.clone()).unwrap();
+            let log = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(log.expect("failed to broadcast"), "a 1,b 1,a 2,b 2,a 3,b 3");
+            assert!(events.send(PlainValue::Nil).is_err());
+        });
+    }
+
+    #[test]
+    fn async_mutex() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local m = async.mutex()
+                        local counter = 0
+                        local function increment()
+                            local seen = counter
+                            async.sleep(1)
+                            counter = seen + 1
+                        end
+                        local tasks = {}
+                        for i = 1, 5 do
+                            tasks[i] = function() m:lock(increment) end
+                        end
+                        async.join_all(tasks)
+                        assert(counter == 5, counter)
+
+                        local ok, err = pcall(m.lock, m, error, "failed under lock")
+                        assert(not ok and err == "failed under lock", err)
+                        local guard = m:lock()
+                        local waiter = async.spawn(function() return m:lock(function() return 42 end) end)
+                        async.sleep(1)
+                        assert(not waiter:is_finished())
+                        guard:release()
+                        return waiter:join()
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let res = executor::block_on(async { f.async_call::<u8>(lua).await });
+            assert_eq!(res.expect("failed to lock"), 42);
+        });
+    }
+
+    #[test]
+    fn async_semaphore() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local s = async.semaphore(2)
+                        local running, max_running = 0, 0
+                        local function request(i)
+                            running = running + 1
+                            max_running = math.max(max_running, running)
+                            async.sleep(5)
+                            running = running - 1
+                            return i
+                        end
+                        local tasks = {}
+                        for i = 1, 6 do
+                            tasks[i] = function() return s:with(request, i) end
+                        end
+                        local res = async.join_all(tasks)
+                        assert(#res == 6 and res[6] == 6)
+                        assert(max_running == 2, max_running)
+
+                        s:acquire()
+                        s:acquire()
+                        local waiter = async.spawn(function() s:acquire() end)
+                        async.sleep(1)
+                        assert(not waiter:is_finished())
+                        s:release()
+                        waiter:join()
+                        s:release()
+                        s:release()
+                        assert(not pcall(s.release, s))
+                        assert(not pcall(async.semaphore, 0))
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            executor::block_on(async { f.async_call::<()>(lua).await })
+                .expect("failed to use the semaphore");
+        });
+    }
+
+    #[test]
+    fn async_rwlock() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local rw = async.rwlock()
+                        local state = {value = 0}
+                        local readers, max_readers = 0, 0
+                        local log = {}
+                        local function read()
+                            readers = readers + 1
+                            max_readers = math.max(max_readers, readers)
+                            local value = state.value
+                            async.sleep(5)
+                            readers = readers - 1
+                            table.insert(log, "read " .. value)
+                        end
+                        local function write(value)
+                            assert(readers == 0)
+                            async.sleep(1)
+                            state.value = value
+                            table.insert(log, "wrote " .. value)
+                        end
+                        async.join_all{
+                            function() rw:read(read) end,
+                            function() rw:read(read) end,
+                            function() rw:write(write, 1) end,
+                            function() rw:read(read) end,
+                        }
+                        assert(max_readers == 2, max_readers)
+
+                        local ok, err = pcall(rw.write, rw, error, "failed under lock")
+                        assert(not ok and err == "failed under lock", err)
+                        local guard = rw:read()
+                        guard:release()
+                        rw:write(function() end)
+                        return table.concat(log, ",")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let log = executor::block_on(async { f.async_call::<String>(lua).await });
+            assert_eq!(log.expect("failed to lock"), "read 0,read 0,wrote 1,read 1");
+        });
+    }
+
+    #[test]
+    fn async_watch() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local w = async.watch(1)
+                        local waiter = async.spawn(function() return w:changed() end)
+                        async.sleep(1)
+                        assert(not waiter:is_finished() and w:get() == 1)
+                        w:set(2)
+                        assert(waiter:join() == 2 and w:get() == 2)
+
+                        local seen = {}
+                        while true do
+                            local c = config:changed()
+                            if c.stop then break end
+                            table.insert(seen, c.level)
+                        end
+                        return table.concat(seen, ",")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+
+            let config = channel::Watch::new(PlainValue::Nil);
+            lua.globals().set("config", config.clone()).unwrap();
+            let entry =
+                |key: &str, value| PlainValue::Table(vec![(PlainValue::String(key.into()), value)]);
+            let updater = std::thread::spawn(move || {
+                for i in 1..=3 {
+                    std::thread::sleep(Duration::from_millis(20));
+                    config.set(entry("level", PlainValue::Integer(i)));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                config.set(entry("stop", PlainValue::Boolean(true)));
+            });
+            let seen = executor::block_on(async { f.async_call::<String>(lua).await });
+            updater.join().unwrap();
+            assert_eq!(seen.expect("failed to watch"), "1,2,3");
+        });
+    }
+
+    #[test]
+    fn async_waitgroup() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local wg = async.waitgroup()
+                        wg:wait()
+                        local done = 0
+                        for i = 1, 3 do
+                            wg:add()
+                            async.spawn(function()
+                                async.sleep(i * 5)
+                                done = done + 1
+                                wg:done()
+                            end)
+                        end
+                        assert(wg:count() == 3)
+                        wg:wait()
+                        assert(done == 3 and wg:count() == 0)
+                        assert(not pcall(wg.done, wg))
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            executor::block_on(async { f.async_call::<()>(lua).await }).expect("failed to wait");
+        });
+    }
+
+    #[test]
+    fn async_event() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local loaded = async.event()
+                        local waiters = {}
+                        for i = 1, 2 do
+                            waiters[i] = async.spawn(function() loaded:wait() return i end)
+                        end
+                        async.sleep(1)
+                        assert(not loaded:is_set() and not waiters[1]:is_finished())
+                        loaded:set()
+                        local res = async.join_all(waiters)
+                        assert(res[1] == 1 and res[2] == 2)
+                        loaded:wait()
+                        loaded:reset()
+                        assert(not loaded:is_set())
+
+                        shutdown:wait()
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+
+            let shutdown = channel::Event::new();
+            lua.globals().set("shutdown", shutdown.clone()).unwrap();
+            let setter = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                shutdown.set();
+            });
+            executor::block_on(async { f.async_call::<()>(lua).await })
+                .expect("failed to wait for the events");
+            setter.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn async_interval() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set(
+                    "busy",
+                    lua.create_function(|_, ms: u64| {
+                        std::thread::sleep(Duration::from_millis(ms));
+                        Ok(())
+                    })
+                    .unwrap(),
+                )
+                .unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local i = async.interval(20)
+                        for _ = 1, 5 do
+                            i:tick()
+                            busy(10)
+                        end
+                        assert(not pcall(async.interval, 0))
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let start = Instant::now();
+            executor::block_on(async { f.async_call::<()>(lua).await }).expect("failed to tick");
+            // 5 ticks and the work after the last one, rather than 5 times the period and the work
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(110), "{:?}", elapsed);
+            assert!(elapsed < Duration::from_millis(145), "{:?}", elapsed);
+        });
+    }
+}
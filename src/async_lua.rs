@@ -0,0 +1,217 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use rlua::{Context, FromLuaMulti, Function, Lua, MultiValue, Result, Thread, ToLuaMulti};
+
+use futures::future;
+
+use crate::{load_eval, owned::OwnedThread, spawn, ThreadExt};
+
+/// A Lua state whose async calls can be awaited from `async fn`s
+///
+/// The values of [`Lua::context`] cannot outlive the closure it takes, so the futures of eg.
+/// [`FunctionExt::call_async`](crate::FunctionExt::call_async) must usually be driven to
+/// completion from inside it, with a nested `block_on`. The futures returned by `AsyncLua`
+/// instead enter the context each time they are polled, keeping the running call in the
+/// registry in-between, so that a whole session can be written as plain async code:
+///
+/// ```
+/// # use rlua_async::{AsyncLua, ContextExt};
+/// # async fn fetch(url: String) -> rlua::Result<String> { Ok(url) }
+/// # async fn run() -> rlua::Result<()> {
+/// let lua = AsyncLua::new();
+/// lua.context(|ctx| ctx.set_async_fn("fetch", |_, url: String| fetch(url)))?;
+/// lua.exec_async(r#"function handler(url) return #fetch(url) end"#).await?;
+/// let len: usize = lua.call_async(|ctx| ctx.globals().get("handler"), "example.org").await?;
+/// # assert_eq!(len, 11);
+/// # Ok(())
+/// # }
+/// # futures::executor::block_on(run()).unwrap();
+/// ```
+pub struct AsyncLua {
+    lua: Lua,
+}
+
+impl AsyncLua {
+    /// Create a new Lua state, see [`Lua::new`]
+    pub fn new() -> AsyncLua {
+        AsyncLua::from_lua(Lua::new())
+    }
+
+    /// Wrap an existing Lua state
+    pub fn from_lua(lua: Lua) -> AsyncLua {
+        AsyncLua { lua }
+    }
+
+    /// Get the wrapped Lua state back
+    pub fn into_lua(self) -> Lua {
+        self.lua
+    }
+
+    /// Enter the context of the Lua state synchronously, see [`Lua::context`]
+    pub fn context<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(Context) -> R,
+    {
+        self.lua.context(f)
+    }
+
+    /// Call the function that `func` picks in an async-compliant way, eg.
+    /// `|ctx| ctx.globals().get("handler")`
+    pub fn call_async<'a, Arg, Ret, F>(&'a self, func: F, args: Arg) -> AsyncLuaCall<'a, Ret>
+    where
+        Arg: 'a + for<'lua> ToLuaMulti<'lua>,
+        Ret: for<'lua> FromLuaMulti<'lua>,
+        F: 'a + for<'lua> FnOnce(Context<'lua>) -> Result<Function<'lua>>,
+    {
+        AsyncLuaCall::new(
+            &self.lua,
+            Box::new(move |ctx| Ok((ctx.create_thread(func(ctx)?)?, args.to_lua_multi(ctx)?))),
+        )
+    }
+
+    /// Execute `source` in an async-compliant way, see
+    /// [`ChunkExt::exec_async`](crate::ChunkExt::exec_async)
+    pub fn exec_async<'a, S>(&'a self, source: &'a S) -> AsyncLuaCall<'a, ()>
+    where
+        S: ?Sized + AsRef<[u8]>,
+    {
+        self.call_async(move |ctx| ctx.load(source).into_function(), ())
+    }
+
+    /// Drive the calls started with [`ContextExt::spawn`](crate::ContextExt::spawn), until none
+    /// is left, see [`ContextExt::run_spawned`](crate::ContextExt::run_spawned)
+    pub fn run_spawned(&self) -> impl '_ + Future<Output = Result<()>> {
+        future::poll_fn(move |fut_ctx| {
+            self.context(|ctx| match spawn::poll_tasks(ctx, fut_ctx) {
+                Ok(true) => Poll::Ready(Ok(())),
+                Ok(false) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            })
+        })
+    }
+
+    /// Evaluate `source` in an async-compliant way, see
+    /// [`ContextExt::eval_async`](crate::ContextExt::eval_async)
+    pub fn eval_async<'a, S, Ret>(&'a self, source: &'a S) -> AsyncLuaCall<'a, Ret>
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Ret: for<'lua> FromLuaMulti<'lua>,
+    {
+        self.call_async(move |ctx| load_eval(ctx, source.as_ref()), ())
+    }
+}
+
+impl Default for AsyncLua {
+    fn default() -> AsyncLua {
+        AsyncLua::new()
+    }
+}
+
+type StartCall<'a> =
+    Box<dyn 'a + for<'lua> FnOnce(Context<'lua>) -> Result<(Thread<'lua>, MultiValue<'lua>)>>;
+
+enum CallState<'a> {
+    /// Not polled yet
+    Start(StartCall<'a>),
+    /// Waiting on the futures of the async functions called by the thread
+    Running(OwnedThread),
+    Done,
+}
+
+/// The future of an async call to a Lua function of an [`AsyncLua`]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct AsyncLuaCall<'a, Ret> {
+    lua: &'a Lua,
+    state: CallState<'a>,
+    _phantom: std::marker::PhantomData<fn() -> Ret>,
+}
+
+impl<'a, Ret> AsyncLuaCall<'a, Ret> {
+    fn new(lua: &'a Lua, start: StartCall<'a>) -> AsyncLuaCall<'a, Ret> {
+        AsyncLuaCall {
+            lua,
+            state: CallState::Start(start),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+// The future is never structurally pinned
+impl<'a, Ret> Unpin for AsyncLuaCall<'a, Ret> {}
+
+impl<'a, Ret> Future for AsyncLuaCall<'a, Ret>
+where
+    Ret: for<'lua> FromLuaMulti<'lua>,
+{
+    type Output = Result<Ret>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<Ret>> {
+        let this = self.get_mut();
+        let lua = this.lua;
+        lua.context(|ctx| {
+            let (thread, args, owned) = match std::mem::replace(&mut this.state, CallState::Done) {
+                CallState::Start(start) => {
+                    let (thread, args) = start(ctx)?;
+                    (thread, args, None)
+                }
+                CallState::Running(owned) => (owned.get(ctx)?, MultiValue::new(), Some(owned)),
+                CallState::Done => panic!("polled a completed `AsyncLuaCall`"),
+            };
+            let mut resume = thread.resume_async::<_, MultiValue>(ctx, args);
+            match Pin::new(&mut resume).poll(fut_ctx) {
+                Poll::Pending => {
+                    let owned = match owned {
+                        Some(owned) => owned,
+                        None => OwnedThread::new(ctx, thread)?,
+                    };
+                    this.state = CallState::Running(owned);
+                    Ok(Poll::Pending)
+                }
+                Poll::Ready(res) => Ok(Poll::Ready(res.and_then(|v| Ret::from_lua_multi(v, ctx)))),
+            }
+        })
+        .unwrap_or_else(|e| Poll::Ready(Err(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::executor;
+
+    use crate::ContextExt;
+
+    #[test]
+    fn async_lua_session() {
+        let lua = AsyncLua::new();
+        lua.context(|ctx| {
+            ctx.set_async_fn("double", |_, a: usize| async move {
+                futures_timer::Delay::new(Duration::from_millis(10)).await;
+                a * 2
+            })
+        })
+        .unwrap();
+
+        executor::block_on(async {
+            lua.exec_async("function handler(a) return double(a) + 1 end")
+                .await
+                .expect("failed to exec");
+            let first: usize = lua
+                .call_async(|ctx| ctx.globals().get("handler"), 20)
+                .await
+                .expect("failed to call");
+            let second: usize = lua
+                .eval_async("handler(handler(1))")
+                .await
+                .expect("failed to eval");
+            assert_eq!((first, second), (41, 7));
+        });
+    }
+}
@@ -0,0 +1,493 @@
+//! Byte buffers shared between Rust and Lua without copies
+//!
+//! Converting large payloads to Lua strings copies them each time. A [`Buffer`] is instead a
+//! reference-counted, immutable slice of bytes, backed by [`Bytes`]: cloning and slicing it is
+//! free, as is creating it from a `Vec<u8>` or [`Bytes`], and only converting it to a Lua string
+//! (eg. with `tostring`) copies the bytes. Similarly, a [`StringBuilder`] lets Lua scripts
+//! assemble large outputs without quadratic string concatenation, to then stream them to an
+//! [`AsyncWrite`].
+
+use std::{io, ops::Deref};
+
+use bytes::{Bytes, BytesMut};
+use futures::io::{AsyncWrite, AsyncWriteExt};
+use rlua::{AnyUserData, Context, Function, MetaMethod, Result, UserData, UserDataMethods, Value};
+
+static WRITE_TO: &[u8] = include_bytes!("write-to.lua");
+
+static WRITE_TO_REGISTRY_KEY: &str = "rlua-async string builder write_to";
+
+/// An immutable, cheaply cloneable and sliceable buffer of bytes
+///
+/// In Lua, it has the following methods:
+///  * `buf:len()` (and `#buf`), the number of bytes in the buffer,
+///  * `buf:sub(i, j)`, the zero-copy equivalent of `string.sub`,
+///  * `buf:byte(i)`, the byte at index `i` (1-based, negative indices count from the end),
+///  * `buf:concat(other)` (and `buf .. other`), a new buffer with the bytes of `buf` followed by
+///    the ones of `other`, that can be a buffer or a string,
+///  * `tostring(buf)`, a Lua string with the bytes of the buffer.
+#[derive(Clone, Debug)]
+pub struct Buffer {
+    data: Bytes,
+}
+
+impl Buffer {
+    /// Create a buffer from the given bytes, without copying them if they are a `Vec<u8>` or
+    /// [`Bytes`]
+    pub fn new<T: Into<Bytes>>(data: T) -> Buffer {
+        Buffer { data: data.into() }
+    }
+
+    /// The bytes of this buffer
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The bytes of this buffer, sharing the same memory
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
+
+    /// The number of bytes in this buffer
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether this buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// A buffer with the bytes `start..end` of this buffer, sharing the same memory
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, start: usize, end: usize) -> Buffer {
+        assert!(
+            start <= end && end <= self.len(),
+            "buffer slice out of bounds"
+        );
+        Buffer {
+            data: self.data.slice(start..end),
+        }
+    }
+
+    /// A new buffer with the bytes of all the given slices
+    pub fn concat<T: AsRef<[u8]>>(parts: &[T]) -> Buffer {
+        let len = parts.iter().map(|p| p.as_ref().len()).sum();
+        let mut data = BytesMut::with_capacity(len);
+        for part in parts {
+            data.extend_from_slice(part.as_ref());
+        }
+        Buffer::new(data.freeze())
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    fn from(data: Vec<u8>) -> Buffer {
+        Buffer::new(data)
+    }
+}
+
+impl From<Bytes> for Buffer {
+    fn from(data: Bytes) -> Buffer {
+        Buffer::new(data)
+    }
+}
+
+impl From<&[u8]> for Buffer {
+    fn from(data: &[u8]) -> Buffer {
+        Buffer::new(Bytes::copy_from_slice(data))
+    }
+}
+
+impl From<Buffer> for Bytes {
+    fn from(buffer: Buffer) -> Bytes {
+        buffer.into_bytes()
+    }
+}
+
+impl PartialEq for Buffer {
+    fn eq(&self, other: &Buffer) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Buffer {}
+
+/// Convert Lua `string.sub`-like indices into a Rust range
+fn lua_range(len: usize, i: Option<rlua::Integer>, j: Option<rlua::Integer>) -> (usize, usize) {
+    let len = len as rlua::Integer;
+    let normalize = |i: rlua::Integer| if i < 0 { len + i + 1 } else { i };
+    let start = normalize(i.unwrap_or(1)).max(1);
+    let end = normalize(j.unwrap_or(-1)).min(len);
+    if start > end {
+        (0, 0)
+    } else {
+        ((start - 1) as usize, end as usize)
+    }
+}
+
+/// Get the bytes of a Lua value that is either a buffer or a string
+pub(crate) fn buffer_or_string<'lua>(ctx: Context<'lua>, v: Value<'lua>) -> Result<Buffer> {
+    match v {
+        Value::UserData(ud) => Ok(ud.borrow::<Buffer>()?.clone()),
+        v => {
+            let s = ctx.unpack::<rlua::String>(v)?;
+            Ok(Buffer::from(s.as_bytes()))
+        }
+    }
+}
+
+impl UserData for Buffer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("len", |_, this, ()| Ok(this.len()));
+
+        methods.add_method(
+            "sub",
+            |_, this, (i, j): (Option<rlua::Integer>, Option<rlua::Integer>)| {
+                let (start, end) = lua_range(this.len(), i, j);
+                Ok(this.slice(start, end))
+            },
+        );
+
+        methods.add_method("byte", |_, this, i: rlua::Integer| {
+            let (start, end) = lua_range(this.len(), Some(i), Some(i));
+            Ok(if start < end { Some(this[start]) } else { None })
+        });
+
+        methods.add_method("concat", |ctx, this, other: Value<'lua>| {
+            Ok(Buffer::concat(&[
+                this.clone(),
+                buffer_or_string(ctx, other)?,
+            ]))
+        });
+
+        methods.add_meta_function(
+            MetaMethod::Concat,
+            |ctx, (a, b): (Value<'lua>, Value<'lua>)| {
+                Ok(Buffer::concat(&[
+                    buffer_or_string(ctx, a)?,
+                    buffer_or_string(ctx, b)?,
+                ]))
+            },
+        );
+
+        methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.len()));
+
+        methods.add_meta_method(MetaMethod::Eq, |ctx, this, other: Value<'lua>| {
+            Ok(*this == buffer_or_string(ctx, other)?)
+        });
+
+        methods.add_meta_method(MetaMethod::ToString, |ctx, this, ()| {
+            ctx.create_string(this.as_slice())
+        });
+    }
+}
+
+/// A list of byte chunks, to assemble large outputs piece by piece
+///
+/// The strings pushed in a row are copied to a single growing chunk, while the buffers are kept
+/// as chunks of their own, without copying them.
+///
+/// In Lua, it has the following methods:
+///  * `builder:push(s)`, to append a string or a [`Buffer`] (without copying it) at the end,
+///  * `builder:len()` (and `#builder`), the total number of bytes,
+///  * `builder:chunks()`, an iterator over the chunks, as buffers, eg. to write them one by one
+///    with an async writer,
+///  * `builder:write_to(writer)`, that writes the chunks in order with `writer:write(chunk)`,
+///    eg. to a [`WriteHandle`](crate::io::WriteHandle) or an
+///    [`AsyncOutput`](crate::output::AsyncOutput), waiting for each write if it is async,
+///  * `builder:to_buffer()`, a single buffer with all the bytes,
+///  * `builder:clear()`, to remove all the chunks,
+///  * `tostring(builder)`, a Lua string with all the bytes.
+///
+/// A new builder can be created from Lua with [`StringBuilder::create_constructor`].
+#[derive(Clone, Debug, Default)]
+pub struct StringBuilder {
+    chunks: Vec<Buffer>,
+    /// The bytes pushed since the last chunk, not frozen into a chunk yet
+    tail: BytesMut,
+    len: usize,
+}
+
+impl StringBuilder {
+    /// Create an empty builder
+    pub fn new() -> StringBuilder {
+        StringBuilder::default()
+    }
+
+    /// Append a chunk at the end of the builder, without copying it
+    pub fn push<T: Into<Buffer>>(&mut self, chunk: T) {
+        let chunk = chunk.into();
+        if !chunk.is_empty() {
+            self.freeze_tail();
+            self.len += chunk.len();
+            self.chunks.push(chunk);
+        }
+    }
+
+    /// Append a copy of `bytes` at the end of the builder, in the same chunk as the bytes
+    /// previously appended this way
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.len += bytes.len();
+        self.tail.extend_from_slice(bytes);
+    }
+
+    /// Turn the pending bytes into a chunk
+    fn freeze_tail(&mut self) {
+        if !self.tail.is_empty() {
+            self.chunks.push(Buffer::new(self.tail.split().freeze()));
+        }
+    }
+
+    /// The total number of bytes in the builder
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the builder is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The chunks of the builder, in order
+    pub fn chunks(&mut self) -> &[Buffer] {
+        self.freeze_tail();
+        &self.chunks
+    }
+
+    /// Remove all the chunks from the builder
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.tail.clear();
+        self.len = 0;
+    }
+
+    /// Concatenate all the chunks in a single buffer
+    pub fn to_buffer(&mut self) -> Buffer {
+        match self.chunks() {
+            [chunk] => chunk.clone(),
+            chunks => Buffer::concat(chunks),
+        }
+    }
+
+    /// Write all the chunks to `writer`, in order
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        for chunk in &self.chunks {
+            writer.write_all(chunk).await?;
+        }
+        writer.write_all(&self.tail).await
+    }
+
+    /// Create a Lua function that returns a new empty builder
+    pub fn create_constructor(ctx: Context) -> Result<rlua::Function> {
+        ctx.create_function(|_, ()| Ok(StringBuilder::new()))
+    }
+}
+
+/// The `builder:write_to(writer)` method, loaded once per Lua state
+fn write_to(ctx: Context) -> Result<Function> {
+    if let Some(f) = ctx.named_registry_value::<_, Option<Function>>(WRITE_TO_REGISTRY_KEY)? {
+        return Ok(f);
+    }
+    let f = ctx
+        .load(WRITE_TO)
+        .set_name(b"rlua-async string builder write_to")?
+        .eval::<Function>()?;
+    ctx.set_named_registry_value(WRITE_TO_REGISTRY_KEY, f.clone())?;
+    Ok(f)
+}
+
+impl UserData for StringBuilder {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("push", |ctx, this, chunk: Value<'lua>| {
+            match chunk {
+                Value::UserData(ud) => this.push(ud.borrow::<Buffer>()?.clone()),
+                chunk => this.push_bytes(ctx.unpack::<rlua::String>(chunk)?.as_bytes()),
+            }
+            Ok(())
+        });
+
+        methods.add_method("len", |_, this, ()| Ok(this.len()));
+
+        methods.add_method_mut("chunks", |ctx, this, ()| {
+            let mut chunks = this.chunks().to_vec().into_iter();
+            ctx.create_function_mut(move |_, ()| Ok(chunks.next()))
+        });
+
+        methods.add_method_mut("to_buffer", |_, this, ()| Ok(this.to_buffer()));
+
+        methods.add_method_mut("clear", |_, this, ()| {
+            this.clear();
+            Ok(())
+        });
+
+        methods.add_meta_method(MetaMethod::Len, |_, this, ()| Ok(this.len()));
+
+        // Written in Lua, so that it can wait for the writes, and only found after the methods
+        methods.add_meta_function(
+            MetaMethod::Index,
+            |ctx, (_, key): (AnyUserData, Value)| match key {
+                Value::String(key) if key.as_bytes() == b"write_to" => {
+                    write_to(ctx).map(Value::Function)
+                }
+                _ => Ok(Value::Nil),
+            },
+        );
+
+        methods.add_meta_method_mut(MetaMethod::ToString, |ctx, this, ()| {
+            ctx.create_string(this.to_buffer().as_slice())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use rlua::{AnyUserData, Lua};
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn buffers_in_lua() {
+        Lua::new().context(|lua| {
+            let buf = Buffer::from(&b"hello, world"[..]);
+            lua.globals().set("buf", buf.clone()).unwrap();
+
+            let (len, sub, byte, cat): (usize, Buffer, u8, String) = lua
+                .load(
+                    r#"return #buf, buf:sub(-5, -2), buf:byte(2), tostring(buf:sub(1, 5) .. "!")"#,
+                )
+                .eval()
+                .unwrap();
+            assert_eq!(len, 12);
+            assert_eq!(sub.as_slice(), b"worl");
+            assert_eq!(sub.as_ptr(), buf[7..].as_ptr());
+            assert_eq!(byte, b'e');
+            assert_eq!(cat, "hello!");
+
+            assert!(lua
+                .load(r#"buf:sub(8, 100) == buf:sub(8)"#)
+                .eval::<bool>()
+                .unwrap());
+            assert_eq!(
+                lua.load(r#"buf:sub(5, 2):len()"#).eval::<usize>().unwrap(),
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn string_builder() {
+        Lua::new().context(|lua| {
+            let ctor = StringBuilder::create_constructor(lua).unwrap();
+            lua.globals().set("string_builder", ctor).unwrap();
+
+            let builder = lua
+                .load(
+                    r#"
+                        local b = string_builder()
+                        for i = 1, 1000 do
+                            b:push(tostring(i % 10))
+                        end
+                        b:push(b:to_buffer():sub(1, 2))
+                        b:push("3")
+                        local n = 0
+                        for chunk in b:chunks() do
+                            n = n + #chunk
+                        end
+                        assert(n == #b and n == b:len() and #tostring(b) == 1003)
+                        return b
+                    "#,
+                )
+                .eval::<AnyUserData>()
+                .unwrap();
+            let mut builder = builder.borrow_mut::<StringBuilder>().unwrap();
+            assert_eq!(builder.chunks().len(), 3);
+
+            let mut out = Vec::new();
+            executor::block_on(builder.write_to(&mut out)).unwrap();
+            assert_eq!(out.len(), 1003);
+            assert_eq!(&out[..12], b"123456789012");
+            assert_eq!(&out[997..], b"890123");
+            assert_eq!(builder.to_buffer().as_slice(), &out[..]);
+        });
+    }
+
+    /// A writer to a vector that outlives it
+    #[derive(Clone, Default)]
+    struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl AsyncWrite for Shared {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn string_builder_write_to() {
+        Lua::new().context(|lua| {
+            let ctor = StringBuilder::create_constructor(lua).unwrap();
+            lua.globals().set("string_builder", ctor).unwrap();
+            let (handle, output) = (Shared::default(), Shared::default());
+            lua.globals()
+                .set("handle", crate::io::WriteHandle::new(handle.clone()))
+                .unwrap();
+            lua.globals()
+                .set("output", crate::output::AsyncOutput::new(output.clone(), 4))
+                .unwrap();
+            executor::block_on(
+                lua.load(
+                    r#"
+                        local b = string_builder()
+                        b:push("hello, ")
+                        b:push("world")
+                        b:write_to(handle)
+                        b:write_to(output)
+                        output:flush()
+                        assert(b.missing == nil)
+                    "#,
+                )
+                .exec_async(lua),
+            )
+            .expect("failed to write");
+            assert_eq!(&*handle.0.lock().unwrap(), b"hello, world");
+            assert_eq!(&*output.0.lock().unwrap(), b"hello, world");
+        });
+    }
+}
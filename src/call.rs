@@ -0,0 +1,333 @@
+use std::{
+    error, fmt,
+    future::{Future, IntoFuture},
+    marker::PhantomData,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either},
+    stream::{FuturesUnordered, StreamExt},
+};
+use rlua::{Context, Error, FromLuaMulti, Function, MultiValue, Result, ToLuaMulti, Value};
+
+use crate::{CallAsyncFuture, ContextExt};
+
+static METHOD_CALL: &[u8] = include_bytes!("method-call.lua");
+static METHOD_CALL_REGISTRY_KEY: &str = "rlua-async method call";
+
+/// Prepare an async call of `obj:name(args)`, see eg.
+/// [`TableExt::call_async_method`](crate::TableExt::call_async_method)
+pub(crate) fn call_async_method<'lua, Arg, Ret>(
+    ctx: Context<'lua>,
+    obj: Value<'lua>,
+    name: &str,
+    args: Arg,
+) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+where
+    Arg: ToLuaMulti<'lua>,
+    Ret: FromLuaMulti<'lua>,
+{
+    let call = (|| {
+        let method_call =
+            match ctx.named_registry_value::<_, Option<Function>>(METHOD_CALL_REGISTRY_KEY)? {
+                Some(method_call) => method_call,
+                None => {
+                    let method_call = ctx
+                        .load(METHOD_CALL)
+                        .set_name(b"rlua-async method call")?
+                        .eval::<Function<'lua>>()?;
+                    ctx.set_named_registry_value(METHOD_CALL_REGISTRY_KEY, method_call.clone())?;
+                    method_call
+                }
+            };
+        let mut all_args = vec![obj, Value::String(ctx.create_string(name)?)];
+        all_args.extend(args.to_lua_multi(ctx)?);
+        Ok((method_call, MultiValue::from_vec(all_args)))
+    })();
+    match call {
+        Ok((method_call, args)) => CallAsyncFuture::from_function(ctx, Ok(method_call), args),
+        Err(e) => CallAsyncFuture::from_function(ctx, Err(e), MultiValue::new()),
+    }
+}
+
+/// The error returned by an async call that did not complete before its timeout
+///
+/// It is returned wrapped in an [`rlua::Error::ExternalError`], and can be recovered by
+/// downcasting.
+#[derive(Clone, Debug)]
+pub struct TimeoutError {
+    name: Option<String>,
+    timeout: Duration,
+}
+
+impl TimeoutError {
+    pub(crate) fn new(name: Option<String>, timeout: Duration) -> TimeoutError {
+        TimeoutError { name, timeout }
+    }
+
+    /// The name of the call that timed out, if it had one
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The timeout that was exceeded
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(
+                f,
+                "async call `{}` timed out after {:?}",
+                name, self.timeout
+            ),
+            None => write!(f, "async call timed out after {:?}", self.timeout),
+        }
+    }
+}
+
+impl error::Error for TimeoutError {}
+
+/// A builder for an async call to a Lua function, see [`FunctionExt::async_call`](crate::FunctionExt::async_call)
+///
+/// The call is only started when the builder is `.await`ed, or converted into a future with
+/// [`IntoFuture::into_future`].
+#[must_use = "async calls do nothing unless `.await`ed"]
+pub struct AsyncCall<'lua, Arg, Ret> {
+    func: Function<'lua>,
+    ctx: Context<'lua>,
+    args: Arg,
+    name: Option<String>,
+    timeout: Option<Duration>,
+    _phantom: PhantomData<fn() -> Ret>,
+}
+
+impl<'lua, Ret> AsyncCall<'lua, (), Ret> {
+    pub(crate) fn new(func: Function<'lua>, ctx: Context<'lua>) -> AsyncCall<'lua, (), Ret> {
+        AsyncCall {
+            func,
+            ctx,
+            args: (),
+            name: None,
+            timeout: None,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'lua, Arg, Ret> AsyncCall<'lua, Arg, Ret> {
+    /// Set the arguments the function will be called with
+    pub fn args<A>(self, args: A) -> AsyncCall<'lua, A, Ret> {
+        AsyncCall {
+            func: self.func,
+            ctx: self.ctx,
+            args,
+            name: self.name,
+            timeout: self.timeout,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Give a name to the call, that will be used in the errors it generates
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Make the call fail with a [`TimeoutError`] if it has not completed after `timeout`
+    ///
+    /// Once the timeout has elapsed, the Lua code of the call is not resumed any longer, even if
+    /// the call was woken at the same time, see [`CallAsyncFuture::set_deadline`]. A timeout too
+    /// large for its deadline to be represented, eg. [`Duration::MAX`], never elapses.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+impl<'lua, Arg, Ret> IntoFuture for AsyncCall<'lua, Arg, Ret>
+where
+    Arg: 'lua + ToLuaMulti<'lua>,
+    Ret: 'lua + FromLuaMulti<'lua>,
+{
+    type Output = Result<Ret>;
+    type IntoFuture = Pin<Box<dyn 'lua + Future<Output = Result<Ret>>>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let mut call = match CallAsyncFuture::new(self.ctx, self.func, self.args) {
+            Ok(call) => call,
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Box::pin(call),
+        };
+        // A timeout too far in the future to be represented never elapses
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return Box::pin(call),
+        };
+        let sleep = match self.ctx.spawner() {
+            Ok(spawner) => spawner.sleep(timeout),
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        let name = self.name;
+        call.set_deadline_error(deadline, TimeoutError::new(name.clone(), timeout));
+        Box::pin(async move {
+            match future::select(call, sleep).await {
+                Either::Left((res, _)) => res,
+                Either::Right(((), _)) => Err(Error::external(TimeoutError::new(name, timeout))),
+            }
+        })
+    }
+}
+
+/// Call all the `calls` concurrently, resolving to their results in the same order
+///
+/// All the threads are created up front, and the calls are then driven by a single future,
+/// that only resumes the threads whose call was woken. This is cheaper than awaiting each
+/// [`FunctionExt::call_async`](crate::FunctionExt::call_async) with eg.
+/// [`futures::future::join_all`], in particular when running many short hooks for one event.
+pub fn call_async_many<'lua, Arg, Ret>(
+    ctx: Context<'lua>,
+    calls: Vec<(Function<'lua>, Arg)>,
+) -> impl 'lua + Future<Output = Vec<Result<Ret>>>
+where
+    Arg: 'lua + ToLuaMulti<'lua>,
+    Ret: 'lua + FromLuaMulti<'lua>,
+{
+    let mut results = Vec::with_capacity(calls.len());
+    let running = FuturesUnordered::new();
+    for (i, (func, args)) in calls.into_iter().enumerate() {
+        match CallAsyncFuture::new(ctx, func, args) {
+            Ok(call) => {
+                results.push(None);
+                running.push(async move { (i, call.await) });
+            }
+            Err(e) => results.push(Some(Err(e))),
+        }
+    }
+    async move {
+        let mut running = running;
+        while let Some((i, res)) = running.next().await {
+            results[i] = Some(res);
+        }
+        results
+            .into_iter()
+            .map(|r| r.expect("call did not complete"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use futures_timer::Delay;
+    use rlua::Lua;
+
+    use crate::{ContextExt, FunctionExt};
+
+    #[test]
+    fn async_call_builder() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, a: u64| async move {
+                    Delay::new(Duration::from_millis(a)).await;
+                    Ok(a + 1)
+                })
+                .unwrap();
+
+            let res: u64 = executor::block_on(async {
+                f.async_call(lua)
+                    .args(10)
+                    .timeout(Duration::from_secs(10))
+                    .await
+            })
+            .expect("failed to call");
+            assert_eq!(res, 11);
+
+            let res: u64 = executor::block_on(async {
+                f.async_call(lua).args(1).timeout(Duration::MAX).await
+            })
+            .expect("failed to call");
+            assert_eq!(res, 2);
+
+            let res = executor::block_on(async {
+                f.async_call::<u64>(lua)
+                    .args(1000)
+                    .name("slow")
+                    .timeout(Duration::from_millis(10))
+                    .await
+            });
+            match res {
+                Err(Error::ExternalError(e)) => {
+                    let e = e.downcast_ref::<TimeoutError>().expect("not a timeout");
+                    assert_eq!(e.name(), Some("slow"));
+                    assert_eq!(e.timeout(), Duration::from_millis(10));
+                }
+                r => panic!("improper return for timed out call: {:?}", r),
+            }
+        });
+    }
+
+    #[test]
+    fn expired_deadline_skips_resume() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, ()| async move {
+                    Delay::new(Duration::from_millis(20)).await;
+                    Ok(())
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+            let func = lua
+                .load(r#"function() f() resumed = true end"#)
+                .eval::<Function>()
+                .unwrap();
+
+            let mut call = CallAsyncFuture::<_, ()>::new(lua, func, ()).unwrap();
+            call.set_deadline(Instant::now() + Duration::from_millis(10));
+            let res = executor::block_on(&mut call);
+            match res {
+                Err(Error::ExternalError(e)) => assert!(e.downcast_ref::<TimeoutError>().is_some()),
+                r => panic!("improper return for expired deadline: {:?}", r),
+            }
+            assert_eq!(call.resumes(), 1);
+            assert_eq!(
+                lua.globals().get::<_, Option<bool>>("resumed").unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn many_calls() {
+        Lua::new().context(|lua| {
+            let sleep = lua
+                .create_async_function(|_, ms: u64| async move {
+                    Delay::new(Duration::from_millis(ms)).await;
+                    Ok(())
+                })
+                .unwrap();
+            lua.globals().set("sleep", sleep).unwrap();
+            let hook = lua
+                .load(r#"function(ms) sleep(ms) if ms == 0 then error("boom") end return ms end"#)
+                .eval::<Function>()
+                .unwrap();
+
+            let calls = vec![(hook.clone(), 30), (hook.clone(), 0), (hook, 10)];
+            let res = executor::block_on(call_async_many::<_, u64>(lua, calls));
+            assert_eq!(res.len(), 3);
+            assert_eq!(res[0].as_ref().unwrap(), &30);
+            assert!(res[1].is_err());
+            assert_eq!(res[2].as_ref().unwrap(), &10);
+        });
+    }
+}
@@ -0,0 +1,1299 @@
+//! Channels to communicate between Lua tasks
+//!
+//! The values sent through the channels of [`install`] are kept in the Lua registry, so such a
+//! channel can only be used inside the Lua state it was created in. The [`bounded`],
+//! [`oneshot`](fn@oneshot) and [`broadcast`] channels and the [`Watch`] cells of
+//! [`PlainValue`]s, as well as the [`Event`]s, can be used from Lua as userdata, and passed
+//! between Lua and Rust.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, Mutex,
+    },
+    task::{self, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture},
+};
+use rlua::{Context, Error, Result, Table, UserData, UserDataMethods, Value};
+
+use crate::{
+    global_table,
+    plain::PlainValue,
+    registry::RegistryValue,
+    spawner::{DefaultSpawner, Spawner},
+    time::Millis,
+    ContextExt, UserDataMethodsExt,
+};
+
+/// Add `waker` to `wakers`, unless a waker that wakes the same task is already registered
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+struct Entry<T> {
+    priority: rlua::Number,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    /// Higher priorities first, then first sent first
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .total_cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct PriorityState<T> {
+    items: BinaryHeap<Entry<T>>,
+    next_seq: u64,
+    closed: bool,
+    receivers: Vec<Waker>,
+}
+
+/// An unbounded channel whose receivers get the highest-priority item first
+///
+/// Items with the same priority are received in the order they were sent. Cloning the channel
+/// gives another handle to it.
+pub struct PriorityChannel<T>(Arc<Mutex<PriorityState<T>>>);
+
+impl<T> Clone for PriorityChannel<T> {
+    fn clone(&self) -> Self {
+        PriorityChannel(self.0.clone())
+    }
+}
+
+impl<T> Default for PriorityChannel<T> {
+    fn default() -> Self {
+        PriorityChannel::new()
+    }
+}
+
+impl<T> PriorityChannel<T> {
+    /// Create an empty channel
+    pub fn new() -> PriorityChannel<T> {
+        PriorityChannel(Arc::new(Mutex::new(PriorityState {
+            items: BinaryHeap::new(),
+            next_seq: 0,
+            closed: false,
+            receivers: Vec::new(),
+        })))
+    }
+
+    /// Send `item` with `priority`, failing if the channel was closed
+    pub fn send(&self, item: T, priority: rlua::Number) -> std::result::Result<(), T> {
+        let mut state = self.0.lock().unwrap();
+        if state.closed {
+            return Err(item);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.items.push(Entry {
+            priority,
+            seq,
+            item,
+        });
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Receive the highest-priority item, waiting for one if the channel is empty, or `None`
+    /// once the channel is closed and empty
+    pub fn recv(&self) -> Recv<T> {
+        Recv(self.clone())
+    }
+
+    /// Receive the highest-priority item if there is one
+    pub fn try_recv(&self) -> Option<T> {
+        self.0.lock().unwrap().items.pop().map(|e| e.item)
+    }
+
+    /// The number of items waiting in the channel
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().items.len()
+    }
+
+    /// Whether the channel is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the channel: further sends fail, and receivers get `None` once the items already
+    /// sent have been received
+    pub fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by [`PriorityChannel::recv`]
+pub struct Recv<T>(PriorityChannel<T>);
+
+impl<T> Future for Recv<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Option<T>> {
+        let mut state = (self.0).0.lock().unwrap();
+        if let Some(entry) = state.items.pop() {
+            return Poll::Ready(Some(entry.item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        register(&mut state.receivers, fut_ctx.waker());
+        Poll::Pending
+    }
+}
+
+struct DelayState<T> {
+    items: BinaryHeap<Reverse<(Instant, u64, DelayItem<T>)>>,
+    next_seq: u64,
+    closed: bool,
+    receivers: Vec<Waker>,
+}
+
+/// Items are only ordered by their deadline and sequence number
+struct DelayItem<T>(T);
+
+impl<T> PartialEq for DelayItem<T> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl<T> Eq for DelayItem<T> {}
+
+impl<T> PartialOrd for DelayItem<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for DelayItem<T> {
+    fn cmp(&self, _: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+/// An unbounded queue whose items can only be received once their deadline has passed
+///
+/// Items are received in deadline order, and items with the same deadline in the order they
+/// were inserted. The receivers wait for the deadlines with the timers of a [`Spawner`].
+/// Cloning the queue gives another handle to it.
+pub struct DelayQueue<T> {
+    state: Arc<Mutex<DelayState<T>>>,
+    spawner: Arc<dyn Spawner>,
+}
+
+impl<T> Clone for DelayQueue<T> {
+    fn clone(&self) -> Self {
+        DelayQueue {
+            state: self.state.clone(),
+            spawner: self.spawner.clone(),
+        }
+    }
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        DelayQueue::new()
+    }
+}
+
+impl<T> DelayQueue<T> {
+    /// Create an empty queue, waiting with the timers of the [`DefaultSpawner`]
+    pub fn new() -> DelayQueue<T> {
+        DelayQueue::with_spawner(Arc::new(DefaultSpawner))
+    }
+
+    /// Create an empty queue, waiting with the timers of `spawner`, eg. the one of a Lua state
+    pub fn with_spawner(spawner: Arc<dyn Spawner>) -> DelayQueue<T> {
+        DelayQueue {
+            state: Arc::new(Mutex::new(DelayState {
+                items: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+                receivers: Vec::new(),
+            })),
+            spawner,
+        }
+    }
+
+    /// Insert `item`, to be received after `deadline`, failing if the queue was closed
+    pub fn insert_at(&self, item: T, deadline: Instant) -> std::result::Result<(), T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(item);
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.items.push(Reverse((deadline, seq, DelayItem(item))));
+        // The receivers may be waiting for a later deadline
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Insert `item`, to be received after `delay`, failing if the queue was closed or if `delay`
+    /// is too large to be represented as an [`Instant`]
+    pub fn insert(&self, item: T, delay: Duration) -> std::result::Result<(), T> {
+        match Instant::now().checked_add(delay) {
+            Some(deadline) => self.insert_at(item, deadline),
+            None => Err(item),
+        }
+    }
+
+    /// Receive the next item once it is due, or `None` once the queue is closed and empty
+    pub fn recv(&self) -> DelayRecv<T> {
+        DelayRecv {
+            queue: self.clone(),
+            timer: None,
+        }
+    }
+
+    /// The number of items in the queue, due or not
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    /// Whether the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the queue: further inserts fail, and receivers get `None` once the items already
+    /// inserted have been received
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for waker in state.receivers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The future returned by [`DelayQueue::recv`]
+pub struct DelayRecv<T> {
+    queue: DelayQueue<T>,
+    /// The timer for the earliest deadline when last polled
+    timer: Option<(Instant, BoxFuture<'static, ()>)>,
+}
+
+impl<T> Future for DelayRecv<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let mut state = this.queue.state.lock().unwrap();
+        let deadline = match state.items.peek() {
+            Some(Reverse((deadline, _, _))) => *deadline,
+            None if state.closed => return Poll::Ready(None),
+            None => {
+                register(&mut state.receivers, fut_ctx.waker());
+                return Poll::Pending;
+            }
+        };
+        if deadline <= Instant::now() {
+            let Reverse((_, _, item)) = state.items.pop().unwrap();
+            return Poll::Ready(Some(item.0));
+        }
+        register(&mut state.receivers, fut_ctx.waker());
+        drop(state);
+
+        if this.timer.as_ref().map(|(d, _)| *d) != Some(deadline) {
+            let sleep = this
+                .queue
+                .spawner
+                .sleep(deadline.saturating_duration_since(Instant::now()));
+            this.timer = Some((deadline, sleep));
+        }
+        let (_, timer) = this.timer.as_mut().unwrap();
+        if timer.as_mut().poll(fut_ctx).is_ready() {
+            // Due now, the next poll takes the item
+            fut_ctx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+struct BoundedState<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    closed: bool,
+    send_wakers: Vec<Waker>,
+    recv_wakers: Vec<Waker>,
+}
+
+impl<T> BoundedState<T> {
+    fn is_closed(&self) -> bool {
+        self.closed || self.receivers == 0
+    }
+}
+
+/// Create a channel holding at most `capacity` items, whose senders wait for room
+///
+/// Both ends can be cloned, the items going to whichever receiver asks first. The channel is
+/// closed once all the senders or all the receivers are dropped, or when closed explicitly.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    assert!(
+        capacity > 0,
+        "a bounded channel needs room for at least one item"
+    );
+    let state = Arc::new(Mutex::new(BoundedState {
+        items: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receivers: 1,
+        closed: false,
+        send_wakers: Vec::new(),
+        recv_wakers: Vec::new(),
+    }));
+    (BoundedSender(state.clone()), BoundedReceiver(state))
+}
+
+fn close<T>(state: &Mutex<BoundedState<T>>) {
+    let mut state = state.lock().unwrap();
+    state.closed = true;
+    state.send_wakers.drain(..).for_each(Waker::wake);
+    state.recv_wakers.drain(..).for_each(Waker::wake);
+}
+
+/// The sending end of a [`bounded`] channel
+///
+/// With `T` being [`PlainValue`], it is a userdata with the async method `tx:send(value)`, that
+/// fails if the channel is closed, and the method `tx:close()`.
+pub struct BoundedSender<T>(Arc<Mutex<BoundedState<T>>>);
+
+impl<T> BoundedSender<T> {
+    /// Send `item`, waiting for room in the channel, or give it back if the channel is closed
+    pub fn send(&self, item: T) -> BoundedSend<T> {
+        BoundedSend {
+            state: self.0.clone(),
+            item: Some(item),
+        }
+    }
+
+    /// Send `item` if there is room in the channel, or give it back
+    pub fn try_send(&self, item: T) -> std::result::Result<(), T> {
+        let mut state = self.0.lock().unwrap();
+        if state.is_closed() || state.items.len() >= state.capacity {
+            return Err(item);
+        }
+        state.items.push_back(item);
+        state.recv_wakers.drain(..).for_each(Waker::wake);
+        Ok(())
+    }
+
+    /// Whether the channel is closed
+    pub fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().is_closed()
+    }
+
+    /// Close the channel: further sends fail, and receivers get `None` once the items already
+    /// sent have been received
+    pub fn close(&self) {
+        close(&self.0)
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().senders += 1;
+        BoundedSender(self.0.clone())
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.recv_wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+impl UserData for BoundedSender<PlainValue> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("send", |_, this, value: Value| {
+            let send = PlainValue::from_lua_value(value).map(|v| this.send(v));
+            async move {
+                send?
+                    .await
+                    .map_err(|_| Error::RuntimeError("send on a closed channel".to_string()))
+            }
+        });
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+    }
+}
+
+/// The future returned by [`BoundedSender::send`]
+pub struct BoundedSend<T> {
+    state: Arc<Mutex<BoundedState<T>>>,
+    item: Option<T>,
+}
+
+impl<T> Unpin for BoundedSend<T> {}
+
+impl<T> Future for BoundedSend<T> {
+    type Output = std::result::Result<(), T>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.state.lock().unwrap();
+        let item = this
+            .item
+            .take()
+            .expect("bounded send polled after completion");
+        if state.is_closed() {
+            return Poll::Ready(Err(item));
+        }
+        if state.items.len() >= state.capacity {
+            this.item = Some(item);
+            register(&mut state.send_wakers, fut_ctx.waker());
+            return Poll::Pending;
+        }
+        state.items.push_back(item);
+        state.recv_wakers.drain(..).for_each(Waker::wake);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The receiving end of a [`bounded`] channel
+///
+/// With `T` being [`PlainValue`], it is a userdata with the async method `rx:recv()`, that
+/// returns `nil` once the channel is closed and empty, and the methods `rx:len()` and
+/// `rx:close()`.
+pub struct BoundedReceiver<T>(Arc<Mutex<BoundedState<T>>>);
+
+impl<T> BoundedReceiver<T> {
+    /// Receive the next item, waiting for one if the channel is empty, or `None` once the
+    /// channel is closed and empty
+    pub fn recv(&self) -> BoundedRecv<T> {
+        BoundedRecv(self.0.clone())
+    }
+
+    /// Receive the next item if there is one
+    pub fn try_recv(&self) -> Option<T> {
+        let mut state = self.0.lock().unwrap();
+        let item = state.items.pop_front();
+        if item.is_some() {
+            state.send_wakers.drain(..).for_each(Waker::wake);
+        }
+        item
+    }
+
+    /// The number of items waiting in the channel
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().items.len()
+    }
+
+    /// Whether the channel is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the channel: further sends fail, and receivers get `None` once the items already
+    /// sent have been received
+    pub fn close(&self) {
+        close(&self.0)
+    }
+}
+
+impl<T> Clone for BoundedReceiver<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().receivers += 1;
+        BoundedReceiver(self.0.clone())
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            state.send_wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+impl UserData for BoundedReceiver<PlainValue> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("recv", |_, this, ()| {
+            let recv = this.recv();
+            async move { Ok(recv.await) }
+        });
+        methods.add_method("len", |_, this, ()| Ok(this.len()));
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+    }
+}
+
+/// The future returned by [`BoundedReceiver::recv`]
+pub struct BoundedRecv<T>(Arc<Mutex<BoundedState<T>>>);
+
+impl<T> Future for BoundedRecv<T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Option<T>> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            state.send_wakers.drain(..).for_each(Waker::wake);
+            return Poll::Ready(Some(item));
+        }
+        if state.closed || state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        register(&mut state.recv_wakers, fut_ctx.waker());
+        Poll::Pending
+    }
+}
+
+/// Create a channel for a single [`PlainValue`], whose ends are userdata
+pub fn oneshot() -> (OneshotSender, OneshotReceiver) {
+    let (tx, rx) = oneshot::channel();
+    (tx.into(), rx.into())
+}
+
+/// The sending end of a [`oneshot`](fn@oneshot) channel
+///
+/// It is a userdata with the method `tx:send(value)`, that fails if a value was already sent or
+/// the receiver was dropped, and `tx:close()`, that drops the sender so that the receiver fails.
+/// Rust code can give Lua the sender of a [`futures::channel::oneshot`] channel by converting it.
+pub struct OneshotSender(Mutex<Option<oneshot::Sender<PlainValue>>>);
+
+impl OneshotSender {
+    /// Send `value`, or give it back if a value was already sent or the receiver was dropped
+    pub fn send(&self, value: PlainValue) -> std::result::Result<(), PlainValue> {
+        match self.0.lock().unwrap().take() {
+            Some(tx) => tx.send(value),
+            None => Err(value),
+        }
+    }
+
+    /// Drop the sender, making the receiver fail if no value was sent
+    pub fn close(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+impl From<oneshot::Sender<PlainValue>> for OneshotSender {
+    fn from(tx: oneshot::Sender<PlainValue>) -> OneshotSender {
+        OneshotSender(Mutex::new(Some(tx)))
+    }
+}
+
+impl UserData for OneshotSender {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("send", |_, this, value: Value| {
+            this.send(PlainValue::from_lua_value(value)?).map_err(|_| {
+                Error::RuntimeError(
+                    "send on a oneshot channel that was already used or closed".to_string(),
+                )
+            })
+        });
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+    }
+}
+
+/// The receiving end of a [`oneshot`](fn@oneshot) channel
+///
+/// It is a userdata with the async method `rx:recv()`, that returns the value once it is sent,
+/// and fails if the sender was dropped without sending one or the value was already received.
+/// Only one task should wait for the value at a time. Rust code can give Lua the receiver of a
+/// [`futures::channel::oneshot`] channel by converting it.
+pub struct OneshotReceiver(Arc<Mutex<Option<oneshot::Receiver<PlainValue>>>>);
+
+impl OneshotReceiver {
+    /// Wait for the value
+    pub fn recv(&self) -> impl Future<Output = Result<PlainValue>> {
+        let rx = self.0.clone();
+        future::poll_fn(move |fut_ctx| {
+            let mut rx = rx.lock().unwrap();
+            let res = match rx.as_mut() {
+                Some(recv) => futures::ready!(Pin::new(recv).poll(fut_ctx)).map_err(|_| {
+                    Error::RuntimeError(
+                        "the oneshot sender was dropped without sending a value".to_string(),
+                    )
+                }),
+                None => Err(Error::RuntimeError(
+                    "the value of the oneshot channel was already received".to_string(),
+                )),
+            };
+            *rx = None;
+            Poll::Ready(res)
+        })
+    }
+}
+
+impl From<oneshot::Receiver<PlainValue>> for OneshotReceiver {
+    fn from(rx: oneshot::Receiver<PlainValue>) -> OneshotReceiver {
+        OneshotReceiver(Arc::new(Mutex::new(Some(rx))))
+    }
+}
+
+impl UserData for OneshotReceiver {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("recv", |_, this, ()| this.recv());
+    }
+}
+
+struct BroadcastState<T> {
+    /// The last `capacity` items sent, the last one having the sequence number `next_seq - 1`
+    items: VecDeque<T>,
+    capacity: usize,
+    next_seq: u64,
+    senders: usize,
+    receivers: usize,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+/// Create a channel whose items are received by all of its receivers, keeping the last
+/// `capacity` items for the receivers that lag behind
+///
+/// Sending never waits: past `capacity` items not yet received by some receiver, the oldest one
+/// is dropped, and that receiver gets [`BroadcastRecvError::Lagged`]. The receivers are created
+/// with [`BroadcastSender::subscribe`], and only get the items sent after that.
+///
+/// # Panics
+///
+/// Panics if `capacity` is 0.
+pub fn broadcast<T: Clone>(capacity: usize) -> BroadcastSender<T> {
+    assert!(
+        capacity > 0,
+        "a broadcast channel needs room for at least one item"
+    );
+    BroadcastSender(Arc::new(Mutex::new(BroadcastState {
+        items: VecDeque::with_capacity(capacity),
+        capacity,
+        next_seq: 0,
+        senders: 1,
+        receivers: 0,
+        closed: false,
+        wakers: Vec::new(),
+    })))
+}
+
+/// The sending end of a [`broadcast`] channel
+///
+/// With `T` being [`PlainValue`], it is a userdata with the methods `tx:send(value)`, that
+/// returns the number of receivers, `tx:subscribe()`, that returns a new receiver, and
+/// `tx:close()`.
+pub struct BroadcastSender<T>(Arc<Mutex<BroadcastState<T>>>);
+
+impl<T: Clone> BroadcastSender<T> {
+    /// Send `item` to the receivers, returning their number, or give it back if the channel is
+    /// closed
+    pub fn send(&self, item: T) -> std::result::Result<usize, T> {
+        let mut state = self.0.lock().unwrap();
+        if state.closed {
+            return Err(item);
+        }
+        if state.items.len() >= state.capacity {
+            state.items.pop_front();
+        }
+        state.items.push_back(item);
+        state.next_seq += 1;
+        state.wakers.drain(..).for_each(Waker::wake);
+        Ok(state.receivers)
+    }
+
+    /// Create a receiver for the items sent from now on
+    pub fn subscribe(&self) -> BroadcastReceiver<T> {
+        let mut state = self.0.lock().unwrap();
+        state.receivers += 1;
+        BroadcastReceiver {
+            state: self.0.clone(),
+            next_seq: Arc::new(AtomicU64::new(state.next_seq)),
+        }
+    }
+
+    /// The number of receivers
+    pub fn receivers(&self) -> usize {
+        self.0.lock().unwrap().receivers
+    }
+
+    /// Close the channel: further sends fail, and the receivers get
+    /// [`BroadcastRecvError::Closed`] once they received the items already sent
+    pub fn close(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.closed = true;
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().senders += 1;
+        BroadcastSender(self.0.clone())
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+impl UserData for BroadcastSender<PlainValue> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("send", |_, this, value: Value| {
+            this.send(PlainValue::from_lua_value(value)?)
+                .map_err(|_| Error::RuntimeError("send on a closed channel".to_string()))
+        });
+        methods.add_method("subscribe", |_, this, ()| Ok(this.subscribe()));
+        methods.add_method("close", |_, this, ()| {
+            this.close();
+            Ok(())
+        });
+    }
+}
+
+/// The error of [`BroadcastReceiver::recv`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BroadcastRecvError {
+    /// The receiver lagged so far behind that this number of items were dropped before it got
+    /// them. The next call returns the oldest item still kept.
+    Lagged(u64),
+    /// The channel is closed, or all its senders were dropped, and the receiver got all the items
+    Closed,
+}
+
+impl fmt::Display for BroadcastRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BroadcastRecvError::Lagged(n) => write!(f, "the receiver lagged behind by {} items", n),
+            BroadcastRecvError::Closed => write!(f, "the channel is closed"),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastRecvError {}
+
+/// A receiving end of a [`broadcast`] channel
+///
+/// Cloning the receiver gives a receiver at the same position in the channel. With `T` being
+/// [`PlainValue`], it is a userdata with the async method `rx:recv()`, that returns the next
+/// value, `nil, "lagged", n` if `n` values were dropped before the receiver got them, or
+/// `nil, "closed"`.
+pub struct BroadcastReceiver<T> {
+    state: Arc<Mutex<BroadcastState<T>>>,
+    /// The sequence number of the next item to receive
+    next_seq: Arc<AtomicU64>,
+}
+
+impl<T: Clone> BroadcastReceiver<T> {
+    /// Receive the next item, waiting for it to be sent
+    pub fn recv(&self) -> BroadcastRecv<T> {
+        BroadcastRecv(BroadcastReceiver {
+            state: self.state.clone(),
+            next_seq: self.next_seq.clone(),
+        })
+    }
+}
+
+impl<T> Clone for BroadcastReceiver<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().unwrap().receivers += 1;
+        let next_seq = self.next_seq.load(atomic::Ordering::SeqCst);
+        BroadcastReceiver {
+            state: self.state.clone(),
+            next_seq: Arc::new(AtomicU64::new(next_seq)),
+        }
+    }
+}
+
+impl<T> Drop for BroadcastReceiver<T> {
+    fn drop(&mut self) {
+        // The futures of `recv` share the position of their receiver, but are not counted
+        if Arc::strong_count(&self.next_seq) == 1 {
+            self.state.lock().unwrap().receivers -= 1;
+        }
+    }
+}
+
+impl UserData for BroadcastReceiver<PlainValue> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("recv", |_, this, ()| {
+            let recv = this.recv();
+            async move {
+                Ok(match recv.await {
+                    Ok(value) => (value, None, None),
+                    Err(BroadcastRecvError::Lagged(n)) => {
+                        (PlainValue::Nil, Some("lagged"), Some(n))
+                    }
+                    Err(BroadcastRecvError::Closed) => (PlainValue::Nil, Some("closed"), None),
+                })
+            }
+        });
+    }
+}
+
+/// The future returned by [`BroadcastReceiver::recv`]
+pub struct BroadcastRecv<T>(BroadcastReceiver<T>);
+
+impl<T: Clone> Future for BroadcastRecv<T> {
+    type Output = std::result::Result<T, BroadcastRecvError>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Self::Output> {
+        let mut state = self.0.state.lock().unwrap();
+        let next_seq = self.0.next_seq.load(atomic::Ordering::SeqCst);
+        let oldest_seq = state.next_seq - state.items.len() as u64;
+        if next_seq < oldest_seq {
+            self.0.next_seq.store(oldest_seq, atomic::Ordering::SeqCst);
+            return Poll::Ready(Err(BroadcastRecvError::Lagged(oldest_seq - next_seq)));
+        }
+        if next_seq < state.next_seq {
+            self.0
+                .next_seq
+                .store(next_seq + 1, atomic::Ordering::SeqCst);
+            let item = state.items[(next_seq - oldest_seq) as usize].clone();
+            return Poll::Ready(Ok(item));
+        }
+        if state.closed || state.senders == 0 {
+            return Poll::Ready(Err(BroadcastRecvError::Closed));
+        }
+        register(&mut state.wakers, fut_ctx.waker());
+        Poll::Pending
+    }
+}
+
+struct WatchState<T> {
+    value: T,
+    /// The number of times the value was set
+    version: u64,
+    wakers: Vec<Waker>,
+}
+
+/// A value that can be watched for changes, eg. the configuration of an application that is
+/// reloaded while it runs
+///
+/// Cloning the cell gives another handle to it. With `T` being [`PlainValue`], it is a userdata
+/// with the methods `w:get()`, `w:set(value)` and the async `w:changed()`, that waits for the
+/// value to be set again and returns the new value.
+pub struct Watch<T>(Arc<Mutex<WatchState<T>>>);
+
+impl<T> Clone for Watch<T> {
+    fn clone(&self) -> Self {
+        Watch(self.0.clone())
+    }
+}
+
+impl<T: Clone> Watch<T> {
+    /// Create a cell holding `value`
+    pub fn new(value: T) -> Watch<T> {
+        Watch(Arc::new(Mutex::new(WatchState {
+            value,
+            version: 0,
+            wakers: Vec::new(),
+        })))
+    }
+
+    /// The current value
+    pub fn get(&self) -> T {
+        self.0.lock().unwrap().value.clone()
+    }
+
+    /// Replace the value, waking the tasks waiting for a change
+    pub fn set(&self, value: T) {
+        let mut state = self.0.lock().unwrap();
+        state.value = value;
+        state.version += 1;
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    /// Wait for the value to be set after this call, and return the new value
+    ///
+    /// When the value is set several times before the future is polled, the future returns the
+    /// last value.
+    pub fn changed(&self) -> Changed<T> {
+        let version = self.0.lock().unwrap().version;
+        Changed {
+            watch: self.clone(),
+            version,
+        }
+    }
+}
+
+impl UserData for Watch<PlainValue> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |_, this, ()| Ok(this.get()));
+        methods.add_method("set", |_, this, value: Value| {
+            this.set(PlainValue::from_lua_value(value)?);
+            Ok(())
+        });
+        methods.add_async_method("changed", |_, this, ()| {
+            let changed = this.changed();
+            async move { Ok(changed.await) }
+        });
+    }
+}
+
+/// The future returned by [`Watch::changed`]
+pub struct Changed<T> {
+    watch: Watch<T>,
+    /// The version of the value when the future was created
+    version: u64,
+}
+
+impl<T: Clone> Future for Changed<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<T> {
+        let mut state = self.watch.0.lock().unwrap();
+        if state.version > self.version {
+            return Poll::Ready(state.value.clone());
+        }
+        register(&mut state.wakers, fut_ctx.waker());
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct EventState {
+    set: bool,
+    wakers: Vec<Waker>,
+}
+
+/// A flag that tasks can wait for, eg. to signal that a level was loaded or that a shutdown was
+/// requested
+///
+/// Cloning the event gives another handle to it. It is a userdata with the async method
+/// `e:wait()`, and the methods `e:set()`, `e:reset()` and `e:is_set()`.
+#[derive(Clone, Default)]
+pub struct Event(Arc<Mutex<EventState>>);
+
+impl Event {
+    /// Create an event that is not set
+    pub fn new() -> Event {
+        Event::default()
+    }
+
+    /// Set the event, waking the tasks waiting for it
+    pub fn set(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.set = true;
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    /// Unset the event, so that the next waits wait for it to be set again
+    pub fn reset(&self) {
+        self.0.lock().unwrap().set = false;
+    }
+
+    /// Whether the event is set
+    pub fn is_set(&self) -> bool {
+        self.0.lock().unwrap().set
+    }
+
+    /// Wait for the event to be set, completing right away if it is
+    pub fn wait(&self) -> impl Future<Output = ()> {
+        let state = self.0.clone();
+        future::poll_fn(move |fut_ctx| {
+            let mut state = state.lock().unwrap();
+            if state.set {
+                return Poll::Ready(());
+            }
+            register(&mut state.wakers, fut_ctx.waker());
+            Poll::Pending
+        })
+    }
+}
+
+impl UserData for Event {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("wait", |_, this, ()| this.wait());
+        methods.add_method("set", |_, this, ()| {
+            this.set();
+            Ok(())
+        });
+        methods.add_method("reset", |_, this, ()| {
+            this.reset();
+            Ok(())
+        });
+        methods.add_method("is_set", |_, this, ()| Ok(this.is_set()));
+    }
+}
+
+fn delay_handle<'lua>(ctx: Context<'lua>) -> Result<Table<'lua>> {
+    let queue = DelayQueue::<RegistryValue>::with_spawner(ctx.spawner()?);
+    let handle = ctx.create_table()?;
+
+    let q = queue.clone();
+    handle.set(
+        "insert",
+        ctx.create_function(move |ctx, (_, item, delay): (Value, Value, Millis)| {
+            let deadline = Instant::now()
+                .checked_add(delay.0)
+                .ok_or_else(|| Error::RuntimeError(format!("delay out of range: {:?}", delay.0)))?;
+            q.insert_at(RegistryValue::new(ctx, item)?, deadline)
+                .map_err(|_| Error::RuntimeError("insert in a closed queue".to_string()))
+        })?,
+    )?;
+
+    let q = queue.clone();
+    handle.set(
+        "recv",
+        ctx.create_named_async_function("delay queue recv", move |_, _: Value| {
+            let recv = q.recv();
+            async move { Ok(recv.await) }
+        })?,
+    )?;
+
+    let q = queue.clone();
+    handle.set("len", ctx.create_function(move |_, _: Value| Ok(q.len()))?)?;
+
+    handle.set(
+        "close",
+        ctx.create_function(move |_, _: Value| {
+            queue.close();
+            Ok(())
+        })?,
+    )?;
+    Ok(handle)
+}
+
+fn priority_handle<'lua>(ctx: Context<'lua>) -> Result<Table<'lua>> {
+    let chan = PriorityChannel::<RegistryValue>::new();
+    let handle = ctx.create_table()?;
+
+    let c = chan.clone();
+    handle.set(
+        "send",
+        ctx.create_function(
+            move |ctx, (_, item, priority): (Value, Value, Option<rlua::Number>)| {
+                c.send(RegistryValue::new(ctx, item)?, priority.unwrap_or(0.))
+                    .map_err(|_| Error::RuntimeError("send on a closed channel".to_string()))
+            },
+        )?,
+    )?;
+
+    let c = chan.clone();
+    handle.set(
+        "recv",
+        ctx.create_named_async_function("channel recv", move |_, _: Value| {
+            let recv = c.recv();
+            async move { Ok(recv.await) }
+        })?,
+    )?;
+
+    let c = chan.clone();
+    handle.set("len", ctx.create_function(move |_, _: Value| Ok(c.len()))?)?;
+
+    handle.set(
+        "close",
+        ctx.create_function(move |_, _: Value| {
+            chan.close();
+            Ok(())
+        })?,
+    )?;
+    Ok(handle)
+}
+
+/// Install the channel constructors in the `channel` table of the globals, creating it if need
+/// be
+///
+/// The installed functions are:
+///  * `channel.priority()`, that creates a [`PriorityChannel`] and returns a handle with the
+///    methods `ch:send(item, priority)` (`priority` being a number defaulting to 0, higher
+///    priorities being received first), the async `ch:recv()` that returns `nil` once the
+///    channel is closed and empty, `ch:len()` and `ch:close()`.
+///  * `channel.delay()`, that creates a [`DelayQueue`] and returns a handle with the methods
+///    `q:insert(item, delay)` (`delay` being in milliseconds), the async `q:recv()` that returns
+///    the next item once it is due, or `nil` once the queue is closed and empty, `q:len()` and
+///    `q:close()`.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let channel = global_table(ctx, "channel")?;
+    channel.set(
+        "priority",
+        ctx.create_function(|ctx, ()| priority_handle(ctx))?,
+    )?;
+    channel.set("delay", ctx.create_function(|ctx, ()| delay_handle(ctx))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn priority_channel() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let order = executor::block_on(
+                lua.load(
+                    r#"
+                        local ch = channel.priority()
+                        ch:send("low", -1)
+                        ch:send("normal")
+                        ch:send("urgent", 10)
+                        ch:send("normal too")
+                        ch:close()
+                        assert(not pcall(ch.send, ch, "late"))
+                        local order = {}
+                        while true do
+                            local item = ch:recv()
+                            if item == nil then break end
+                            order[#order + 1] = item
+                        end
+                        return table.concat(order, ",")
+                    "#,
+                )
+                .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(order, "urgent,normal,normal too,low");
+        });
+    }
+
+    /// A spawner counting the timers it creates
+    struct CountingSleeps(Arc<AtomicU64>);
+
+    impl Spawner for CountingSleeps {
+        fn spawn(
+            &self,
+            fut: BoxFuture<'static, ()>,
+        ) -> std::result::Result<(), futures::task::SpawnError> {
+            DefaultSpawner.spawn(fut)
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.0.fetch_add(1, atomic::Ordering::SeqCst);
+            DefaultSpawner.sleep(duration)
+        }
+    }
+
+    #[test]
+    fn delay_queue() {
+        let sleeps = Arc::new(AtomicU64::new(0));
+        Lua::new().context(|lua| {
+            lua.set_spawner(CountingSleeps(sleeps.clone())).unwrap();
+            install(lua).unwrap();
+            let start = Instant::now();
+            let order = executor::block_on(
+                lua.load(
+                    r#"
+                        local q = channel.delay()
+                        assert(not pcall(q.insert, q, "never", 1e19))
+                        q:insert("late", 30)
+                        q:insert("soon", 10)
+                        q:insert("now", 0)
+                        q:close()
+                        local order = {}
+                        for item in q.recv, q do
+                            order[#order + 1] = item
+                        end
+                        return table.concat(order, ",")
+                    "#,
+                )
+                .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(order, "now,soon,late");
+            assert!(start.elapsed() >= Duration::from_millis(30));
+            assert!(sleeps.load(atomic::Ordering::SeqCst) >= 2);
+        });
+    }
+
+    #[test]
+    fn bounded_channel() {
+        let (tx, rx) = bounded(1);
+        executor::block_on(async {
+            tx.send(1).await.unwrap();
+            assert_eq!(tx.try_send(2), Err(2));
+            let mut blocked = tx.send(2);
+            assert!(futures::poll!(&mut blocked).is_pending());
+            assert_eq!(rx.recv().await, Some(1));
+            blocked.await.unwrap();
+
+            let other = tx.clone();
+            drop(tx);
+            assert!(!other.is_closed());
+            drop(other);
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, None);
+
+            let (tx, rx) = bounded::<u8>(1);
+            drop(rx);
+            assert_eq!(tx.send(3).await, Err(3));
+        });
+    }
+
+    #[test]
+    fn broadcast_channel() {
+        let tx = broadcast(2);
+        let first = tx.subscribe();
+        assert_eq!(tx.send(1), Ok(1));
+        let second = tx.subscribe();
+        assert_eq!(tx.send(2), Ok(2));
+        assert_eq!(tx.send(3), Ok(2));
+        executor::block_on(async {
+            assert_eq!(first.recv().await, Err(BroadcastRecvError::Lagged(1)));
+            assert_eq!(first.recv().await, Ok(2));
+            assert_eq!(first.recv().await, Ok(3));
+            assert_eq!(second.recv().await, Ok(2));
+            let third = second.clone();
+            assert_eq!(tx.receivers(), 3);
+            drop(tx);
+            assert_eq!(second.recv().await, Ok(3));
+            assert_eq!(third.recv().await, Ok(3));
+            assert_eq!(first.recv().await, Err(BroadcastRecvError::Closed));
+        });
+    }
+}
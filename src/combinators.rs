@@ -0,0 +1,172 @@
+//! Combinators over async calls
+//!
+//! The combinators are available both to Rust, and to Lua in the `task` table installed by
+//! [`install`]. In Lua, they run the calls they combine as spawned tasks, see
+//! [`ContextExt::spawn`], so that only the calls that were woken are resumed, and the calls
+//! they no longer need are cancelled as soon as they are done.
+
+use std::{future::Future, task::Poll, time::Duration};
+
+use futures::{
+    future::{self, Either},
+    pin_mut,
+};
+use rlua::{Context, Function, MultiValue, Result};
+
+use crate::{
+    global_table, make_async, spawn, spawner::Spawner, time::Millis, ContextExt, TaskHandle,
+};
+
+/// Run `attempt`, and run it a second time if the first run has not completed after `delay`,
+/// returning the result of whichever run completes first and dropping the other
+///
+/// The delay is waited for with the timers of `spawner`, eg. the [`ContextExt::spawner`] of a
+/// Lua state.
+///
+/// This trades a bit more load for lower tail latency, eg. for idempotent requests to replicated
+/// backends.
+pub async fn hedge<T, Fut, F>(spawner: &dyn Spawner, delay: Duration, mut attempt: F) -> T
+where
+    Fut: Future<Output = T>,
+    F: FnMut() -> Fut,
+{
+    let first = attempt();
+    pin_mut!(first);
+    let first = match future::select(first, spawner.sleep(delay)).await {
+        Either::Left((res, _)) => return res,
+        Either::Right(((), first)) => first,
+    };
+    let second = attempt();
+    pin_mut!(second);
+    future::select(first, second).await.factor_first().0
+}
+
+/// `task.hedge`
+fn lua_hedge<'lua>(
+    ctx: Context<'lua>,
+    (delay, f, args): (Millis, Function<'lua>, MultiValue<'lua>),
+) -> Result<Function<'lua>> {
+    let mut sleep = Some(ctx.spawner()?.sleep(delay.0));
+    let mut attempts = vec![ctx.spawn(f.clone(), args.clone())?];
+    // Kept for the second attempt, as the poller cannot hold Lua values
+    let f = ctx.create_registry_value(f)?;
+    let args = args
+        .into_iter()
+        .map(|v| ctx.create_registry_value(v))
+        .collect::<Result<Vec<_>>>()?;
+    spawn::poller(ctx, move |ctx, fut_ctx| {
+        for attempt in &attempts {
+            if let Poll::Ready(res) = attempt.poll_values(ctx, fut_ctx) {
+                attempts.iter().for_each(TaskHandle::cancel);
+                // Drop the futures of the other attempt right away
+                return match spawn::poll_tasks(ctx, fut_ctx) {
+                    Ok(_) => Poll::Ready(res),
+                    Err(e) => Poll::Ready(Err(e)),
+                };
+            }
+        }
+        if let Some(timer) = &mut sleep {
+            if timer.as_mut().poll(fut_ctx).is_ready() {
+                sleep = None;
+                let second = ctx.registry_value::<Function>(&f).and_then(|f| {
+                    let args = args.iter().map(|k| ctx.registry_value(k));
+                    ctx.spawn(f, MultiValue::from_vec(args.collect::<Result<_>>()?))
+                });
+                match second {
+                    Ok(second) => attempts.push(second),
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// Install the combinators in the `task` table of the globals, creating it if need be
+///
+/// The installed functions are:
+///  * `task.hedge(delay, f, ...)`, that calls `f(...)`, and calls it a second time if the first
+///    call has not returned after `delay` milliseconds, returning the results of whichever call
+///    returns (or raises an error) first, see [`hedge`]. The other call is then cancelled, like
+///    with `task:cancel()` of the `async` library: it is never resumed again, and its pending
+///    async calls are dropped when it is garbage-collected.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    global_table(ctx, "task")?.set(
+        "hedge",
+        make_async(ctx, "task.hedge", ctx.create_function(lua_hedge)?)?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use futures::executor;
+    use futures_timer::Delay;
+    use rlua::Lua;
+
+    use crate::{spawner::DefaultSpawner, ChunkExt};
+
+    #[test]
+    fn hedged_calls() {
+        let attempts = AtomicUsize::new(0);
+        let res = executor::block_on(hedge(&DefaultSpawner, Duration::from_millis(10), || {
+            let n = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                Delay::new(Duration::from_millis(if n == 0 { 1000 } else { 1 })).await;
+                n
+            }
+        }));
+        assert_eq!(res, 1);
+
+        struct Dropped(Arc<AtomicUsize>);
+
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let (a, d) = (attempts.clone(), dropped.clone());
+            let backend = lua
+                .create_async_function(move |_, ()| {
+                    let n = a.fetch_add(1, Ordering::SeqCst);
+                    let guard = Dropped(d.clone());
+                    async move {
+                        let _guard = guard;
+                        Delay::new(Duration::from_millis(if n == 0 { 1000 } else { 1 })).await;
+                        Ok(n)
+                    }
+                })
+                .unwrap();
+            lua.globals().set("backend", backend).unwrap();
+            let d = dropped.clone();
+            let count = lua
+                .create_function(move |_, ()| Ok(d.load(Ordering::SeqCst)))
+                .unwrap();
+            lua.globals().set("dropped", count).unwrap();
+            let (n, extra, dropped) = executor::block_on(
+                lua.load(
+                    r#"
+                        local n, x = task.hedge(10, function(x) return backend(), x end, "x")
+                        collectgarbage()
+                        return n, x, dropped()
+                    "#,
+                )
+                .call_async::<_, (usize, String, usize)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!((n, extra.as_str(), dropped), (1, "x", 2));
+        });
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,152 @@
+//! Driving existing Lua coroutines from async Rust
+
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use rlua::{Context, FromLuaMulti, MultiValue, Result, Thread, ThreadStatus, ToLuaMulti};
+
+use crate::{depth::DepthGuard, is_pending_yield, stream::CoroutineStream, FUTURE_CTX};
+
+/// Extension trait for [`rlua::Thread`]
+pub trait ThreadExt<'lua> {
+    /// Resume the thread in an async-compliant way. See also [`rlua::Thread::resume`].
+    ///
+    /// The future completes with the values the coroutine next `coroutine.yield`s, or with its
+    /// return values if it finishes. The yields generated by the async functions it calls, eg.
+    /// created with [`ContextExt::create_async_function`](crate::ContextExt), are instead
+    /// waited for. This makes it possible to drive coroutines created by Lua code itself.
+    fn resume_async<Arg, Ret>(&self, ctx: Context<'lua>, args: Arg) -> ResumeAsync<'lua, Arg, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>;
+
+    /// Consume the values the coroutine yields as a [`Stream`](futures::Stream), see
+    /// [`coroutine_to_stream`](crate::stream::coroutine_to_stream)
+    fn into_stream<T: FromLuaMulti<'lua>>(self, ctx: Context<'lua>) -> CoroutineStream<'lua, T>;
+}
+
+impl<'lua> ThreadExt<'lua> for Thread<'lua> {
+    fn resume_async<Arg, Ret>(&self, ctx: Context<'lua>, args: Arg) -> ResumeAsync<'lua, Arg, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        ResumeAsync {
+            ctx,
+            thread: self.clone(),
+            args: Some(args),
+            _phantom: PhantomData,
+        }
+    }
+
+    fn into_stream<T: FromLuaMulti<'lua>>(self, ctx: Context<'lua>) -> CoroutineStream<'lua, T> {
+        CoroutineStream::new(ctx, self)
+    }
+}
+
+/// The future returned by [`ThreadExt::resume_async`]
+pub struct ResumeAsync<'lua, Arg, Ret> {
+    ctx: Context<'lua>,
+    thread: Thread<'lua>,
+    /// The arguments of the resume, until the first poll
+    args: Option<Arg>,
+    _phantom: PhantomData<Ret>,
+}
+
+// The future is never structurally pinned
+impl<'lua, Arg, Ret> Unpin for ResumeAsync<'lua, Arg, Ret> {}
+
+impl<'lua, Arg, Ret> Future for ResumeAsync<'lua, Arg, Ret>
+where
+    Arg: ToLuaMulti<'lua>,
+    Ret: FromLuaMulti<'lua>,
+{
+    type Output = Result<Ret>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<Ret>> {
+        let this = self.get_mut();
+        let resume_ret = DepthGuard::enter().and_then(|_guard| {
+            FUTURE_CTX.set(&(fut_ctx as *mut _ as *mut ()), || match this.args.take() {
+                Some(args) => this.thread.resume::<_, MultiValue>(args),
+                None => this.thread.resume::<_, MultiValue>(()),
+            })
+        });
+
+        match resume_ret {
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(v) => {
+                if this.thread.status() == ThreadStatus::Resumable && is_pending_yield(&v) {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(Ret::from_lua_multi(v, this.ctx))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{executor, StreamExt};
+    use rlua::{Lua, Thread};
+
+    use crate::ContextExt;
+
+    #[test]
+    fn resume_lua_coroutine() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, a: usize| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(a * 2)
+                })
+                .unwrap();
+            lua.globals().set("double", f).unwrap();
+            let co = lua
+                .load(
+                    r#"coroutine.create(function(a)
+                        local b = coroutine.yield(double(a))
+                        return double(b) + 1
+                    end)"#,
+                )
+                .eval::<Thread>()
+                .unwrap();
+
+            let first = executor::block_on(co.resume_async::<_, usize>(lua, 1)).unwrap();
+            assert_eq!(first, 2);
+            assert_eq!(co.status(), ThreadStatus::Resumable);
+            let last = executor::block_on(co.resume_async::<_, usize>(lua, 5)).unwrap();
+            assert_eq!(last, 11);
+            assert_eq!(co.status(), ThreadStatus::Unresumable);
+        });
+    }
+
+    #[test]
+    fn coroutine_stream() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, a: usize| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(a * 2)
+                })
+                .unwrap();
+            lua.globals().set("double", f).unwrap();
+            let co = lua
+                .load(r#"coroutine.create(function() for i = 1, 3 do coroutine.yield(double(i)) end end)"#)
+                .eval::<Thread>()
+                .unwrap();
+
+            let items = executor::block_on(co.into_stream::<usize>(lua).collect::<Vec<_>>());
+            let items = items.into_iter().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(items, vec![2, 4, 6]);
+        });
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    cell::Cell,
+    error, fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rlua::{Error, Result};
+
+/// The default maximum nesting of async calls, see [`set_max_call_depth`]
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100;
+
+static MAX_CALL_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_CALL_DEPTH);
+
+thread_local! {
+    // The number of Lua threads currently being resumed by this crate on this OS thread, ie. of
+    // nested resumes on its C stack
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Set the maximum nesting of async calls
+///
+/// Each time an async call (or a [`CoroutineStream`](crate::stream::CoroutineStream)) resumes
+/// its Lua thread from inside another one, eg. from a Rust callback that drives an async call to
+/// completion, the C stack grows a bit more. Past `depth` nested resumes on the same OS thread,
+/// the innermost call fails with a [`RecursionLimitError`] instead of risking a stack overflow.
+///
+/// This setting is global to the process, and defaults to [`DEFAULT_MAX_CALL_DEPTH`].
+pub fn set_max_call_depth(depth: usize) {
+    MAX_CALL_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// The maximum nesting of async calls, see [`set_max_call_depth`]
+pub fn max_call_depth() -> usize {
+    MAX_CALL_DEPTH.load(Ordering::Relaxed)
+}
+
+/// The error returned by an async call nested too deep, see [`set_max_call_depth`]
+///
+/// It is returned wrapped in an [`rlua::Error::ExternalError`], and can be recovered by
+/// downcasting.
+#[derive(Clone, Debug)]
+pub struct RecursionLimitError {
+    limit: usize,
+}
+
+impl RecursionLimitError {
+    /// The limit that was exceeded
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+impl fmt::Display for RecursionLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "async calls are nested more than {} levels deep",
+            self.limit
+        )
+    }
+}
+
+impl error::Error for RecursionLimitError {}
+
+/// Marks one more nested resume on the current OS thread while alive
+pub(crate) struct DepthGuard(());
+
+impl DepthGuard {
+    /// Enter a nested resume, failing if this would exceed the maximum call depth
+    pub(crate) fn enter() -> Result<DepthGuard> {
+        let limit = max_call_depth();
+        CALL_DEPTH.with(|depth| {
+            if depth.get() >= limit {
+                return Err(Error::external(RecursionLimitError { limit }));
+            }
+            depth.set(depth.get() + 1);
+            Ok(DepthGuard(()))
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor, FutureExt};
+    use rlua::{Function, Lua};
+
+    use crate::FunctionExt;
+
+    #[test]
+    fn deep_nesting_fails_cleanly() {
+        Lua::new().context(|lua| {
+            let nest = lua
+                .create_function(|ctx, n: i64| {
+                    let down = ctx.globals().get::<_, Function>("down")?;
+                    // The nested call never awaits, so it completes on its first poll
+                    down.call_async::<_, i64>(ctx, n + 1)
+                        .now_or_never()
+                        .expect("nested call is pending")
+                })
+                .unwrap();
+            lua.globals().set("nest", nest).unwrap();
+            let down = lua
+                .load(r#"function(n) if n == 20 then return n end return nest(n) end"#)
+                .eval::<Function>()
+                .unwrap();
+            lua.globals().set("down", down.clone()).unwrap();
+
+            // Other tests nest their calls much less than this
+            assert!(max_call_depth() > 20);
+            let res = executor::block_on(down.call_async::<_, i64>(lua, 0));
+            assert_eq!(res.unwrap(), 20);
+
+            let res =
+                executor::block_on(down.call_async::<_, i64>(lua, 10 - max_call_depth() as i64));
+            let mut err = res.unwrap_err();
+            loop {
+                match err {
+                    Error::CallbackError { cause, .. } => err = (*cause).clone(),
+                    Error::ExternalError(e) => {
+                        let e = e.downcast_ref::<RecursionLimitError>().expect("bad error");
+                        assert_eq!(e.limit(), max_call_depth());
+                        break;
+                    }
+                    e => panic!("improper error: {:?}", e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,1061 @@
+//! Filesystem access for Lua scripts, without blocking the executor
+//!
+//! The standard library filesystem API is blocking, so the functions of this module run it with
+//! [`Spawner::spawn_blocking`] on the spawner of the Lua
+//! state, and hand the results over to async Lua functions. The scripts only get
+//! filesystem access in the Lua states where [`install`] or [`install_with`] is called, the
+//! latter restricting it with [`FsOptions`].
+//!
+//! This module is only available with the `fs` feature, enabled by default.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use futures::{channel::mpsc, lock::Mutex, task::SpawnError, SinkExt, StreamExt};
+use rlua::{Context, Error, Function, Result, Table, ToLua, Value};
+
+use crate::{
+    buffer::Buffer, global_table, input::LineReader, spawner::Spawner, time::UnixTime, ContextExt,
+};
+
+/// The number of entries a directory walk reads ahead of the script consuming them
+const WALK_BUFFER: usize = 64;
+
+/// The restrictions on the filesystem access of the scripts, see [`install_with`]
+#[derive(Clone, Debug, Default)]
+pub struct FsOptions {
+    /// Only give access to the files below this directory, relative paths being relative to it.
+    /// Symbolic links are resolved before checking that a path is below it, so they cannot be
+    /// used to escape it.
+    pub root: Option<PathBuf>,
+    /// Refuse to create, modify or remove files
+    pub read_only: bool,
+}
+
+impl FsOptions {
+    /// Resolve the path given by a script, failing if it is outside of the root
+    ///
+    /// This is blocking as it resolves symbolic links, so should run on the blocking threads.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let root = match &self.root {
+            Some(root) => root.canonicalize()?,
+            None => return Ok(PathBuf::from(path)),
+        };
+        let path = root.join(path);
+        // The last component of the path may not exist yet, eg. for a file being created
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match (path.parent(), path.file_name()) {
+                    (Some(parent), Some(name)) => parent.canonicalize()?.join(name),
+                    _ => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        if !resolved.starts_with(&root) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is outside of the filesystem root", path.display()),
+            ));
+        }
+        Ok(resolved)
+    }
+
+    /// Fail if the scripts must not write
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the filesystem is read-only for this Lua state",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn file_kind(file_type: fs::FileType) -> &'static str {
+    if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_file() {
+        "file"
+    } else {
+        "other"
+    }
+}
+
+/// The options of a directory walk, see [`walk`]
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    /// Do not descend more than this many levels below the root, `1` listing only its direct
+    /// children
+    pub max_depth: Option<usize>,
+    /// Descend into symbolic links to directories
+    pub follow_links: bool,
+}
+
+impl WalkOptions {
+    fn from_lua_table(opts: Option<Table>) -> Result<WalkOptions> {
+        let opts = match opts {
+            Some(opts) => opts,
+            None => return Ok(WalkOptions::default()),
+        };
+        Ok(WalkOptions {
+            max_depth: opts.get("max_depth")?,
+            follow_links: opts
+                .get::<_, Option<bool>>("follow_links")?
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// An entry found while walking a directory
+#[derive(Clone, Debug)]
+pub struct WalkEntry {
+    /// The path of the entry, starting with the root of the walk
+    pub path: PathBuf,
+    /// The number of levels below the root, its direct children having depth `1`
+    pub depth: usize,
+    /// The metadata of the entry, not following symbolic links
+    pub metadata: fs::Metadata,
+}
+
+/// Converted to a table with fields `path`, `name`, `depth`, `type` (one of `"file"`, `"dir"`,
+/// `"symlink"` and `"other"`), `size` and `modified` (in seconds since the Unix epoch)
+impl<'lua> ToLua<'lua> for WalkEntry {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        let kind = file_kind(self.metadata.file_type());
+        let entry = ctx.create_table()?;
+        entry.set("path", self.path.to_string_lossy().as_ref())?;
+        entry.set(
+            "name",
+            self.path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned()),
+        )?;
+        entry.set("depth", self.depth)?;
+        entry.set("type", kind)?;
+        entry.set("size", self.metadata.len())?;
+        entry.set("modified", self.metadata.modified().ok().map(UnixTime))?;
+        Ok(Value::Table(entry))
+    }
+}
+
+/// Whether the walk may descend into `path`, a symbolic link to a directory: with a `limit`,
+/// only if it leads to a directory below it
+fn may_follow(path: &Path, limit: Option<&Path>) -> bool {
+    match limit {
+        None => true,
+        Some(limit) => path
+            .canonicalize()
+            .is_ok_and(|target| target.starts_with(limit)),
+    }
+}
+
+fn walk_dir(
+    root: &Path,
+    opts: &WalkOptions,
+    limit: Option<&Path>,
+    tx: &mut mpsc::Sender<io::Result<WalkEntry>>,
+) -> io::Result<()> {
+    // Depth-first, with the entries of each directory in name order
+    let mut to_visit = vec![(root.to_path_buf(), 0)];
+    while let Some((dir, depth)) = to_visit.pop() {
+        if opts.max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+        let mut entries = fs::read_dir(&dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        let mut subdirs = Vec::new();
+        for path in entries {
+            let metadata = fs::symlink_metadata(&path)?;
+            let is_dir = metadata.is_dir()
+                || (opts.follow_links
+                    && metadata.file_type().is_symlink()
+                    && fs::metadata(&path).is_ok_and(|m| m.is_dir())
+                    && may_follow(&path, limit));
+            if is_dir {
+                subdirs.push(path.clone());
+            }
+            let entry = WalkEntry {
+                path,
+                depth: depth + 1,
+                metadata,
+            };
+            if futures::executor::block_on(tx.send(Ok(entry))).is_err() {
+                // The walk was abandoned
+                return Ok(());
+            }
+        }
+        to_visit.extend(subdirs.into_iter().rev().map(|d| (d, depth + 1)));
+    }
+    Ok(())
+}
+
+/// Walk the tree below `root` lazily, on a blocking task of `spawner`
+///
+/// The entries are returned depth-first, with the entries of each directory in name order and
+/// each directory right before its contents. The root itself is not returned. The walk stops at
+/// the first error, that is returned as the last item. Symbolic links are only followed if
+/// [`WalkOptions::follow_links`] is set, and then loops are not detected, so setting
+/// [`WalkOptions::max_depth`] too is recommended.
+///
+/// The task reads a few entries ahead, and stops once the returned stream is dropped. It keeps
+/// the blocking thread it runs on until then.
+pub fn walk<P: Into<PathBuf>>(
+    spawner: &dyn Spawner,
+    root: P,
+    opts: WalkOptions,
+) -> std::result::Result<mpsc::Receiver<io::Result<WalkEntry>>, SpawnError> {
+    walk_within(spawner, root.into(), opts, None)
+}
+
+/// Walk the tree below `root`, only following the symbolic links that lead below `limit`, eg.
+/// the root of the [`FsOptions`]
+fn walk_within(
+    spawner: &dyn Spawner,
+    root: PathBuf,
+    opts: WalkOptions,
+    limit: Option<PathBuf>,
+) -> std::result::Result<mpsc::Receiver<io::Result<WalkEntry>>, SpawnError> {
+    let (mut tx, rx) = mpsc::channel(WALK_BUFFER);
+    spawner.spawn_blocking(Box::new(move || {
+        if let Err(e) = walk_dir(&root, &opts, limit.as_deref(), &mut tx) {
+            let _ = futures::executor::block_on(tx.send(Err(e)));
+        }
+    }))?;
+    Ok(rx)
+}
+
+fn create_walk_function<'lua>(
+    ctx: Context<'lua>,
+    fs_opts: Arc<FsOptions>,
+) -> Result<Function<'lua>> {
+    ctx.create_function(move |ctx, (root, opts): (String, Option<Table>)| {
+        // This only blocks for the few system calls resolving the root
+        let root = fs_opts.resolve(&root).map_err(Error::external)?;
+        let limit = fs_opts.root.as_ref().map(|r| r.canonicalize());
+        let limit = limit.transpose().map_err(Error::external)?;
+        let opts = WalkOptions::from_lua_table(opts)?;
+        let entries = walk_within(&*ctx.spawner()?, root, opts, limit).map_err(Error::external)?;
+        let entries = Arc::new(Mutex::new(entries));
+        ctx.create_named_async_function("fs.walk iterator", move |_, ()| {
+            let entries = entries.clone();
+            async move {
+                match entries.lock().await.next().await {
+                    None => Ok(None),
+                    Some(entry) => entry.map(Some).map_err(Error::external),
+                }
+            }
+        })
+    })
+}
+
+/// Run `f` with the spawner of `ctx`, see [`crate::blocking`]
+fn blocking<T, F>(ctx: Context, f: F) -> impl Future<Output = Result<T>>
+where
+    T: 'static + Send,
+    F: 'static + Send + FnOnce() -> io::Result<T>,
+{
+    crate::blocking(ctx.spawner(), "fs", f)
+}
+
+/// A temporary file or directory, removed when dropped unless it was already removed
+struct TempPath {
+    path: PathBuf,
+    is_dir: bool,
+    removed: AtomicBool,
+}
+
+impl TempPath {
+    /// Create a new temporary file or directory, with a random name, only accessible to the
+    /// current user on unix
+    fn create(is_dir: bool) -> io::Result<TempPath> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            // Not guessable by other users, unlike the pid, so that they cannot create the path
+            // beforehand
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+            let path = std::env::temp_dir().join(format!(
+                "rlua-async-{}-{:016x}",
+                process::id(),
+                hasher.finish()
+            ));
+            let res = if is_dir {
+                let mut builder = fs::DirBuilder::new();
+                #[cfg(unix)]
+                std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+                builder.create(&path)
+            } else {
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options.open(&path).map(|_| ())
+            };
+            match res {
+                Ok(()) => {
+                    return Ok(TempPath {
+                        path,
+                        is_dir,
+                        removed: AtomicBool::new(false),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        if self.removed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        if self.is_dir {
+            fs::remove_dir_all(&self.path)
+        } else {
+            fs::remove_file(&self.path)
+        }
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = self.remove();
+    }
+}
+
+/// Create the Lua handle of a temporary file or directory
+///
+/// Its functions all keep the temporary path alive, so that it is removed once the handle and
+/// the pending calls to its functions are gone.
+fn temp_handle<'lua>(ctx: Context<'lua>, temp: TempPath) -> Result<Table<'lua>> {
+    let temp = Arc::new(temp);
+    let handle = ctx.create_table()?;
+    handle.set("path", temp.path.to_string_lossy().as_ref())?;
+
+    if !temp.is_dir {
+        let t = temp.clone();
+        handle.set(
+            "write",
+            ctx.create_named_async_function(
+                "fs.tempfile write",
+                move |ctx, (_, data): (Value, rlua::String)| {
+                    let t = t.clone();
+                    let data = data.as_bytes().to_vec();
+                    blocking(ctx, move || {
+                        fs::OpenOptions::new()
+                            .append(true)
+                            .open(&t.path)?
+                            .write_all(&data)
+                    })
+                },
+            )?,
+        )?;
+
+        let t = temp.clone();
+        handle.set(
+            "read",
+            ctx.create_named_async_function("fs.tempfile read", move |ctx, _: Value| {
+                let t = t.clone();
+                let data = blocking(ctx, move || fs::read(&t.path));
+                async move { Ok(Buffer::from(data.await?)) }
+            })?,
+        )?;
+    }
+
+    let t = temp;
+    handle.set(
+        "close",
+        ctx.create_named_async_function("fs.temp close", move |ctx, _: Value| {
+            let t = t.clone();
+            blocking(ctx, move || t.remove())
+        })?,
+    )?;
+    Ok(handle)
+}
+
+/// An opened named pipe, converted to its Lua handle
+enum Pipe {
+    Read(fs::File),
+    Write(fs::File),
+}
+
+/// The writing end of a named pipe, that can be closed while a write is blocked on it
+struct PipeWriter {
+    file: std::sync::Mutex<Option<fs::File>>,
+    closed: AtomicBool,
+}
+
+impl PipeWriter {
+    /// Write all of `data`, blocking until it is
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let res = match &mut *self.file.lock().unwrap() {
+            Some(file) if !self.closed.load(Ordering::SeqCst) => file.write_all(data),
+            _ => Err(io::Error::other("the pipe is closed")),
+        };
+        // The pipe may have been closed during the write, while the file was still in use
+        if self.closed.load(Ordering::SeqCst) {
+            self.release();
+        }
+        res
+    }
+
+    /// Close the pipe, without waiting for a pending write: the file is then closed once that
+    /// write returns
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.release();
+    }
+
+    fn release(&self) {
+        if let Ok(mut file) = self.file.try_lock() {
+            file.take();
+        }
+    }
+}
+
+impl<'lua> ToLua<'lua> for Pipe {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        let handle = ctx.create_table()?;
+        match self {
+            Pipe::Read(file) => {
+                LineReader::new(io::BufReader::new(file))
+                    .map_err(Error::external)?
+                    .set_functions(ctx, &handle, "fs.open_pipe read_line")?;
+            }
+            Pipe::Write(file) => {
+                let pipe = Arc::new(PipeWriter {
+                    file: std::sync::Mutex::new(Some(file)),
+                    closed: AtomicBool::new(false),
+                });
+                let p = pipe.clone();
+                handle.set(
+                    "write",
+                    ctx.create_named_async_function(
+                        "fs.open_pipe write",
+                        move |ctx, (_, data): (Value, rlua::String)| {
+                            let p = p.clone();
+                            let data = data.as_bytes().to_vec();
+                            blocking(ctx, move || p.write(&data))
+                        },
+                    )?,
+                )?;
+                handle.set(
+                    "close",
+                    ctx.create_function(move |_, _: Value| {
+                        pipe.close();
+                        Ok(())
+                    })?,
+                )?;
+            }
+        }
+        Ok(Value::Table(handle))
+    }
+}
+
+/// The metadata of a file, converted to a table with fields `type` (as in [`WalkEntry`]),
+/// `size`, `modified` (in seconds since the Unix epoch) and `readonly`
+struct Metadata(fs::Metadata);
+
+impl<'lua> ToLua<'lua> for Metadata {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        let metadata = ctx.create_table()?;
+        metadata.set("type", file_kind(self.0.file_type()))?;
+        metadata.set("size", self.0.len())?;
+        metadata.set("modified", self.0.modified().ok().map(UnixTime))?;
+        metadata.set("readonly", self.0.permissions().readonly())?;
+        Ok(Value::Table(metadata))
+    }
+}
+
+/// An opened file, converted to its Lua handle
+struct File(fs::File);
+
+/// The file of a Lua file handle, `None` once closed
+type SharedFile = Arc<std::sync::Mutex<Option<fs::File>>>;
+
+/// Run `op` on the file of a handle with the spawner of `ctx`, failing if it was closed
+fn with_file<T, F>(ctx: Context, file: &SharedFile, op: F) -> impl Future<Output = Result<T>>
+where
+    T: 'static + Send,
+    F: 'static + Send + FnOnce(&mut fs::File) -> io::Result<T>,
+{
+    let file = file.clone();
+    blocking(ctx, move || match &mut *file.lock().unwrap() {
+        Some(file) => op(file),
+        None => Err(io::Error::other("the file is closed")),
+    })
+}
+
+impl<'lua> ToLua<'lua> for File {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        let file: SharedFile = Arc::new(std::sync::Mutex::new(Some(self.0)));
+        let handle = ctx.create_table()?;
+
+        let f = file.clone();
+        handle.set(
+            "read",
+            ctx.create_named_async_function("fs.open read", move |ctx, (_, n): (Value, usize)| {
+                let read = with_file(ctx, &f, move |file| {
+                    let mut data = Vec::with_capacity(n);
+                    file.take(n as u64).read_to_end(&mut data)?;
+                    Ok(data)
+                });
+                async move {
+                    let data = read.await?;
+                    Ok((n == 0 || !data.is_empty()).then(|| Buffer::from(data)))
+                }
+            })?,
+        )?;
+
+        let f = file.clone();
+        handle.set(
+            "write",
+            ctx.create_named_async_function(
+                "fs.open write",
+                move |ctx, (_, data): (Value, rlua::String)| {
+                    let data = data.as_bytes().to_vec();
+                    with_file(ctx, &f, move |file| file.write_all(&data))
+                },
+            )?,
+        )?;
+
+        let f = file.clone();
+        handle.set(
+            "seek",
+            ctx.create_named_async_function(
+                "fs.open seek",
+                move |ctx, (_, offset): (Value, u64)| {
+                    with_file(ctx, &f, move |file| {
+                        file.seek(SeekFrom::Start(offset)).map(drop)
+                    })
+                },
+            )?,
+        )?;
+
+        handle.set(
+            "close",
+            ctx.create_function(move |_, _: Value| {
+                file.lock().unwrap().take();
+                Ok(())
+            })?,
+        )?;
+        Ok(Value::Table(handle))
+    }
+}
+
+/// Open `path` with the `fopen`-like `mode`
+fn open(path: &Path, mode: &str, opts: &FsOptions) -> io::Result<fs::File> {
+    let mut open = fs::OpenOptions::new();
+    match mode {
+        "r" => open.read(true),
+        "w" => open.write(true).create(true).truncate(true),
+        "a" => open.append(true).create(true),
+        "r+" => open.read(true).write(true),
+        "w+" => open.read(true).write(true).create(true).truncate(true),
+        "a+" => open.read(true).append(true).create(true),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid file mode {:?}", mode),
+            ))
+        }
+    };
+    if mode != "r" {
+        opts.check_writable()?;
+    }
+    open.open(path)
+}
+
+/// Install the filesystem functions in the `fs` table of the globals, creating it if need be,
+/// with no restrictions on the files the scripts can access
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    install_with(ctx, FsOptions::default())
+}
+
+/// Install the filesystem functions in the `fs` table of the globals, creating it if need be,
+/// restricted by `opts`
+///
+/// The installed functions are:
+///  * `fs.open(path, mode)`, that opens the file at `path` with the `mode` of `io.open` (without
+///    `b`, files being always binary). The handle has the async methods `f:read(n)`, that reads
+///    up to `n` bytes as a [`Buffer`], returning `nil` at the end of the file, `f:write(data)`
+///    and `f:seek(offset)`, that moves to `offset` bytes from the start, and the method
+///    `f:close()`.
+///  * `fs.read(path)`, that reads the whole file at `path` as a [`Buffer`].
+///  * `fs.write(path, data)`, that creates or truncates the file at `path` and writes `data` to
+///    it.
+///  * `fs.read_dir(path)`, that returns the names of the entries of the directory at `path`, in
+///    name order.
+///  * `fs.metadata(path)`, that returns the metadata of the file at `path`, following symbolic
+///    links, as a table with fields `type` (as in [`WalkEntry`]), `size`, `modified` and
+///    `readonly`, or `nil` if there is no such file.
+///  * `fs.walk(root, opts)`, that returns an async iterator over the entries below `root`, for
+///    use in `for` loops. See [`walk`] for the order and semantics, and [`WalkEntry`] for the
+///    fields of the entries. `opts` is an optional table with the fields of [`WalkOptions`].
+///    With a root, the symbolic links leading out of it are listed but not followed.
+///  * `fs.tempfile()`, that creates an empty temporary file and returns a handle to it, with
+///    field `path` and async methods `f:write(data)` to append to it, `f:read()` to read it
+///    whole as a [`Buffer`], and `f:close()` to remove it.
+///  * `fs.tempdir()`, that creates an empty temporary directory and returns a handle to it,
+///    with field `path` and async method `d:close()` to remove it and its contents.
+///  * `fs.open_pipe(path, mode)`, that opens an existing named pipe (a FIFO on Unix, eg.
+///    `\\.\pipe\name` on Windows) for reading if `mode` is `"r"`, or writing if it is
+///    `"w"`. It is an async function, as opening a FIFO waits for the other end to be opened.
+///    Handles opened for reading have the async methods `p:read_line()` and `p:lines()`, like
+///    [`LineReader`], and handles opened for writing the async method `p:write(data)` and the
+///    method `p:close()`, that does not wait for a pending write: the pipe is then closed once
+///    that write returns, and the later writes fail.
+///
+/// Temporary files and directories that are not `close`d are removed when their handle is
+/// garbage-collected, or at the latest when the Lua state is dropped. They are not installed
+/// if `opts` has a root or is read-only, as they live out of the root.
+///
+/// The paths given to all the functions are checked against the restrictions of `opts`.
+pub fn install_with<'lua>(ctx: Context<'lua>, opts: FsOptions) -> Result<()> {
+    let opts = Arc::new(opts);
+    let fs = global_table(ctx, "fs")?;
+
+    let o = opts.clone();
+    fs.set(
+        "open",
+        ctx.create_named_async_function("fs.open", move |ctx, (path, mode): (String, String)| {
+            let o = o.clone();
+            blocking(ctx, move || open(&o.resolve(&path)?, &mode, &o).map(File))
+        })?,
+    )?;
+    let o = opts.clone();
+    fs.set(
+        "read",
+        ctx.create_named_async_function("fs.read", move |ctx, path: String| {
+            let o = o.clone();
+            let data = blocking(ctx, move || fs::read(o.resolve(&path)?));
+            async move { Ok(Buffer::from(data.await?)) }
+        })?,
+    )?;
+    let o = opts.clone();
+    fs.set(
+        "write",
+        ctx.create_named_async_function(
+            "fs.write",
+            move |ctx, (path, data): (String, rlua::String)| {
+                let o = o.clone();
+                let data = data.as_bytes().to_vec();
+                blocking(ctx, move || {
+                    o.check_writable()?;
+                    fs::write(o.resolve(&path)?, data)
+                })
+            },
+        )?,
+    )?;
+    let o = opts.clone();
+    fs.set(
+        "read_dir",
+        ctx.create_named_async_function("fs.read_dir", move |ctx, path: String| {
+            let o = o.clone();
+            blocking(ctx, move || {
+                let mut names = fs::read_dir(o.resolve(&path)?)?
+                    .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+                    .collect::<io::Result<Vec<_>>>()?;
+                names.sort();
+                Ok(names)
+            })
+        })?,
+    )?;
+    let o = opts.clone();
+    fs.set(
+        "metadata",
+        ctx.create_named_async_function("fs.metadata", move |ctx, path: String| {
+            let o = o.clone();
+            blocking(ctx, move || match fs::metadata(o.resolve(&path)?) {
+                Ok(metadata) => Ok(Some(Metadata(metadata))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+        })?,
+    )?;
+
+    fs.set("walk", create_walk_function(ctx, opts.clone())?)?;
+    if opts.root.is_none() && !opts.read_only {
+        fs.set(
+            "tempfile",
+            ctx.create_function(|ctx, ()| {
+                temp_handle(ctx, TempPath::create(false).map_err(Error::external)?)
+            })?,
+        )?;
+        fs.set(
+            "tempdir",
+            ctx.create_function(|ctx, ()| {
+                temp_handle(ctx, TempPath::create(true).map_err(Error::external)?)
+            })?,
+        )?;
+    }
+    fs.set(
+        "open_pipe",
+        ctx.create_named_async_function(
+            "fs.open_pipe",
+            move |ctx, (path, mode): (String, String)| {
+                let opts = opts.clone();
+                let write = match mode.as_str() {
+                    "r" => Ok(false),
+                    "w" => Ok(true),
+                    _ => Err(Error::RuntimeError(format!(
+                        "invalid pipe mode {:?}, expected \"r\" or \"w\"",
+                        mode
+                    ))),
+                };
+                let opened = write.map(|write| {
+                    blocking(ctx, move || {
+                        let path = opts.resolve(&path)?;
+                        if write {
+                            opts.check_writable()?;
+                            fs::OpenOptions::new()
+                                .write(true)
+                                .open(path)
+                                .map(Pipe::Write)
+                        } else {
+                            fs::File::open(path).map(Pipe::Read)
+                        }
+                    })
+                });
+                async move { opened?.await }
+            },
+        )?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::{ChunkExt, FunctionExt};
+
+    #[test]
+    fn walk_from_lua() {
+        let root = std::env::temp_dir().join(format!("rlua-async-walk-{}", std::process::id()));
+        fs::create_dir_all(root.join("b/c")).unwrap();
+        fs::write(root.join("a"), b"hello").unwrap();
+        fs::write(root.join("b/c/d"), b"").unwrap();
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("root", root.to_string_lossy().as_ref())
+                .unwrap();
+            let (all, shallow) = executor::block_on(
+                lua.load(
+                    r#"
+                        local all = {}
+                        for e in fs.walk(root) do
+                            all[#all + 1] = e.name .. ":" .. e.type .. ":" .. e.depth
+                            if e.name == "a" then assert(e.size == 5 and e.modified) end
+                        end
+                        local shallow = 0
+                        for e in fs.walk(root, { max_depth = 1 }) do
+                            shallow = shallow + 1
+                        end
+                        return table.concat(all, " "), shallow
+                    "#,
+                )
+                .call_async::<_, (String, usize)>(lua, ()),
+            )
+            .expect("failed to walk");
+            assert_eq!(all, "a:file:1 b:dir:1 c:dir:2 d:file:3");
+            assert_eq!(shallow, 2);
+
+            let res = executor::block_on(
+                lua.load(r#"for e in fs.walk(root .. "/missing") do end"#)
+                    .exec_async(lua),
+            );
+            assert!(res.is_err());
+
+            // A spawner that cannot run the walk makes `fs.walk` itself fail
+            struct NoBlocking;
+            impl Spawner for NoBlocking {
+                fn spawn(
+                    &self,
+                    fut: futures::future::BoxFuture<'static, ()>,
+                ) -> std::result::Result<(), SpawnError> {
+                    crate::spawner::DefaultSpawner.spawn(fut)
+                }
+
+                fn sleep(
+                    &self,
+                    duration: std::time::Duration,
+                ) -> futures::future::BoxFuture<'static, ()> {
+                    crate::spawner::DefaultSpawner.sleep(duration)
+                }
+
+                fn spawn_blocking(
+                    &self,
+                    _: Box<dyn FnOnce() + Send>,
+                ) -> std::result::Result<(), SpawnError> {
+                    Err(SpawnError::shutdown())
+                }
+            }
+            lua.set_spawner(NoBlocking).unwrap();
+            let err = lua.load("fs.walk(root)").exec().unwrap_err();
+            assert!(err.to_string().contains("shut"), "{}", err);
+        });
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn file_functions() {
+        let root = std::env::temp_dir().join(format!("rlua-async-files-{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("root", root.to_string_lossy().as_ref())
+                .unwrap();
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        fs.write(root .. "/a", "hello")
+                        local f = fs.open(root .. "/a", "a")
+                        f:write(", world")
+                        f:close()
+
+                        f = fs.open(root .. "/a", "r")
+                        local parts = {}
+                        while true do
+                            local part = f:read(5)
+                            if not part then break end
+                            parts[#parts + 1] = tostring(part)
+                        end
+                        f:seek(7)
+                        local rest = tostring(f:read(100))
+                        f:close()
+
+                        local meta = fs.metadata(root .. "/a")
+                        return table.concat(parts, "|"), rest, tostring(fs.read(root .. "/a")),
+                            table.concat(fs.read_dir(root), ","), meta.type, meta.size,
+                            fs.metadata(root .. "/missing") == nil
+                    "#,
+                )
+                .call_async::<_, (String, String, String, String, String, u64, bool)>(lua, ()),
+            )
+            .expect("failed to use the files");
+            assert_eq!(res.0, "hello|, wor|ld");
+            assert_eq!(res.1, "world");
+            assert_eq!(res.2, "hello, world");
+            assert_eq!(res.3, "a,sub");
+            assert_eq!((res.4.as_str(), res.5, res.6), ("file", 12, true));
+
+            let closed = r#"local f = fs.open(root .. "/a", "r") f:close() f:read(1)"#;
+            let err = executor::block_on(lua.load(closed).exec_async(lua)).unwrap_err();
+            assert!(err.to_string().contains("the file is closed"), "{}", err);
+        });
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sandboxed_files() {
+        let base = std::env::temp_dir().join(format!("rlua-async-sandbox-{}", std::process::id()));
+        fs::create_dir_all(base.join("root/sub")).unwrap();
+        fs::write(base.join("secret"), b"secret").unwrap();
+        fs::write(base.join("root/sub/file"), b"public").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("secret"), base.join("root/link")).unwrap();
+
+        Lua::new().context(|lua| {
+            let opts = FsOptions {
+                root: Some(base.join("root")),
+                read_only: true,
+            };
+            install_with(lua, opts).unwrap();
+            let (public, temp) = executor::block_on(
+                lua.load(r#"return tostring(fs.read("sub/file")), fs.tempfile"#)
+                    .call_async::<_, (String, Option<Function>)>(lua, ()),
+            )
+            .expect("failed to read in the root");
+            assert_eq!(public, "public");
+            assert!(temp.is_none());
+
+            for denied in &[
+                r#"fs.read("../secret")"#,
+                r#"fs.read("sub/../../secret")"#,
+                r#"fs.metadata("/")"#,
+                r#"fs.write("sub/file", "overwritten")"#,
+                r#"fs.open("new", "w")"#,
+                #[cfg(unix)]
+                r#"fs.read("link")"#,
+            ] {
+                let res = executor::block_on(lua.load(denied).exec_async(lua));
+                let err = res.expect_err(denied).to_string();
+                assert!(
+                    err.contains("outside of the filesystem root") || err.contains("read-only"),
+                    "{}: {}",
+                    denied,
+                    err
+                );
+            }
+
+            // Walks do not follow the links leading out of the root
+            #[cfg(unix)]
+            {
+                fs::create_dir_all(base.join("outside")).unwrap();
+                fs::write(base.join("outside/hidden"), b"").unwrap();
+                std::os::unix::fs::symlink(base.join("outside"), base.join("root/out")).unwrap();
+                std::os::unix::fs::symlink(base.join("root/sub"), base.join("root/in")).unwrap();
+                let names = executor::block_on(
+                    lua.load(
+                        r#"
+                            local names = {}
+                            for e in fs.walk(".", { follow_links = true }) do
+                                names[#names + 1] = e.name
+                            end
+                            return table.concat(names, " ")
+                        "#,
+                    )
+                    .call_async::<_, String>(lua, ()),
+                )
+                .expect("failed to walk");
+                assert_eq!(names, "in link out sub file file");
+            }
+        });
+
+        assert_eq!(fs::read(base.join("root/sub/file")).unwrap(), b"public");
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn temporary_files() {
+        let lua = Lua::new();
+        let dir = lua.context(|lua| {
+            install(lua).unwrap();
+            let (content, removed, dir) = executor::block_on(
+                lua.load(
+                    r#"
+                        local f = fs.tempfile()
+                        f:write("hello, ")
+                        f:write("world")
+                        local content = tostring(f:read())
+                        local path = f.path
+                        f:close()
+                        local removed = io.open(path) == nil
+                        dir = fs.tempdir()
+                        return content, removed, dir.path
+                    "#,
+                )
+                .call_async::<_, (String, bool, String)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(content, "hello, world");
+            assert!(removed);
+            fs::write(Path::new(&dir).join("file"), b"").unwrap();
+            dir
+        });
+        assert!(Path::new(&dir).is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        drop(lua);
+        assert!(!Path::new(&dir).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn named_pipes() {
+        let path = std::env::temp_dir().join(format!("rlua-async-fifo-{}", process::id()));
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .expect("failed to run mkfifo");
+        assert!(status.success());
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("path", path.to_string_lossy().as_ref())
+                .unwrap();
+            let writer = lua
+                .load(
+                    r#"function()
+                        local p = fs.open_pipe(path, "w")
+                        p:write("hello\nworld\n")
+                        p:close()
+                    end"#,
+                )
+                .eval::<Function>()
+                .unwrap();
+            let reader = lua
+                .load(
+                    r#"function()
+                        local p = fs.open_pipe(path, "r")
+                        local lines = {}
+                        for line in p:lines() do
+                            lines[#lines + 1] = line
+                        end
+                        return lines
+                    end"#,
+                )
+                .eval::<Function>()
+                .unwrap();
+            let (lines, ()) = executor::block_on(futures::future::try_join(
+                reader.call_async::<_, Vec<String>>(lua, ()),
+                writer.call_async::<_, ()>(lua, ()),
+            ))
+            .expect("failed to call");
+            assert_eq!(lines, vec!["hello", "world"]);
+
+            // Closing while a write is blocked on the full pipe
+            let (start, started) = std::sync::mpsc::channel();
+            let p = path.clone();
+            let reader = std::thread::spawn(move || {
+                let mut file = fs::File::open(p).unwrap();
+                started.recv().unwrap();
+                let mut read = Vec::new();
+                file.read_to_end(&mut read).unwrap();
+            });
+            let open = lua
+                .load(r#"return fs.open_pipe(path, "w")"#)
+                .call_async::<_, Table>(lua, ());
+            let pipe = executor::block_on(open).expect("failed to open");
+            let big = vec![b'x'; 1 << 20];
+            let write = pipe
+                .get::<_, Function>("write")
+                .unwrap()
+                .call_async::<_, ()>(lua, (pipe.clone(), lua.create_string(&big).unwrap()));
+            futures::pin_mut!(write);
+            assert!(executor::block_on(futures::future::poll_fn(|cx| {
+                std::task::Poll::Ready(write.as_mut().poll(cx).is_pending())
+            })));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let close = pipe.get::<_, Function>("close").unwrap();
+            close.call::<_, ()>(pipe).unwrap();
+            start.send(()).unwrap();
+            let _ = executor::block_on(write);
+            reader.join().unwrap();
+        });
+
+        fs::remove_file(&path).unwrap();
+    }
+}
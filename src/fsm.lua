@@ -0,0 +1,32 @@
+function(def, next_event, set_state)
+    local function call(f, ...)
+        if f ~= nil then
+            return f(...)
+        end
+    end
+
+    return function()
+        local current = def.initial
+        call(def.states[current].enter)
+        while true do
+            local event, payload = next_event()
+            if event == nil then
+                return
+            end
+            local state = def.states[current]
+            local target = state.on and state.on[event]
+            if type(target) == "function" then
+                target = target(payload)
+            end
+            if target ~= nil then
+                if def.states[target] == nil then
+                    error("transition to unknown state " .. tostring(target), 0)
+                end
+                call(state.exit, event, payload)
+                current = target
+                set_state(current)
+                call(def.states[current].enter, event, payload)
+            end
+        end
+    end
+end
@@ -0,0 +1,228 @@
+//! Finite-state machines defined in Lua and driven from Rust
+//!
+//! A state machine is defined by a Lua table of the form
+//!
+//! ```lua
+//! {
+//!     initial = "idle",
+//!     states = {
+//!         idle = { on = { start = "running" } },
+//!         running = {
+//!             enter = function(event, payload) --[[ ... ]] end,
+//!             exit = function(event, payload) --[[ ... ]] end,
+//!             on = {
+//!                 stop = "idle",
+//!                 fail = function(payload) return payload.retry and "running" or "idle" end,
+//!             },
+//!         },
+//!     },
+//! }
+//! ```
+//!
+//! Each state can have `enter` and `exit` handlers, called with the event causing the
+//! transition and its payload, and maps events to the next state in `on`, either directly or
+//! through a function of the payload returning the next state, or `nil` to stay in the current
+//! state. Events without a transition in the current state are ignored. The `enter` handler of
+//! the initial state is called without arguments when the machine starts. All the handlers can
+//! call async functions.
+//!
+//! The host sends events and observes the state changes through a [`FsmHandle`], while
+//! [`Fsm::run`] processes the events one after the other.
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::{channel::mpsc, lock::Mutex as AsyncMutex, Stream, StreamExt};
+use rlua::{Context, Error, Function, Result, Table};
+
+use crate::{plain::PlainValue, ContextExt, FunctionExt};
+
+static FSM: &[u8] = include_bytes!("fsm.lua");
+
+struct Shared {
+    state: String,
+    observers: Vec<mpsc::UnboundedSender<String>>,
+}
+
+/// A handle to send events to a state machine, and observe its state
+///
+/// Cloning the handle gives another handle to the same machine. It can be sent to other
+/// threads.
+#[derive(Clone)]
+pub struct FsmHandle {
+    events: mpsc::UnboundedSender<(String, PlainValue)>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl FsmHandle {
+    /// Send `event` with `payload` to the machine, failing if it stopped running
+    pub fn send(&self, event: &str, payload: PlainValue) -> Result<()> {
+        self.events
+            .unbounded_send((event.to_string(), payload))
+            .map_err(|_| Error::RuntimeError("the state machine stopped".to_string()))
+    }
+
+    /// The current state of the machine
+    pub fn state(&self) -> String {
+        self.shared.lock().unwrap().state.clone()
+    }
+
+    /// A stream of the states the machine enters from now on, that ends when the machine stops
+    pub fn state_changes(&self) -> impl Stream<Item = String> {
+        let (tx, rx) = mpsc::unbounded();
+        self.shared.lock().unwrap().observers.push(tx);
+        rx
+    }
+}
+
+/// A state machine defined in Lua, see the [module documentation](self)
+pub struct Fsm<'lua> {
+    ctx: Context<'lua>,
+    run: Function<'lua>,
+    handle: FsmHandle,
+}
+
+impl<'lua> Fsm<'lua> {
+    /// Create a state machine from its definition
+    pub fn new(ctx: Context<'lua>, definition: Table<'lua>) -> Result<Fsm<'lua>> {
+        let initial = definition.get::<_, String>("initial")?;
+        let states = definition.get::<_, Table>("states")?;
+        if !states.contains_key(initial.as_str())? {
+            return Err(Error::RuntimeError(format!(
+                "unknown initial state {:?}",
+                initial
+            )));
+        }
+
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let shared = Arc::new(Mutex::new(Shared {
+            state: initial,
+            observers: Vec::new(),
+        }));
+        let events = Arc::new(AsyncMutex::new(events_rx));
+        let next_event = ctx.create_named_async_function("fsm next event", move |_, ()| {
+            let events = events.clone();
+            async move {
+                Ok(match events.lock().await.next().await {
+                    Some((event, payload)) => (Some(event), payload),
+                    None => (None, PlainValue::Nil),
+                })
+            }
+        })?;
+        let s = shared.clone();
+        let set_state = ctx.create_function(move |_, state: String| {
+            let mut shared = s.lock().unwrap();
+            shared
+                .observers
+                .retain(|o| o.unbounded_send(state.clone()).is_ok());
+            shared.state = state;
+            Ok(())
+        })?;
+
+        let run = ctx
+            .load(FSM)
+            .set_name(b"rlua-async fsm")?
+            .eval::<Function<'lua>>()?
+            .call((definition, next_event, set_state))?;
+        Ok(Fsm {
+            ctx,
+            run,
+            handle: FsmHandle {
+                events: events_tx,
+                shared,
+            },
+        })
+    }
+
+    /// Get a handle to the machine
+    pub fn handle(&self) -> FsmHandle {
+        self.handle.clone()
+    }
+
+    /// Run the machine, processing the events until all the handles are dropped or a handler
+    /// raises an error
+    ///
+    /// The state changes are published before the `enter` handler of the new state is called.
+    pub fn run(self) -> impl 'lua + Future<Output = Result<()>> {
+        let Fsm { ctx, run, handle } = self;
+        let shared = handle.shared.clone();
+        // Only the handles given out keep the machine running
+        drop(handle);
+        let call = run.call_async::<_, ()>(ctx, ());
+        async move {
+            let res = call.await;
+            // Terminate the state change streams
+            shared.lock().unwrap().observers.clear();
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{executor, future};
+    use rlua::Lua;
+
+    #[test]
+    fn state_machine() {
+        Lua::new().context(|lua| {
+            let work = lua
+                .create_async_function(|_, ()| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(())
+                })
+                .unwrap();
+            lua.globals().set("work", work).unwrap();
+            let definition = lua
+                .load(
+                    r#"{
+                        initial = "idle",
+                        states = {
+                            idle = { on = { start = "running" } },
+                            running = {
+                                enter = function(event, payload) work() jobs = payload.jobs end,
+                                on = {
+                                    stop = "idle",
+                                    fail = function(p) if p.retry then return "running" end end,
+                                },
+                            },
+                        },
+                    }"#,
+                )
+                .eval::<Table>()
+                .unwrap();
+
+            let fsm = Fsm::new(lua, definition).unwrap();
+            let handle = fsm.handle();
+            let changes = handle.state_changes();
+            assert_eq!(handle.state(), "idle");
+
+            let jobs = PlainValue::Table(vec![(
+                PlainValue::String(b"jobs".to_vec()),
+                PlainValue::Integer(3),
+            )]);
+            let retry = PlainValue::Table(vec![(
+                PlainValue::String(b"retry".to_vec()),
+                PlainValue::Boolean(true),
+            )]);
+            handle.send("stop", PlainValue::Nil).unwrap();
+            handle.send("start", jobs.clone()).unwrap();
+            handle.send("fail", PlainValue::Table(Vec::new())).unwrap();
+            handle.send("fail", retry).unwrap();
+            handle.send("stop", PlainValue::Nil).unwrap();
+            drop(handle);
+
+            let (res, changes) =
+                executor::block_on(future::join(fsm.run(), changes.collect::<Vec<_>>()));
+            res.expect("state machine failed");
+            assert_eq!(changes, vec!["running", "running", "idle"]);
+            assert_eq!(lua.globals().get::<_, Option<i64>>("jobs").unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, Weak},
+};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use rlua::{Context, FromLuaMulti, Function, MultiValue, Result, ToLuaMulti};
+
+use crate::{plain::PlainValue, semaphore::Semaphore, ContextExt, IntoLuaResult, DEFAULT_NAME};
+
+/// The calls of a singleflight function in flight, with their arguments
+type InFlight<Ret> = Mutex<Vec<(Vec<PlainValue>, Shared<BoxFuture<'static, Result<Ret>>>)>>;
+
+/// A builder for async functions with extra options, see [`ContextExt::async_function_builder`]
+///
+/// Without any option set, the functions built are the same as the ones created by
+/// [`ContextExt::create_async_function`] and [`ContextExt::create_async_function_mut`].
+#[must_use = "the function is only created by `build` or `build_mut`"]
+pub struct AsyncFunctionBuilder<'lua> {
+    ctx: Context<'lua>,
+    name: Option<String>,
+    max_concurrency: Option<usize>,
+}
+
+impl<'lua> AsyncFunctionBuilder<'lua> {
+    pub(crate) fn new(ctx: Context<'lua>) -> AsyncFunctionBuilder<'lua> {
+        AsyncFunctionBuilder {
+            ctx,
+            name: None,
+            max_concurrency: None,
+        }
+    }
+
+    /// Give a name to the function, see [`ContextExt::create_named_async_function`]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Allow at most `max` calls of the function to be in flight at the same time
+    ///
+    /// The futures of the excess calls only start being polled once earlier calls completed, in
+    /// the order the calls were made. Note that the closure itself is still called right away,
+    /// so it should leave all the expensive work to the future.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn max_concurrency(mut self, max: usize) -> Self {
+        assert!(
+            max > 0,
+            "an async function needs to allow at least one call"
+        );
+        self.max_concurrency = Some(max);
+        self
+    }
+
+    /// Create the function, see [`ContextExt::create_async_function`]
+    pub fn build<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAME.to_string());
+        let limit = self.max_concurrency.map(Semaphore::new);
+        self.ctx
+            .create_named_async_function(&name, move |ctx, arg| limited(&limit, func(ctx, arg)))
+    }
+
+    /// Create the function from a mutable closure, see
+    /// [`ContextExt::create_async_function_mut`]
+    pub fn build_mut<Arg, Ret, RetFut, F>(self, mut func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut,
+    {
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAME.to_string());
+        let limit = self.max_concurrency.map(Semaphore::new);
+        self.ctx
+            .create_named_async_function_mut(&name, move |ctx, arg| limited(&limit, func(ctx, arg)))
+    }
+
+    /// Create a function that coalesces identical concurrent calls, see
+    /// [`ContextExt::create_async_function`]
+    ///
+    /// When the function is called while a previous call with the same arguments is still in
+    /// flight, `func` is not called again: the new call waits for the previous one, and returns
+    /// the same result. This only applies to calls whose arguments are all plain values, see
+    /// [`PlainValue`], tables comparing equal when they have the same contents in the same
+    /// iteration order. Other calls always call `func`.
+    pub fn build_singleflight<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + Sync + Clone + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        let name = self
+            .name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAME.to_string());
+        let limit = self.max_concurrency.map(Semaphore::new);
+        let in_flight = Arc::new(InFlight::<Ret>::default());
+        self.ctx
+            .create_named_async_function(&name, move |ctx, args: MultiValue<'lua>| {
+                let key = args
+                    .iter()
+                    .map(|a| PlainValue::from_lua_value(a.clone()))
+                    .collect::<Result<Vec<_>>>();
+                let key = match key {
+                    Ok(key) => key,
+                    Err(_) => {
+                        return match Arg::from_lua_multi(args, ctx) {
+                            Ok(arg) => limited(&limit, func(ctx, arg)).boxed(),
+                            Err(e) => futures::future::err(e).boxed(),
+                        };
+                    }
+                };
+
+                let mut calls = in_flight.lock().unwrap();
+                if let Some((_, call)) = calls.iter().find(|(k, _)| *k == key) {
+                    return call.clone().boxed();
+                }
+                let fut = match Arg::from_lua_multi(args, ctx) {
+                    Ok(arg) => limited(&limit, func(ctx, arg)),
+                    Err(e) => return futures::future::err(e).boxed(),
+                };
+                let call = singleflight_call(Arc::downgrade(&in_flight), key.clone(), fut);
+                calls.push((key, call.clone()));
+                call.boxed()
+            })
+    }
+}
+
+/// Run `fut`, removing it from `in_flight` once done
+fn singleflight_call<Ret, RetFut>(
+    in_flight: Weak<InFlight<Ret>>,
+    key: Vec<PlainValue>,
+    fut: RetFut,
+) -> Shared<BoxFuture<'static, Result<Ret>>>
+where
+    Ret: 'static + Send + Sync + Clone,
+    RetFut: 'static + Send + Future,
+    RetFut::Output: IntoLuaResult<Ret>,
+{
+    async move {
+        let res = fut.await.into_lua_result();
+        if let Some(in_flight) = in_flight.upgrade() {
+            in_flight.lock().unwrap().retain(|(k, _)| *k != key);
+        }
+        res
+    }
+    .boxed()
+    .shared()
+}
+
+/// Wait for a permit of `limit`, if any, before running `fut`
+fn limited<Ret, RetFut>(
+    limit: &Option<Semaphore>,
+    fut: RetFut,
+) -> impl 'static + Send + Future<Output = Result<Ret>>
+where
+    RetFut: 'static + Send + Future,
+    RetFut::Output: IntoLuaResult<Ret>,
+{
+    let acquire = limit.as_ref().map(Semaphore::acquire);
+    async move {
+        let _permit = match acquire {
+            Some(acquire) => Some(acquire.await),
+            None => None,
+        };
+        fut.await.into_lua_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::{ChunkExt, FunctionExt};
+
+    #[test]
+    fn concurrency_limit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_seen = Arc::new(AtomicUsize::new(0));
+        Lua::new().context(|lua| {
+            let (i, m) = (in_flight.clone(), max_seen.clone());
+            let f = lua
+                .async_function_builder()
+                .name("backend")
+                .max_concurrency(2)
+                .build(move |_, ()| {
+                    let (i, m) = (i.clone(), m.clone());
+                    async move {
+                        let now = i.fetch_add(1, Ordering::SeqCst) + 1;
+                        m.fetch_max(now, Ordering::SeqCst);
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        i.fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    }
+                })
+                .unwrap();
+            lua.globals().set("backend", f).unwrap();
+
+            let calls = (0..5)
+                .map(|_| {
+                    lua.load(r#"function() backend() end"#)
+                        .eval::<Function>()
+                        .unwrap()
+                })
+                .map(|f| (f, ()))
+                .collect::<Vec<_>>();
+            let res = executor::block_on(crate::call_async_many::<_, ()>(lua, calls));
+            assert!(res.into_iter().all(|r| r.is_ok()));
+            executor::block_on(lua.load(r#"backend()"#).exec_async(lua)).unwrap();
+        });
+        assert_eq!(max_seen.load(Ordering::SeqCst), 2);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn singleflight() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        Lua::new().context(|lua| {
+            let c = calls.clone();
+            let f = lua
+                .async_function_builder()
+                .build_singleflight(move |_, key: String| {
+                    c.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        Ok(key + "!")
+                    }
+                })
+                .unwrap();
+            lua.globals().set("fetch", f).unwrap();
+
+            let func = lua
+                .load(r#"function(key) return fetch(key) end"#)
+                .eval::<Function>()
+                .unwrap();
+            let keys = ["a", "a", "b", "a"];
+            let batch = keys.iter().map(|k| (func.clone(), *k)).collect();
+            let res = executor::block_on(crate::call_async_many::<_, String>(lua, batch));
+            let res = res.into_iter().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(res, vec!["a!", "a!", "b!", "a!"]);
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+            // Once the calls completed, the next ones run again
+            executor::block_on(func.call_async::<_, String>(lua, "a")).unwrap();
+            assert_eq!(calls.load(Ordering::SeqCst), 3);
+        });
+    }
+}
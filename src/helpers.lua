@@ -0,0 +1,48 @@
+function(pending)
+    local helpers = {}
+
+    function helpers.async_wrap(f)
+        local co = coroutine.create(f)
+        return function(...)
+            local res = table.pack(coroutine.resume(co, ...))
+            while true do
+                if not res[1] then
+                    error(res[2], 0)
+                elseif coroutine.status(co) ~= "dead" and res[2] == pending then
+                    res = table.pack(coroutine.resume(co, coroutine.yield(pending)))
+                else
+                    return table.unpack(res, 2, res.n)
+                end
+            end
+        end
+    end
+
+    local function is_async_iterable(obj)
+        return type(obj) == "userdata" or (type(obj) == "table" and type(obj.next) == "function")
+    end
+
+    function helpers.async_pairs(obj)
+        if not is_async_iterable(obj) then
+            return pairs(obj)
+        end
+        return function()
+            return obj:next()
+        end, obj, nil
+    end
+
+    function helpers.async_ipairs(obj)
+        if not is_async_iterable(obj) then
+            return ipairs(obj)
+        end
+        local i = 0
+        return function()
+            local v = obj:next()
+            if v ~= nil then
+                i = i + 1
+                return i, v
+            end
+        end, obj, nil
+    end
+
+    return helpers
+end
@@ -0,0 +1,356 @@
+//! An HTTP client for Lua scripts, without blocking the executor
+//!
+//! The requests are made with the blocking client of [`reqwest`], on the blocking threads of the
+//! spawner of the Lua state like the filesystem functions, so that the crate still does not
+//! depend on an async runtime.
+//!
+//! This module is only available with the `http` feature.
+
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use reqwest::{blocking::Client, Method};
+use rlua::{Context, Error, Result, Table, ToLua, UserData, UserDataMethods, Value};
+
+use crate::{
+    blocking,
+    buffer::{buffer_or_string, Buffer},
+    global_table,
+    time::Millis,
+    ContextExt, UserDataMethodsExt,
+};
+
+/// The client of a Lua state, created on first use as creating it blocks
+#[derive(Clone, Default)]
+struct LazyClient(Arc<std::sync::Mutex<Option<Client>>>);
+
+impl LazyClient {
+    /// Get the client, creating it if need be, from the blocking threads
+    fn get(&self) -> io::Result<Client> {
+        let mut client = self.0.lock().unwrap();
+        if let Some(client) = &*client {
+            // The clients are reference-counted handles
+            return Ok(client.clone());
+        }
+        let created = Client::builder().build().map_err(io::Error::other)?;
+        *client = Some(created.clone());
+        Ok(created)
+    }
+}
+
+/// A request built from the arguments of `http.get` or `http.request`
+struct Request {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Buffer>,
+    timeout: Option<Duration>,
+}
+
+impl Request {
+    fn get(url: String) -> Request {
+        Request {
+            method: Method::GET,
+            url,
+            headers: Vec::new(),
+            body: None,
+            timeout: None,
+        }
+    }
+
+    fn from_lua_table<'lua>(ctx: Context<'lua>, opts: Table<'lua>) -> Result<Request> {
+        let mut request = Request::get(opts.get("url")?);
+        if let Some(method) = opts.get::<_, Option<String>>("method")? {
+            request.method = Method::from_bytes(method.as_bytes())
+                .map_err(|_| Error::RuntimeError(format!("invalid HTTP method {:?}", method)))?;
+        }
+        if let Some(headers) = opts.get::<_, Option<Table>>("headers")? {
+            for header in headers.pairs::<String, String>() {
+                request.headers.push(header?);
+            }
+        }
+        request.body = match opts.get::<_, Value>("body")? {
+            Value::Nil => None,
+            body => Some(buffer_or_string(ctx, body)?),
+        };
+        request.timeout = opts.get::<_, Option<Millis>>("timeout")?.map(|t| t.0);
+        Ok(request)
+    }
+
+    /// Send the request with the spawner of `ctx` once `client` is available, returning the
+    /// response once its headers are received
+    fn send(self, ctx: Context, client: LazyClient) -> impl Future<Output = Result<Response>> {
+        blocking(ctx.spawner(), "http", move || {
+            let mut builder = client.get()?.request(self.method, &self.url);
+            for (name, value) in self.headers {
+                builder = builder.header(name, value);
+            }
+            if let Some(body) = self.body {
+                builder = builder.body(body.into_bytes());
+            }
+            if let Some(timeout) = self.timeout {
+                builder = builder.timeout(timeout);
+            }
+            let response = builder.send().map_err(io::Error::other)?;
+            Ok(Response::new(response))
+        })
+    }
+}
+
+/// The body of a response, read at most once, by the first `body` or `json` call
+enum Body {
+    Unread(reqwest::blocking::Response),
+    Read(Shared<BoxFuture<'static, Result<Buffer>>>),
+}
+
+/// A response, the Lua handle returned by `http.get` and `http.request`
+struct Response {
+    status: u16,
+    url: String,
+    /// The headers, with lowercase names, in the order they were received
+    headers: Vec<(String, String)>,
+    body: Arc<std::sync::Mutex<Option<Body>>>,
+}
+
+impl Response {
+    fn new(response: reqwest::blocking::Response) -> Response {
+        let headers = response.headers().iter().map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            (name.as_str().to_string(), value.into_owned())
+        });
+        Response {
+            status: response.status().as_u16(),
+            url: response.url().to_string(),
+            headers: headers.collect(),
+            body: Arc::new(std::sync::Mutex::new(Some(Body::Unread(response)))),
+        }
+    }
+
+    /// The values of the header `name`, joined with commas, if there are any
+    fn header(&self, name: &str) -> Option<String> {
+        let name = name.to_ascii_lowercase();
+        let values = self.headers.iter().filter(|(n, _)| *n == name);
+        let values = values.map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    /// Read the body with the spawner of `ctx`, or wait for the read started by a previous call
+    ///
+    /// The read goes on if the returned future is dropped, for the next calls.
+    fn body(&self, ctx: Context) -> Shared<BoxFuture<'static, Result<Buffer>>> {
+        let mut body = self.body.lock().unwrap();
+        let read = match body.take() {
+            Some(Body::Unread(response)) => blocking(ctx.spawner(), "http", move || {
+                let read = response.bytes().map_err(io::Error::other)?;
+                Ok(Buffer::from(read))
+            })
+            .boxed()
+            .shared(),
+            Some(Body::Read(read)) => read,
+            None => unreachable!("the body is only taken out while locked"),
+        };
+        *body = Some(Body::Read(read.clone()));
+        read
+    }
+}
+
+/// A JSON value, converted to the equivalent Lua value, with `null` converted to `nil`
+struct Json(serde_json::Value);
+
+impl<'lua> ToLua<'lua> for Json {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        use serde_json::Value as J;
+        Ok(match self.0 {
+            J::Null => Value::Nil,
+            J::Bool(b) => Value::Boolean(b),
+            J::Number(n) => match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Number(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            J::String(s) => Value::String(ctx.create_string(&s)?),
+            J::Array(items) => Value::Table(ctx.create_sequence_from(items.into_iter().map(Json))?),
+            J::Object(fields) => {
+                Value::Table(ctx.create_table_from(fields.into_iter().map(|(k, v)| (k, Json(v))))?)
+            }
+        })
+    }
+}
+
+impl UserData for Response {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("status", |_, this, ()| Ok(this.status));
+        methods.add_method("url", |_, this, ()| Ok(this.url.clone()));
+        methods.add_method("header", |_, this, name: String| Ok(this.header(&name)));
+        methods.add_method("headers", |ctx, this, ()| {
+            let headers = ctx.create_table()?;
+            for (name, _) in &this.headers {
+                headers.set(name.as_str(), this.header(name))?;
+            }
+            Ok(headers)
+        });
+
+        methods.add_async_method("body", |ctx, this, ()| this.body(ctx));
+        methods.add_async_method("json", |ctx, this, ()| {
+            let body = this.body(ctx);
+            async move {
+                let json = serde_json::from_slice(&body.await?).map_err(Error::external)?;
+                Ok(Json(json))
+            }
+        });
+    }
+}
+
+/// Install the HTTP client functions in the `http` table of the globals, creating it if need
+/// be
+///
+/// The installed functions are:
+///  * `http.get(url)`, that sends a `GET` request to `url`.
+///  * `http.request(opts)`, that sends the request described by the `opts` table: its `url`,
+///    and optionally its `method` (`"GET"` by default), its `headers`, as a table from names to
+///    values, its `body`, as a string or [`Buffer`], and its `timeout` in milliseconds, covering
+///    the whole request including the reading of the body.
+///
+/// Both are async and return the response `r` once its headers are received, whatever its
+/// status, failing only if no response was received. `r` has the methods `r:status()`,
+/// `r:url()`, the final URL after redirections, `r:header(name)`, the value of the header
+/// `name` (case-insensitively, the values of repeated headers being joined with commas), and
+/// `r:headers()`, a table of all of them by lowercase name. Its body is read by the async
+/// methods `r:body()`, that returns it as a [`Buffer`], and `r:json()`, that parses it as JSON
+/// into Lua values, JSON `null`s becoming `nil`s. Both can be called several times, the body
+/// being read only once.
+///
+/// All the calls made from the Lua state share a connection pool.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let client = LazyClient::default();
+    let http = global_table(ctx, "http")?;
+    let c = client.clone();
+    http.set(
+        "get",
+        ctx.create_named_async_function("http.get", move |ctx, url: String| {
+            Request::get(url).send(ctx, c.clone())
+        })?,
+    )?;
+    http.set(
+        "request",
+        ctx.create_named_async_function("http.request", move |ctx, opts: Table| {
+            let sent = Request::from_lua_table(ctx, opts).map(|r| r.send(ctx, client.clone()));
+            async move { sent?.await }
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::FunctionExt;
+
+    /// Serve `count` requests, replying to each with its request line and body, as JSON
+    fn echo_server(count: usize) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for _ in 0..count {
+                let (socket, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(socket);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let (mut length, mut test_header) = (0, String::new());
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end().to_ascii_lowercase();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some(l) = header.strip_prefix("content-length: ") {
+                        length = l.parse().unwrap();
+                    }
+                    if let Some(t) = header.strip_prefix("x-test: ") {
+                        test_header = t.to_string();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let reply = serde_json::json!({
+                    "request": request_line.trim_end(),
+                    "body": String::from_utf8(body).unwrap(),
+                    "header": test_header,
+                    "nothing": null,
+                    "numbers": [1, 2.5],
+                })
+                .to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nX-Multi: a\r\n\
+                     X-Multi: b\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    reply.len(),
+                    reply,
+                )
+                .unwrap();
+            }
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn http_requests() {
+        let (port, server) = echo_server(2);
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let res = lua
+                .load(
+                    r#"
+                        return function(base)
+                            local r = http.get(base .. "/path?q=1")
+                            local got = r:json()
+                            assert(r:status() == 201 and r:header("content-TYPE") == "application/json")
+                            assert(r:header("x-multi") == "a, b" and r:headers()["x-multi"] == "a, b")
+                            assert(got.nothing == nil and got.numbers[1] == 1 and got.numbers[2] == 2.5)
+                            assert(tostring(r:body()):find('"request"'))
+
+                            r = http.request {
+                                url = base .. "/post",
+                                method = "POST",
+                                headers = { ["X-Test"] = "yes" },
+                                body = "payload",
+                                timeout = 5000,
+                            }
+                            local posted = r:json()
+                            return got.request, posted.request, posted.body, posted.header
+                        end
+                    "#,
+                )
+                .eval::<rlua::Function>()
+                .unwrap();
+            let base = format!("http://127.0.0.1:{}", port);
+            let res = executor::block_on(
+                res.call_async::<_, (String, String, String, String)>(lua, base),
+            )
+            .expect("failed to make the requests");
+            assert_eq!(res.0, "GET /path?q=1 HTTP/1.1");
+            assert_eq!(res.1, "POST /post HTTP/1.1");
+            assert_eq!((res.2.as_str(), res.3.as_str()), ("payload", "yes"));
+
+            let refused = lua
+                .load(r#"function() http.get("http://127.0.0.1:1") end"#)
+                .eval::<rlua::Function>()
+                .unwrap();
+            assert!(executor::block_on(refused.call_async::<_, ()>(lua, ())).is_err());
+        });
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Reading lines from the host's stdin without blocking the executor
+//!
+//! Reading from stdin blocks, and there is no portable way to do it asynchronously. A
+//! [`LineReader`] thus reads lines on a dedicated thread, and hands them over to the async
+//! functions it installs in Lua.
+
+use std::{
+    io::{self, BufRead},
+    sync::Arc,
+    thread,
+};
+
+use futures::{channel::mpsc, lock::Mutex, SinkExt, StreamExt};
+use rlua::{Context, Error, Function, MultiValue, Result, Table};
+
+use crate::{global_table, ContextExt};
+
+/// A reader of lines, shared by the `io_async.read_line` and `io_async.lines` Lua functions
+///
+/// Cloning it gives another handle to the same reader. Reading a line is cancellation-safe: if
+/// the future returned by [`LineReader::read_line`] (or the Lua call) is dropped before
+/// completing, no line is lost, and the next read returns it.
+#[derive(Clone)]
+pub struct LineReader {
+    lines: Arc<Mutex<mpsc::Receiver<io::Result<String>>>>,
+}
+
+impl LineReader {
+    /// Read lines from `reader`, on a new thread
+    ///
+    /// The thread reads at most one line ahead of the async readers. It stops at the end of the
+    /// input, on the first error, or when the next line is read after all the handles to the
+    /// reader have been dropped. This fails if the thread cannot be spawned.
+    pub fn new<R: 'static + Send + BufRead>(mut reader: R) -> io::Result<LineReader> {
+        let (mut tx, rx) = mpsc::channel(0);
+        thread::Builder::new()
+            .name("rlua-async line reader".to_string())
+            .spawn(move || loop {
+                let mut line = String::new();
+                let res = match reader.read_line(&mut line) {
+                    Ok(0) => return,
+                    Ok(_) => {
+                        if line.ends_with('\n') {
+                            line.pop();
+                            if line.ends_with('\r') {
+                                line.pop();
+                            }
+                        }
+                        Ok(line)
+                    }
+                    Err(e) => Err(e),
+                };
+                let is_err = res.is_err();
+                if futures::executor::block_on(tx.send(res)).is_err() || is_err {
+                    return;
+                }
+            })?;
+        Ok(LineReader {
+            lines: Arc::new(Mutex::new(rx)),
+        })
+    }
+
+    /// Read lines from the process' stdin
+    pub fn stdin() -> io::Result<LineReader> {
+        LineReader::new(io::BufReader::new(io::stdin()))
+    }
+
+    /// Read the next line, without its line terminator, or `None` at the end of the input
+    pub async fn read_line(&self) -> io::Result<Option<String>> {
+        self.lines.lock().await.next().await.transpose()
+    }
+
+    /// Install `io_async.read_line()` and `io_async.lines()` in the globals, creating the
+    /// `io_async` table if need be
+    ///
+    /// `io_async.read_line()` is an async function returning the next line, or `nil` at the end
+    /// of the input, and `io_async.lines()` returns an iterator over the lines, for use in `for`
+    /// loops.
+    pub fn install<'lua>(&self, ctx: Context<'lua>) -> Result<()> {
+        let io_async = global_table(ctx, "io_async")?;
+        self.set_functions(ctx, &io_async, "io_async.read_line")
+    }
+
+    /// Set the `read_line` and `lines` functions of `table`, `name` being the name of
+    /// `read_line` for the middleware and in errors
+    ///
+    /// The functions ignore their arguments, so that they can be called as methods.
+    pub(crate) fn set_functions<'lua>(
+        &self,
+        ctx: Context<'lua>,
+        table: &Table<'lua>,
+        name: &str,
+    ) -> Result<()> {
+        table.set("read_line", self.create_read_line_function(ctx, name)?)?;
+        let reader = self.clone();
+        let name = name.to_string();
+        table.set(
+            "lines",
+            ctx.create_function(move |ctx, _: MultiValue| {
+                reader.create_read_line_function(ctx, &name)
+            })?,
+        )
+    }
+
+    /// Install `prompt(text)` in the globals, an async function that writes `text` to `output`,
+    /// flushes it, and returns the next line read, or `nil` at the end of the input
+    ///
+    /// Other tasks keep running while the user types. There is no line editing beyond what the
+    /// terminal itself provides.
+    #[cfg(feature = "prompt")]
+    pub fn install_prompt<'lua, W>(
+        &self,
+        ctx: Context<'lua>,
+        output: crate::output::AsyncOutput<W>,
+    ) -> Result<()>
+    where
+        W: 'static + Send + Unpin + futures::io::AsyncWrite,
+    {
+        let reader = self.clone();
+        let prompt = ctx.create_named_async_function("prompt", move |_, text: rlua::String| {
+            let text = text.as_bytes().to_vec();
+            let reader = reader.clone();
+            let output = output.clone();
+            async move {
+                output.write(&text).await.map_err(Error::external)?;
+                output.flush().await.map_err(Error::external)?;
+                reader.read_line().await.map_err(Error::external)
+            }
+        })?;
+        ctx.globals().set("prompt", prompt)
+    }
+
+    fn create_read_line_function<'lua>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+    ) -> Result<Function<'lua>> {
+        let reader = self.clone();
+        ctx.create_named_async_function(name, move |_, _: MultiValue| {
+            let reader = reader.clone();
+            async move { reader.read_line().await.map_err(Error::external) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn read_lines() {
+        let reader =
+            LineReader::new(io::Cursor::new(b"first\nsecond\r\nthird\nfourth".to_vec())).unwrap();
+        Lua::new().context(|lua| {
+            reader.install(lua).unwrap();
+            let lines = executor::block_on(
+                lua.load(
+                    r#"
+                        local lines = { io_async.read_line() }
+                        for line in io_async.lines() do
+                            lines[#lines + 1] = line
+                        end
+                        return lines
+                    "#,
+                )
+                .call_async::<_, Vec<String>>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(lines, vec!["first", "second", "third", "fourth"]);
+        });
+        assert!(executor::block_on(reader.read_line()).unwrap().is_none());
+    }
+
+    #[cfg(feature = "prompt")]
+    #[test]
+    fn prompt() {
+        let reader = LineReader::new(io::Cursor::new(b"Ferris\n".to_vec())).unwrap();
+        let output = crate::output::AsyncOutput::new(Vec::new(), 16);
+        Lua::new().context(|lua| {
+            reader.install_prompt(lua, output.clone()).unwrap();
+            let res = executor::block_on(
+                lua.load(r#"return prompt("name? "), prompt("again? ")"#)
+                    .call_async::<_, (String, Option<String>)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, ("Ferris".to_string(), None));
+        });
+    }
+}
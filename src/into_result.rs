@@ -0,0 +1,99 @@
+use rlua::{Result, Variadic};
+
+use crate::{
+    buffer::Buffer,
+    owned::{OwnedFunction, OwnedTable, OwnedThread},
+    plain::PlainValue,
+    ModuleSource, RegistryValue,
+};
+
+/// The outputs of the futures of async functions, whose results are `Ret`
+///
+/// This lets the futures of infallible async functions output their results directly, instead of
+/// wrapping them in `Ok`. It is implemented for [`rlua::Result`], and for the usual types that
+/// can be returned to Lua. Other types can still be returned by wrapping them in `Ok`.
+pub trait IntoLuaResult<Ret> {
+    /// Convert the output into the result of the async function
+    fn into_lua_result(self) -> Result<Ret>;
+}
+
+impl<Ret> IntoLuaResult<Ret> for Result<Ret> {
+    fn into_lua_result(self) -> Result<Ret> {
+        self
+    }
+}
+
+macro_rules! impl_into_lua_result {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl IntoLuaResult<$ty> for $ty {
+                fn into_lua_result(self) -> Result<$ty> {
+                    Ok(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_into_lua_result!(
+    (),
+    bool,
+    i8,
+    u8,
+    i16,
+    u16,
+    i32,
+    u32,
+    i64,
+    u64,
+    i128,
+    u128,
+    isize,
+    usize,
+    f32,
+    f64,
+    String,
+    &'static str,
+    Buffer,
+    PlainValue,
+    RegistryValue,
+    OwnedFunction,
+    OwnedTable,
+    OwnedThread,
+    ModuleSource,
+);
+
+impl<T> IntoLuaResult<Option<T>> for Option<T> {
+    fn into_lua_result(self) -> Result<Option<T>> {
+        Ok(self)
+    }
+}
+
+impl<T> IntoLuaResult<Vec<T>> for Vec<T> {
+    fn into_lua_result(self) -> Result<Vec<T>> {
+        Ok(self)
+    }
+}
+
+impl<T> IntoLuaResult<Variadic<T>> for Variadic<T> {
+    fn into_lua_result(self) -> Result<Variadic<T>> {
+        Ok(self)
+    }
+}
+
+macro_rules! impl_into_lua_result_tuple {
+    ($($name:ident),+) => {
+        impl<$($name),+> IntoLuaResult<($($name,)+)> for ($($name,)+) {
+            fn into_lua_result(self) -> Result<($($name,)+)> {
+                Ok(self)
+            }
+        }
+    };
+}
+
+impl_into_lua_result_tuple!(A);
+impl_into_lua_result_tuple!(A, B);
+impl_into_lua_result_tuple!(A, B, C);
+impl_into_lua_result_tuple!(A, B, C, D);
+impl_into_lua_result_tuple!(A, B, C, D, E);
+impl_into_lua_result_tuple!(A, B, C, D, E, F);
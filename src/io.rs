@@ -0,0 +1,259 @@
+//! Handing Rust async readers and writers to Lua scripts
+//!
+//! A [`ReadHandle`] wraps any [`AsyncRead`], eg. a socket, a pipe or a decompression stream, into
+//! a userdata with async methods, so that scripts read from all of them the same way, without
+//! blocking the executor. A [`WriteHandle`] does the same for any [`AsyncWrite`], eg. a file, a
+//! socket or an encoder.
+
+use std::sync::Arc;
+
+use futures::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    lock::Mutex,
+};
+use rlua::{Context, Error, Result, ToLua, UserData, UserDataMethods, Value};
+
+use crate::{buffer::buffer_or_string, UserDataMethodsExt};
+
+/// The maximum number of bytes `r:read(n)` reads at once, whatever `n`
+const MAX_READ: usize = 64 * 1024;
+
+/// Bytes read for Lua, converted to a Lua string
+struct Bytes(Vec<u8>);
+
+impl<'lua> ToLua<'lua> for Bytes {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        ctx.create_string(&self.0).map(Value::String)
+    }
+}
+
+/// A reader, readable from Lua
+///
+/// In Lua, it has the async methods:
+///  * `r:read(n)`, that reads at most `n` bytes, as soon as some are available, and returns them
+///    as a string, or `nil` at the end of the input. At most 64KiB are read at once, whatever
+///    `n`.
+///  * `r:read_exact(n)`, that reads exactly `n` bytes, failing if the input ends before,
+///  * `r:read_to_end()`, that reads all the rest of the input.
+///
+/// The calls are served one at a time, in order. Cancelling a `r:read_exact(n)` or
+/// `r:read_to_end()` call midway, eg. with `async.timeout`, loses the bytes it already read.
+///
+/// Cloning the handle gives another handle to the same reader.
+pub struct ReadHandle<R> {
+    reader: Arc<Mutex<R>>,
+}
+
+impl<R> Clone for ReadHandle<R> {
+    fn clone(&self) -> Self {
+        ReadHandle {
+            reader: self.reader.clone(),
+        }
+    }
+}
+
+impl<R> ReadHandle<R>
+where
+    R: 'static + Send + Unpin + AsyncRead,
+{
+    /// Wrap `reader`
+    pub fn new(reader: R) -> ReadHandle<R> {
+        ReadHandle {
+            reader: Arc::new(Mutex::new(reader)),
+        }
+    }
+}
+
+impl<R> UserData for ReadHandle<R>
+where
+    R: 'static + Send + Unpin + AsyncRead,
+{
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("read", |_, this, n: usize| {
+            let reader = this.reader.clone();
+            async move {
+                if n == 0 {
+                    return Ok(Some(Bytes(Vec::new())));
+                }
+                let mut buf = vec![0; n.min(MAX_READ)];
+                let read = reader
+                    .lock()
+                    .await
+                    .read(&mut buf)
+                    .await
+                    .map_err(Error::external)?;
+                buf.truncate(read);
+                Ok((read > 0).then_some(Bytes(buf)))
+            }
+        });
+        methods.add_async_method("read_exact", |_, this, n: usize| {
+            let reader = this.reader.clone();
+            async move {
+                // Not allocated upfront, as `n` may be far larger than the input
+                let mut buf = Vec::new();
+                let mut reader = reader.lock().await;
+                (&mut *reader)
+                    .take(n as u64)
+                    .read_to_end(&mut buf)
+                    .await
+                    .map_err(Error::external)?;
+                if buf.len() < n {
+                    return Err(Error::external(std::io::Error::from(
+                        std::io::ErrorKind::UnexpectedEof,
+                    )));
+                }
+                Ok(Bytes(buf))
+            }
+        });
+        methods.add_async_method("read_to_end", |_, this, ()| {
+            let reader = this.reader.clone();
+            async move {
+                let mut buf = Vec::new();
+                let mut reader = reader.lock().await;
+                reader
+                    .read_to_end(&mut buf)
+                    .await
+                    .map_err(Error::external)?;
+                Ok(Bytes(buf))
+            }
+        });
+    }
+}
+
+/// A writer, writable from Lua
+///
+/// In Lua, it has the async methods:
+///  * `w:write(data)`, that writes all of `data`, a string or a [`Buffer`](crate::buffer::Buffer),
+///  * `w:flush()`, that waits for the written data to reach its destination, eg. for the
+///    buffers of the writer to be flushed,
+///  * `w:shutdown()`, that flushes and closes the writer, eg. to signal the end of the output to
+///    the peer of a socket.
+///
+/// The calls are served one at a time, in order. Cancelling a `w:write(data)` call midway, eg.
+/// with `async.timeout`, may leave only part of `data` written.
+///
+/// Cloning the handle gives another handle to the same writer.
+pub struct WriteHandle<W> {
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W> Clone for WriteHandle<W> {
+    fn clone(&self) -> Self {
+        WriteHandle {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<W> WriteHandle<W>
+where
+    W: 'static + Send + Unpin + AsyncWrite,
+{
+    /// Wrap `writer`
+    pub fn new(writer: W) -> WriteHandle<W> {
+        WriteHandle {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl<W> UserData for WriteHandle<W>
+where
+    W: 'static + Send + Unpin + AsyncWrite,
+{
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("write", |ctx, this, data: Value| {
+            let data = buffer_or_string(ctx, data);
+            let writer = this.writer.clone();
+            async move {
+                let data = data?;
+                let mut writer = writer.lock().await;
+                writer.write_all(&data).await.map_err(Error::external)
+            }
+        });
+        methods.add_async_method("flush", |_, this, ()| {
+            let writer = this.writer.clone();
+            async move { writer.lock().await.flush().await.map_err(Error::external) }
+        });
+        methods.add_async_method("shutdown", |_, this, ()| {
+            let writer = this.writer.clone();
+            async move { writer.lock().await.close().await.map_err(Error::external) }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{executor, io::Cursor};
+    use rlua::Lua;
+
+    use crate::{buffer::Buffer, ChunkExt};
+
+    #[test]
+    fn read_handle() {
+        Lua::new().context(|lua| {
+            let input = Cursor::new(b"head\0body and tail".to_vec());
+            lua.globals().set("input", ReadHandle::new(input)).unwrap();
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local head = input:read(5)
+                        local body = input:read_exact(4)
+                        assert(input:read(0) == "")
+                        local tail = input:read_to_end()
+                        assert(input:read(1) == nil and input:read_to_end() == "")
+                        return head, body, tail
+                    "#,
+                )
+                .call_async::<_, (rlua::String, String, String)>(lua, ()),
+            )
+            .expect("failed to read");
+            assert_eq!(res.0.as_bytes(), b"head\0");
+            assert_eq!((res.1.as_str(), res.2.as_str()), ("body", " and tail"));
+
+            let eof = lua.load("input:read_exact(1)").exec_async(lua);
+            let err = executor::block_on(eof).unwrap_err();
+            assert!(err.to_string().contains("end of file"), "{}", err);
+
+            // Not allocating the sizes asked for
+            let input = Cursor::new(b"short".to_vec());
+            lua.globals().set("input", ReadHandle::new(input)).unwrap();
+            let huge = lua
+                .load("return input:read(1 << 40)")
+                .call_async::<_, String>(lua, ());
+            assert_eq!(executor::block_on(huge).unwrap(), "short");
+            let huge = lua.load("input:read_exact(1 << 40)").exec_async(lua);
+            assert!(executor::block_on(huge).is_err());
+        });
+    }
+
+    #[test]
+    fn write_handle() {
+        Lua::new().context(|lua| {
+            let output = WriteHandle::new(Cursor::new(Vec::new()));
+            lua.globals().set("output", output.clone()).unwrap();
+            lua.globals()
+                .set("buffer", Buffer::from(b" \0buffer".to_vec()))
+                .unwrap();
+            executor::block_on(
+                lua.load(
+                    r#"
+                        output:write("string")
+                        output:write(buffer)
+                        output:flush()
+                        output:shutdown()
+                    "#,
+                )
+                .exec_async(lua),
+            )
+            .expect("failed to write");
+            let written = output.writer.try_lock().unwrap().get_ref().clone();
+            assert_eq!(written, b"string \0buffer");
+
+            let invalid = lua.load("output:write({})").exec_async(lua);
+            assert!(executor::block_on(invalid).is_err());
+        });
+    }
+}
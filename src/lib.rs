@@ -1,4 +1,3 @@
-// #![feature(async_closure)] // For when the remaining scope test is useful
 //! See the [README](https://github.com/Ekleog/rlua-async) for more general information
 // TODO: uncomment when stable #![doc(include = "../README.md")]
 // TODO: also add a link to the changelog somewhere (as a module?)
@@ -6,28 +5,101 @@
 // # Implementation details note
 //
 // Having a `Poll::Pending` returned from a Rust `async` function will trigger a
-// `coroutine.yield()`, with no arguments and that doesn't use its return value.
-// This `coroutine.yield()` will bubble up to the closest `create_thread` Rust call (assuming the
-// Lua code doesn't use coroutines in-between, which would break all hell loose).
+// `coroutine.yield(marker)`, with the light userdata returned by `pending_marker()` as only
+// argument and that doesn't use its return value.
+// This `coroutine.yield(marker)` will bubble up to the closest `create_thread` Rust call (assuming
+// the Lua code doesn't use coroutines in-between, which would break all hell loose). The marker
+// allows telling these yields apart from yields done by the Lua code itself, eg. in generators.
 
 use std::{
+    ffi::c_void,
     future::Future,
     marker::PhantomData,
     pin::Pin,
+    sync::Arc,
     task::{self, Poll},
+    time::{Duration, Instant},
 };
 
-use futures::future;
+use futures::FutureExt;
 use rlua::{
-    Chunk, Context, FromLuaMulti, Function, MultiValue, Result, Scope, Thread, ThreadStatus,
-    ToLuaMulti, UserData, UserDataMethods,
+    AnyUserData, Chunk, Context, FromLuaMulti, Function, LightUserData, MultiValue, Result, Scope,
+    Thread, ThreadStatus, ToLuaMulti, UserData, UserDataMethods,
 };
 use scoped_tls::scoped_thread_local;
 
+pub mod actor;
+mod app_data;
+#[cfg(feature = "async-lib")]
+pub mod async_lib;
+mod async_lua;
+pub mod buffer;
+mod call;
+pub mod channel;
+pub mod combinators;
+mod coroutine;
+mod depth;
+#[cfg(feature = "fs")]
+pub mod fs;
+pub mod fsm;
+mod function;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod input;
+mod into_result;
+pub mod io;
+mod local;
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub mod log;
+pub mod middleware;
+mod module;
+pub mod net;
+pub mod offload;
+pub mod output;
+pub mod owned;
+pub mod plain;
+pub mod pool;
+pub mod process;
+pub mod reactor;
+mod registry;
+mod require;
+mod semaphore;
+mod spawn;
+pub mod spawner;
+pub mod stream;
+mod table;
+pub mod time;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod userdata;
+#[cfg(feature = "ws")]
+pub mod ws;
+
+use depth::DepthGuard;
+use local::ThreadBound;
+use middleware::Middleware;
+use spawner::Spawner;
+
+pub use async_lua::{AsyncLua, AsyncLuaCall};
+pub use call::{call_async_many, AsyncCall, TimeoutError};
+pub use coroutine::{ResumeAsync, ThreadExt};
+pub use depth::{max_call_depth, set_max_call_depth, RecursionLimitError, DEFAULT_MAX_CALL_DEPTH};
+pub use function::AsyncFunctionBuilder;
+pub use into_result::IntoLuaResult;
+pub use module::AsyncModuleBuilder;
+pub use registry::RegistryValue;
+pub use require::ModuleSource;
+pub use spawn::{RunSpawned, TaskHandle};
+pub use table::TableExt;
+pub use userdata::{AnyUserDataExt, UserDataMethodsExt};
+
 /// A "prelude" that provides all the extension traits that need to be in scope for the
 /// `async`-related functions to be usable.
 pub mod prelude {
-    pub use super::{ChunkExt, ContextExt, FunctionExt, ScopeExt};
+    pub use super::{
+        AnyUserDataExt, ChunkExt, ContextExt, FunctionExt, ScopeExt, TableExt, ThreadExt,
+        UserDataMethodsExt,
+    };
 }
 
 // Safety invariant: This always points to a valid `task::Context`.
@@ -39,6 +111,80 @@ pub mod prelude {
 //  * we can't clone the `Context`, as it's not `Clone`
 scoped_thread_local!(static FUTURE_CTX: *mut ());
 
+// Only the address of this static matters, see `pending_marker`
+static PENDING_MARKER: u8 = 0;
+
+/// The name of the async functions created without one, eg. by
+/// [`ContextExt::create_async_function`]
+pub(crate) static DEFAULT_NAME: &str = "async function";
+
+/// The value yielded by the coroutine yield helpers when the future they poll is pending
+fn pending_marker() -> LightUserData {
+    LightUserData(&PENDING_MARKER as *const u8 as *mut c_void)
+}
+
+/// Whether the values yielded by a thread are a yield from the coroutine yield helpers
+fn is_pending_yield(values: &MultiValue) -> bool {
+    match values.iter().next() {
+        Some(rlua::Value::LightUserData(ud)) => *ud == pending_marker(),
+        _ => false,
+    }
+}
+
+/// Compile `source` for evaluation, see [`ContextExt::eval_async`]
+fn load_eval<'lua>(ctx: Context<'lua>, source: &[u8]) -> Result<Function<'lua>> {
+    // First, try interpreting the lua as an expression by adding "return", then as a
+    // statement. This is the same thing the actual lua repl, as well as rlua, do.
+    let mut expression = b"return ".to_vec();
+    expression.extend_from_slice(source);
+    match ctx.load(&expression).into_function() {
+        Ok(fun) => Ok(fun),
+        Err(_) => ctx.load(source).into_function(),
+    }
+}
+
+/// Get the global table `name`, creating it if need be, for the modules installing Lua libraries
+fn global_table<'lua>(ctx: Context<'lua>, name: &str) -> Result<rlua::Table<'lua>> {
+    let globals = ctx.globals();
+    match globals.get::<_, Option<rlua::Table<'lua>>>(name)? {
+        Some(table) => Ok(table),
+        None => {
+            let table = ctx.create_table()?;
+            globals.set(name, table.clone())?;
+            Ok(table)
+        }
+    }
+}
+
+/// Run the blocking `f` with [`Spawner::spawn_blocking`] on `spawner`, for the modules wrapping
+/// blocking std APIs, `name` naming them in the error if `f` panics
+///
+/// `f` is spawned right away, so that it runs to completion even if the future is dropped.
+fn blocking<T, F>(
+    spawner: Result<Arc<dyn Spawner>>,
+    name: &str,
+    f: F,
+) -> impl Future<Output = Result<T>>
+where
+    T: 'static + Send,
+    F: 'static + Send + FnOnce() -> std::io::Result<T>,
+{
+    let (tx, rx) = futures::channel::oneshot::channel();
+    let spawned = spawner.and_then(|spawner| {
+        let f = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        spawner.spawn_blocking(f).map_err(rlua::Error::external)
+    });
+    let panicked = format!("the blocking {} call panicked", name);
+    async move {
+        spawned?;
+        rx.await
+            .map_err(|_| rlua::Error::RuntimeError(panicked))?
+            .map_err(rlua::Error::external)
+    }
+}
+
 /// Extension trait for [`rlua::Context`]
 pub trait ContextExt<'lua> {
     /// Create an asynchronous function.
@@ -48,19 +194,341 @@ pub trait ContextExt<'lua> {
     /// Lua, it will generate a coroutine, that will prevent any use of coroutines in the said Lua
     /// code and is designed to be called from an `async`-compliant caller such as
     /// [`FunctionExt::call_async`]
+    ///
+    /// If the future is pending while the function is called from a place it cannot yield from,
+    /// eg. outside of an async call or from a `table.sort` comparator, the call fails with an
+    /// error naming the function and explaining the problem.
+    ///
+    /// The future can output a [`Result`], or directly the return values of infallible
+    /// functions, see [`IntoLuaResult`].
     fn create_async_function<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
     where
         Arg: FromLuaMulti<'lua>,
-        Ret: ToLuaMulti<'lua>,
-        RetFut: 'static + Send + Future<Output = Result<Ret>>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
         F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
 
+    /// Create a mutable asynchronous function.
+    ///
+    /// This works exactly like [`ContextExt::create_async_function`], except that `func` can be
+    /// an [`FnMut`], like with [`Context::create_function_mut`], so it can keep mutable state
+    /// (counters, caches, etc.) without wrapping it in a [`Mutex`](std::sync::Mutex). Only the
+    /// call to `func` has mutable access to this state, the returned future must not borrow it.
+    /// If `func` is called again while it is already running, eg. from a function it calls, the
+    /// call fails with [`rlua::Error::RecursiveMutCallback`].
     fn create_async_function_mut<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
     where
         Arg: FromLuaMulti<'lua>,
-        Ret: ToLuaMulti<'lua>,
-        RetFut: 'static + Send + Future<Output = Result<Ret>>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
         F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut;
+
+    /// Create an asynchronous function that can only be called once.
+    ///
+    /// This works like [`ContextExt::create_async_function`], except that `func` is an
+    /// [`FnOnce`], that can consume the resources it captured, eg. a
+    /// [`oneshot::Sender`](futures::channel::oneshot::Sender). `func` is dropped after the first
+    /// call, and all the later calls fail with an error.
+    fn create_async_function_once<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnOnce(Context<'lua>, Arg) -> RetFut;
+
+    /// Create an asynchronous function whose closure, future and results need not be `Send`.
+    ///
+    /// This works like [`ContextExt::create_async_function`], for embedders that keep the Lua
+    /// state on a single thread and want their futures to hold eg. [`Rc`](std::rc::Rc)s or
+    /// [`RefCell`](std::cell::RefCell)s. The function can only be called, and its future
+    /// polled, from the thread that created it: otherwise the call fails with an error. If the
+    /// Lua state is dropped from another thread, `func` and the futures in flight are leaked.
+    fn create_async_function_local<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + ToLuaMulti<'lua>,
+        RetFut: 'static + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Fn(Context<'lua>, Arg) -> RetFut;
+
+    /// Create an asynchronous function with a name.
+    ///
+    /// This works exactly like [`ContextExt::create_async_function`], except that `name` is the
+    /// name the installed [`Middleware`] will see for this function, and the one used in the
+    /// errors it generates, instead of `"async function"`.
+    fn create_named_async_function<Arg, Ret, RetFut, F>(
+        self,
+        name: &str,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
+
+    /// Create a mutable asynchronous function with a name. See
+    /// [`ContextExt::create_named_async_function`] for more details.
+    fn create_named_async_function_mut<Arg, Ret, RetFut, F>(
+        self,
+        name: &str,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut;
+
+    /// Create an asynchronous function whose future can hold Lua values across `await`s.
+    ///
+    /// The arguments of `func` declared as [`RegistryValue`]s, eg. tables or callbacks, are
+    /// stored in the registry before the future is built. Once the future is ready, its output is
+    /// passed to `then` along with the [`Context`], that can resolve them back with
+    /// [`RegistryValue::get`] and compute the return values of the function:
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use futures_timer::Delay;
+    /// # use rlua::{Lua, Table};
+    /// # use rlua_async::{ContextExt, RegistryValue};
+    /// # Lua::new().context(|ctx| -> rlua::Result<()> {
+    /// ctx.create_async_function_with_registry(
+    ///     |_, (opts, ms): (RegistryValue, u64)| async move {
+    ///         Delay::new(Duration::from_millis(ms)).await;
+    ///         opts
+    ///     },
+    ///     |ctx, opts: RegistryValue| opts.get::<Table>(ctx)?.get::<_, String>("name"),
+    /// )?;
+    /// # Ok(())
+    /// # }).unwrap();
+    /// ```
+    fn create_async_function_with_registry<Arg, Out, Ret, RetFut, F, G>(
+        self,
+        func: F,
+        then: G,
+    ) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Out: 'static + Send,
+        Ret: ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Out>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+        G: 'static + Send + Sync + Fn(Context<'lua>, Out) -> Result<Ret>;
+
+    /// Create an asynchronous function running the blocking `func` off the executor.
+    ///
+    /// The arguments are converted from Lua first, then `func` runs with
+    /// [`Spawner::spawn_blocking`] on the spawner of the Lua state, eg. for image processing or
+    /// database calls that would otherwise stall the other async calls. If `func` panics, the
+    /// call fails with an error.
+    fn create_blocking_function<Arg, Ret, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: 'static + Send + FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        F: 'static + Send + Sync + Fn(Arg) -> Result<Ret>;
+
+    /// Create an asynchronous function named `name`, and set it as the global `name`. See
+    /// [`ContextExt::create_named_async_function`] and [`TableExt::set_async_fn`].
+    fn set_async_fn<Arg, Ret, RetFut, F>(self, name: &str, func: F) -> Result<()>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
+
+    /// Set all the given asynchronous functions as globals, see [`ContextExt::set_async_fn`]
+    ///
+    /// As the functions all have the same type, this is mostly useful with boxed closures
+    /// returning boxed futures, eg. [`BoxFuture`](futures::future::BoxFuture)s.
+    fn set_async_fns<'a, I, Arg, Ret, RetFut, F>(self, funcs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a str, F)>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
+
+    /// Create an asynchronous function that only holds a weak reference to `state`.
+    ///
+    /// This is meant for callbacks of long-lived Rust subsystems (timers, event buses, etc.) that
+    /// themselves keep the Lua state alive: capturing an [`Arc`] to the subsystem in the callback
+    /// would create a reference cycle, and the Lua state would never be freed. Here, `state` is
+    /// upgraded each time the function is called, and only kept alive until the returned future
+    /// completes. If `state` has already been dropped, the call fails with
+    /// [`rlua::Error::CallbackDestructed`].
+    fn create_async_function_weak<S, Arg, Ret, RetFut, F>(
+        self,
+        state: &Arc<S>,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        S: 'static + Send + Sync,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arc<S>, Arg) -> RetFut;
+
+    /// Create a table forwarding to `ud`, whose fields are accessed and set with the async
+    /// `__index` and `__newindex` metamethods of its type if it has some, and that is called
+    /// with its async `__call` metamethod, see [`UserDataMethodsExt::add_async_meta_method`].
+    /// The other fields, including the methods, are the ones of `ud`.
+    fn create_async_proxy(self, ud: AnyUserData<'lua>) -> Result<rlua::Table<'lua>>;
+
+    /// Asynchronously evaluate `source` as either an expression or a block, like
+    /// [`rlua::Chunk::eval`] does. See also [`ChunkExt::exec_async`].
+    fn eval_async<S, Ret>(self, source: &S) -> CallAsyncFuture<'lua, (), Ret>
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Ret: FromLuaMulti<'lua>;
+
+    /// Install a [`Middleware`] that will wrap all the calls to the async functions created
+    /// through this trait. See the [`middleware`] module for more details.
+    fn add_async_middleware<M: Middleware>(self, middleware: M) -> Result<()>;
+
+    /// Create a userdata consuming `stream`, whose `s:next()` is an async method returning the
+    /// next item of the stream, or `nil` once it ended
+    ///
+    /// The stream is only polled by `s:next()`, so that a stream fed by a Rust producer, eg. the
+    /// receiver of a bounded channel, applies backpressure to it. The items that are errors
+    /// raise them, while the other ones are converted to the return values of `s:next()`. The
+    /// stream can also be iterated with `for`, as `for item in s:iter() do ... end`, whose loop
+    /// stops at the first `nil` return value as usual.
+    fn create_async_stream<S, T>(self, stream: S) -> Result<AnyUserData<'lua>>
+    where
+        S: 'static + Send + futures::Stream,
+        S::Item: IntoLuaResult<T>,
+        T: 'static + Send + for<'all> ToLuaMulti<'all>;
+
+    /// Create a userdata feeding `sink`, whose `s:send(...)` and `s:close()` are async methods
+    /// that send their arguments as an item and close the sink
+    ///
+    /// `s:send(...)` waits for the sink to be ready for the item and flushed, so that a sink
+    /// consumed by Rust code, eg. the sender of a bounded channel, applies backpressure to the
+    /// Lua scripts. The errors of the sink are raised in Lua.
+    fn create_async_sink<S, T>(self, sink: S) -> Result<AnyUserData<'lua>>
+    where
+        S: 'static + Send + futures::Sink<T>,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        T: 'static + Send + for<'all> FromLuaMulti<'all>;
+
+    /// Start building an async function with extra options, eg. a limit on the number of calls
+    /// in flight, see [`AsyncFunctionBuilder`]
+    fn async_function_builder(self) -> AsyncFunctionBuilder<'lua>;
+
+    /// Start building a Lua module, ie. a table of async functions and constants, see
+    /// [`AsyncModuleBuilder`]
+    fn async_module_builder(self) -> AsyncModuleBuilder<'lua>;
+
+    /// Add an async searcher to `package.searchers`, so that `require` can get the sources of
+    /// modules from async sources, eg. a database or over HTTP
+    ///
+    /// `searcher` is called with the name of the required module, and its future resolves to
+    /// the [`ModuleSource`] of this module, or to `None` to let the next searchers look for it.
+    /// It is appended to `package.searchers`, so is tried after the searchers already there,
+    /// including the ones of the standard library that look for files: remove those from
+    /// `package.searchers` to only load modules through async searchers.
+    ///
+    /// As the `require` of the standard library cannot wait on async functions, it is replaced
+    /// by an equivalent written in Lua, once for the Lua state. With it, the searchers, and the
+    /// code of the modules when first required, can call async functions, as long as `require`
+    /// is called from an async call. The requires of a module that another task is loading
+    /// wait for it to be loaded, rather than loading it again.
+    fn add_async_searcher<RetFut, F>(self, searcher: F) -> Result<()>
+    where
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Option<ModuleSource>>,
+        F: 'static + Send + Fn(Context<'lua>, String) -> RetFut;
+
+    /// Register the module `name`, whose [`ModuleSource`] is the output of the future `source`,
+    /// in `package.preload`
+    ///
+    /// `source` is only polled once the module is first required, eg. to only download or
+    /// decrypt the modules that are used, and the module is then cached in `package.loaded` like
+    /// the other ones. Its output is kept too, so that failing to get the source fails all the
+    /// requires of the module. Like with [`ContextExt::add_async_searcher`], `require` is
+    /// replaced by an equivalent that can wait on async functions, and the requires racing with
+    /// the first one wait for it to run the module, that thus runs only once.
+    fn preload_async<RetFut>(self, name: &str, source: RetFut) -> Result<()>
+    where
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<ModuleSource>;
+
+    /// Attach a value of type `T` to the Lua state, replacing and returning the previous value of
+    /// the same type if there was one.
+    ///
+    /// This value can then be retrieved with [`ContextExt::get_app_data`], eg. from inside the
+    /// closures passed to [`ContextExt::create_async_function`], without having to capture it in
+    /// each closure.
+    fn set_app_data<T: 'static + Send + Sync>(self, data: T) -> Result<Option<Arc<T>>>;
+
+    /// Retrieve the value of type `T` previously attached with [`ContextExt::set_app_data`]
+    fn get_app_data<T: 'static + Send + Sync>(self) -> Result<Option<Arc<T>>>;
+
+    /// Detach the value of type `T` from the Lua state, returning it if there was one
+    fn remove_app_data<T: 'static + Send + Sync>(self) -> Result<Option<Arc<T>>>;
+
+    /// Start calling `func` with `args` in the background, returning a handle to the call.
+    ///
+    /// The call is not driven by the caller, as with [`FunctionExt::call_async`], but by the
+    /// other async calls of the Lua state while they wait, and by the future returned by
+    /// [`ContextExt::run_spawned`] (or [`AsyncLua::run_spawned`]), that the embedder runs
+    /// alongside them for the calls left once they are done. This fits fire-and-forget calls,
+    /// eg. to event handlers, that nothing needs to wait for.
+    fn spawn<Arg: ToLuaMulti<'lua>>(self, func: Function<'lua>, args: Arg) -> Result<TaskHandle>;
+
+    /// Drive the calls started with [`ContextExt::spawn`], until none is left
+    fn run_spawned(self) -> RunSpawned<'lua>;
+
+    /// Select the executor and timers used by the crate for this Lua state, see the [`spawner`]
+    /// module
+    ///
+    /// The Lua libraries of the crate use the spawner selected when they are installed, so this
+    /// should be called first.
+    fn set_spawner<S: Spawner>(self, spawner: S) -> Result<()>;
+
+    /// Get the [`Spawner`] of this Lua state, [`DefaultSpawner`](spawner::DefaultSpawner) if
+    /// none was selected
+    fn spawner(self) -> Result<Arc<dyn Spawner>>;
+
+    /// Install the Lua helper library in the globals. It contains:
+    ///  * `async_wrap(f)`, that works like `coroutine.wrap(f)`, except that the yields due to
+    ///    async functions called by `f` are passed through to the outer async caller, while the
+    ///    yields done by `f` itself are returned to the caller of the wrapper, as usual. This
+    ///    makes it possible to write generators that call async functions.
+    ///  * `async_pairs(obj)` and `async_ipairs(obj)`, that work like `pairs` and `ipairs` on
+    ///    tables, but iterate over userdata (or tables with a `next` function) by repeatedly
+    ///    calling `obj:next()`, which can be an async function. For `async_pairs`, `next` must
+    ///    return the key and the value, and for `async_ipairs` only the value, the index being
+    ///    computed by `async_ipairs` itself. In both cases, `next` returns `nil` at the end of
+    ///    the iteration.
+    fn install_async_helpers(self) -> Result<()>;
+}
+
+/// The output of the futures of [`ContextExt::create_async_function_with_registry`], converted
+/// to the return values of the function by `then` once back in Lua
+struct Then<G, Out> {
+    then: Arc<G>,
+    out: Out,
+}
+
+impl<'lua, G, Out, Ret> ToLuaMulti<'lua> for Then<G, Out>
+where
+    Ret: ToLuaMulti<'lua>,
+    G: Fn(Context<'lua>, Out) -> Result<Ret>,
+{
+    fn to_lua_multi(self, ctx: Context<'lua>) -> Result<MultiValue<'lua>> {
+        (self.then)(ctx, self.out)?.to_lua_multi(ctx)
+    }
 }
 
 fn poller_fn<'lua, Ret, RetFut>(
@@ -69,17 +537,19 @@ fn poller_fn<'lua, Ret, RetFut>(
 ) -> Result<Function<'lua>>
 where
     Ret: ToLuaMulti<'lua>,
-    RetFut: 'static + Send + Future<Output = Result<Ret>>,
+    RetFut: 'static + Send + ?Sized + Future<Output = Result<Ret>>,
 {
     ctx.create_function_mut(move |ctx, _: MultiValue<'lua>| {
+        if !FUTURE_CTX.is_set() {
+            // Not called from an async call, there is nothing to wait with
+            return ToLuaMulti::to_lua_multi((false, true), ctx);
+        }
         FUTURE_CTX.with(|fut_ctx| {
             let fut_ctx_ref = unsafe { &mut *(*fut_ctx as *mut task::Context) };
             match Future::poll(fut.as_mut(), fut_ctx_ref) {
-                Poll::Pending => ToLuaMulti::to_lua_multi((rlua::Value::Nil, false), ctx),
-                Poll::Ready(v) => {
-                    let v = ToLuaMulti::to_lua_multi(v?, ctx)?.into_vec();
-                    ToLuaMulti::to_lua_multi((v, true), ctx)
-                }
+                Poll::Pending => ToLuaMulti::to_lua_multi((false, false), ctx),
+                // The values are passed as is rather than in a table, not to lose their `nil`s
+                Poll::Ready(v) => ToLuaMulti::to_lua_multi((true, false, v?), ctx),
             }
         })
     })
@@ -87,41 +557,329 @@ where
 
 static MAKE_POLLER: &[u8] = include_bytes!("make-poller.lua");
 
+/// Wrap `poller`, that returns the polling function of a call from its arguments, in the Lua
+/// trampoline that waits for the call
+fn make_async<'lua>(
+    ctx: Context<'lua>,
+    name: &str,
+    poller: Function<'lua>,
+) -> Result<Function<'lua>> {
+    ctx.load(MAKE_POLLER)
+        .set_name(b"coroutine yield helper")?
+        .eval::<Function<'lua>>()? // TODO: find some way to cache this eval, maybe?
+        .call((poller, pending_marker(), name))
+}
+static HELPERS: &[u8] = include_bytes!("helpers.lua");
+
 impl<'lua> ContextExt<'lua> for Context<'lua> {
     fn create_async_function<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
     where
         Arg: FromLuaMulti<'lua>,
-        Ret: ToLuaMulti<'lua>,
-        RetFut: 'static + Send + Future<Output = Result<Ret>>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        self.create_named_async_function(DEFAULT_NAME, func)
+    }
+
+    fn create_async_function_mut<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut,
+    {
+        self.create_named_async_function_mut(DEFAULT_NAME, func)
+    }
+
+    fn create_async_function_once<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnOnce(Context<'lua>, Arg) -> RetFut,
+    {
+        let mut func = Some(func);
+        self.create_named_async_function_mut(DEFAULT_NAME, move |ctx, arg| {
+            let fut = func.take().map(|func| func(ctx, arg));
+            async move {
+                match fut {
+                    Some(fut) => fut.await.into_lua_result(),
+                    None => Err(rlua::Error::RuntimeError(
+                        "the async function can only be called once".to_string(),
+                    )),
+                }
+            }
+        })
+    }
+
+    fn create_async_function_local<Arg, Ret, RetFut, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + ToLuaMulti<'lua>,
+        RetFut: 'static + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        let func = ThreadBound::new(func);
+        self.create_named_async_function(DEFAULT_NAME, move |ctx, arg| {
+            let fut = func.get().map(|func| func(ctx, arg));
+            ThreadBound::new(async move { Ok(ThreadBound::new(fut?.await.into_lua_result()?)) })
+        })
+    }
+
+    fn create_named_async_function<Arg, Ret, RetFut, F>(
+        self,
+        name: &str,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
         F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
     {
-        let wrapped_fun = self.create_function(move |ctx, arg| {
-            let fut = Box::pin(func(ctx, arg));
+        let name: Arc<str> = name.into();
+        let fun_name = name.clone();
+        let wrapped_fun = self.create_function(move |ctx, args| {
+            let fut = middleware::wrap(ctx, &name, args, |args| {
+                Ok(func(ctx, FromLuaMulti::from_lua_multi(args, ctx)?))
+            })?;
             poller_fn(ctx, fut)
         })?;
 
-        self.load(MAKE_POLLER)
-            .set_name(b"coroutine yield helper")?
-            .eval::<Function<'lua>>()? // TODO: find some way to cache this eval, maybe?
-            .call(wrapped_fun)
+        make_async(self, &fun_name, wrapped_fun)
     }
 
-    fn create_async_function_mut<Arg, Ret, RetFut, F>(self, mut func: F) -> Result<Function<'lua>>
+    fn create_named_async_function_mut<Arg, Ret, RetFut, F>(
+        self,
+        name: &str,
+        mut func: F,
+    ) -> Result<Function<'lua>>
     where
         Arg: FromLuaMulti<'lua>,
-        Ret: ToLuaMulti<'lua>,
-        RetFut: 'static + Send + Future<Output = Result<Ret>>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
         F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut,
     {
-        let wrapped_fun = self.create_function_mut(move |ctx, arg| {
-            let fut = Box::pin(func(ctx, arg));
+        let name: Arc<str> = name.into();
+        let fun_name = name.clone();
+        let wrapped_fun = self.create_function_mut(move |ctx, args| {
+            let fut = middleware::wrap(ctx, &name, args, |args| {
+                Ok(func(ctx, FromLuaMulti::from_lua_multi(args, ctx)?))
+            })?;
             poller_fn(ctx, fut)
         })?;
 
-        self.load(MAKE_POLLER)
-            .set_name(b"coroutine yield helper")?
-            .eval::<Function<'lua>>()? // TODO: find some way to cache this eval, maybe?
-            .call(wrapped_fun)
+        make_async(self, &fun_name, wrapped_fun)
+    }
+
+    fn create_async_function_with_registry<Arg, Out, Ret, RetFut, F, G>(
+        self,
+        func: F,
+        then: G,
+    ) -> Result<Function<'lua>>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Out: 'static + Send,
+        Ret: ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Out>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+        G: 'static + Send + Sync + Fn(Context<'lua>, Out) -> Result<Ret>,
+    {
+        let then = Arc::new(then);
+        self.create_named_async_function(DEFAULT_NAME, move |ctx, args| {
+            let then = then.clone();
+            func(ctx, args).map(move |out| {
+                Ok(Then {
+                    then,
+                    out: out.into_lua_result()?,
+                })
+            })
+        })
+    }
+
+    fn create_blocking_function<Arg, Ret, F>(self, func: F) -> Result<Function<'lua>>
+    where
+        Arg: 'static + Send + FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        F: 'static + Send + Sync + Fn(Arg) -> Result<Ret>,
+    {
+        let func = Arc::new(func);
+        self.create_named_async_function(DEFAULT_NAME, move |ctx, args: Arg| {
+            let func = func.clone();
+            let spawner = ctx.spawner();
+            async move {
+                let (tx, rx) = futures::channel::oneshot::channel();
+                spawner?
+                    .spawn_blocking(Box::new(move || {
+                        let _ = tx.send(func(args));
+                    }))
+                    .map_err(rlua::Error::external)?;
+                rx.await.map_err(|_| {
+                    rlua::Error::RuntimeError("the blocking function panicked".to_string())
+                })?
+            }
+        })
+    }
+
+    fn set_async_fn<Arg, Ret, RetFut, F>(self, name: &str, func: F) -> Result<()>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        self.globals().set_async_fn(self, name, func)
+    }
+
+    fn set_async_fns<'a, I, Arg, Ret, RetFut, F>(self, funcs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (&'a str, F)>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        for (name, func) in funcs {
+            self.set_async_fn(name, func)?;
+        }
+        Ok(())
+    }
+
+    fn create_async_function_weak<S, Arg, Ret, RetFut, F>(
+        self,
+        state: &Arc<S>,
+        func: F,
+    ) -> Result<Function<'lua>>
+    where
+        S: 'static + Send + Sync,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arc<S>, Arg) -> RetFut,
+    {
+        let state = Arc::downgrade(state);
+        self.create_named_async_function(DEFAULT_NAME, move |ctx, arg| {
+            let state = state.upgrade();
+            let fut = state.map(|state| func(ctx, state, arg));
+            async move {
+                match fut {
+                    Some(fut) => fut.await.into_lua_result(),
+                    None => Err(rlua::Error::CallbackDestructed),
+                }
+            }
+        })
+    }
+
+    fn create_async_proxy(self, ud: AnyUserData<'lua>) -> Result<rlua::Table<'lua>> {
+        userdata::proxy(self, ud)
+    }
+
+    fn eval_async<S, Ret>(self, source: &S) -> CallAsyncFuture<'lua, (), Ret>
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        CallAsyncFuture::from_function(self, load_eval(self, source.as_ref()), ())
+    }
+
+    fn add_async_middleware<M: Middleware>(self, middleware: M) -> Result<()> {
+        middleware::add(self, middleware)
+    }
+
+    fn create_async_stream<S, T>(self, stream: S) -> Result<AnyUserData<'lua>>
+    where
+        S: 'static + Send + futures::Stream,
+        S::Item: IntoLuaResult<T>,
+        T: 'static + Send + for<'all> ToLuaMulti<'all>,
+    {
+        stream::create(self, stream)
+    }
+
+    fn create_async_sink<S, T>(self, sink: S) -> Result<AnyUserData<'lua>>
+    where
+        S: 'static + Send + futures::Sink<T>,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        T: 'static + Send + for<'all> FromLuaMulti<'all>,
+    {
+        stream::create_sink(self, sink)
+    }
+
+    fn async_function_builder(self) -> AsyncFunctionBuilder<'lua> {
+        AsyncFunctionBuilder::new(self)
+    }
+
+    fn async_module_builder(self) -> AsyncModuleBuilder<'lua> {
+        AsyncModuleBuilder::new(self)
+    }
+
+    fn add_async_searcher<RetFut, F>(self, searcher: F) -> Result<()>
+    where
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Option<ModuleSource>>,
+        F: 'static + Send + Fn(Context<'lua>, String) -> RetFut,
+    {
+        require::add_searcher(self, searcher)
+    }
+
+    fn preload_async<RetFut>(self, name: &str, source: RetFut) -> Result<()>
+    where
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<ModuleSource>,
+    {
+        require::preload(self, name, source)
+    }
+
+    fn set_app_data<T: 'static + Send + Sync>(self, data: T) -> Result<Option<Arc<T>>> {
+        app_data::set(self, data)
+    }
+
+    fn get_app_data<T: 'static + Send + Sync>(self) -> Result<Option<Arc<T>>> {
+        app_data::get(self)
+    }
+
+    fn remove_app_data<T: 'static + Send + Sync>(self) -> Result<Option<Arc<T>>> {
+        app_data::remove(self)
+    }
+
+    fn spawn<Arg: ToLuaMulti<'lua>>(self, func: Function<'lua>, args: Arg) -> Result<TaskHandle> {
+        spawn::spawn(self, func, args)
+    }
+
+    fn run_spawned(self) -> RunSpawned<'lua> {
+        RunSpawned::new(self)
+    }
+
+    fn set_spawner<S: Spawner>(self, spawner: S) -> Result<()> {
+        spawner::set(self, spawner)
+    }
+
+    fn spawner(self) -> Result<Arc<dyn Spawner>> {
+        spawner::get(self)
+    }
+
+    fn install_async_helpers(self) -> Result<()> {
+        let helpers = self
+            .load(HELPERS)
+            .set_name(b"rlua-async helpers")?
+            .eval::<Function<'lua>>()?
+            .call::<_, rlua::Table<'lua>>(pending_marker())?;
+        let globals = self.globals();
+        for pair in helpers.pairs::<rlua::Value<'lua>, rlua::Value<'lua>>() {
+            let (k, v) = pair?;
+            globals.set(k, v)?;
+        }
+        Ok(())
     }
 }
 
@@ -141,12 +899,12 @@ impl<Arg, RetFut, F> FutGen<Arg, RetFut, F> {
     }
 }
 
-impl<'scope, Arg, Ret, RetFut, F> UserData for FutGen<Arg, RetFut, F>
+impl<Arg, Ret, RetFut, F> UserData for FutGen<Arg, RetFut, F>
 where
     Arg: for<'all> FromLuaMulti<'all>,
     Ret: for<'all> ToLuaMulti<'all>,
-    RetFut: 'scope + Future<Output = Result<Ret>>,
-    F: 'scope + for<'all> FnMut(Context<'all>, Arg) -> RetFut,
+    RetFut: Future<Output = Result<Ret>>,
+    F: for<'all> FnMut(Context<'all>, Arg) -> RetFut,
 {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method_mut("set_arg", |ctx, this, arg: Arg| {
@@ -159,6 +917,10 @@ where
         });
 
         methods.add_method_mut("poll", |ctx, this, _: ()| {
+            if !FUTURE_CTX.is_set() {
+                // Not called from an async call, there is nothing to wait with
+                return ToLuaMulti::to_lua_multi((false, true), ctx);
+            }
             let mut fut = this
                 .cur_fut
                 .take()
@@ -169,12 +931,9 @@ where
                 match Future::poll(fut.as_mut(), fut_ctx_ref) {
                     Poll::Pending => {
                         this.cur_fut = Some(fut); // Restore future for next poll
-                        ToLuaMulti::to_lua_multi((rlua::Value::Nil, false), ctx)
-                    }
-                    Poll::Ready(v) => {
-                        let v = ToLuaMulti::to_lua_multi(v?, ctx)?.into_vec();
-                        ToLuaMulti::to_lua_multi((v, true), ctx)
+                        ToLuaMulti::to_lua_multi((false, false), ctx)
                     }
+                    Poll::Ready(v) => ToLuaMulti::to_lua_multi((true, false, v?), ctx),
                 }
             })
         });
@@ -192,6 +951,11 @@ pub trait ScopeExt<'lua, 'scope> {
     /// [`rlua::Scope::create_function`]. This is required for compiling the implementation, and
     /// should not be an issue. If it is, please report a bug to `rlua-async` with instructions on
     /// how to reproduce and your use case.
+    ///
+    /// Unlike with [`ContextExt::create_async_function`], `func` and the futures it returns can
+    /// borrow data that lives for `'scope`, eg. `move |_, ()| async move { c_ref.set(1); Ok(()) }`
+    /// with `c_ref` a reference to a local [`Cell`](std::cell::Cell). When the scope ends, the
+    /// futures still in flight are dropped, and the later calls to the function fail.
     fn create_async_function<Arg, Ret, RetFut, F>(
         &self,
         ctx: Context<'lua>,
@@ -233,7 +997,7 @@ impl<'lua, 'scope> ScopeExt<'lua, 'scope> for Scope<'lua, 'scope> {
         ctx.load(MAKE_USERDATA_POLLER)
             .set_name(b"coroutine yield helper")?
             .eval::<Function<'lua>>()? // TODO: find some way to cache this eval, maybe?
-            .call(ud)
+            .call((ud, pending_marker(), DEFAULT_NAME))
     }
 
     fn create_async_function_mut<Arg, Ret, RetFut, F>(
@@ -251,52 +1015,205 @@ impl<'lua, 'scope> ScopeExt<'lua, 'scope> for Scope<'lua, 'scope> {
         ctx.load(MAKE_USERDATA_POLLER)
             .set_name(b"coroutine yield helper")?
             .eval::<Function<'lua>>()? // TODO: find some way to cache this eval, maybe?
-            .call(ud)
+            .call((ud, pending_marker(), DEFAULT_NAME))
     }
 }
 
-struct PollThreadFut<'lua, Arg, Ret> {
+/// The future of an async call to a Lua function
+///
+/// This is what [`FunctionExt::call_async`] and friends return. Schedulers and diagnostics tools
+/// can build it directly with [`CallAsyncFuture::new`], to get access to statistics about the
+/// call and to the underlying [`Thread`].
+pub struct CallAsyncFuture<'lua, Arg, Ret> {
     /// If set to Some(a), contains the arguments that will be passed at the first resume, ie. the
     /// function arguments
     args: Option<Arg>,
     ctx: Context<'lua>,
-    thread: Thread<'lua>,
+    /// The thread running the call, or the error that prevented creating it, returned by the
+    /// first poll
+    thread: std::result::Result<Thread<'lua>, Option<rlua::Error>>,
+    resumes: usize,
+    first_poll: Option<Instant>,
+    finished: Option<Instant>,
+    time_running: Duration,
+    /// The instant past which the thread must not be resumed any longer, and the error to
+    /// return then
+    deadline: Option<(Instant, TimeoutError)>,
     _phantom: PhantomData<Ret>,
 }
 
-impl<'lua, Arg, Ret> Future for PollThreadFut<'lua, Arg, Ret>
+// The future is never structurally pinned, so it can be moved even after having been polled
+impl<'lua, Arg, Ret> Unpin for CallAsyncFuture<'lua, Arg, Ret> {}
+
+impl<'lua, Arg, Ret> CallAsyncFuture<'lua, Arg, Ret> {
+    /// Prepare a call to `func` with arguments `args`, creating the [`Thread`] it will run in
+    pub fn new(ctx: Context<'lua>, func: Function<'lua>, args: Arg) -> Result<Self> {
+        let thread = ctx.create_thread(func)?;
+        Ok(CallAsyncFuture::from_thread(ctx, Ok(thread), args))
+    }
+
+    /// Prepare a call that fails with the error, if any, that prevented creating its thread
+    fn from_thread(ctx: Context<'lua>, thread: Result<Thread<'lua>>, args: Arg) -> Self {
+        CallAsyncFuture {
+            args: Some(args),
+            ctx,
+            thread: thread.map_err(Some),
+            resumes: 0,
+            first_poll: None,
+            finished: None,
+            time_running: Duration::from_secs(0),
+            deadline: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Prepare a call to the function that `func` evaluates to, that fails with its error if
+    /// it is an error
+    fn from_function(ctx: Context<'lua>, func: Result<Function<'lua>>, args: Arg) -> Self {
+        let thread = func.and_then(|func| ctx.create_thread(func));
+        CallAsyncFuture::from_thread(ctx, thread, args)
+    }
+
+    /// Make the call fail with a [`TimeoutError`] instead of resuming the thread if it is polled
+    /// after `deadline`
+    ///
+    /// This does not wake the call when the deadline passes: the caller must make sure it is
+    /// polled again then, eg. by racing it against a timer, as
+    /// [`AsyncCall::timeout`](crate::AsyncCall::timeout) does. What the deadline guarantees is
+    /// that no more Lua code runs once it has passed, even if the call was woken meanwhile.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        self.set_deadline_error(deadline, TimeoutError::new(None, timeout));
+    }
+
+    pub(crate) fn set_deadline_error(&mut self, deadline: Instant, error: TimeoutError) {
+        self.deadline = Some((deadline, error));
+    }
+
+    /// The deadline of the call, if it has one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.as_ref().map(|(deadline, _)| *deadline)
+    }
+
+    /// The status of the thread running the call, [`ThreadStatus::Error`] if it could not be
+    /// created
+    pub fn status(&self) -> ThreadStatus {
+        match &self.thread {
+            Ok(thread) => thread.status(),
+            Err(_) => ThreadStatus::Error,
+        }
+    }
+
+    /// The number of times the thread running the call has been resumed so far
+    pub fn resumes(&self) -> usize {
+        self.resumes
+    }
+
+    /// The time spent actually running Lua code (and the synchronous part of the async
+    /// functions it calls) so far
+    pub fn time_running(&self) -> Duration {
+        self.time_running
+    }
+
+    /// The time spent waiting since the call was first polled, ie. the time elapsed since the
+    /// first poll (until completion if the call completed), minus [`Self::time_running`]
+    pub fn time_pending(&self) -> Duration {
+        let first_poll = match self.first_poll {
+            Some(first_poll) => first_poll,
+            None => return Duration::from_secs(0),
+        };
+        let end = self.finished.unwrap_or_else(Instant::now);
+        (end - first_poll)
+            .checked_sub(self.time_running)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+
+    /// The thread running the call, if it could be created
+    ///
+    /// It is always there for the calls created by [`CallAsyncFuture::new`]. For the ones
+    /// returned by eg. [`FunctionExt::call_async`], the error preventing its creation is
+    /// returned by the first poll instead.
+    pub fn thread(&self) -> Option<&Thread<'lua>> {
+        self.thread.as_ref().ok()
+    }
+
+    /// Cancel the call, recovering the thread running it if it could be created
+    ///
+    /// If the call did not complete yet, the thread will still be resumable, but resuming it
+    /// outside of a [`CallAsyncFuture`] will most likely make the Lua code fail.
+    pub fn into_thread(self) -> Option<Thread<'lua>> {
+        self.thread.ok()
+    }
+}
+
+impl<'lua, Arg, Ret> Future for CallAsyncFuture<'lua, Arg, Ret>
 where
     Arg: ToLuaMulti<'lua>,
     Ret: FromLuaMulti<'lua>,
 {
     type Output = Result<Ret>;
 
-    fn poll(mut self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<Ret>> {
-        FUTURE_CTX.set(&(fut_ctx as *mut _ as *mut ()), || {
-            let taken_args = unsafe { self.as_mut().get_unchecked_mut().args.take() };
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<Ret>> {
+        let this = self.get_mut();
+        let resume_start = Instant::now();
+        this.first_poll.get_or_insert(resume_start);
+        let thread = match &mut this.thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                this.finished = Some(resume_start);
+                let e = e.take().expect("polled a failed call after completion");
+                return Poll::Ready(Err(e));
+            }
+        };
+        if let Some((deadline, error)) = &this.deadline {
+            if resume_start >= *deadline {
+                this.finished = Some(resume_start);
+                return Poll::Ready(Err(rlua::Error::external(error.clone())));
+            }
+        }
+        this.resumes += 1;
 
-            let resume_ret = if let Some(a) = taken_args {
-                self.thread.resume::<_, rlua::MultiValue>(a)
+        let guard = match DepthGuard::enter() {
+            Ok(guard) => guard,
+            Err(e) => {
+                this.finished = Some(resume_start);
+                return Poll::Ready(Err(e));
+            }
+        };
+        let args = this.args.take();
+        let resume_ret = FUTURE_CTX.set(&(fut_ctx as *mut _ as *mut ()), || {
+            if let Some(a) = args {
+                thread.resume::<_, rlua::MultiValue>(a)
             } else {
-                self.thread.resume::<_, rlua::MultiValue>(())
-            };
-
-            match resume_ret {
-                Err(e) => Poll::Ready(Err(e)),
-                Ok(v) => {
-                    match self.thread.status() {
-                        ThreadStatus::Resumable => Poll::Pending,
+                thread.resume::<_, rlua::MultiValue>(())
+            }
+        });
 
-                        ThreadStatus::Unresumable => {
-                            Poll::Ready(FromLuaMulti::from_lua_multi(v, self.ctx))
+        drop(guard);
+        let resume_end = Instant::now();
+        this.time_running += resume_end - resume_start;
+
+        let res = match resume_ret {
+            Err(e) => Err(e),
+            Ok(v) => {
+                match thread.status() {
+                    ThreadStatus::Resumable => {
+                        // Let the spawned tasks run while the call waits, eg. for them
+                        match spawn::drive_tasks(this.ctx, fut_ctx) {
+                            Ok(()) => return Poll::Pending,
+                            Err(e) => Err(e),
                         }
-
-                        // The `Error` case should be caught by the `Err(e)` match above
-                        ThreadStatus::Error => unreachable!(),
                     }
+
+                    ThreadStatus::Unresumable => FromLuaMulti::from_lua_multi(v, this.ctx),
+
+                    // The `Error` case should be caught by the `Err(e)` match above
+                    ThreadStatus::Error => unreachable!(),
                 }
             }
-        })
+        };
+        this.finished = Some(resume_end);
+        Poll::Ready(res)
     }
 }
 
@@ -307,125 +1224,62 @@ pub trait FunctionExt<'lua> {
     /// By using this on the Rust side, you can recover as a [`Future`] the potentiall
     /// [`Poll::Pending`] that might have been sent by eg. a downstream
     /// [`ContextExt::create_async_function`]
-    // TODO: make the return type `impl trait`... when GAT + existential types will be stable?
-    fn call_async<'fut, Arg, Ret>(
+    fn call_async<Arg, Ret>(
         &self,
         ctx: Context<'lua>,
         args: Arg,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
+    ) -> CallAsyncFuture<'lua, Arg, Ret>
     where
-        'lua: 'fut,
-        Arg: 'fut + ToLuaMulti<'lua>,
-        Ret: 'fut + FromLuaMulti<'lua>;
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>;
+
+    /// Prepare an async call, to be configured with the methods of [`AsyncCall`] and then
+    /// `.await`ed, eg. `f.async_call(ctx).args(2).timeout(Duration::from_secs(1)).await`
+    fn async_call<Ret>(&self, ctx: Context<'lua>) -> AsyncCall<'lua, (), Ret>;
 }
 
 impl<'lua> FunctionExt<'lua> for Function<'lua> {
-    fn call_async<'fut, Arg, Ret>(
-        &self,
-        ctx: Context<'lua>,
-        args: Arg,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
+    fn call_async<Arg, Ret>(&self, ctx: Context<'lua>, args: Arg) -> CallAsyncFuture<'lua, Arg, Ret>
     where
-        'lua: 'fut,
-        Arg: 'fut + ToLuaMulti<'lua>,
-        Ret: 'fut + FromLuaMulti<'lua>,
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
     {
-        let thread = match ctx.create_thread(self.clone()) {
-            Ok(thread) => thread,
-            Err(e) => return Box::pin(future::err(e)),
-        };
+        CallAsyncFuture::from_function(ctx, Ok(self.clone()), args)
+    }
 
-        Box::pin(PollThreadFut {
-            args: Some(args),
-            ctx,
-            thread,
-            _phantom: PhantomData,
-        })
+    fn async_call<Ret>(&self, ctx: Context<'lua>) -> AsyncCall<'lua, (), Ret> {
+        AsyncCall::new(self.clone(), ctx)
     }
 }
 
 /// Extension trait for [`rlua::Chunk`]
 ///
-/// Note that there is currently no `eval_async` function to match [`rlua::Chunk::eval`]. This is
-/// due to a missing function in the API of [`rlua::Chunk`]. See also [this pull
-/// request](https://github.com/kyren/rlua/pull/169).
+/// Note that there is no `eval_async` function to match [`rlua::Chunk::eval`], as the source of a
+/// [`rlua::Chunk`] cannot be retrieved to try it as an expression. See also [this pull
+/// request](https://github.com/kyren/rlua/pull/169). Use [`ContextExt::eval_async`] instead.
 pub trait ChunkExt<'lua, 'a> {
     /// Asynchronously execute this chunk of code. See also [`rlua::Chunk::exec`].
-    fn exec_async<'fut>(
-        self,
-        ctx: Context<'lua>,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<()>>>>
-    where
-        'lua: 'fut;
-
-    /*
-    /// Asynchronously evaluate the chunk as either an expression or block. See also
-    /// [`rlua::Chunk::eval`].
-    fn eval_async<'fut, Ret>(
-        self,
-        ctx: Context<'lua>,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
-    where
-        'lua: 'fut,
-        Ret: 'fut + FromLuaMulti<'lua>;
-    */
+    fn exec_async(self, ctx: Context<'lua>) -> CallAsyncFuture<'lua, (), ()>;
 
     /// Load the chunk function and call it with the given arguments. See also
     /// [`rlua::Chunk::call`].
-    fn call_async<'fut, Arg, Ret>(
-        self,
-        ctx: Context<'lua>,
-        args: Arg,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
+    fn call_async<Arg, Ret>(self, ctx: Context<'lua>, args: Arg) -> CallAsyncFuture<'lua, Arg, Ret>
     where
-        'lua: 'fut,
-        Arg: 'fut + ToLuaMulti<'lua>,
-        Ret: 'fut + FromLuaMulti<'lua>;
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>;
 }
 
 impl<'lua, 'a> ChunkExt<'lua, 'a> for Chunk<'lua, 'a> {
-    fn exec_async<'fut>(
-        self,
-        ctx: Context<'lua>,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<()>>>>
-    where
-        'lua: 'fut,
-    {
+    fn exec_async(self, ctx: Context<'lua>) -> CallAsyncFuture<'lua, (), ()> {
         self.call_async(ctx, ())
     }
 
-    /*
-    // TODO: implement, then remove the note in the ChunkExt doc (and uncomment the test)
-    fn eval_async<'fut, Ret>(
-        self,
-        ctx: Context<'lua>,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
+    fn call_async<Arg, Ret>(self, ctx: Context<'lua>, args: Arg) -> CallAsyncFuture<'lua, Arg, Ret>
     where
-        'lua: 'fut,
-        Ret: 'fut + FromLuaMulti<'lua>,
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
     {
-        // First, try interpreting the lua as an expression by adding "return", then as a
-        // statement. This is the same thing the actual lua repl, as well as rlua, do.
-        unimplemented!()
-    }
-    */
-
-    fn call_async<'fut, Arg, Ret>(
-        self,
-        ctx: Context<'lua>,
-        args: Arg,
-    ) -> Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>
-    where
-        'lua: 'fut,
-        Arg: 'fut + ToLuaMulti<'lua>,
-        Ret: 'fut + FromLuaMulti<'lua>,
-    {
-        let fun = match self.into_function() {
-            Ok(fun) => fun,
-            Err(e) => return Box::pin(future::err(e)),
-        };
-
-        fun.call_async(ctx, args)
+        CallAsyncFuture::from_function(ctx, self.into_function(), args)
     }
 }
 
@@ -438,7 +1292,7 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
-    use futures::executor;
+    use futures::{executor, future};
     use rlua::{Error, Lua};
 
     #[test]
@@ -498,6 +1352,109 @@ mod tests {
         });
     }
 
+    #[test]
+    fn call_future_statistics() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, a: usize| async move {
+                    futures_timer::Delay::new(Duration::from_millis(50)).await;
+                    Ok(a + 1)
+                })
+                .unwrap();
+
+            let mut fut = CallAsyncFuture::<_, usize>::new(lua, f.clone(), 2).unwrap();
+            assert_eq!(fut.resumes(), 0);
+            assert_eq!(executor::block_on(&mut fut).expect("failed to call"), 3);
+            assert!(fut.resumes() >= 2);
+            assert!(fut.time_pending() >= Duration::from_millis(40));
+            assert_eq!(fut.status(), ThreadStatus::Unresumable);
+
+            let mut fut = CallAsyncFuture::<_, usize>::new(lua, f, 2).unwrap();
+            assert!(futures::FutureExt::now_or_never(&mut fut).is_none());
+            assert_eq!(fut.resumes(), 1);
+            assert_eq!(fut.into_thread().unwrap().status(), ThreadStatus::Resumable);
+        });
+    }
+
+    #[test]
+    fn call_future_of_invalid_chunk() {
+        Lua::new().context(|lua| {
+            let fut = lua.load(r#"return (("#).exec_async(lua);
+            assert!(fut.thread().is_none());
+            assert_eq!(fut.status(), ThreadStatus::Error);
+            match executor::block_on(fut) {
+                Err(Error::SyntaxError { .. }) => {}
+                r => panic!("improper return for invalid chunk: {:?}", r),
+            }
+        });
+    }
+
+    #[test]
+    fn async_wrap_generator() {
+        Lua::new().context(|lua| {
+            lua.install_async_helpers().unwrap();
+            let f = lua
+                .create_async_function(|_, a: usize| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(a * 10)
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local gen = async_wrap(function(n)
+                            for i = 1, n do
+                                coroutine.yield(f(i))
+                            end
+                            return "end"
+                        end)
+                        return gen(2), gen(), gen()
+                    "#,
+                )
+                .call_async::<_, (usize, usize, String)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, (10, 20, "end".to_string()));
+        });
+    }
+
+    #[test]
+    fn async_iteration_helpers() {
+        Lua::new().context(|lua| {
+            lua.install_async_helpers().unwrap();
+            let mut remaining = vec![("c", 3), ("b", 2), ("a", 1)];
+            let next = lua
+                .create_async_function_mut(move |_, _: rlua::Value| {
+                    let (k, v) = remaining.pop().unzip();
+                    future::ok((k.map(str::to_string), v))
+                })
+                .unwrap();
+            let cursor = lua.create_table().unwrap();
+            cursor.set("next", next).unwrap();
+            lua.globals().set("cursor", cursor).unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local res = ""
+                        for k, v in async_pairs(cursor) do
+                            res = res .. k .. v
+                        end
+                        for i, v in async_ipairs({ "x", "y" }) do
+                            res = res .. i .. v
+                        end
+                        return res
+                    "#,
+                )
+                .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, "a1b2c31x2y");
+        });
+    }
+
     #[test]
     fn async_fn_mut() {
         let lua = Lua::new();
@@ -533,6 +1490,197 @@ mod tests {
         });
     }
 
+    #[test]
+    fn async_fn_mut_state() {
+        Lua::new().context(|lua| {
+            let mut calls = 0;
+            let f = lua
+                .create_async_function_mut(move |_, ()| {
+                    calls += 1;
+                    let n = calls;
+                    async move {
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        Ok(n)
+                    }
+                })
+                .unwrap();
+            lua.globals().set("count", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"return count() + count() * 10 + count() * 100"#)
+                    .call_async::<_, usize>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 321);
+        });
+    }
+
+    #[test]
+    fn set_async_fns() {
+        Lua::new().context(|lua| {
+            lua.set_async_fn("incr", |_, a: usize| future::ok(a + 1))
+                .unwrap();
+            type Op =
+                Box<dyn Send + Fn(Context, usize) -> future::BoxFuture<'static, Result<usize>>>;
+            let ops: Vec<(&str, Op)> = vec![
+                ("double", Box::new(|_, a| Box::pin(future::ok(a * 2)))),
+                (
+                    "square",
+                    Box::new(|_, a| {
+                        Box::pin(async move {
+                            futures_timer::Delay::new(Duration::from_millis(10)).await;
+                            Ok(a * a)
+                        })
+                    }),
+                ),
+            ];
+            lua.set_async_fns(ops).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"return square(double(incr(2)))"#)
+                    .call_async::<_, usize>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 36);
+        });
+    }
+
+    #[test]
+    fn async_fn_returns_nils() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, ()| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    (None::<usize>, "err", None::<usize>)
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r##"return select("#", f()), f()"##)
+                    .call_async::<_, (usize, Option<usize>, String)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, (3, None, "err".to_string()));
+        });
+    }
+
+    #[test]
+    fn async_fn_with_registry() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function_with_registry(
+                    |_, (t, ms): (RegistryValue, u64)| async move {
+                        futures_timer::Delay::new(Duration::from_millis(ms)).await;
+                        t
+                    },
+                    |ctx, t: RegistryValue| {
+                        let n = t.get::<rlua::Table>(ctx)?.get::<_, usize>("n")?;
+                        Ok((n * 2, t))
+                    },
+                )
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local t = { n = 21 }
+                        local n, same = f(t, 10)
+                        return n, rawequal(t, same)
+                    "#,
+                )
+                .call_async::<_, (usize, bool)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, (42, true));
+        });
+    }
+
+    #[test]
+    fn blocking_function() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_blocking_function(|(a, ms): (usize, u64)| {
+                    std::thread::sleep(Duration::from_millis(ms));
+                    if a == 0 {
+                        panic!("blocking function failure");
+                    }
+                    Ok(a * 2)
+                })
+                .unwrap();
+
+            let res = executor::block_on(f.call_async::<_, usize>(lua, (21, 10)));
+            assert_eq!(res.expect("failed to call"), 42);
+            let err = executor::block_on(f.call_async::<_, usize>(lua, (0, 0))).unwrap_err();
+            assert!(err.to_string().contains("panicked"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn async_fn_infallible() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, (a, b): (usize, String)| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    (a + 1, b.to_uppercase())
+                })
+                .unwrap();
+
+            let res = executor::block_on(f.call_async::<_, (usize, String)>(lua, (41, "ok")));
+            assert_eq!(res.expect("failed to call"), (42, "OK".to_string()));
+        });
+    }
+
+    #[test]
+    fn async_fn_once() {
+        Lua::new().context(|lua| {
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let f = lua
+                .create_async_function_once(move |_, v: usize| {
+                    let sent = tx.send(v).is_ok();
+                    future::ok(sent)
+                })
+                .unwrap();
+
+            assert!(executor::block_on(f.call_async::<_, bool>(lua, 42)).expect("failed to call"));
+            assert_eq!(executor::block_on(rx), Ok(42));
+            let err = executor::block_on(f.call_async::<_, bool>(lua, 42)).unwrap_err();
+            assert!(
+                err.to_string().contains("can only be called once"),
+                "{}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn async_fn_local() {
+        Lua::new().context(|lua| {
+            let calls = Rc::new(Cell::new(0));
+            let c = calls.clone();
+            let f = lua
+                .create_async_function_local(move |_, a: usize| {
+                    let c = c.clone();
+                    async move {
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        c.set(c.get() + 1);
+                        Ok(a + c.get())
+                    }
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"return f(10) + f(20)"#)
+                    .call_async::<_, usize>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 33);
+            assert_eq!(calls.get(), 2);
+        });
+    }
+
     #[test]
     fn async_chunk() {
         let lua = Lua::new();
@@ -582,24 +1730,18 @@ mod tests {
                 3,
             );
 
-            /*
-            // TODO: uncomment
             assert_eq!(
-                executor::block_on(
-                    lua_ctx
-                        .load(r#"f(2)"#)
-                        .set_name(b"example")
-                        .expect("failed to set name")
-                        .eval_async::<usize>(lua_ctx)
-                )
-                .expect("failed to eval"),
+                executor::block_on(lua_ctx.eval_async::<_, usize>("f(2)")).expect("failed to eval"),
                 3
             );
-            */
+            assert_eq!(
+                executor::block_on(lua_ctx.eval_async::<_, usize>("local a = f(3) return a"))
+                    .expect("failed to eval"),
+                4
+            );
         });
     }
 
-    /* TODO: uncomment when async move closures will be stable
     #[test]
     fn scopes_allow_allowed_things() {
         Lua::new().context(|lua| {
@@ -607,7 +1749,7 @@ mod tests {
             lua.scope(|scope| {
                 let c_ref = &c;
                 let f: Function = scope
-                    .create_async_function(lua, async move |_, ()| {
+                    .create_async_function(lua, move |_, ()| async move {
                         futures_timer::Delay::new(Duration::from_millis(50)).await;
                         c_ref.set(c_ref.get() + 1);
                         futures_timer::Delay::new(Duration::from_millis(50)).await;
@@ -632,7 +1774,6 @@ mod tests {
             };
         });
     }
-    // */
 
     #[test]
     fn scopes_do_drop_things() {
@@ -671,6 +1812,64 @@ mod tests {
         });
     }
 
+    #[test]
+    fn non_yieldable_waits_are_diagnosed() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_named_async_function("sleepy", |_, ()| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(1)
+                })
+                .unwrap();
+            lua.globals().set("sleepy", f.clone()).unwrap();
+
+            let err = f.call::<_, usize>(()).unwrap_err().to_string();
+            assert!(
+                err.contains("`sleepy` cannot wait outside of an async call"),
+                "{}",
+                err
+            );
+
+            let res = executor::block_on(
+                lua.load(r#"table.sort({ 2, 1 }, function(a, b) return sleepy() < 2 end)"#)
+                    .exec_async(lua),
+            );
+            let err = res.unwrap_err().to_string();
+            assert!(
+                err.contains("`sleepy` cannot wait from a metamethod"),
+                "{}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn async_fn_weak() {
+        Lua::new().context(|lua| {
+            let state = Arc::new(Mutex::new(0));
+            let f = lua
+                .create_async_function_weak(&state, |_, state, a: usize| {
+                    *state.lock().unwrap() += a;
+                    future::ok(())
+                })
+                .unwrap();
+            assert_eq!(Arc::strong_count(&state), 1);
+
+            executor::block_on(f.call_async::<_, ()>(lua, 42)).expect("failed to call");
+            assert_eq!(*state.lock().unwrap(), 42);
+            assert_eq!(Arc::strong_count(&state), 1);
+
+            drop(state);
+            match executor::block_on(f.call_async::<_, ()>(lua, 42)) {
+                Err(Error::CallbackError { cause, .. }) => match *cause {
+                    Error::CallbackDestructed => {}
+                    ref e => panic!("improper error for dropped state: {:?}", e),
+                },
+                r => panic!("improper return for dropped state: {:?}", r),
+            }
+        });
+    }
+
     #[test]
     fn scopes_async_fn_mut() {
         Lua::new().context(|lua| {
@@ -0,0 +1,123 @@
+//! Support for async functions whose closures and futures are not `Send`
+//!
+//! See [`ContextExt::create_async_function_local`](crate::ContextExt::create_async_function_local).
+//! `rlua` requires the callbacks stored in a Lua state to be `Send`, as the state itself can be
+//! moved to another thread. [`ThreadBound`] makes a value `Send` by refusing any access to it
+//! from another thread than the one that created it.
+
+use std::{
+    future::Future,
+    mem::ManuallyDrop,
+    pin::Pin,
+    task::{self, Poll},
+    thread::{self, ThreadId},
+};
+
+use rlua::{Context, Error, MultiValue, Result, ToLuaMulti};
+
+fn wrong_thread() -> Error {
+    Error::RuntimeError(
+        "a local async function was used from another thread than the one that created it"
+            .to_string(),
+    )
+}
+
+/// A value that can only be used from the thread that created it
+pub(crate) struct ThreadBound<T> {
+    value: ManuallyDrop<T>,
+    thread: ThreadId,
+}
+
+// Safety: the value is only ever accessed, including dropped, from the thread that created it
+unsafe impl<T> Send for ThreadBound<T> {}
+
+impl<T> ThreadBound<T> {
+    pub(crate) fn new(value: T) -> ThreadBound<T> {
+        ThreadBound {
+            value: ManuallyDrop::new(value),
+            thread: thread::current().id(),
+        }
+    }
+
+    fn is_owned(&self) -> bool {
+        self.thread == thread::current().id()
+    }
+
+    pub(crate) fn get(&self) -> Result<&T> {
+        if self.is_owned() {
+            Ok(&self.value)
+        } else {
+            Err(wrong_thread())
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> Result<T> {
+        if !self.is_owned() {
+            return Err(wrong_thread());
+        }
+        let mut this = ManuallyDrop::new(self);
+        // Safety: `this` is never used nor dropped again
+        Ok(unsafe { ManuallyDrop::take(&mut this.value) })
+    }
+}
+
+impl<T> Drop for ThreadBound<T> {
+    fn drop(&mut self) {
+        // Dropping the value from another thread would not be safe, so it is leaked instead
+        if self.is_owned() {
+            // Safety: the value is not used after this
+            unsafe { ManuallyDrop::drop(&mut self.value) }
+        }
+    }
+}
+
+impl<T, Fut> Future for ThreadBound<Fut>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<T>> {
+        if !self.is_owned() {
+            return Poll::Ready(Err(wrong_thread()));
+        }
+        // Safety: the value is structurally pinned, it is never moved out of a pinned wrapper
+        let fut = unsafe { self.map_unchecked_mut(|this| &mut *this.value) };
+        fut.poll(cx)
+    }
+}
+
+impl<'lua, T: ToLuaMulti<'lua>> ToLuaMulti<'lua> for ThreadBound<T> {
+    fn to_lua_multi(self, ctx: Context<'lua>) -> Result<MultiValue<'lua>> {
+        self.into_inner()?.to_lua_multi(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    #[test]
+    fn only_usable_from_its_thread() {
+        let rc = Rc::new(42);
+        let bound = ThreadBound::new(rc.clone());
+        assert_eq!(**bound.get().unwrap(), 42);
+
+        let bound = thread::spawn(move || {
+            assert!(bound.get().is_err());
+            bound
+        })
+        .join()
+        .unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(bound.into_inner().unwrap());
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        // Dropping it from another thread leaks the value
+        let bound = ThreadBound::new(rc.clone());
+        thread::spawn(move || drop(bound)).join().unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
+    }
+}
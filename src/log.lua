@@ -0,0 +1,12 @@
+function(emit)
+    local lib = {}
+    for _, level in ipairs({ "error", "warn", "info", "debug", "trace" }) do
+        lib[level] = function(message, fields)
+            -- The position of the caller, from the `chunk:line: ` prefix `error` adds to its
+            -- message: level 1 is `pcall`, and level 2 this function
+            local _, where = pcall(error, "", 3)
+            emit(level, where, message, fields)
+        end
+    end
+    return lib
+end
@@ -0,0 +1,396 @@
+//! Structured logging from Lua scripts to the [`log`] crate or to [`tracing`]
+//!
+//! `print` writes to stdout, that production embeddings rarely want, and blocks while doing so.
+//! The `log` Lua library instead hands the messages over to the logger of the embedder, with
+//! their fields as key-values and the position of the call as the file and line of the records.
+//! The embedder picks where they go: [`install`] logs them to the `log` crate, and
+//! [`install_tracing`] emits them as events to the [`tracing`] subscriber.
+//!
+//! This module is only available with the `log` or `tracing` feature, [`install`] with the
+//! former and [`install_tracing`] with the latter.
+//!
+//! [`log`]: https://docs.rs/log
+//! [`tracing`]: https://docs.rs/tracing
+
+use std::fmt;
+
+#[cfg(feature = "log")]
+use ::log::{
+    kv::{ToValue, Value as KvValue},
+    Level, Record,
+};
+use rlua::{Context, Function, Result, Table, Value};
+
+use crate::global_table;
+
+static LOG_LIB: &[u8] = include_bytes!("log.lua");
+
+/// The target of the records of the Lua scripts
+pub const TARGET: &str = "lua";
+
+/// The value of a field of a record
+enum Field {
+    Boolean(bool),
+    Integer(rlua::Integer),
+    Number(rlua::Number),
+    String(String),
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Field::Boolean(b) => b.fmt(f),
+            Field::Integer(i) => i.fmt(f),
+            Field::Number(n) => n.fmt(f),
+            Field::String(s) => s.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl ToValue for Field {
+    fn to_value(&self) -> KvValue<'_> {
+        match self {
+            Field::Boolean(b) => b.to_value(),
+            Field::Integer(i) => i.to_value(),
+            Field::Number(n) => n.to_value(),
+            Field::String(s) => s.to_value(),
+        }
+    }
+}
+
+/// Convert `value` with the `tostring` of the Lua state
+fn tostring<'lua>(tostring: &Function<'lua>, value: Value<'lua>) -> Result<String> {
+    let s = tostring.call::<_, rlua::String>(value)?;
+    Ok(String::from_utf8_lossy(s.as_bytes()).into_owned())
+}
+
+/// Parse the `chunk:line: ` prefix of the error messages into the chunk name and line
+fn parse_location(location: &str) -> Option<(&str, u32)> {
+    let (chunk, line) = location.strip_suffix(": ")?.rsplit_once(':')?;
+    Some((chunk, line.parse().ok()?))
+}
+
+/// The arguments of the emitting function, from the Lua library: the level, the error message
+/// prefix locating the call, the message and the fields
+type EmitArgs<'lua> = (String, String, Value<'lua>, Option<Table<'lua>>);
+
+/// Convert the message and the fields of a record, the latter in key order
+fn convert<'lua>(
+    ctx: Context<'lua>,
+    message: Value<'lua>,
+    fields: Option<Table<'lua>>,
+) -> Result<(String, Vec<(String, Field)>)> {
+    let tostring_fn = ctx.globals().get::<_, Function>("tostring")?;
+    let message = tostring(&tostring_fn, message)?;
+    let mut kvs = Vec::new();
+    if let Some(fields) = fields {
+        for pair in fields.pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            let value = match value {
+                Value::Boolean(b) => Field::Boolean(b),
+                Value::Integer(i) => Field::Integer(i),
+                Value::Number(n) => Field::Number(n),
+                value => Field::String(tostring(&tostring_fn, value)?),
+            };
+            kvs.push((tostring(&tostring_fn, key)?, value));
+        }
+    }
+    // The fields are in the random order of `pairs` otherwise
+    kvs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok((message, kvs))
+}
+
+/// Create the `log` Lua library, sending the records to `emit`, in the `log` table of the
+/// globals
+fn install_with<'lua>(ctx: Context<'lua>, emit: Function<'lua>) -> Result<()> {
+    let lib = ctx
+        .load(LOG_LIB)
+        .set_name(b"rlua-async log library")?
+        .eval::<Function<'lua>>()?
+        .call::<_, Table<'lua>>(emit)?;
+    let log = global_table(ctx, "log")?;
+    for pair in lib.pairs::<Value, Value>() {
+        let (name, f) = pair?;
+        log.set(name, f)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "log")]
+fn emit<'lua>(
+    ctx: Context<'lua>,
+    (level, location, message, fields): EmitArgs<'lua>,
+) -> Result<()> {
+    let level = level
+        .parse::<Level>()
+        .expect("invalid log level in the log library");
+    if !::log::log_enabled!(target: TARGET, level) {
+        return Ok(());
+    }
+    let (message, kvs) = convert(ctx, message, fields)?;
+    let location = parse_location(&location);
+    ::log::logger().log(
+        &Record::builder()
+            .target(TARGET)
+            .level(level)
+            .file(location.map(|(chunk, _)| chunk))
+            .line(location.map(|(_, line)| line))
+            .key_values(&kvs)
+            .args(format_args!("{}", message))
+            .build(),
+    );
+    Ok(())
+}
+
+/// Install the `log` Lua library in the `log` table of the globals, creating it if need be, to
+/// log to the `log` crate
+///
+/// It has the functions `log.error`, `log.warn`, `log.info`, `log.debug` and `log.trace`, that
+/// take a message, converted with `tostring`, and optionally a table of fields. Those are
+/// passed as the key-values of the record, booleans and numbers as such and other values
+/// converted with `tostring`, in key order. The records have the [`TARGET`] target, and as file
+/// and line the chunk name and line of the call, unless it is a tail call (eg.
+/// `return log.info(...)`) whose position Lua forgets.
+///
+/// The records below the maximum level of the logger are dropped before converting anything.
+/// The functions are not async, the loggers being expected not to block.
+#[cfg(feature = "log")]
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    install_with(ctx, ctx.create_function(emit)?)
+}
+
+#[cfg(feature = "tracing")]
+fn emit_tracing<'lua>(
+    ctx: Context<'lua>,
+    (level, location, message, fields): EmitArgs<'lua>,
+) -> Result<()> {
+    use ::tracing::{event, level_filters::LevelFilter, Level};
+
+    let level = level
+        .parse::<Level>()
+        .expect("invalid log level in the log library");
+    if level > LevelFilter::current() {
+        return Ok(());
+    }
+    let (message, kvs) = convert(ctx, message, fields)?;
+    let fields = kvs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let location = parse_location(&location);
+    let (chunk, line) = (
+        location.map(|(chunk, _)| chunk),
+        location.map(|(_, line)| line),
+    );
+    // The levels of the events must be constants
+    macro_rules! emit_at {
+        ($level:expr) => {
+            event!(target: TARGET, $level, chunk, line, fields, "{}", message)
+        };
+    }
+    match level {
+        Level::ERROR => emit_at!(Level::ERROR),
+        Level::WARN => emit_at!(Level::WARN),
+        Level::INFO => emit_at!(Level::INFO),
+        Level::DEBUG => emit_at!(Level::DEBUG),
+        Level::TRACE => emit_at!(Level::TRACE),
+    }
+    Ok(())
+}
+
+/// Install the `log` Lua library in the `log` table of the globals, creating it if need be, to
+/// emit [`tracing`] events
+///
+/// The Lua library is the same as with [`install`], only the records become events with the
+/// [`TARGET`] target, the message of the call, and as fields `chunk` and `line`, the position of
+/// the call if Lua knows it, and `fields`, the fields of the call formatted as `key=value` pairs
+/// separated with spaces, as the event fields cannot be named at runtime.
+///
+/// The events above the maximum level of the subscribers are dropped before converting
+/// anything.
+#[cfg(feature = "tracing")]
+pub fn install_tracing<'lua>(ctx: Context<'lua>) -> Result<()> {
+    install_with(ctx, ctx.create_function(emit_tracing)?)
+}
+
+#[cfg(all(test, feature = "log"))]
+mod tests {
+    use super::*;
+
+    use std::sync::{Mutex, Once};
+
+    use ::log::{
+        kv::{Key, VisitSource},
+        LevelFilter, Log, Metadata,
+    };
+    use rlua::Lua;
+
+    /// The records logged to [`TestLogger`], formatted
+    static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct TestLogger;
+
+    struct Formatter<'a>(&'a mut String);
+
+    impl<'kvs> VisitSource<'kvs> for Formatter<'_> {
+        fn visit_pair(
+            &mut self,
+            key: Key<'kvs>,
+            value: KvValue<'kvs>,
+        ) -> std::result::Result<(), ::log::kv::Error> {
+            self.0.push_str(&format!(" {}={}", key, value));
+            Ok(())
+        }
+    }
+
+    impl Log for TestLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let mut line = format!(
+                "{} {} {}:{} {}",
+                record.level(),
+                record.target(),
+                record.file().unwrap_or("?"),
+                record.line().unwrap_or(0),
+                record.args()
+            );
+            record
+                .key_values()
+                .visit(&mut Formatter(&mut line))
+                .unwrap();
+            RECORDS.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn log_records() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            ::log::set_logger(&TestLogger).unwrap();
+            ::log::set_max_level(LevelFilter::Trace);
+        });
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.load(
+                r#"
+                    log.info("started", { b = 2.5, a = 1, c = true, d = "text" })
+                    local function work()
+                        log.warn(42)
+                        log.debug("dropped")
+                    end
+                    work()
+                    return log.error("tail call")
+                "#,
+            )
+            .set_name(b"=script")
+            .unwrap()
+            .exec()
+            .unwrap();
+        });
+
+        let records = RECORDS.lock().unwrap();
+        assert_eq!(
+            *records,
+            vec![
+                "INFO lua script:2 started a=1 b=2.5 c=true d=text",
+                "WARN lua script:4 42",
+                "ERROR lua ?:0 tail call",
+            ]
+        );
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tracing_tests {
+    use super::*;
+
+    use std::{fmt::Debug, sync::Mutex};
+
+    use ::tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+    use rlua::Lua;
+
+    /// A subscriber formatting the events it receives, up to `INFO`
+    #[derive(Default)]
+    struct TestSubscriber(Mutex<Vec<String>>);
+
+    struct Formatter<'a>(&'a mut String);
+
+    impl Visit for Formatter<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for &'static TestSubscriber {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            *metadata.level() <= ::tracing::Level::INFO
+        }
+
+        fn max_level_hint(&self) -> Option<::tracing::level_filters::LevelFilter> {
+            Some(::tracing::level_filters::LevelFilter::INFO)
+        }
+
+        fn event(&self, event: &Event) {
+            let metadata = event.metadata();
+            let mut line = format!("{} {}", metadata.level(), metadata.target());
+            event.record(&mut Formatter(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn new_span(&self, _: &span::Attributes) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn tracing_events() {
+        let subscriber: &'static TestSubscriber = Box::leak(Box::default());
+        ::tracing::subscriber::with_default(subscriber, || {
+            Lua::new().context(|lua| {
+                install_tracing(lua).unwrap();
+                lua.load(
+                    r#"
+                        log.info("started", { b = 2.5, a = 1, c = true, d = "text" })
+                        log.debug("dropped")
+                        return log.error("tail call")
+                    "#,
+                )
+                .set_name(b"=script")
+                .unwrap()
+                .exec()
+                .unwrap();
+            })
+        });
+
+        let events = subscriber.0.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec![
+                r#"INFO lua message=started chunk="script" line=2 fields="a=1 b=2.5 c=true d=text""#,
+                r#"ERROR lua message=tail call fields="""#,
+            ]
+        );
+    }
+}
@@ -1,13 +1,27 @@
-function(f)
+function(f, pending, name)
+    local isyieldable = coroutine.isyieldable
+
+    -- Wait for the results of `poller`, that are passed as varargs rather than packed in a table
+    -- so that their `nil`s are kept. This only ever tail-calls itself, so doesn't grow the stack.
+    local function wait(poller, ready, no_executor, ...)
+        if ready then
+            return ...
+        elseif no_executor then
+            error("async function `" .. name .. "` cannot wait outside of an async call: "
+                .. "run the Lua code with `call_async` or `exec_async`", 2)
+        elseif isyieldable() then
+            coroutine.yield(pending)
+        else
+            error("async function `" .. name .. "` cannot wait from a metamethod, iterator or "
+                .. "callback called from Rust or C (eg. a `table.sort` comparator, `__index` on "
+                .. "a userdata or a `string.gsub` replacement function): await it outside and "
+                .. "pass its result instead", 2)
+        end
+        return wait(poller, poller())
+    end
+
     return function(...)
         local poll = f(...)
-        while true do
-            local t, ready = poll()
-            if ready then
-                return table.unpack(t)
-            else
-                coroutine.yield()
-            end
-        end
+        return wait(poll, poll())
     end
 end
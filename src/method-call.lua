@@ -0,0 +1,7 @@
+function(obj, name, ...)
+    local method = obj[name]
+    if method == nil then
+        error("no method `" .. tostring(name) .. "` to call", 2)
+    end
+    return method(obj, ...)
+end
@@ -0,0 +1,244 @@
+//! Middleware wrapping the async functions created through [`ContextExt`](crate::ContextExt)
+//!
+//! Middleware is installed per Lua state with [`ContextExt::add_async_middleware`], and applies
+//! to every call of an async function created with [`ContextExt::create_async_function`] or one
+//! of its variants, including functions that were created before the middleware was installed.
+//! Middleware is run in installation order: the first installed middleware sees the call first
+//! and wraps all the other ones.
+//!
+//! [`ContextExt::add_async_middleware`]: crate::ContextExt::add_async_middleware
+//! [`ContextExt::create_async_function`]: crate::ContextExt::create_async_function
+
+use std::{any::Any, future::Future, pin::Pin, sync::Arc};
+
+use futures::FutureExt;
+use rlua::{AnyUserData, Context, MultiValue, Result, UserData};
+
+use crate::IntoLuaResult;
+
+/// The future of an async function call, as seen by middleware
+pub type Next = Pin<Box<dyn Future<Output = Result<AsyncReturn>> + Send>>;
+
+/// The type-erased return value of an async function, as seen by middleware
+pub struct AsyncReturn(Box<dyn Any + Send>);
+
+impl AsyncReturn {
+    /// Wrap a value so that it can be returned from a [`Next`] future
+    ///
+    /// Note that the type of `value` must be exactly the return type of the Rust closure that
+    /// was used to create the async function, otherwise the call will fail with an error.
+    pub fn new<T: 'static + Send>(value: T) -> AsyncReturn {
+        AsyncReturn(Box::new(value))
+    }
+
+    /// Check whether the value is of type `T`
+    pub fn is<T: 'static>(&self) -> bool {
+        self.0.is::<T>()
+    }
+
+    /// Get a reference to the value, if it is of type `T`
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    pub(crate) fn downcast<T: 'static>(self) -> Result<T> {
+        self.0.downcast().map(|v| *v).map_err(|_| {
+            rlua::Error::RuntimeError(
+                "async middleware returned a value of an unexpected type".to_string(),
+            )
+        })
+    }
+}
+
+/// A hook wrapping calls to async functions
+///
+/// All the methods have a default implementation that does nothing, so that implementors only
+/// need to override the hooks they are interested in.
+pub trait Middleware: 'static + Send + Sync {
+    /// Called synchronously when the function is called from Lua, before the Rust closure is
+    /// called. Returning an error aborts the call, and raises the error in Lua.
+    fn before<'lua>(
+        &self,
+        _ctx: Context<'lua>,
+        _name: &str,
+        _args: &MultiValue<'lua>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Wrap the future that will compute the result of the call. `next` resolves to the result
+    /// of the rest of the middleware chain, and ultimately of the Rust closure.
+    fn around(&self, _name: &str, next: Next) -> Next {
+        next
+    }
+
+    /// Called once the call completed, with its result
+    fn after(&self, _name: &str, _result: &Result<AsyncReturn>) {}
+}
+
+static MIDDLEWARE_REGISTRY_KEY: &str = "rlua-async middleware";
+
+#[derive(Clone, Default)]
+struct Middlewares(Vec<Arc<dyn Middleware>>);
+
+impl UserData for Middlewares {}
+
+fn installed(ctx: Context) -> Result<Vec<Arc<dyn Middleware>>> {
+    match ctx.named_registry_value::<_, Option<AnyUserData>>(MIDDLEWARE_REGISTRY_KEY)? {
+        None => Ok(Vec::new()),
+        Some(ud) => Ok(ud.borrow::<Middlewares>()?.0.clone()),
+    }
+}
+
+pub(crate) fn add<M: Middleware>(ctx: Context, middleware: M) -> Result<()> {
+    let mut middlewares = installed(ctx)?;
+    middlewares.push(Arc::new(middleware));
+    ctx.set_named_registry_value(MIDDLEWARE_REGISTRY_KEY, Middlewares(middlewares))
+}
+
+/// Either calls `call` directly, or runs it through the installed middleware chain if there is
+/// one.
+pub(crate) fn wrap<'lua, Ret, RetFut, F>(
+    ctx: Context<'lua>,
+    name: &Arc<str>,
+    args: MultiValue<'lua>,
+    call: F,
+) -> Result<Pin<Box<dyn Future<Output = Result<Ret>> + Send>>>
+where
+    Ret: 'static + Send,
+    RetFut: 'static + Send + Future,
+    RetFut::Output: IntoLuaResult<Ret>,
+    F: FnOnce(MultiValue<'lua>) -> Result<RetFut>,
+{
+    let middlewares = installed(ctx)?;
+    if middlewares.is_empty() {
+        return Ok(Box::pin(call(args)?.map(IntoLuaResult::into_lua_result)));
+    }
+
+    for m in &middlewares {
+        m.before(ctx, name, &args)?;
+    }
+
+    let mut next: Next = Box::pin(call(args)?.map(|r| r.into_lua_result().map(AsyncReturn::new)));
+    for m in middlewares.iter().rev() {
+        next = m.around(name, next);
+    }
+
+    let name = name.clone();
+    Ok(Box::pin(next.map(move |res| {
+        for m in middlewares.iter().rev() {
+            m.after(&name, &res);
+        }
+        res?.downcast()
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use futures::{executor, future};
+    use rlua::{Error, Function, Lua};
+
+    use crate::{ContextExt, FunctionExt};
+
+    struct Logger(Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Logger {
+        fn before<'lua>(
+            &self,
+            _: Context<'lua>,
+            name: &str,
+            args: &MultiValue<'lua>,
+        ) -> Result<()> {
+            let mut log = self.0.lock().unwrap();
+            log.push(format!("before {} with {} args", name, args.len()));
+            Ok(())
+        }
+
+        fn around(&self, name: &str, next: Next) -> Next {
+            let log = self.0.clone();
+            let name = name.to_string();
+            Box::pin(async move {
+                log.lock().unwrap().push(format!("around {}", name));
+                next.await
+            })
+        }
+
+        fn after(&self, name: &str, result: &Result<AsyncReturn>) {
+            let value = result.as_ref().ok().and_then(|r| r.downcast_ref::<usize>());
+            let mut log = self.0.lock().unwrap();
+            log.push(format!("after {}: {:?}", name, value));
+        }
+    }
+
+    struct DenyAll;
+
+    impl Middleware for DenyAll {
+        fn before<'lua>(&self, _: Context<'lua>, name: &str, _: &MultiValue<'lua>) -> Result<()> {
+            Err(Error::RuntimeError(format!(
+                "calling {} is forbidden",
+                name
+            )))
+        }
+    }
+
+    #[test]
+    fn middleware_wraps_calls() {
+        Lua::new().context(|lua| {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let f = lua
+                .create_named_async_function("incr", |_, a: usize| future::ok(a + 1))
+                .unwrap();
+            lua.globals().set("incr", f).unwrap();
+            lua.add_async_middleware(Logger(log.clone())).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"function(a) return incr(a) end"#)
+                    .eval::<Function>()
+                    .unwrap()
+                    .call_async::<_, usize>(lua, 2),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 3);
+            assert_eq!(
+                *log.lock().unwrap(),
+                vec![
+                    "before incr with 1 args".to_string(),
+                    "around incr".to_string(),
+                    "after incr: Some(3)".to_string(),
+                ]
+            );
+
+            lua.add_async_middleware(DenyAll).unwrap();
+            let res = executor::block_on(
+                lua.load(r#"function(a) return incr(a) end"#)
+                    .eval::<Function>()
+                    .unwrap()
+                    .call_async::<_, usize>(lua, 2),
+            );
+            match res {
+                Err(Error::CallbackError { cause, .. }) => {
+                    assert_eq!(
+                        cause.to_string(),
+                        "runtime error: calling incr is forbidden"
+                    )
+                }
+                r => panic!("middleware did not deny the call: {:?}", r),
+            }
+
+            let unnamed = lua
+                .create_async_function(|_, a: usize| future::ok(a + 1))
+                .unwrap();
+            let err = executor::block_on(unnamed.call_async::<_, usize>(lua, 2)).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("calling async function is forbidden"),
+                "{}",
+                err
+            );
+        });
+    }
+}
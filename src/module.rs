@@ -0,0 +1,141 @@
+use std::future::Future;
+
+use rlua::{Context, FromLuaMulti, Result, Table, ToLua, ToLuaMulti};
+
+use crate::{ContextExt, IntoLuaResult, TableExt};
+
+/// A builder for Lua modules made of async functions and constants, see
+/// [`ContextExt::async_module_builder`]
+///
+/// The errors are deferred until [`AsyncModuleBuilder::build`] or
+/// [`AsyncModuleBuilder::install`], so that a whole API can be registered in one chain:
+///
+/// ```
+/// # use rlua::Lua;
+/// # use rlua_async::ContextExt;
+/// # async fn fetch(url: String) -> rlua::Result<String> { Ok(url) }
+/// # async fn post(url: String, body: String) -> rlua::Result<String> { Ok(url + &body) }
+/// # Lua::new().context(|ctx| -> rlua::Result<()> {
+/// ctx.async_module_builder()
+///     .function("fetch", |_, url: String| fetch(url))
+///     .function("post", |_, (url, body): (String, String)| post(url, body))
+///     .constant("VERSION", "1.0")
+///     .install("net")?;
+/// # Ok(())
+/// # }).unwrap();
+/// ```
+#[must_use = "the module is only created by `build` or `install`"]
+pub struct AsyncModuleBuilder<'lua> {
+    ctx: Context<'lua>,
+    table: Result<Table<'lua>>,
+}
+
+impl<'lua> AsyncModuleBuilder<'lua> {
+    pub(crate) fn new(ctx: Context<'lua>) -> AsyncModuleBuilder<'lua> {
+        AsyncModuleBuilder {
+            ctx,
+            table: ctx.create_table(),
+        }
+    }
+
+    /// Add an async function named `name`, see [`TableExt::set_async_fn`]
+    pub fn function<Arg, Ret, RetFut, F>(mut self, name: &str, func: F) -> Self
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        let ctx = self.ctx;
+        self.table = self
+            .table
+            .and_then(|t| t.set_async_fn(ctx, name, func).map(|()| t));
+        self
+    }
+
+    /// Add a mutable async function named `name`, see
+    /// [`ContextExt::create_async_function_mut`]
+    pub fn function_mut<Arg, Ret, RetFut, F>(mut self, name: &str, func: F) -> Self
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + FnMut(Context<'lua>, Arg) -> RetFut,
+    {
+        let ctx = self.ctx;
+        self.table = self.table.and_then(|t| {
+            t.set(name, ctx.create_named_async_function_mut(name, func)?)?;
+            Ok(t)
+        });
+        self
+    }
+
+    /// Add any other value, eg. a constant or a regular function, named `name`
+    pub fn constant<V: ToLua<'lua>>(mut self, name: &str, value: V) -> Self {
+        self.table = self.table.and_then(|t| t.set(name, value).map(|()| t));
+        self
+    }
+
+    /// Get the table of the module
+    pub fn build(self) -> Result<Table<'lua>> {
+        self.table
+    }
+
+    /// Get the table of the module, and install it in `package.loaded`, so that `require(name)`
+    /// returns it
+    pub fn install(self, name: &str) -> Result<Table<'lua>> {
+        let table = self.table?;
+        let package = self.ctx.globals().get::<_, Table>("package")?;
+        package
+            .get::<_, Table>("loaded")?
+            .set(name, table.clone())?;
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{executor, future};
+    use rlua::Lua;
+
+    use crate::{ChunkExt, ContextExt};
+
+    #[test]
+    fn async_module() {
+        Lua::new().context(|lua| {
+            let mut calls = 0;
+            lua.async_module_builder()
+                .function("fetch", |_, url: String| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(format!("contents of {}", url))
+                })
+                .function_mut("count", move |_, ()| {
+                    calls += 1;
+                    future::ok(calls)
+                })
+                .constant("VERSION", "1.0")
+                .install("net")
+                .unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local net = require("net")
+                        net.count()
+                        return net.fetch("example.org"), net.count(), net.VERSION
+                    "#,
+                )
+                .call_async::<_, (String, usize, String)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(
+                res,
+                ("contents of example.org".to_string(), 2, "1.0".to_string())
+            );
+        });
+    }
+}
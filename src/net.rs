@@ -0,0 +1,757 @@
+//! Networking for Lua scripts, without blocking the executor
+//!
+//! Like the filesystem, the sockets of the standard library are blocking. Connecting and writing
+//! thus run on the blocking threads of the spawner of the Lua state, and each connected socket
+//! has a thread reading it ahead of the scripts, so that a read that is abandoned (eg. by
+//! `async.timeout`) loses no data. Accepting
+//! connections and receiving datagrams run there too, one call at a time per socket, so that
+//! closing a listener or UDP socket can wake the pending call up, by connecting or sending an
+//! empty datagram to the socket itself.
+
+use std::{
+    io::{self, Read, Write},
+    net::{self, Shutdown, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    thread,
+};
+
+#[cfg(unix)]
+use std::os::unix::net as unix;
+
+use futures::{channel::mpsc, lock::Mutex, SinkExt, StreamExt};
+use rlua::{Context, Error, Result, Table, UserData, UserDataMethods, Value};
+
+use crate::{
+    blocking,
+    buffer::{buffer_or_string, Buffer},
+    global_table,
+    spawner::Spawner,
+    ContextExt, UserDataMethodsExt,
+};
+
+/// The maximum number of bytes the reader thread of a socket reads at once
+const READ_CHUNK: usize = 16 * 1024;
+
+/// The default and maximum size of the buffer UDP datagrams are received in, that fits any
+/// datagram
+const MAX_RECV_BUFFER: usize = 65535;
+
+/// Get the `net.<name>` table, creating it if need be
+fn net_table<'lua>(ctx: Context<'lua>, name: &str) -> Result<Table<'lua>> {
+    let net = global_table(ctx, "net")?;
+    match net.get::<_, Option<Table>>(name)? {
+        Some(table) => Ok(table),
+        None => {
+            let table = ctx.create_table()?;
+            net.set(name, table.clone())?;
+            Ok(table)
+        }
+    }
+}
+
+/// The error of the operations on a socket that was closed
+fn closed(what: &str) -> io::Error {
+    io::Error::other(format!("{} closed", what))
+}
+
+/// The socket in `slot`, or the error of `what` being closed if it is `None`
+fn opened<T>(slot: &std::sync::Mutex<Option<Arc<T>>>, what: &str) -> io::Result<Arc<T>> {
+    slot.lock().unwrap().clone().ok_or_else(|| closed(what))
+}
+
+/// An address to reach the socket bound to `addr` at, the loopback one if it is unspecified
+fn reachable(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    addr
+}
+
+/// The reading side of a connected socket: the chunks read ahead by its thread, and what is
+/// left of the last one
+struct ReadHalf {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    rest: Buffer,
+}
+
+impl ReadHalf {
+    /// Read `reader` on a new thread, at most one chunk ahead of the async reads
+    ///
+    /// The thread stops at the end of the stream, on the first error, or when the next chunk is
+    /// read after the `ReadHalf` is dropped.
+    fn new<R: 'static + Send + Read>(mut reader: R) -> io::Result<ReadHalf> {
+        let (mut tx, rx) = mpsc::channel(0);
+        thread::Builder::new()
+            .name("rlua-async socket reader".to_string())
+            .spawn(move || loop {
+                let mut chunk = vec![0; READ_CHUNK];
+                let res = match reader.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let is_err = res.is_err();
+                if futures::executor::block_on(tx.send(res)).is_err() || is_err {
+                    return;
+                }
+            })?;
+        Ok(ReadHalf {
+            chunks: rx,
+            rest: Buffer::from(Vec::new()),
+        })
+    }
+
+    /// Read at most `max` bytes, or what is available if `None`, returning `None` at the end
+    /// of the stream
+    async fn read(&mut self, max: Option<usize>) -> Result<Option<Buffer>> {
+        if self.rest.is_empty() {
+            match self.chunks.next().await {
+                None => return Ok(None),
+                Some(chunk) => self.rest = Buffer::from(chunk.map_err(Error::external)?),
+            }
+        }
+        let len = self.rest.len();
+        let n = max.map_or(len, |max| max.min(len));
+        let read = self.rest.slice(0, n);
+        self.rest = self.rest.slice(n, len);
+        Ok(Some(read))
+    }
+}
+
+/// The std stream sockets, TCP or Unix, behind the connected socket handles
+trait Socket: 'static + Send + Sync + Read + Sized {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn write_all(&self, data: &[u8]) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+    fn local_addr(&self) -> io::Result<String>;
+    fn peer_addr(&self) -> io::Result<String>;
+}
+
+impl Socket for net::TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        net::TcpStream::try_clone(self)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, data)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        net::TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        net::TcpStream::local_addr(self).map(|a| a.to_string())
+    }
+
+    fn peer_addr(&self) -> io::Result<String> {
+        net::TcpStream::peer_addr(self).map(|a| a.to_string())
+    }
+}
+
+/// A connected socket, the Lua handle returned by the `connect` functions and `l:accept()`
+struct Stream<S> {
+    socket: Arc<S>,
+    reader: Arc<Mutex<ReadHalf>>,
+    /// Held while writing, so that concurrent writes are not interleaved
+    writing: Arc<Mutex<()>>,
+}
+
+impl<S: Socket> Stream<S> {
+    fn new(socket: S) -> io::Result<Stream<S>> {
+        let reader = ReadHalf::new(socket.try_clone()?)?;
+        Ok(Stream {
+            socket: Arc::new(socket),
+            reader: Arc::new(Mutex::new(reader)),
+            writing: Arc::new(Mutex::new(())),
+        })
+    }
+}
+
+impl<S: Socket> UserData for Stream<S> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("read", |_, this, max: Option<usize>| {
+            let reader = this.reader.clone();
+            async move { reader.lock().await.read(max).await }
+        });
+
+        methods.add_async_method("write", |ctx, this, data: Value| {
+            let data = buffer_or_string(ctx, data);
+            let socket = this.socket.clone();
+            let writing = this.writing.clone();
+            let spawner = ctx.spawner();
+            async move {
+                let data = data?;
+                let _writing = writing.lock().await;
+                blocking(spawner, "net", move || socket.write_all(&data)).await
+            }
+        });
+
+        methods.add_method("close", |_, this, ()| match this.socket.shutdown() {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(Error::external(e)),
+            _ => Ok(()),
+        });
+
+        methods.add_method("local_addr", |_, this, ()| {
+            this.socket.local_addr().map_err(Error::external)
+        });
+        methods.add_method("peer_addr", |_, this, ()| {
+            this.socket.peer_addr().map_err(Error::external)
+        });
+    }
+}
+
+/// The std listeners, TCP or Unix, behind the listener handles
+trait SocketListener: 'static + Send + Sync {
+    type Socket: Socket;
+
+    /// Accept a connection, returning the socket and the address of its peer
+    fn accept(&self) -> io::Result<(Self::Socket, String)>;
+
+    /// Connect to the listener, to wake a pending `accept` up
+    fn wake(&self) -> io::Result<()>;
+}
+
+impl SocketListener for net::TcpListener {
+    type Socket = net::TcpStream;
+
+    fn accept(&self) -> io::Result<(net::TcpStream, String)> {
+        let (socket, peer) = net::TcpListener::accept(self)?;
+        Ok((socket, peer.to_string()))
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        net::TcpStream::connect(reachable(self.local_addr()?)).map(drop)
+    }
+}
+
+/// The path of a Unix socket address, or an empty string if it has none
+#[cfg(unix)]
+fn unix_addr(addr: io::Result<unix::SocketAddr>) -> io::Result<String> {
+    addr.map(|a| {
+        a.as_pathname()
+            .map_or_else(String::new, |p| p.display().to_string())
+    })
+}
+
+#[cfg(unix)]
+impl Socket for unix::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        unix::UnixStream::try_clone(self)
+    }
+
+    fn write_all(&self, data: &[u8]) -> io::Result<()> {
+        Write::write_all(&mut &*self, data)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        unix::UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn local_addr(&self) -> io::Result<String> {
+        unix_addr(unix::UnixStream::local_addr(self))
+    }
+
+    fn peer_addr(&self) -> io::Result<String> {
+        unix_addr(unix::UnixStream::peer_addr(self))
+    }
+}
+
+#[cfg(unix)]
+impl SocketListener for unix::UnixListener {
+    type Socket = unix::UnixStream;
+
+    fn accept(&self) -> io::Result<(unix::UnixStream, String)> {
+        let (socket, peer) = unix::UnixListener::accept(self)?;
+        Ok((socket, unix_addr(Ok(peer))?))
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        match self.local_addr()?.as_pathname() {
+            Some(path) => unix::UnixStream::connect(path).map(drop),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A listening socket, the Lua handle returned by the `listen` functions
+struct Listener<L> {
+    /// `None` once closed
+    listener: Arc<std::sync::Mutex<Option<Arc<L>>>>,
+    /// Held while accepting, so that closing has at most one blocking accept to wake up
+    accepting: Arc<Mutex<()>>,
+    local_addr: String,
+}
+
+impl<L> Listener<L> {
+    fn new(listener: L, local_addr: String) -> Listener<L> {
+        Listener {
+            listener: Arc::new(std::sync::Mutex::new(Some(Arc::new(listener)))),
+            accepting: Arc::new(Mutex::new(())),
+            local_addr,
+        }
+    }
+}
+
+impl<L: SocketListener> UserData for Listener<L> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("accept", |ctx, this, ()| {
+            let listener = this.listener.clone();
+            let accepting = this.accepting.clone();
+            let spawner = ctx.spawner();
+            async move {
+                let _accepting = accepting.lock().await;
+                let (socket, peer) = blocking(spawner, "net", move || {
+                    let accepted = opened(&listener, "listener")?.accept();
+                    // The connection of `close` waking the accept up is not a peer
+                    opened(&listener, "listener")?;
+                    accepted
+                })
+                .await?;
+                let stream = Stream::new(socket).map_err(Error::external)?;
+                Ok((stream, peer))
+            }
+        });
+
+        methods.add_method("close", |_, this, ()| {
+            if let Some(listener) = this.listener.lock().unwrap().take() {
+                // The pending accept, if any, holds the listener open until it is woken up
+                let _ = listener.wake();
+            }
+            Ok(())
+        });
+
+        methods.add_method("local_addr", |_, this, ()| Ok(this.local_addr.clone()));
+    }
+}
+
+/// A UDP socket, the Lua handle returned by `net.udp.bind`
+struct UdpSocket {
+    /// `None` once closed
+    socket: Arc<std::sync::Mutex<Option<Arc<net::UdpSocket>>>>,
+    /// Held while receiving, so that closing has at most one blocking receive to wake up
+    receiving: Arc<Mutex<()>>,
+    local_addr: SocketAddr,
+    /// The size of the buffer each datagram is received in
+    recv_buffer: usize,
+}
+
+/// Resolve `addr`, with `spawner` if it is not a socket address already
+async fn resolve(spawner: Arc<dyn Spawner>, addr: String) -> Result<SocketAddr> {
+    if let Ok(addr) = addr.parse() {
+        return Ok(addr);
+    }
+    blocking(Ok(spawner), "net", move || {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} not found", addr));
+        addr.to_socket_addrs()?.next().ok_or_else(not_found)
+    })
+    .await
+}
+
+impl UserData for UdpSocket {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("send_to", |ctx, this, (data, addr): (Value, String)| {
+            let data = buffer_or_string(ctx, data);
+            let socket = this.socket.clone();
+            let spawner = ctx.spawner();
+            async move {
+                let (data, spawner) = (data?, spawner?);
+                let addr = resolve(spawner.clone(), addr).await?;
+                blocking(Ok(spawner), "net", move || {
+                    opened(&socket, "socket")?.send_to(&data, addr).map(drop)
+                })
+                .await
+            }
+        });
+
+        methods.add_async_method("recv_from", |ctx, this, ()| {
+            let socket = this.socket.clone();
+            let receiving = this.receiving.clone();
+            let recv_buffer = this.recv_buffer;
+            let spawner = ctx.spawner();
+            async move {
+                let _receiving = receiving.lock().await;
+                let (datagram, from) = blocking(spawner, "net", move || {
+                    let mut datagram = vec![0; recv_buffer];
+                    let (n, from) = opened(&socket, "socket")?.recv_from(&mut datagram)?;
+                    // The datagram of `close` waking the receive up is not from a peer
+                    opened(&socket, "socket")?;
+                    datagram.truncate(n);
+                    Ok((datagram, from))
+                })
+                .await?;
+                Ok((Buffer::from(datagram), from.to_string()))
+            }
+        });
+
+        methods.add_method("close", |_, this, ()| {
+            if let Some(socket) = this.socket.lock().unwrap().take() {
+                // The pending receive, if any, holds the socket open until it is woken up
+                let _ = socket.send_to(&[], reachable(this.local_addr));
+            }
+            Ok(())
+        });
+
+        methods.add_method("local_addr", |_, this, ()| Ok(this.local_addr.to_string()));
+    }
+}
+
+/// Install the networking functions in the `net` table of the globals, creating it if need be
+///
+/// The installed functions are:
+///  * `net.tcp.connect(host, port)`, that connects to `port` of `host`, a name or an address,
+///    and returns the connected socket.
+///  * `net.tcp.listen(addr)`, that listens on `addr`, eg. `"127.0.0.1:8080"` (port 0 letting
+///    the system pick one), and returns the listener.
+///
+/// A connected socket `s` has the async methods `s:read(max)`, that reads at most `max` bytes,
+/// or what is available if `max` is `nil`, as a [`Buffer`], returning `nil` once the peer
+/// closed the connection, and `s:write(data)`, that writes a string or buffer. It also has the
+/// methods `s:close()`, that shuts the connection down, and `s:local_addr()` and
+/// `s:peer_addr()`. A write that is abandoned, eg. by a timeout, still completes, and a read
+/// loses no data.
+///
+/// A listener `l` has the async method `l:accept()`, that returns the next connected socket and
+/// the address of its peer, and the methods `l:close()` and `l:local_addr()`.
+///
+/// On Unix platforms, `net.unix.connect(path)` and `net.unix.listen(path)` do the same with Unix
+/// domain sockets, the addresses of which are their paths, or empty strings for the unnamed
+/// ones, eg. of connecting sockets. Listening fails if a file exists at `path`, and the socket
+/// file is not removed when the listener is closed.
+///
+/// `net.udp.bind(addr, opts)` binds a UDP socket to `addr`, with the `opts` table, if any,
+/// setting `recv_buffer`, the size in bytes of the buffer the datagrams are received in, from 1
+/// to 65535, the default. The datagrams that do not fit in it are truncated or dropped, depending on the
+/// platform. The socket `u` has the async methods `u:send_to(data, addr)`, that sends a string
+/// or buffer to `addr`, eg. `"example.com:53"`, and `u:recv_from()`, that returns the next
+/// datagram as a [`Buffer`] and the address of its sender, and the methods `u:close()` and
+/// `u:local_addr()`.
+///
+/// `net.resolve(host)` looks the name `host` up with the system resolver, and returns the list
+/// of its IP addresses, without duplicates, in the order of preference of the resolver. The
+/// functions taking addresses resolve them by themselves, it is only needed to pick among the
+/// addresses or to connect to several of them.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let net = global_table(ctx, "net")?;
+    net.set(
+        "resolve",
+        ctx.create_named_async_function("net.resolve", |ctx, host: String| {
+            blocking(ctx.spawner(), "net", move || {
+                let mut ips = Vec::new();
+                for addr in (host.as_str(), 0).to_socket_addrs()? {
+                    let ip = addr.ip().to_string();
+                    if !ips.contains(&ip) {
+                        ips.push(ip);
+                    }
+                }
+                Ok(ips)
+            })
+        })?,
+    )?;
+
+    let tcp = net_table(ctx, "tcp")?;
+    tcp.set(
+        "connect",
+        ctx.create_named_async_function("net.tcp.connect", |ctx, (host, port): (String, u16)| {
+            blocking(ctx.spawner(), "net", move || {
+                Stream::new(net::TcpStream::connect((host.as_str(), port))?)
+            })
+        })?,
+    )?;
+    tcp.set(
+        "listen",
+        ctx.create_named_async_function("net.tcp.listen", |ctx, addr: String| {
+            blocking(ctx.spawner(), "net", move || {
+                let listener = net::TcpListener::bind(addr.as_str())?;
+                let local_addr = listener.local_addr()?.to_string();
+                Ok(Listener::new(listener, local_addr))
+            })
+        })?,
+    )?;
+
+    #[cfg(unix)]
+    {
+        let unix = net_table(ctx, "unix")?;
+        unix.set(
+            "connect",
+            ctx.create_named_async_function("net.unix.connect", |ctx, path: String| {
+                blocking(ctx.spawner(), "net", move || {
+                    Stream::new(unix::UnixStream::connect(path)?)
+                })
+            })?,
+        )?;
+        unix.set(
+            "listen",
+            ctx.create_named_async_function("net.unix.listen", |ctx, path: String| {
+                blocking(ctx.spawner(), "net", move || {
+                    let listener = unix::UnixListener::bind(&path)?;
+                    Ok(Listener::new(listener, path))
+                })
+            })?,
+        )?;
+    }
+
+    let udp = net_table(ctx, "udp")?;
+    udp.set(
+        "bind",
+        ctx.create_named_async_function(
+            "net.udp.bind",
+            |ctx, (addr, opts): (String, Option<Table>)| {
+                let recv_buffer = match opts {
+                    Some(opts) => opts.get::<_, Option<usize>>("recv_buffer"),
+                    None => Ok(None),
+                };
+                let spawner = ctx.spawner();
+                async move {
+                    let recv_buffer = recv_buffer?.unwrap_or(MAX_RECV_BUFFER);
+                    if recv_buffer == 0 || recv_buffer > MAX_RECV_BUFFER {
+                        return Err(Error::RuntimeError(format!(
+                            "the receive buffer of a UDP socket must be from 1 to {} bytes",
+                            MAX_RECV_BUFFER
+                        )));
+                    }
+                    blocking(spawner, "net", move || {
+                        let socket = net::UdpSocket::bind(addr.as_str())?;
+                        Ok(UdpSocket {
+                            local_addr: socket.local_addr()?,
+                            socket: Arc::new(std::sync::Mutex::new(Some(Arc::new(socket)))),
+                            receiving: Arc::new(Mutex::new(())),
+                            recv_buffer,
+                        })
+                    })
+                    .await
+                }
+            },
+        )?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{executor, future};
+    use rlua::Lua;
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn tcp_client() {
+        let server = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let echo = thread::spawn(move || {
+            let (mut socket, _) = server.accept().unwrap();
+            let mut received = [0; 12];
+            socket.read_exact(&mut received).unwrap();
+            socket.write_all(&received).unwrap();
+        });
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals().set("port", port).unwrap();
+            let echoed = executor::block_on(
+                lua.load(
+                    r#"
+                        local s = net.tcp.connect("127.0.0.1", port)
+                        assert(s:peer_addr() == "127.0.0.1:" .. port)
+                        s:write("hello, ")
+                        s:write("world")
+                        local parts, len = {}, 0
+                        while len < 12 do
+                            local part = s:read(5)
+                            parts[#parts + 1] = tostring(part)
+                            len = len + #part
+                        end
+                        local eof = s:read()
+                        s:close()
+                        return parts, eof == nil
+                    "#,
+                )
+                .call_async::<_, (Vec<String>, bool)>(lua, ()),
+            )
+            .expect("failed to talk to the echo server");
+            assert_eq!(echoed.0.iter().map(|p| p.len()).max(), Some(5));
+            assert_eq!(echoed.0.concat(), "hello, world");
+            assert!(echoed.1);
+        });
+        echo.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_sockets() {
+        let path = std::env::temp_dir().join(format!("rlua-async-net-{}", std::process::id()));
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("path", path.to_string_lossy().as_ref())
+                .unwrap();
+            let (message, server_peer, client_peer) = executor::block_on(
+                lua.load(
+                    r#"
+                        local listener = net.unix.listen(path)
+                        assert(listener:local_addr() == path)
+                        local client = net.unix.connect(path)
+                        local server, peer = listener:accept()
+                        client:write("hello")
+                        client:close()
+                        local message = tostring(server:read())
+                        assert(server:read() == nil)
+                        listener:close()
+                        return message, peer, client:peer_addr()
+                    "#,
+                )
+                .call_async::<_, (String, String, String)>(lua, ()),
+            )
+            .expect("failed to use the unix socket");
+            assert_eq!(message, "hello");
+            assert_eq!(server_peer, "");
+            assert_eq!(client_peer, path.to_string_lossy());
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resolve_names() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let (localhost, literal) = executor::block_on(
+                lua.load(
+                    r#"
+                        local localhost = {}
+                        for _, ip in ipairs(net.resolve("localhost")) do
+                            localhost[ip] = true
+                        end
+                        return localhost["127.0.0.1"] or localhost["::1"],
+                            table.concat(net.resolve("10.1.2.3"), ",")
+                    "#,
+                )
+                .call_async::<_, (bool, String)>(lua, ()),
+            )
+            .expect("failed to resolve");
+            assert!(localhost);
+            assert_eq!(literal, "10.1.2.3");
+        });
+    }
+
+    #[test]
+    fn udp_sockets() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let (request, reply) = executor::block_on(
+                lua.load(
+                    r#"
+                        server = net.udp.bind("127.0.0.1:0", { recv_buffer = 5 })
+                        local client = net.udp.bind("127.0.0.1:0")
+                        client:send_to("ping", server:local_addr())
+                        local request, from = server:recv_from()
+                        assert(from == client:local_addr())
+                        server:send_to(request .. "!", from)
+                        local reply = client:recv_from()
+                        client:close()
+                        return tostring(request), tostring(reply)
+                    "#,
+                )
+                .call_async::<_, (String, String)>(lua, ()),
+            )
+            .expect("failed to exchange datagrams");
+            assert_eq!((request.as_str(), reply.as_str()), ("ping", "ping!"));
+
+            for size in [0, 65536] {
+                let bind = format!(
+                    r#"net.udp.bind("127.0.0.1:0", {{ recv_buffer = {} }})"#,
+                    size
+                );
+                let err = executor::block_on(lua.load(&bind).exec_async(lua)).unwrap_err();
+                assert!(err.to_string().contains("from 1 to 65535"), "{}", err);
+            }
+
+            let closed = "server:close() server:recv_from()";
+            let err = executor::block_on(lua.load(closed).exec_async(lua)).unwrap_err();
+            assert!(err.to_string().contains("socket closed"), "{}", err);
+
+            let bind = lua
+                .load(r#"pending = net.udp.bind("0.0.0.0:0")"#)
+                .exec_async(lua);
+            executor::block_on(bind).unwrap();
+            let pending = lua.load("pending:recv_from()").exec_async(lua);
+            let closed = executor::block_on(future::join(pending, async {
+                futures_timer::Delay::new(Duration::from_millis(20)).await;
+                lua.load("pending:close()").exec()
+            }));
+            assert!(closed.0.unwrap_err().to_string().contains("socket closed"));
+            closed.1.unwrap();
+        });
+    }
+
+    #[test]
+    fn tcp_listener() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let addr = executor::block_on(
+                lua.load(
+                    r#"listener = net.tcp.listen("127.0.0.1:0") return listener:local_addr()"#,
+                )
+                .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to listen");
+
+            let client = thread::spawn(move || {
+                let mut socket = net::TcpStream::connect(addr).unwrap();
+                socket.write_all(b"ping").unwrap();
+                socket.shutdown(Shutdown::Write).unwrap();
+                let mut reply = String::new();
+                socket.read_to_string(&mut reply).unwrap();
+                reply
+            });
+            executor::block_on(
+                lua.load(
+                    r#"
+                        local s, peer = listener:accept()
+                        assert(peer == s:peer_addr())
+                        while true do
+                            local part = s:read()
+                            if not part then break end
+                            s:write(part)
+                        end
+                        s:write("!")
+                        s:close()
+                        listener:close()
+                    "#,
+                )
+                .exec_async(lua),
+            )
+            .expect("failed to accept");
+            assert_eq!(client.join().unwrap(), "ping!");
+
+            let res = executor::block_on(lua.load("listener:accept()").exec_async(lua));
+            assert!(res.unwrap_err().to_string().contains("listener closed"));
+
+            let bind = lua
+                .load(r#"pending = net.tcp.listen("0.0.0.0:0")"#)
+                .exec_async(lua);
+            executor::block_on(bind).unwrap();
+            let pending = lua.load("pending:accept()").exec_async(lua);
+            let closed = executor::block_on(future::join(pending, async {
+                futures_timer::Delay::new(Duration::from_millis(20)).await;
+                lua.load("pending:close()").exec()
+            }));
+            assert!(closed
+                .0
+                .unwrap_err()
+                .to_string()
+                .contains("listener closed"));
+            closed.1.unwrap();
+        });
+    }
+}
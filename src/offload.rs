@@ -0,0 +1,188 @@
+//! Running pure Lua functions on a pool of worker Lua states
+//!
+//! Lua states are single-threaded, so CPU-heavy Lua code blocks the executor driving the state
+//! it runs in. A [`WorkerPool`] owns a set of Lua states, each on its own thread, and runs the
+//! functions offloaded to it there. As the function runs in another Lua state, it can neither
+//! capture upvalues nor receive or return anything but plain data, see [`PlainValue`].
+
+use std::{
+    future::Future,
+    io,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use futures::channel::oneshot;
+use rlua::{Context, Error, Function, Lua, Result, Value, Variadic};
+
+use crate::{global_table, plain::PlainValue, ContextExt};
+
+struct Job {
+    source: Vec<u8>,
+    args: Vec<PlainValue>,
+    reply: oneshot::Sender<Result<Vec<PlainValue>>>,
+}
+
+fn run_job<'lua>(
+    ctx: Context<'lua>,
+    source: &[u8],
+    args: Vec<PlainValue>,
+) -> Result<Vec<PlainValue>> {
+    // Give each job its own globals, so that offloaded functions don't see each other's state
+    let env = ctx.create_table()?;
+    let env_meta = ctx.create_table()?;
+    env_meta.set("__index", ctx.globals())?;
+    env.set_metatable(Some(env_meta));
+    env.set("_G", env.clone())?;
+
+    let func = ctx
+        .load(source)
+        .set_name(b"offloaded function")?
+        .set_environment(env)?
+        .eval::<Function<'lua>>()?;
+    let res = func.call::<_, Variadic<Value<'lua>>>(args.into_iter().collect::<Variadic<_>>())?;
+    res.into_iter().map(PlainValue::from_lua_value).collect()
+}
+
+fn worker(jobs: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    let lua = Lua::new();
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            // The pool was dropped
+            Err(mpsc::RecvError) => return,
+        };
+        let Job {
+            source,
+            args,
+            reply,
+        } = job;
+        let res = lua.context(|ctx| run_job(ctx, &source, args));
+        // The caller may have given up waiting
+        let _ = reply.send(res);
+        lua.context(|ctx| ctx.expire_registry_values());
+    }
+}
+
+/// A pool of worker threads, each owning a Lua state
+///
+/// Cloning the pool gives another handle to the same workers. The worker threads stop once all
+/// the handles have been dropped.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: Arc<Mutex<mpsc::Sender<Job>>>,
+}
+
+impl WorkerPool {
+    /// Start a pool of `workers` threads
+    ///
+    /// This fails if `workers` is 0, or if a thread cannot be spawned, in which case the threads
+    /// already started stop.
+    pub fn new(workers: usize) -> io::Result<WorkerPool> {
+        if workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a worker pool needs at least one worker",
+            ));
+        }
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..workers {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("rlua-async worker {}", i))
+                .spawn(move || worker(rx))?;
+        }
+        Ok(WorkerPool {
+            jobs: Arc::new(Mutex::new(tx)),
+        })
+    }
+
+    /// Run a Lua function on one of the workers, and wait for its results
+    ///
+    /// `source` must be Lua source code evaluating to a function, eg.
+    /// `function(a, b) return a + b end`. Binary chunks, as generated by `string.dump`, are
+    /// refused by `rlua`.
+    ///
+    /// Each call gets its own globals, including `_G`, so the globals it sets are gone once it
+    /// returns. The standard library tables, eg. `string` or `math`, are still shared by all the
+    /// calls run by the same worker, so changes to their fields are seen by the later calls.
+    pub fn run(
+        &self,
+        source: &[u8],
+        args: Vec<PlainValue>,
+    ) -> impl Future<Output = Result<Vec<PlainValue>>> {
+        let (reply, res) = oneshot::channel();
+        let job = Job {
+            source: source.to_vec(),
+            args,
+            reply,
+        };
+        let sent = self.jobs.lock().unwrap().send(job);
+        async move {
+            sent.map_err(|_| Error::RuntimeError("the worker pool stopped".to_string()))?;
+            res.await
+                .map_err(|_| Error::RuntimeError("the worker stopped".to_string()))?
+        }
+    }
+
+    /// Create an async Lua function offloading to this pool, with signature
+    /// `offload(source, ...)`: `source` is run as per [`WorkerPool::run`], with the other
+    /// arguments, and the function returns its results.
+    pub fn create_offload_function<'lua>(&self, ctx: Context<'lua>) -> Result<Function<'lua>> {
+        let pool = self.clone();
+        ctx.create_named_async_function(
+            "offload",
+            move |_, (source, args): (rlua::String<'lua>, Variadic<Value<'lua>>)| {
+                let args = args
+                    .into_iter()
+                    .map(PlainValue::from_lua_value)
+                    .collect::<Result<Vec<_>>>();
+                let res = args.map(|args| pool.run(source.as_bytes(), args));
+                async move { Ok(res?.await?.into_iter().collect::<Variadic<_>>()) }
+            },
+        )
+    }
+
+    /// Install the offload function as `task.offload` in the globals, creating the `task` table
+    /// if need be
+    pub fn install<'lua>(&self, ctx: Context<'lua>) -> Result<()> {
+        global_table(ctx, "task")?.set("offload", self.create_offload_function(ctx)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn offload_to_worker() {
+        assert!(WorkerPool::new(0).is_err());
+        let pool = WorkerPool::new(2).unwrap();
+        Lua::new().context(|lua| {
+            pool.install(lua).unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local sum, t = task.offload(
+                            "function(a, b) _G.leaked = true; return a + b, { x = a } end",
+                            1,
+                            2
+                        )
+                        local ok = pcall(task.offload, "function() return print end")
+                        local leaked = task.offload("function() return leaked end")
+                        return sum, t.x, ok, leaked
+                    "#,
+                )
+                .call_async::<_, (usize, usize, bool, Option<bool>)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, (3, 1, false, None));
+        });
+    }
+}
@@ -0,0 +1,353 @@
+//! Replacing `print` and `io.write` with async writers
+//!
+//! The standard `print` and `io.write` write synchronously to the process' stdout, blocking the
+//! executor when it is a slow pipe. [`AsyncOutput`] instead routes them through a bounded buffer
+//! to an [`AsyncWrite`]: once the buffer is full, scripts wait for the writer to catch up,
+//! letting the other tasks run, instead of blocking or filling the memory.
+//!
+//! The process' stdout itself is blocking, so [`AsyncOutput::stdout`] writes to it on a
+//! dedicated thread, through a [`BlockingWriter`]. Along with the `io_async.read_line` of
+//! [`LineReader::stdin`](crate::input::LineReader::stdin), this lets interactive scripts run
+//! without ever blocking the executor on the terminal.
+
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    thread,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    lock::Mutex,
+    ready, FutureExt, Sink,
+};
+use rlua::{Context, Error, Function, Result, UserData, UserDataMethods, Value, Variadic};
+
+use crate::{buffer::buffer_or_string, global_table, ContextExt, UserDataMethodsExt};
+
+/// An async writer shared by the `print`, `io.write` and `io.flush` functions of Lua states
+///
+/// It can also be passed to Lua directly, where it has the async methods `out:write(data)`, that
+/// writes `data`, a string or a [`Buffer`](crate::buffer::Buffer), and `out:flush()`, as per
+/// [`AsyncOutput::write`] and [`AsyncOutput::flush`].
+///
+/// Cloning it gives another handle to the same writer and buffer.
+pub struct AsyncOutput<W> {
+    writer: Arc<Mutex<BufWriter<W>>>,
+}
+
+impl<W> Clone for AsyncOutput<W> {
+    fn clone(&self) -> Self {
+        AsyncOutput {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<W> AsyncOutput<W>
+where
+    W: 'static + Send + Unpin + AsyncWrite,
+{
+    /// Wrap `writer`, buffering at most `capacity` bytes of output before waiting for it
+    pub fn new(writer: W, capacity: usize) -> AsyncOutput<W> {
+        AsyncOutput {
+            writer: Arc::new(Mutex::new(BufWriter::with_capacity(capacity, writer))),
+        }
+    }
+
+    /// Write `data` to the buffer, waiting for the writer if the buffer is full
+    pub async fn write(&self, data: &[u8]) -> io::Result<()> {
+        self.writer.lock().await.write_all(data).await
+    }
+
+    /// Write everything that is still buffered to the writer, and flush it
+    pub async fn flush(&self) -> io::Result<()> {
+        self.writer.lock().await.flush().await
+    }
+
+    /// Replace `print`, `io.write` and `io.flush` with async functions writing to this output
+    ///
+    /// Like the standard ones, `print` converts its arguments with `tostring` and separates
+    /// them with tabs, and `io.write` accepts strings and numbers. Note that output is only
+    /// flushed to the writer when the buffer is full or when `io.flush` (or [`Self::flush`]) is
+    /// called.
+    pub fn install<'lua>(&self, ctx: Context<'lua>) -> Result<()> {
+        let output = self.clone();
+        let print =
+            ctx.create_named_async_function("print", move |ctx, args: Variadic<Value<'lua>>| {
+                let line = format_print(ctx, args);
+                let output = output.clone();
+                async move { output.write(&line?).await.map_err(Error::external) }
+            })?;
+        ctx.globals().set("print", print)?;
+
+        let io = global_table(ctx, "io")?;
+        let output = self.clone();
+        io.set(
+            "write",
+            ctx.create_named_async_function(
+                "io.write",
+                move |_, args: Variadic<rlua::String<'lua>>| {
+                    let mut data = Vec::new();
+                    for a in args.iter() {
+                        data.extend_from_slice(a.as_bytes());
+                    }
+                    let output = output.clone();
+                    async move { output.write(&data).await.map_err(Error::external) }
+                },
+            )?,
+        )?;
+
+        let output = self.clone();
+        io.set(
+            "flush",
+            ctx.create_named_async_function("io.flush", move |_, ()| {
+                let output = output.clone();
+                async move { output.flush().await.map_err(Error::external) }
+            })?,
+        )
+    }
+}
+
+impl<W> UserData for AsyncOutput<W>
+where
+    W: 'static + Send + Unpin + AsyncWrite,
+{
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("write", |ctx, this, data: Value| {
+            let data = buffer_or_string(ctx, data);
+            let output = this.clone();
+            async move { output.write(&data?).await.map_err(Error::external) }
+        });
+        methods.add_async_method("flush", |_, this, ()| {
+            let output = this.clone();
+            async move { output.flush().await.map_err(Error::external) }
+        });
+    }
+}
+
+impl AsyncOutput<BlockingWriter> {
+    /// Write to the process' stdout, buffering at most `capacity` bytes of output before
+    /// waiting for it, failing if the writer thread cannot be spawned
+    pub fn stdout(capacity: usize) -> io::Result<AsyncOutput<BlockingWriter>> {
+        Ok(AsyncOutput::new(
+            BlockingWriter::new(io::stdout())?,
+            capacity,
+        ))
+    }
+}
+
+enum Chunk {
+    Data(Vec<u8>),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+/// An [`AsyncWrite`] writing to a blocking [`Write`] on a dedicated thread
+///
+/// The data is handed over to the thread one write at a time: the writes wait for the thread to
+/// be done with the previous one. Write errors are reported by the next flush, after which the
+/// thread stops: what is written afterwards is lost, and the flushes fail.
+pub struct BlockingWriter {
+    chunks: mpsc::Sender<Chunk>,
+    flushed: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the writer thread stopped")
+}
+
+impl BlockingWriter {
+    /// Write to `writer` on a new thread, that stops once the `BlockingWriter` is dropped,
+    /// failing if the thread cannot be spawned
+    pub fn new<W: 'static + Send + Write>(mut writer: W) -> io::Result<BlockingWriter> {
+        let (tx, rx) = mpsc::channel(0);
+        thread::Builder::new()
+            .name("rlua-async writer".to_string())
+            .spawn(move || {
+                let mut res = Ok(());
+                for chunk in futures::executor::block_on_stream(rx) {
+                    match chunk {
+                        Chunk::Data(data) => {
+                            if res.is_ok() {
+                                res = writer.write_all(&data);
+                            }
+                        }
+                        Chunk::Flush(done) => {
+                            let failed = res.is_err();
+                            let _ = done.send(res.and_then(|()| writer.flush()));
+                            if failed {
+                                return;
+                            }
+                            res = Ok(());
+                        }
+                    }
+                }
+            })?;
+        Ok(BlockingWriter {
+            chunks: tx,
+            flushed: None,
+        })
+    }
+
+    /// Wait for the thread to take another chunk, and hand `chunk` over to it
+    fn poll_send(&mut self, fut_ctx: &mut task::Context, chunk: Chunk) -> Poll<io::Result<()>> {
+        let ready = ready!(Pin::new(&mut self.chunks).poll_ready(fut_ctx));
+        ready.map_err(|_| writer_stopped())?;
+        Pin::new(&mut self.chunks)
+            .start_send(chunk)
+            .map_err(|_| writer_stopped())?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BlockingWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        fut_ctx: &mut task::Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(fut_ctx, Chunk::Data(buf.to_vec())))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.flushed.is_none() {
+            let (done, flushed) = oneshot::channel();
+            ready!(this.poll_send(fut_ctx, Chunk::Flush(done)))?;
+            this.flushed = Some(flushed);
+        }
+        let res = ready!(this.flushed.as_mut().unwrap().poll_unpin(fut_ctx));
+        this.flushed = None;
+        Poll::Ready(res.unwrap_or_else(|_| Err(writer_stopped())))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.poll_flush(fut_ctx)
+    }
+}
+
+/// Format the arguments of `print` like the standard one does
+fn format_print<'lua>(ctx: Context<'lua>, args: Variadic<Value<'lua>>) -> Result<Vec<u8>> {
+    let tostring = ctx.globals().get::<_, Function>("tostring")?;
+    let mut line = Vec::new();
+    for (i, a) in args.into_iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(tostring.call::<_, rlua::String>(a)?.as_bytes());
+    }
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex as SyncMutex;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::ChunkExt;
+
+    /// A blocking writer appending to a shared vector
+    struct SharedVec(Arc<SyncMutex<Vec<u8>>>);
+
+    impl Write for SharedVec {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A blocking writer that always fails
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("failing writer"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A writer accepting at most one byte per write
+    #[derive(Clone, Default)]
+    struct SlowWriter(Arc<SyncMutex<Vec<u8>>>);
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut task::Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(1);
+            self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut task::Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut task::Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn blocking_writer() {
+        let written = Arc::new(SyncMutex::new(Vec::new()));
+        let w = written.clone();
+        let writer = BlockingWriter::new(SharedVec(w)).unwrap();
+        let output = AsyncOutput::new(writer, 4);
+        Lua::new().context(|lua| {
+            output.install(lua).unwrap();
+            executor::block_on(
+                lua.load(
+                    r#"print("hello") io.write("a", 2, "long enough to overflow") io.flush()"#,
+                )
+                .exec_async(lua),
+            )
+            .expect("failed to run");
+        });
+        assert_eq!(
+            &*written.lock().unwrap(),
+            b"hello\na2long enough to overflow"
+        );
+
+        let mut failing = BlockingWriter::new(FailingWriter).unwrap();
+        executor::block_on(failing.write_all(b"lost")).unwrap();
+        assert!(executor::block_on(failing.flush()).is_err());
+        let _ = executor::block_on(failing.write_all(b"more"));
+        let err = executor::block_on(failing.flush()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn async_print() {
+        let writer = SlowWriter::default();
+        let output = AsyncOutput::new(writer.clone(), 4);
+        Lua::new().context(|lua| {
+            output.install(lua).unwrap();
+            executor::block_on(
+                lua.load(r#"print("hello", 1, nil) io.write("a", 2, "\n") io.flush()"#)
+                    .exec_async(lua),
+            )
+            .expect("failed to run");
+        });
+        assert_eq!(&*writer.0.lock().unwrap(), b"hello\t1\tnil\na2\n");
+    }
+}
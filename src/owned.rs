@@ -0,0 +1,153 @@
+//! Owned handles to Lua values, that can be held by `'static` futures
+//!
+//! [`Function`], [`Table`] and [`Thread`] borrow the Lua state, so they cannot be captured by the
+//! `'static` futures of async functions, nor by the tasks spawned on an executor. The handles of
+//! this module store the values in the registry instead, and are resolved back with a
+//! [`Context`] of the same Lua state when they are used. Dropping a handle only queues its value
+//! for removal: the value stays in the registry until the next call to
+//! [`Context::expire_registry_values`].
+
+use rlua::{
+    Context, FromLua, FromLuaMulti, Function, MultiValue, RegistryKey, Result, Table, Thread,
+    ToLua, ToLuaMulti, Value,
+};
+
+use crate::{CallAsyncFuture, FunctionExt, TableExt};
+
+macro_rules! owned_handle {
+    ($(#[$meta:meta])* $name:ident, $ty:ident) => {
+        $(#[$meta])*
+        pub struct $name(RegistryKey);
+
+        impl $name {
+            /// Store `value` in the registry of its Lua state
+            pub fn new<'lua>(ctx: Context<'lua>, value: $ty<'lua>) -> Result<$name> {
+                ctx.create_registry_value(value).map($name)
+            }
+
+            #[doc = concat!("Get the [`", stringify!($ty), "`] back")]
+            ///
+            /// This fails with [`rlua::Error::MismatchedRegistryKey`] if `ctx` is not a context
+            /// of the Lua state the handle was created with.
+            pub fn get<'lua>(&self, ctx: Context<'lua>) -> Result<$ty<'lua>> {
+                ctx.registry_value(&self.0)
+            }
+        }
+
+        impl<'lua> FromLua<'lua> for $name {
+            fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<$name> {
+                $name::new(ctx, $ty::from_lua(value, ctx)?)
+            }
+        }
+
+        impl<'lua> ToLua<'lua> for $name {
+            fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+                self.get(ctx)?.to_lua(ctx)
+            }
+        }
+    };
+}
+
+owned_handle!(
+    /// An owned handle to a Lua function, see the [module documentation](self)
+    OwnedFunction,
+    Function
+);
+
+owned_handle!(
+    /// An owned handle to a Lua table, see the [module documentation](self)
+    OwnedTable,
+    Table
+);
+
+owned_handle!(
+    /// An owned handle to a Lua thread, see the [module documentation](self)
+    OwnedThread,
+    Thread
+);
+
+impl OwnedFunction {
+    /// Call the function in an async-compliant way, see [`FunctionExt::call_async`]
+    pub fn call_async<'lua, Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, Arg, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        match self.get(ctx) {
+            Ok(func) => func.call_async(ctx, args),
+            Err(e) => CallAsyncFuture::from_function(ctx, Err(e), args),
+        }
+    }
+}
+
+impl OwnedTable {
+    /// Call the method `name` of the table in an async-compliant way, see
+    /// [`TableExt::call_async_method`]
+    pub fn call_async_method<'lua, Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        match self.get(ctx) {
+            Ok(table) => table.call_async_method(ctx, name, args),
+            Err(e) => CallAsyncFuture::from_function(ctx, Err(e), MultiValue::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::ContextExt;
+
+    #[test]
+    fn owned_handles() {
+        Lua::new().context(|lua| {
+            lua.set_async_fn("sleep", |_, ms: u64| async move {
+                futures_timer::Delay::new(Duration::from_millis(ms)).await;
+            })
+            .unwrap();
+            let (callback, obj) = lua
+                .load(
+                    r#"
+                        return function(a) sleep(10) return a * 2 end,
+                            { n = 40, add = function(self, a) sleep(10) return self.n + a end }
+                    "#,
+                )
+                .eval::<(OwnedFunction, OwnedTable)>()
+                .unwrap();
+
+            let res = executor::block_on(callback.call_async::<_, usize>(lua, 21));
+            assert_eq!(res.expect("failed to call"), 42);
+            let res = executor::block_on(obj.call_async_method::<_, usize>(lua, "add", 2));
+            assert_eq!(res.expect("failed to call"), 42);
+            assert_eq!(obj.get(lua).unwrap().get::<_, usize>("n").unwrap(), 40);
+        });
+    }
+
+    #[test]
+    fn owned_handles_of_other_states() {
+        let callback = Lua::new().context(|lua| {
+            OwnedFunction::new(lua, lua.load("function() end").eval().unwrap()).unwrap()
+        });
+        Lua::new().context(|lua| {
+            let err = executor::block_on(callback.call_async::<_, ()>(lua, ())).unwrap_err();
+            assert!(matches!(err, rlua::Error::MismatchedRegistryKey), "{}", err);
+        });
+    }
+}
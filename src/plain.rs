@@ -0,0 +1,97 @@
+//! Lua values that can be moved between Lua states
+//!
+//! Lua values are bound to the state they were created in. A [`PlainValue`] holds a copy of
+//! the data of a Lua value instead, so that it can be sent to another thread or Lua state, eg.
+//! through the [`channel`](crate::channel)s or to a [`WorkerPool`](crate::offload::WorkerPool).
+//! Only plain data can be copied this way, not functions, userdata or threads.
+
+use rlua::{Context, Error, Result, ToLua, Value};
+
+/// The maximum nesting of tables in a [`PlainValue`], that also protects against cycles
+const MAX_PLAIN_DEPTH: usize = 64;
+
+/// A Lua value that does not reference any Lua state, and can thus be moved between states
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlainValue {
+    /// `nil`
+    Nil,
+    /// A boolean
+    Boolean(bool),
+    /// An integer, Lua 5.3 keeping integers and floats apart
+    Integer(rlua::Integer),
+    /// A float
+    Number(rlua::Number),
+    /// The bytes of a string, that need not be UTF-8
+    String(Vec<u8>),
+    /// The key-value pairs of a table, in no particular order
+    Table(Vec<(PlainValue, PlainValue)>),
+}
+
+impl PlainValue {
+    /// Convert a Lua value into a plain value, failing if it is or contains a function,
+    /// userdata or thread, or if its tables are nested too deep
+    pub fn from_lua_value(value: Value) -> Result<PlainValue> {
+        PlainValue::from_lua_value_at(value, 0)
+    }
+
+    fn from_lua_value_at(value: Value, depth: usize) -> Result<PlainValue> {
+        Ok(match value {
+            Value::Nil => PlainValue::Nil,
+            Value::Boolean(b) => PlainValue::Boolean(b),
+            Value::Integer(i) => PlainValue::Integer(i),
+            Value::Number(n) => PlainValue::Number(n),
+            Value::String(s) => PlainValue::String(s.as_bytes().to_vec()),
+            Value::Table(t) => {
+                if depth >= MAX_PLAIN_DEPTH {
+                    return Err(Error::RuntimeError(
+                        "tables are nested too deep to be moved to another Lua state".to_string(),
+                    ));
+                }
+                let mut pairs = Vec::new();
+                for pair in t.pairs::<Value, Value>() {
+                    let (k, v) = pair?;
+                    pairs.push((
+                        PlainValue::from_lua_value_at(k, depth + 1)?,
+                        PlainValue::from_lua_value_at(v, depth + 1)?,
+                    ));
+                }
+                PlainValue::Table(pairs)
+            }
+            Value::LightUserData(_) | Value::UserData(_) => {
+                return Err(Error::RuntimeError(
+                    "a userdata cannot be moved to another Lua state".to_string(),
+                ))
+            }
+            Value::Function(_) => {
+                return Err(Error::RuntimeError(
+                    "a function cannot be moved to another Lua state".to_string(),
+                ))
+            }
+            Value::Thread(_) => {
+                return Err(Error::RuntimeError(
+                    "a thread cannot be moved to another Lua state".to_string(),
+                ))
+            }
+            Value::Error(e) => return Err(e),
+        })
+    }
+}
+
+impl<'lua> ToLua<'lua> for PlainValue {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        Ok(match self {
+            PlainValue::Nil => Value::Nil,
+            PlainValue::Boolean(b) => Value::Boolean(b),
+            PlainValue::Integer(i) => Value::Integer(i),
+            PlainValue::Number(n) => Value::Number(n),
+            PlainValue::String(s) => Value::String(ctx.create_string(&s)?),
+            PlainValue::Table(pairs) => {
+                let t = ctx.create_table()?;
+                for (k, v) in pairs {
+                    t.raw_set(k, v)?;
+                }
+                Value::Table(t)
+            }
+        })
+    }
+}
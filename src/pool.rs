@@ -0,0 +1,240 @@
+//! A pool of pre-initialized Lua states
+//!
+//! Creating a Lua state and loading the scripts of an application into it is usually too slow to
+//! be done for every request of a service, and a single state cannot serve several requests at
+//! once. [`LuaPool`] keeps the states around between uses instead, creating them on demand up to
+//! a maximum number, and making [`LuaPool::get`] wait for one to be given back past it:
+//!
+//! ```
+//! # use rlua_async::pool::LuaPool;
+//! # async fn run(request: String) -> rlua::Result<()> {
+//! let pool = LuaPool::builder()
+//!     .max_size(8)
+//!     .init(|ctx| ctx.load("function handle(req) return 'hello ' .. req end").exec())
+//!     .build();
+//!
+//! let lua = pool.get().await?;
+//! let res: String = lua.call_async(|ctx| ctx.globals().get("handle"), request).await?;
+//! # assert_eq!(res, "hello world");
+//! # Ok(())
+//! # }
+//! # futures::executor::block_on(run("world".to_string())).unwrap();
+//! ```
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use rlua::{Context, Lua, Result};
+
+use crate::{
+    semaphore::{Permit, Semaphore},
+    AsyncLua,
+};
+
+type Init = Box<dyn Fn(Context) -> Result<()> + Send + Sync>;
+
+struct Inner {
+    idle: Mutex<Vec<AsyncLua>>,
+    limit: Semaphore,
+    max_size: usize,
+    new_lua: Box<dyn Fn() -> Lua + Send + Sync>,
+    init: Vec<Init>,
+}
+
+/// A pool of Lua states, see the [module documentation](self)
+///
+/// Cloning the pool gives another handle to the same states.
+#[derive(Clone)]
+pub struct LuaPool(Arc<Inner>);
+
+impl LuaPool {
+    /// Create a pool of at most `max_size` states, made with [`Lua::new`] and not initialized
+    pub fn new(max_size: usize) -> LuaPool {
+        LuaPool::builder().max_size(max_size).build()
+    }
+
+    /// Start building a pool
+    pub fn builder() -> LuaPoolBuilder {
+        LuaPoolBuilder {
+            max_size: 16,
+            new_lua: Box::new(Lua::new),
+            init: Vec::new(),
+        }
+    }
+
+    /// Check a state out of the pool, creating it if none is idle
+    ///
+    /// This waits for another state to be given back if `max_size` states are already checked
+    /// out, the callers being served in order. This fails if an init hook of a new state fails,
+    /// in which case the state is dropped.
+    pub async fn get(&self) -> Result<PooledLua> {
+        let permit = self.0.limit.acquire().await;
+        let idle = self.0.idle.lock().unwrap().pop();
+        let lua = match idle {
+            Some(lua) => lua,
+            None => {
+                let lua = AsyncLua::from_lua((self.0.new_lua)());
+                lua.context(|ctx| self.0.init.iter().try_for_each(|init| init(ctx)))?;
+                lua
+            }
+        };
+        Ok(PooledLua {
+            lua: Some(lua),
+            pool: self.0.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// The maximum number of states of the pool
+    pub fn max_size(&self) -> usize {
+        self.0.max_size
+    }
+
+    /// The number of states currently idle in the pool
+    pub fn idle(&self) -> usize {
+        self.0.idle.lock().unwrap().len()
+    }
+}
+
+impl fmt::Debug for LuaPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LuaPool")
+            .field("max_size", &self.max_size())
+            .field("idle", &self.idle())
+            .finish()
+    }
+}
+
+/// A builder for [`LuaPool`]s, see [`LuaPool::builder`]
+#[must_use = "the pool is only created by `build`"]
+pub struct LuaPoolBuilder {
+    max_size: usize,
+    new_lua: Box<dyn Fn() -> Lua + Send + Sync>,
+    init: Vec<Init>,
+}
+
+impl LuaPoolBuilder {
+    /// Set the maximum number of states of the pool, 16 by default
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_size` is 0.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        assert!(max_size > 0, "a Lua pool needs room for at least one state");
+        self.max_size = max_size;
+        self
+    }
+
+    /// Create the states with `new_lua` instead of [`Lua::new`], eg. to only load some of the
+    /// standard libraries
+    pub fn new_lua<F>(mut self, new_lua: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn() -> Lua,
+    {
+        self.new_lua = Box::new(new_lua);
+        self
+    }
+
+    /// Run `init` on each new state, after the hooks added before it, eg. to register async
+    /// functions or load scripts
+    pub fn init<F>(mut self, init: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(Context) -> Result<()>,
+    {
+        self.init.push(Box::new(init));
+        self
+    }
+
+    /// Create the pool. The states are only created when they are first needed.
+    pub fn build(self) -> LuaPool {
+        LuaPool(Arc::new(Inner {
+            idle: Mutex::new(Vec::with_capacity(self.max_size)),
+            limit: Semaphore::new(self.max_size),
+            max_size: self.max_size,
+            new_lua: self.new_lua,
+            init: self.init,
+        }))
+    }
+}
+
+/// A state checked out of a [`LuaPool`], given back to it when dropped
+pub struct PooledLua {
+    lua: Option<AsyncLua>,
+    pool: Arc<Inner>,
+    _permit: Permit,
+}
+
+impl PooledLua {
+    /// Take the state out of the pool for good, eg. because a script left it in a bad state.
+    /// The pool creates a new state in its place when needed.
+    pub fn detach(mut self) -> AsyncLua {
+        self.lua.take().expect("pooled Lua state already taken")
+    }
+}
+
+impl Deref for PooledLua {
+    type Target = AsyncLua;
+
+    fn deref(&self) -> &AsyncLua {
+        self.lua.as_ref().expect("pooled Lua state already taken")
+    }
+}
+
+impl Drop for PooledLua {
+    fn drop(&mut self) {
+        if let Some(lua) = self.lua.take() {
+            self.pool.idle.lock().unwrap().push(lua);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use futures::{executor, FutureExt};
+
+    use crate::ContextExt;
+
+    #[test]
+    fn pooled_states() {
+        let inits = Arc::new(AtomicUsize::new(0));
+        let i = inits.clone();
+        let pool = LuaPool::builder()
+            .max_size(1)
+            .init(move |ctx| {
+                i.fetch_add(1, Ordering::SeqCst);
+                ctx.set_async_fn("sleep", |_, ms: u64| {
+                    futures_timer::Delay::new(Duration::from_millis(ms))
+                })
+            })
+            .init(|ctx| ctx.load("calls = 0").exec())
+            .build();
+
+        executor::block_on(async {
+            let first = pool.get().await.expect("failed to get a state");
+            let mut second = pool.get().boxed();
+            assert!((&mut second).now_or_never().is_none());
+            first
+                .exec_async("sleep(10) calls = calls + 1")
+                .await
+                .expect("failed to exec");
+            drop(first);
+
+            let second = second.await.expect("failed to get a state");
+            let calls: usize = second.eval_async("calls").await.expect("failed to eval");
+            assert_eq!(calls, 1);
+            assert_eq!(pool.idle(), 0);
+        });
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(inits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,7 @@
+function(source)
+    -- Run the module like the ones found by the searchers, with its chunk name as second argument
+    return function(name)
+        local loader, chunk_name = source()
+        return loader(name, chunk_name)
+    end
+end
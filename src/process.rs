@@ -0,0 +1,188 @@
+//! Spawning child processes from Lua scripts
+//!
+//! The output of the children is read on dedicated threads, and exposed to Lua as async line
+//! iterators, see [`LineReader`].
+
+use std::{
+    io::{self, BufReader},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use rlua::{Context, Error, Result, Table};
+
+use crate::{global_table, input::LineReader, ContextExt};
+
+/// Converted for Lua to the exit code, or `nil` and `"signal"` if the child was killed by a
+/// signal
+fn exit_status(status: ExitStatus) -> (Option<i32>, Option<&'static str>) {
+    match status.code() {
+        Some(code) => (Some(code), None),
+        None => (None, Some("signal")),
+    }
+}
+
+/// Wait for `child` to exit, blocking
+///
+/// On Unix, this first waits for the exit without reaping the child, and without holding the
+/// lock, so that the child can still be killed meanwhile, and only locks it to reap it once it
+/// exited. Elsewhere, the lock is held for the whole wait.
+fn wait(child: &Mutex<Child>) -> io::Result<ExitStatus> {
+    #[cfg(unix)]
+    {
+        let pid = child.lock().unwrap().id() as libc::id_t;
+        loop {
+            // Safety: `info` is only written by `waitid`, and a zeroed `siginfo_t` is valid
+            let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+            let flags = libc::WEXITED | libc::WNOWAIT;
+            if unsafe { libc::waitid(libc::P_PID, pid, &mut info, flags) } == 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Already reaped by another `wait`, whose status `Child::wait` returns
+                Some(libc::ECHILD) => break,
+                _ => return Err(e),
+            }
+        }
+    }
+    child.lock().unwrap().wait()
+}
+
+/// Create the Lua handle of a spawned child
+fn child_handle<'lua>(ctx: Context<'lua>, mut child: Child) -> Result<Table<'lua>> {
+    let handle = ctx.create_table()?;
+    handle.set("pid", child.id())?;
+    let stdout = child
+        .stdout
+        .take()
+        .map(|o| LineReader::new(BufReader::new(o)));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|e| LineReader::new(BufReader::new(e)));
+    let (stdout, stderr) = match (stdout.transpose(), stderr.transpose()) {
+        (Ok(stdout), Ok(stderr)) => (stdout, stderr),
+        (Err(e), _) | (_, Err(e)) => {
+            // Without a handle, nothing could wait for the child any more
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::external(e));
+        }
+    };
+    for (name, reader) in [("stdout", stdout), ("stderr", stderr)] {
+        if let Some(reader) = reader {
+            let stream = ctx.create_table()?;
+            reader.set_functions(ctx, &stream, &format!("process {} read_line", name))?;
+            handle.set(name, stream)?;
+        }
+    }
+
+    let child = Arc::new(Mutex::new(child));
+    let c = child.clone();
+    handle.set(
+        "wait",
+        ctx.create_named_async_function("process wait", move |ctx, _: rlua::MultiValue| {
+            let c = c.clone();
+            let status = crate::blocking(ctx.spawner(), "process wait", move || wait(&c));
+            async move { Ok(exit_status(status.await?)) }
+        })?,
+    )?;
+    handle.set(
+        "kill",
+        ctx.create_function(move |_, _: rlua::MultiValue| {
+            child.lock().unwrap().kill().map_err(Error::external)
+        })?,
+    )?;
+    Ok(handle)
+}
+
+/// Install `process.spawn(program, args)` in the globals, creating the `process` table if need
+/// be
+///
+/// `process.spawn` starts `program` with the arguments in the optional `args` list, with no
+/// stdin and piped stdout and stderr, and returns a handle with:
+///  * `pid`, the process id of the child,
+///  * `stdout` and `stderr`, with the async methods `read_line()`, returning the next line or
+///    `nil` at the end of the output, and `lines()`, returning an iterator over the lines, for
+///    use in `for` loops: `for line in child.stdout:lines() do ... end`,
+///  * `wait()`, an async function returning the exit code of the child, or `nil` and
+///    `"signal"` if it was killed by a signal,
+///  * `kill()`, that kills the child, waking the pending `wait()`s.
+///
+/// Note that the child is not killed when its handle is garbage-collected.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let process = global_table(ctx, "process")?;
+    process.set(
+        "spawn",
+        ctx.create_function(|ctx, (program, args): (String, Option<Vec<String>>)| {
+            let child = Command::new(program)
+                .args(args.unwrap_or_default())
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(Error::external)?;
+            child_handle(ctx, child)
+        })?,
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use std::future::Future;
+
+    use crate::{ChunkExt, FunctionExt};
+
+    #[test]
+    fn child_output_lines() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let (out, err, code) = executor::block_on(
+                lua.load(
+                    r#"
+                        local child = process.spawn("sh", { "-c", "echo a; echo b; echo c >&2; exit 3" })
+                        local out = {}
+                        for line in child.stdout:lines() do
+                            out[#out + 1] = line
+                        end
+                        return table.concat(out, ","), child.stderr:read_line(), child:wait()
+                    "#,
+                )
+                .call_async::<_, (String, String, i32)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!((out.as_str(), err.as_str(), code), ("a,b", "c", 3));
+        });
+    }
+
+    #[test]
+    fn kill_while_waiting() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            let child = lua
+                .load(r#"return process.spawn("sleep", { "60" })"#)
+                .eval::<Table>()
+                .unwrap();
+            let wait = child
+                .get::<_, rlua::Function>("wait")
+                .unwrap()
+                .call_async::<_, (Option<i32>, Option<String>)>(lua, child.clone());
+            futures::pin_mut!(wait);
+            assert!(executor::block_on(futures::future::poll_fn(|cx| {
+                std::task::Poll::Ready(wait.as_mut().poll(cx).is_pending())
+            })));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let kill = child.get::<_, rlua::Function>("kill").unwrap();
+            kill.call::<_, ()>(child.clone()).unwrap();
+            let status = executor::block_on(wait).expect("failed to wait");
+            assert_eq!(status, (None, Some("signal".to_string())));
+        });
+    }
+}
@@ -0,0 +1,185 @@
+//! Driving async Lua calls from custom event loops
+//!
+//! Game engines and GUI frameworks usually come with their own event loop, and no
+//! [`futures`] executor. [`ManualCall`] wraps the future returned by eg.
+//! [`FunctionExt::call_async`](crate::FunctionExt::call_async) so that it can be polled
+//! directly from such a loop, while [`LuaWakerHandle`] tells the loop when polling again is
+//! worth it.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{self, Poll, Waker},
+};
+
+use futures::task::{waker, ArcWake};
+use rlua::Result;
+
+#[derive(Default)]
+struct WakeState {
+    woken: AtomicBool,
+    notifier: Mutex<Option<Arc<dyn Fn() + Send + Sync>>>,
+}
+
+impl ArcWake for WakeState {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        // Called without the lock, as the notifier may replace itself or wake this call again
+        let notifier = arc_self.notifier.lock().unwrap().clone();
+        if let Some(notifier) = notifier {
+            notifier();
+        }
+    }
+}
+
+/// A handle allowing to both wake a [`ManualCall`] and check whether it has been woken
+#[derive(Clone)]
+pub struct LuaWakerHandle(Arc<WakeState>);
+
+impl LuaWakerHandle {
+    /// Mark the call as needing to be polled again. This is what the [`Waker`]s handed to the
+    /// futures of the call do, but it can also be called by the host itself, eg. from its own
+    /// event sources.
+    pub fn wake(&self) {
+        ArcWake::wake_by_ref(&self.0)
+    }
+
+    /// Check whether the call has been woken since it was last polled
+    pub fn is_woken(&self) -> bool {
+        self.0.woken.load(Ordering::SeqCst)
+    }
+
+    /// Get a [`Waker`] that wakes this call
+    pub fn waker(&self) -> Waker {
+        waker(self.0.clone())
+    }
+}
+
+impl fmt::Debug for LuaWakerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LuaWakerHandle")
+            .field("woken", &self.is_woken())
+            .finish()
+    }
+}
+
+/// An async call that can be polled without an executor
+///
+/// The call starts woken, so that the host knows it must poll it at least once.
+pub struct ManualCall<'fut, Ret> {
+    fut: Pin<Box<dyn 'fut + Future<Output = Result<Ret>>>>,
+    handle: LuaWakerHandle,
+    waker: Waker,
+}
+
+impl<'fut, Ret> ManualCall<'fut, Ret> {
+    /// Wrap `fut` for manual polling
+    pub fn new<Fut>(fut: Fut) -> ManualCall<'fut, Ret>
+    where
+        Fut: 'fut + Future<Output = Result<Ret>>,
+    {
+        let handle = LuaWakerHandle(Arc::new(WakeState::default()));
+        handle.0.woken.store(true, Ordering::SeqCst);
+        let waker = handle.waker();
+        ManualCall {
+            fut: Box::pin(fut),
+            handle,
+            waker,
+        }
+    }
+
+    /// Set a function that will be called each time the call is woken, eg. to post an event to
+    /// the host's event loop
+    ///
+    /// Note that this function can be called from any thread, and while the call is being
+    /// polled.
+    pub fn set_notifier<N>(&self, notifier: N)
+    where
+        N: 'static + Send + Sync + Fn(),
+    {
+        *self.handle.0.notifier.lock().unwrap() = Some(Arc::new(notifier));
+    }
+
+    /// Get a handle to wake this call, or check whether it has been woken
+    pub fn waker_handle(&self) -> LuaWakerHandle {
+        self.handle.clone()
+    }
+
+    /// Check whether the call has been woken since it was last polled
+    pub fn is_woken(&self) -> bool {
+        self.handle.is_woken()
+    }
+
+    /// Poll the call once, resetting its woken state
+    pub fn poll(&mut self) -> Poll<Result<Ret>> {
+        self.handle.0.woken.store(false, Ordering::SeqCst);
+        let mut fut_ctx = task::Context::from_waker(&self.waker);
+        self.fut.as_mut().poll(&mut fut_ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use futures::channel::oneshot;
+    use rlua::{Function, Lua};
+
+    use crate::{ContextExt, FunctionExt};
+
+    #[test]
+    fn manual_polling() {
+        Lua::new().context(|lua| {
+            let (tx, rx) = oneshot::channel::<usize>();
+            let rx = Mutex::new(Some(rx));
+            let f = lua
+                .create_async_function(move |_, ()| {
+                    let rx = rx.lock().unwrap().take().expect("called twice");
+                    async move { Ok(rx.await.expect("sender dropped") + 1) }
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let mut call = ManualCall::new(
+                lua.load(r#"function() return f() end"#)
+                    .eval::<Function>()
+                    .unwrap()
+                    .call_async::<_, usize>(lua, ()),
+            );
+            let notified = Arc::new(AtomicUsize::new(0));
+            let notified_clone = notified.clone();
+            let handle = call.waker_handle();
+            let rewake = handle.clone();
+            call.set_notifier(move || {
+                // Waking the call from its notifier calls the notifier again
+                if notified_clone.fetch_add(1, Ordering::SeqCst) == 0 {
+                    rewake.wake();
+                }
+            });
+
+            assert!(call.is_woken());
+            assert!(call.poll().is_pending());
+            assert!(!call.is_woken());
+
+            handle.wake();
+            assert!(call.is_woken());
+            assert!(call.poll().is_pending());
+            assert!(!call.is_woken());
+
+            tx.send(41).unwrap();
+            assert!(call.is_woken());
+            assert_eq!(notified.load(Ordering::SeqCst), 3);
+            match call.poll() {
+                Poll::Ready(Ok(42)) => {}
+                r => panic!("improper result: {:?}", r),
+            }
+        });
+    }
+}
@@ -0,0 +1,39 @@
+use rlua::{Context, FromLua, RegistryKey, Result, ToLua, Value};
+
+/// A Lua value stored in the registry, that can be held by `'static + Send` futures
+///
+/// Declaring an argument of an async function as a `RegistryValue` stores it in the registry
+/// before the future is built, so that eg. a table or a callback can be held across `await`s. It
+/// can then be resolved back with [`RegistryValue::get`], eg. from the `then` callback of
+/// [`ContextExt::create_async_function_with_registry`](crate::ContextExt), or returned to Lua as
+/// is, which also removes it from the registry.
+///
+/// Dropping a `RegistryValue` otherwise only queues the value for removal: it stays in the
+/// registry until the next call to [`Context::expire_registry_values`].
+pub struct RegistryValue(pub(crate) RegistryKey);
+
+impl RegistryValue {
+    /// Store `value` in the registry
+    pub fn new<'lua>(ctx: Context<'lua>, value: Value<'lua>) -> Result<RegistryValue> {
+        ctx.create_registry_value(value).map(RegistryValue)
+    }
+
+    /// Get the stored value, converted to `T`
+    pub fn get<'lua, T: FromLua<'lua>>(&self, ctx: Context<'lua>) -> Result<T> {
+        ctx.registry_value(&self.0)
+    }
+}
+
+impl<'lua> FromLua<'lua> for RegistryValue {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<RegistryValue> {
+        RegistryValue::new(ctx, value)
+    }
+}
+
+impl<'lua> ToLua<'lua> for RegistryValue {
+    fn to_lua(self, ctx: Context<'lua>) -> Result<Value<'lua>> {
+        let value = ctx.registry_value(&self.0)?;
+        ctx.remove_registry_value(self.0)?;
+        Ok(value)
+    }
+}
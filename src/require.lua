@@ -0,0 +1,93 @@
+function(package, new_event)
+    local loaded = package.loaded
+    -- The modules being loaded, with the thread loading each and the event it sets once done,
+    -- that the concurrent requires of the same module wait on
+    local loading = {}
+    -- The thread each load runs in, to the thread that required the module
+    local parents = setmetatable({}, { __mode = "k" })
+
+    -- Whether `thread` runs the load of `name`, directly or in the loads it runs itself
+    local function is_loading(thread, name)
+        while thread do
+            if thread == loading[name].thread then
+                return true
+            end
+            thread = parents[thread]
+        end
+        return false
+    end
+
+    -- Resume `co` until it is done, like `pcall` does, but passing its yields on: the `pcall`
+    -- of rlua cannot yield, so it could not wait for async functions
+    local function protect(co, ok, ...)
+        if not ok or coroutine.status(co) == "dead" then
+            return ok, ...
+        end
+        return protect(co, coroutine.resume(co, coroutine.yield(...)))
+    end
+
+    -- Find and run the loader of `name`, returning its module, or `nil` and the error message
+    -- if there is none
+    local function load(name)
+        local searchers = package.searchers
+        if type(searchers) ~= "table" then
+            return nil, "'package.searchers' must be a table"
+        end
+        local messages = {}
+        local loader, data
+        for _, searcher in ipairs(searchers) do
+            local found, extra = searcher(name)
+            if type(found) == "function" then
+                loader, data = found, extra
+                break
+            elseif type(found) == "string" then
+                messages[#messages + 1] = found
+            end
+        end
+        if not loader then
+            return nil, "module '" .. name .. "' not found:" .. table.concat(messages)
+        end
+
+        local module = loader(name, data)
+        if module ~= nil then
+            loaded[name] = module
+        end
+        if loaded[name] == nil then
+            loaded[name] = true
+        end
+        return loaded[name]
+    end
+
+    -- The `require` of Lua 5.3, except that it is written in Lua, so that the searchers and
+    -- loaders it calls can wait on async functions
+    return function(name)
+        if type(name) ~= "string" and type(name) ~= "number" then
+            error("bad argument #1 to 'require' (string expected, got " .. type(name) .. ")", 2)
+        end
+        name = tostring(name)
+        -- Wait for the loads by other threads, and load the module again if they failed
+        while loading[name] and not loaded[name] do
+            if is_loading(coroutine.running(), name) then
+                error("loop loading module '" .. name .. "'", 2)
+            end
+            loading[name].done:wait()
+        end
+        if loaded[name] then
+            return loaded[name]
+        end
+
+        local co = coroutine.create(load)
+        parents[co] = coroutine.running()
+        local current = { thread = co, done = new_event() }
+        loading[name] = current
+        local ok, module, message = protect(co, coroutine.resume(co, name))
+        loading[name] = nil
+        current.done:set()
+        if not ok then
+            error(module, 0)
+        elseif module == nil then
+            error(message, 2)
+        end
+        return module
+    end
+end
@@ -0,0 +1,298 @@
+use std::future::Future;
+
+use futures::FutureExt;
+
+use rlua::{Context, Error, Function, MultiValue, Result, Table, ToLuaMulti};
+
+use crate::{channel::Event, ContextExt, IntoLuaResult};
+
+static REQUIRE: &[u8] = include_bytes!("require.lua");
+
+static PRELOAD: &[u8] = include_bytes!("preload.lua");
+
+static REQUIRE_REGISTRY_KEY: &str = "rlua-async require";
+
+/// The Lua source of a module, found by a searcher added with
+/// [`ContextExt::add_async_searcher`]
+#[derive(Clone, Debug)]
+pub struct ModuleSource {
+    chunk_name: String,
+    source: Vec<u8>,
+}
+
+impl ModuleSource {
+    /// A module made of the Lua code `source`, compiled with the chunk name `chunk_name`
+    ///
+    /// Like the file names for the modules found in `package.path`, the chunk name is passed to
+    /// the module as its second argument, after the name of the module.
+    pub fn new<N: Into<String>, S: Into<Vec<u8>>>(chunk_name: N, source: S) -> ModuleSource {
+        ModuleSource {
+            chunk_name: chunk_name.into(),
+            source: source.into(),
+        }
+    }
+}
+
+/// The result of an async searcher, converted to the return values Lua expects of searchers
+struct Searched {
+    name: String,
+    found: Option<ModuleSource>,
+}
+
+impl<'lua> ToLuaMulti<'lua> for Searched {
+    fn to_lua_multi(self, ctx: Context<'lua>) -> Result<MultiValue<'lua>> {
+        let Searched { name, found } = self;
+        let module = match found {
+            None => return format!("\n\tno async module '{}'", name).to_lua_multi(ctx),
+            Some(module) => module,
+        };
+        let loader = ctx
+            .load(&module.source)
+            .set_name(&module.chunk_name)?
+            .into_function()
+            .map_err(|e| {
+                Error::RuntimeError(format!(
+                    "error loading module '{}' from '{}':\n\t{}",
+                    name, module.chunk_name, e
+                ))
+            })?;
+        (loader, module.chunk_name).to_lua_multi(ctx)
+    }
+}
+
+/// Replace the `require` global by one written in Lua, if not done already, and return the
+/// `package` table
+fn install_require<'lua>(ctx: Context<'lua>) -> Result<Table<'lua>> {
+    let package = ctx.globals().get::<_, Table>("package")?;
+    if ctx
+        .named_registry_value::<_, Option<Function>>(REQUIRE_REGISTRY_KEY)?
+        .is_none()
+    {
+        let new_event = ctx.create_function(|_, ()| Ok(Event::new()))?;
+        let require = ctx
+            .load(REQUIRE)
+            .set_name(b"rlua-async require")?
+            .eval::<Function<'lua>>()?
+            .call::<_, Function<'lua>>((package.clone(), new_event))?;
+        ctx.set_named_registry_value(REQUIRE_REGISTRY_KEY, require.clone())?;
+        ctx.globals().set("require", require)?;
+    }
+    Ok(package)
+}
+
+pub(crate) fn add_searcher<'lua, RetFut, F>(ctx: Context<'lua>, searcher: F) -> Result<()>
+where
+    RetFut: 'static + Send + Future,
+    RetFut::Output: IntoLuaResult<Option<ModuleSource>>,
+    F: 'static + Send + Fn(Context<'lua>, String) -> RetFut,
+{
+    let searchers = install_require(ctx)?.get::<_, Table>("searchers")?;
+    let searcher = ctx.create_named_async_function("require", move |ctx, name: String| {
+        let found = searcher(ctx, name.clone());
+        async move {
+            Ok(Searched {
+                name,
+                found: found.await.into_lua_result()?,
+            })
+        }
+    })?;
+    searchers.set(searchers.raw_len() + 1, searcher)
+}
+
+pub(crate) fn preload<'lua, RetFut>(ctx: Context<'lua>, name: &str, source: RetFut) -> Result<()>
+where
+    RetFut: 'static + Send + Future,
+    RetFut::Output: IntoLuaResult<ModuleSource>,
+{
+    let preload = install_require(ctx)?.get::<_, Table>("preload")?;
+    // Shared, so that the requires racing with or following a failed load do not poll it again
+    let source = source.map(IntoLuaResult::into_lua_result).boxed().shared();
+    let module = name.to_string();
+    let source = ctx.create_named_async_function("require", move |_, ()| {
+        let (module, source) = (module.clone(), source.clone());
+        async move {
+            Ok(Searched {
+                name: module,
+                found: Some(source.await?),
+            })
+        }
+    })?;
+    let loader = ctx
+        .load(PRELOAD)
+        .set_name(b"rlua-async preload")?
+        .eval::<Function<'lua>>()?
+        .call::<_, Function<'lua>>(source)?;
+    preload.set(name, loader)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::{executor, future};
+    use rlua::Lua;
+
+    use super::*;
+    use crate::ChunkExt;
+
+    #[test]
+    fn async_searcher() {
+        Lua::new().context(|lua| {
+            let searches = Arc::new(AtomicUsize::new(0));
+            let s = searches.clone();
+            lua.add_async_searcher(move |_, name: String| {
+                s.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(match name.as_str() {
+                        "greet" => Some(ModuleSource::new(
+                            "db:greet",
+                            r#"
+                                local name, origin = ...
+                                pause()
+                                return {
+                                    hello = function(who) return "hello " .. who end,
+                                    origin = name .. " from " .. origin,
+                                }
+                            "#,
+                        )),
+                        "broken" => Some(ModuleSource::new("db:broken", "return {")),
+                        _ => None,
+                    })
+                }
+            })
+            .unwrap();
+            lua.globals()
+                .set(
+                    "pause",
+                    lua.create_async_function(|_, ()| {
+                        futures_timer::Delay::new(Duration::from_millis(10))
+                    })
+                    .unwrap(),
+                )
+                .unwrap();
+
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local greet = require("greet")
+                        assert(require("greet") == greet)
+                        return greet.hello("world"), greet.origin
+                    "#,
+                )
+                .call_async::<_, (String, String)>(lua, ()),
+            )
+            .expect("failed to require");
+            assert_eq!(
+                res,
+                ("hello world".to_string(), "greet from db:greet".to_string())
+            );
+            assert_eq!(searches.load(Ordering::SeqCst), 1);
+
+            let missing = lua.load(r#"require("missing")"#).exec_async(lua);
+            let err = executor::block_on(missing).unwrap_err().to_string();
+            assert!(err.contains("module 'missing' not found:"), "{}", err);
+            assert!(
+                err.contains("no field package.preload['missing']"),
+                "{}",
+                err
+            );
+            assert!(err.contains("no async module 'missing'"), "{}", err);
+
+            let broken = lua.load(r#"require("broken")"#).exec_async(lua);
+            let err = executor::block_on(broken).unwrap_err().to_string();
+            assert!(
+                err.contains("error loading module 'broken' from 'db:broken'"),
+                "{}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    fn concurrent_requires() {
+        Lua::new().context(|lua| {
+            let searches = Arc::new(AtomicUsize::new(0));
+            let s = searches.clone();
+            lua.add_async_searcher(move |_, _| {
+                s.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(Some(ModuleSource::new("=slow", "return {}")))
+                }
+            })
+            .unwrap();
+            let requiring = lua
+                .load("function() modules = (modules or 0) + 1 return require('slow') end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            let first = lua.spawn(requiring.clone(), ()).unwrap();
+            let second = lua.spawn(requiring, ()).unwrap();
+            executor::block_on(lua.run_spawned()).expect("failed to require");
+            executor::block_on(future::try_join(first, second)).expect("failed to require");
+            assert_eq!(searches.load(Ordering::SeqCst), 1);
+            assert_eq!(lua.globals().get::<_, usize>("modules").unwrap(), 2);
+
+            let cycle = ModuleSource::new("=cycle", "return require('cycle')");
+            lua.preload_async("cycle", future::ready(cycle)).unwrap();
+            let err = lua.load("require('cycle')").exec_async(lua);
+            let err = executor::block_on(err).unwrap_err().to_string();
+            assert!(err.contains("loop loading module 'cycle'"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn async_preload() {
+        Lua::new().context(|lua| {
+            let started = Arc::new(AtomicUsize::new(0));
+            let s = started.clone();
+            lua.preload_async("config", async move {
+                s.fetch_add(1, Ordering::SeqCst);
+                futures_timer::Delay::new(Duration::from_millis(10)).await;
+                ModuleSource::new("=decrypted", "return { key = ... }")
+            })
+            .unwrap();
+            lua.preload_async("failing", async {
+                Err::<ModuleSource, _>(Error::RuntimeError("download failed".to_string()))
+            })
+            .unwrap();
+            assert_eq!(started.load(Ordering::SeqCst), 0);
+
+            let same = executor::block_on(
+                lua.load(r#"return require("config") == require("config"), require("config").key"#)
+                    .call_async::<_, (bool, String)>(lua, ()),
+            )
+            .expect("failed to require");
+            assert_eq!(same, (true, "config".to_string()));
+            assert_eq!(started.load(Ordering::SeqCst), 1);
+
+            let failing = lua.load(r#"require("failing")"#).exec_async(lua);
+            let err = executor::block_on(failing).unwrap_err().to_string();
+            assert!(err.contains("download failed"), "{}", err);
+
+            let s = started.clone();
+            lua.preload_async("counted", async move {
+                s.fetch_add(1, Ordering::SeqCst);
+                futures_timer::Delay::new(Duration::from_millis(10)).await;
+                ModuleSource::new("=counted", "runs = (runs or 0) + 1 return {}")
+            })
+            .unwrap();
+            let requiring = lua
+                .load("function() return require('counted') end")
+                .eval::<rlua::Function>()
+                .unwrap();
+            let first = lua.spawn(requiring.clone(), ()).unwrap();
+            let second = lua.spawn(requiring, ()).unwrap();
+            executor::block_on(lua.run_spawned()).expect("failed to require");
+            executor::block_on(future::try_join(first, second)).expect("failed to require");
+            assert_eq!(started.load(Ordering::SeqCst), 2);
+            assert_eq!(lua.globals().get::<_, usize>("runs").unwrap(), 1);
+        });
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll, Waker},
+};
+
+struct State {
+    permits: usize,
+    /// The ids of the acquirers waiting for permits, in arrival order, with the number of
+    /// permits they wait for
+    waiters: VecDeque<(u64, usize, Waker)>,
+    next_id: u64,
+}
+
+impl State {
+    fn wake_front(&self) {
+        if let Some((_, n, waker)) = self.waiters.front() {
+            if self.permits >= *n {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// A fair async semaphore: permits are handed out in the order they were asked for
+#[derive(Clone)]
+pub(crate) struct Semaphore(Arc<Mutex<State>>);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Semaphore {
+        Semaphore(Arc::new(Mutex::new(State {
+            permits,
+            waiters: VecDeque::new(),
+            next_id: 0,
+        })))
+    }
+
+    /// Wait for a permit, that is given back when the returned guard is dropped
+    pub(crate) fn acquire(&self) -> Acquire {
+        self.acquire_many(1)
+    }
+
+    /// Wait for `n` permits at once, that are given back when the returned guard is dropped
+    pub(crate) fn acquire_many(&self, n: usize) -> Acquire {
+        Acquire {
+            sem: self.clone(),
+            id: None,
+            n,
+        }
+    }
+}
+
+/// The future returned by [`Semaphore::acquire`]
+pub(crate) struct Acquire {
+    sem: Semaphore,
+    /// Our id in the waiters queue, if we are in it
+    id: Option<u64>,
+    n: usize,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Permit> {
+        let this = self.get_mut();
+        let mut state = this.sem.0.lock().unwrap();
+        let first = match this.id {
+            None => state.waiters.is_empty(),
+            Some(id) => state.waiters.front().map(|(i, _, _)| *i) == Some(id),
+        };
+        if first && state.permits >= this.n {
+            state.permits -= this.n;
+            if this.id.take().is_some() {
+                state.waiters.pop_front();
+            }
+            state.wake_front();
+            return Poll::Ready(Permit(this.sem.clone(), this.n));
+        }
+        match this.id {
+            Some(id) => {
+                let waiter = state.waiters.iter_mut().find(|(i, _, _)| *i == id);
+                waiter.expect("waiter left the queue").2 = fut_ctx.waker().clone();
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state
+                    .waiters
+                    .push_back((id, this.n, fut_ctx.waker().clone()));
+                this.id = Some(id);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let mut state = self.sem.0.lock().unwrap();
+            state.waiters.retain(|(i, _, _)| *i != id);
+            // We may have been woken to take a permit, pass it on
+            state.wake_front();
+        }
+    }
+}
+
+/// Permits of a [`Semaphore`], given back on drop
+pub(crate) struct Permit(Semaphore, usize);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = (self.0).0.lock().unwrap();
+        state.permits += self.1;
+        state.wake_front();
+    }
+}
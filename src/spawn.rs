@@ -0,0 +1,447 @@
+use std::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{self, Poll, Waker},
+};
+
+use futures::task::{waker_ref, ArcWake};
+use rlua::{
+    AnyUserData, Context, Error, Function, MultiValue, RegistryKey, Result, Thread, ThreadStatus,
+    ToLuaMulti, UserData,
+};
+
+use crate::ThreadExt;
+
+static TASKS_REGISTRY_KEY: &str = "rlua-async spawned tasks";
+
+#[derive(Default)]
+struct TaskState {
+    result: Mutex<Option<Result<()>>>,
+    /// The return values of the call, once it returned
+    values: Mutex<Vec<RegistryKey>>,
+    wakers: Mutex<Vec<Waker>>,
+    cancelled: AtomicBool,
+    /// Whether the call must be resumed the next time the tasks are driven
+    woken: AtomicBool,
+    /// The waker of the future driving the tasks, shared by all the tasks
+    driver: Arc<Mutex<Option<Waker>>>,
+}
+
+impl TaskState {
+    /// Set the result of the call, unless it is already done
+    fn finish(&self, result: Result<()>) {
+        let mut res = self.result.lock().unwrap();
+        if res.is_none() {
+            *res = Some(result);
+            for waker in self.wakers.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The waker of the futures a task waits on, that marks the task to be resumed
+impl ArcWake for TaskState {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        if let Some(driver) = &*arc_self.driver.lock().unwrap() {
+            driver.wake_by_ref();
+        }
+    }
+}
+
+struct Task {
+    thread: RegistryKey,
+    /// The arguments of the call, until it is first resumed
+    args: Option<Vec<RegistryKey>>,
+    state: Arc<TaskState>,
+}
+
+#[derive(Default)]
+struct Tasks {
+    tasks: Vec<Task>,
+    /// The waker of the future driving the tasks, woken when a task is spawned or woken
+    driver: Arc<Mutex<Option<Waker>>>,
+}
+
+impl UserData for Tasks {}
+
+fn tasks(ctx: Context) -> Result<AnyUserData> {
+    if let Some(ud) = ctx.named_registry_value::<_, Option<AnyUserData>>(TASKS_REGISTRY_KEY)? {
+        return Ok(ud);
+    }
+    let ud = ctx.create_userdata(Tasks::default())?;
+    ctx.set_named_registry_value(TASKS_REGISTRY_KEY, ud.clone())?;
+    Ok(ud)
+}
+
+/// A handle to a call started with [`ContextExt::spawn`](crate::ContextExt::spawn)
+///
+/// The handle can be sent to other threads, and is a future that completes once the call is
+/// done, with the error of the call if it failed. The return values of the call are kept in the
+/// Lua state until the last handle is dropped, for `task:join()` of the `async` Lua library.
+/// Dropping the handle does not stop the call, use [`TaskHandle::cancel`] for that.
+#[derive(Clone)]
+pub struct TaskHandle(Arc<TaskState>);
+
+impl TaskHandle {
+    /// Whether the call is done, either because it returned, it failed or it was cancelled
+    pub fn is_finished(&self) -> bool {
+        self.0.result.lock().unwrap().is_some()
+    }
+
+    /// Stop the call. It completes with an error right away, and its thread is let go of the
+    /// next time the spawned tasks are driven: the futures it was waiting on are dropped once
+    /// the thread is garbage-collected.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        let cancelled = Error::RuntimeError("the spawned task was cancelled".to_string());
+        self.0.finish(Err(cancelled));
+        ArcWake::wake_by_ref(&self.0);
+    }
+}
+
+impl Future for TaskHandle {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<()>> {
+        // The result is locked first, like in `TaskState::finish`, so that the call cannot finish
+        // between the check and the waker registration
+        match &*self.0.result.lock().unwrap() {
+            Some(res) => Poll::Ready(res.clone()),
+            None => {
+                let mut wakers = self.0.wakers.lock().unwrap();
+                if !wakers.iter().any(|w| w.will_wake(fut_ctx.waker())) {
+                    wakers.push(fut_ctx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub(crate) fn spawn<'lua, Arg>(
+    ctx: Context<'lua>,
+    func: Function<'lua>,
+    args: Arg,
+) -> Result<TaskHandle>
+where
+    Arg: ToLuaMulti<'lua>,
+{
+    let thread = ctx.create_registry_value(ctx.create_thread(func)?)?;
+    let args = args
+        .to_lua_multi(ctx)?
+        .into_iter()
+        .map(|v| ctx.create_registry_value(v))
+        .collect::<Result<Vec<_>>>()?;
+    let ud = tasks(ctx)?;
+    let mut tasks = ud.borrow_mut::<Tasks>()?;
+    let state = Arc::new(TaskState {
+        woken: AtomicBool::new(true),
+        driver: tasks.driver.clone(),
+        ..TaskState::default()
+    });
+    tasks.tasks.push(Task {
+        thread,
+        args: Some(args),
+        state: state.clone(),
+    });
+    drop(tasks);
+    ArcWake::wake_by_ref(&state);
+    Ok(TaskHandle(state))
+}
+
+/// Resume `task` once, returning its return values if it is done
+fn poll_task<'lua>(ctx: Context<'lua>, task: &mut Task) -> Result<Option<MultiValue<'lua>>> {
+    let waker = waker_ref(&task.state);
+    let fut_ctx = &mut task::Context::from_waker(&waker);
+    let thread = ctx.registry_value::<Thread>(&task.thread)?;
+    let args = match task.args.take() {
+        Some(keys) => keys
+            .iter()
+            .map(|k| ctx.registry_value(k))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let mut resume = thread.resume_async::<_, MultiValue>(ctx, MultiValue::from_vec(args));
+    match Pin::new(&mut resume).poll(fut_ctx) {
+        Poll::Pending => Ok(None),
+        Poll::Ready(res) => {
+            let values = res?;
+            if thread.status() == ThreadStatus::Resumable {
+                // The task yielded by itself, let the other ones run before resuming it
+                fut_ctx.waker().wake_by_ref();
+                return Ok(None);
+            }
+            Ok(Some(values))
+        }
+    }
+}
+
+/// Drive the spawned tasks, resuming the ones that were woken, and return whether none is left
+pub(crate) fn poll_tasks(ctx: Context, fut_ctx: &mut task::Context) -> Result<bool> {
+    let ud = tasks(ctx)?;
+    set_driver(&ud.borrow::<Tasks>()?.driver, fut_ctx.waker());
+    // The tasks are taken out while they run, as they can spawn new tasks
+    let running = mem::take(&mut ud.borrow_mut::<Tasks>()?.tasks);
+    let mut kept = Vec::with_capacity(running.len());
+    for mut task in running {
+        if task.state.cancelled.load(Ordering::SeqCst) {
+            // Already finished by `TaskHandle::cancel`. The thread is removed from the registry
+            // right away, rather than on the next `expire_registry_values`, so that its futures
+            // are dropped as soon as it is garbage-collected. Failing to do so only concerns this
+            // task, the others still have to be run or kept.
+            if let Err(e) = ctx.remove_registry_value(task.thread) {
+                task.state.finish(Err(e));
+            }
+            continue;
+        }
+        if !task.state.woken.swap(false, Ordering::SeqCst) {
+            kept.push(task);
+            continue;
+        }
+        let res = poll_task(ctx, &mut task).and_then(|values| match values {
+            None => Ok(false),
+            Some(values) => {
+                let keys = values
+                    .into_iter()
+                    .map(|v| ctx.create_registry_value(v))
+                    .collect::<Result<Vec<_>>>()?;
+                *task.state.values.lock().unwrap() = keys;
+                Ok(true)
+            }
+        });
+        match res {
+            Ok(false) => kept.push(task),
+            Ok(true) => task.state.finish(Ok(())),
+            Err(e) => task.state.finish(Err(e)),
+        }
+    }
+
+    let mut tasks = ud.borrow_mut::<Tasks>()?;
+    kept.append(&mut tasks.tasks);
+    tasks.tasks = kept;
+    // The tasks run meanwhile may have driven the tasks they spawned themselves
+    set_driver(&tasks.driver, fut_ctx.waker());
+    Ok(tasks.tasks.is_empty())
+}
+
+fn set_driver(driver: &Mutex<Option<Waker>>, waker: &Waker) {
+    let mut driver = driver.lock().unwrap();
+    if !driver.as_ref().is_some_and(|d| d.will_wake(waker)) {
+        *driver = Some(waker.clone());
+    }
+}
+
+/// Drive the spawned tasks if there are any, without creating their registry otherwise
+pub(crate) fn drive_tasks(ctx: Context, fut_ctx: &mut task::Context) -> Result<()> {
+    if ctx
+        .named_registry_value::<_, Option<AnyUserData>>(TASKS_REGISTRY_KEY)?
+        .is_some()
+    {
+        poll_tasks(ctx, fut_ctx)?;
+    }
+    Ok(())
+}
+
+impl TaskHandle {
+    /// Poll the call for the Lua code, that needs its return values
+    pub(crate) fn poll_values<'lua>(
+        &self,
+        ctx: Context<'lua>,
+        fut_ctx: &mut task::Context,
+    ) -> Poll<Result<MultiValue<'lua>>> {
+        match Pin::new(&mut self.clone()).poll(fut_ctx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(res) => Poll::Ready(res.and_then(|()| {
+                let values = self.0.values.lock().unwrap();
+                let values = values.iter().map(|k| ctx.registry_value(k));
+                Ok(MultiValue::from_vec(values.collect::<Result<_>>()?))
+            })),
+        }
+    }
+}
+
+#[cfg(feature = "async-lib")]
+impl TaskHandle {
+    /// Whether the call returned rather than failed or was cancelled, once it is done
+    pub(crate) fn succeeded(&self) -> Option<bool> {
+        self.0.result.lock().unwrap().as_ref().map(Result::is_ok)
+    }
+}
+
+/// Create a polling function for the trampoline of the async functions, that drives the
+/// spawned tasks before calling `poll`, eg. to wait for some of them
+pub(crate) fn poller<'lua, F>(ctx: Context<'lua>, mut poll: F) -> Result<Function<'lua>>
+where
+    F: 'static + Send + FnMut(Context<'lua>, &mut task::Context) -> Poll<Result<MultiValue<'lua>>>,
+{
+    ctx.create_function_mut(move |ctx, _: MultiValue| {
+        if !crate::FUTURE_CTX.is_set() {
+            // Not called from an async call, there is nothing to wait with
+            return (false, true).to_lua_multi(ctx);
+        }
+        crate::FUTURE_CTX.with(|fut_ctx| {
+            // Safety: See comment on FUTURE_CTX
+            let fut_ctx = unsafe { &mut *(*fut_ctx as *mut task::Context) };
+            poll_tasks(ctx, fut_ctx)?;
+            match poll(ctx, fut_ctx) {
+                Poll::Pending => (false, false).to_lua_multi(ctx),
+                Poll::Ready(values) => (true, false, values?).to_lua_multi(ctx),
+            }
+        })
+    })
+}
+
+/// The future returned by [`ContextExt::run_spawned`](crate::ContextExt::run_spawned)
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct RunSpawned<'lua> {
+    ctx: Context<'lua>,
+}
+
+impl<'lua> RunSpawned<'lua> {
+    pub(crate) fn new(ctx: Context<'lua>) -> RunSpawned<'lua> {
+        RunSpawned { ctx }
+    }
+}
+
+impl<'lua> Future for RunSpawned<'lua> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Result<()>> {
+        match poll_tasks(self.ctx, fut_ctx) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{Poll, Waker},
+        time::Duration,
+    };
+
+    use futures::{executor, future};
+    use rlua::Lua;
+
+    use crate::{AsyncLua, ContextExt};
+
+    #[test]
+    fn spawned_tasks() {
+        Lua::new().context(|lua| {
+            lua.set_async_fn("sleep", |_, ms: u64| {
+                futures_timer::Delay::new(Duration::from_millis(ms))
+            })
+            .unwrap();
+            let handler = lua
+                .load(
+                    r#"
+                        done = {}
+                        return function(a, ms)
+                            sleep(ms)
+                            if a == nil then error("no argument") end
+                            table.insert(done, a)
+                        end
+                    "#,
+                )
+                .eval::<rlua::Function>()
+                .unwrap();
+
+            let slow = lua.spawn(handler.clone(), (1, 30)).unwrap();
+            let fast = lua.spawn(handler.clone(), (2, 10)).unwrap();
+            let failing = lua.spawn(handler.clone(), (rlua::Nil, 10)).unwrap();
+            let cancelled = lua.spawn(handler, (3, 10)).unwrap();
+            cancelled.cancel();
+            assert!(!slow.is_finished());
+
+            executor::block_on(lua.run_spawned()).expect("failed to drive the tasks");
+            assert!(slow.is_finished() && fast.is_finished());
+            let results = executor::block_on(future::join4(slow, fast, failing, cancelled));
+            assert!(results.0.is_ok() && results.1.is_ok());
+            let err = results.2.unwrap_err();
+            assert!(err.to_string().contains("no argument"), "{}", err);
+            assert!(results.3.is_err());
+            let done = lua.load(r#"table.concat(done, ",")"#).eval::<String>();
+            assert_eq!(done.unwrap(), "2,1");
+        });
+    }
+
+    #[test]
+    fn woken_tasks() {
+        Lua::new().context(|lua| {
+            lua.set_async_fn("sleep", |_, ms: u64| {
+                futures_timer::Delay::new(Duration::from_millis(ms))
+            })
+            .unwrap();
+            let (open, polls) = (
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(AtomicUsize::new(0)),
+            );
+            let waiter = Arc::new(Mutex::new(None::<Waker>));
+            let (o, p, w) = (open.clone(), polls.clone(), waiter.clone());
+            lua.set_async_fn("gate", move |_, ()| {
+                let (o, p, w) = (o.clone(), p.clone(), w.clone());
+                future::poll_fn(move |fut_ctx| {
+                    p.fetch_add(1, Ordering::SeqCst);
+                    if o.load(Ordering::SeqCst) {
+                        return Poll::Ready(());
+                    }
+                    *w.lock().unwrap() = Some(fut_ctx.waker().clone());
+                    Poll::Pending
+                })
+            })
+            .unwrap();
+            lua.set_async_fn("open", move |_, ()| {
+                open.store(true, Ordering::SeqCst);
+                waiter.lock().unwrap().take().unwrap().wake();
+                future::ready(())
+            })
+            .unwrap();
+
+            let waiting = lua.load("function() gate() end").eval().unwrap();
+            let waiting = lua.spawn(waiting, ()).unwrap();
+            let opener = lua.load("function() for _ = 1, 5 do sleep(1) end open() end");
+            lua.spawn(opener.eval().unwrap(), ()).unwrap();
+            executor::block_on(lua.run_spawned()).expect("failed to drive the tasks");
+            assert!(waiting.is_finished());
+            // Not resumed while the other task sleeps
+            assert_eq!(polls.load(Ordering::SeqCst), 2);
+
+            // Cancelling the task completes its handle, without the tasks being driven
+            let never = lua.load("function() sleep(60000) end").eval().unwrap();
+            let never = lua.spawn(never, ()).unwrap();
+            let res = executor::block_on(future::join(never.clone(), async { never.cancel() }));
+            assert!(res.0.is_err());
+        });
+    }
+
+    #[test]
+    fn spawned_tasks_of_async_lua() {
+        let lua = AsyncLua::new();
+        let task = lua.context(|ctx| {
+            ctx.set_async_fn("sleep", |_, ms: u64| {
+                futures_timer::Delay::new(Duration::from_millis(ms))
+            })
+            .unwrap();
+            let f = ctx.load("function() sleep(10) done = true end").eval();
+            ctx.spawn(f.unwrap(), ()).unwrap()
+        });
+
+        executor::block_on(future::join(lua.run_spawned(), task))
+            .1
+            .unwrap();
+        assert!(lua.context(|ctx| ctx.globals().get::<_, bool>("done").unwrap()));
+    }
+}
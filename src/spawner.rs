@@ -0,0 +1,191 @@
+//! Running the background futures and timers of the crate on the embedder's executor
+//!
+//! The crate does not depend on any async runtime by default. The few places that need to spawn futures, to
+//! run blocking code or to wait for some time, eg. [`AsyncCall::timeout`](crate::AsyncCall::timeout) or the `sleep`
+//! of [`combinators`](crate::combinators), go through the [`Spawner`] of the Lua state instead,
+//! that embedders can replace with [`ContextExt::set_spawner`](crate::ContextExt::set_spawner)
+//! to use the executor and timers of their runtime. With the `tokio` feature, the `TokioSpawner`
+//! of the `tokio` module does it for tokio. Other runtimes only need a few lines, eg. to hand
+//! the futures over to an executor fed by a channel:
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use futures::{channel::mpsc, future::BoxFuture, task::SpawnError};
+//! # use rlua::Lua;
+//! # use rlua_async::{spawner::Spawner, ContextExt};
+//! struct Queue(mpsc::UnboundedSender<BoxFuture<'static, ()>>);
+//!
+//! impl Spawner for Queue {
+//!     fn spawn(&self, fut: BoxFuture<'static, ()>) -> Result<(), SpawnError> {
+//!         self.0.unbounded_send(fut).map_err(|_| SpawnError::shutdown())
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+//!         Box::pin(futures_timer::Delay::new(duration))
+//!     }
+//! }
+//!
+//! # let (tx, _rx) = mpsc::unbounded();
+//! # Lua::new().context(|ctx| -> rlua::Result<()> {
+//! ctx.set_spawner(Queue(tx))?;
+//! # Ok(())
+//! # }).unwrap();
+//! ```
+//!
+//! Without one, [`DefaultSpawner`] is used.
+
+use std::{future::Future, sync::Arc, thread, time::Duration};
+
+use futures::{
+    future::{self, BoxFuture, Either, LocalBoxFuture},
+    task::SpawnError,
+};
+use futures_timer::Delay;
+use rlua::{Context, Result};
+
+use crate::{app_data, TimeoutError};
+
+/// An executor and timer, see the [module documentation](self)
+pub trait Spawner: 'static + Send + Sync {
+    /// Run `fut` to completion in the background
+    fn spawn(&self, fut: BoxFuture<'static, ()>) -> std::result::Result<(), SpawnError>;
+
+    /// Run `fut`, that is not `Send`, to completion in the background on the current thread.
+    ///
+    /// The default implementation fails, for executors that have no local tasks.
+    fn spawn_local(&self, fut: LocalBoxFuture<'static, ()>) -> std::result::Result<(), SpawnError> {
+        drop(fut);
+        Err(SpawnError::shutdown())
+    }
+
+    /// A future that completes after `duration`
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Run the blocking `f` where it does not stall the executor, eg. on a thread pool.
+    ///
+    /// The default implementation runs it on a new thread.
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> std::result::Result<(), SpawnError> {
+        thread::Builder::new()
+            .name("rlua-async blocking".to_string())
+            .spawn(f)
+            .map(drop)
+            .map_err(|_| SpawnError::shutdown())
+    }
+}
+
+impl dyn Spawner {
+    /// Run `fut`, failing if it does not complete within `duration`
+    pub fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        fut: F,
+    ) -> impl Future<Output = std::result::Result<F::Output, TimeoutError>> {
+        let sleep = self.sleep(duration);
+        async move {
+            futures::pin_mut!(fut);
+            match future::select(fut, sleep).await {
+                Either::Left((res, _)) => Ok(res),
+                Either::Right(((), _)) => Err(TimeoutError::new(None, duration)),
+            }
+        }
+    }
+}
+
+/// The spawner used when the embedder selected none
+///
+/// It runs each spawned future and blocking function on a new thread, and uses the timers of
+/// [`futures_timer`]. Local futures cannot be spawned.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultSpawner;
+
+impl Spawner for DefaultSpawner {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) -> std::result::Result<(), SpawnError> {
+        thread::Builder::new()
+            .name("rlua-async task".to_string())
+            .spawn(move || futures::executor::block_on(fut))
+            .map(drop)
+            .map_err(|_| SpawnError::shutdown())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(Delay::new(duration))
+    }
+}
+
+/// The spawner of a Lua state, stored as app data
+struct Selected(Arc<dyn Spawner>);
+
+pub(crate) fn set<S: Spawner>(ctx: Context, spawner: S) -> Result<()> {
+    app_data::set(ctx, Selected(Arc::new(spawner))).map(drop)
+}
+
+pub(crate) fn get(ctx: Context) -> Result<Arc<dyn Spawner>> {
+    match app_data::get::<Selected>(ctx)? {
+        Some(selected) => Ok(selected.0.clone()),
+        None => Ok(Arc::new(DefaultSpawner)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{channel::oneshot, executor};
+    use rlua::Lua;
+
+    use crate::{ContextExt, FunctionExt};
+
+    #[derive(Default)]
+    struct CountingSpawner(AtomicUsize);
+
+    impl Spawner for Arc<CountingSpawner> {
+        fn spawn(&self, fut: BoxFuture<'static, ()>) -> std::result::Result<(), SpawnError> {
+            DefaultSpawner.spawn(fut)
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            DefaultSpawner.sleep(duration)
+        }
+    }
+
+    #[test]
+    fn default_spawner() {
+        let spawner: Arc<dyn Spawner> = Arc::new(DefaultSpawner);
+        let (tx, rx) = oneshot::channel();
+        spawner
+            .spawn(Box::pin(async move {
+                let _ = tx.send(42);
+            }))
+            .unwrap();
+        assert_eq!(executor::block_on(rx), Ok(42));
+        assert!(spawner.spawn_local(Box::pin(future::ready(()))).is_err());
+
+        let res =
+            executor::block_on(spawner.timeout(Duration::from_millis(10), future::pending::<()>()));
+        assert_eq!(res.unwrap_err().timeout(), Duration::from_millis(10));
+        let res = executor::block_on(spawner.timeout(Duration::from_secs(10), future::ready(1)));
+        assert_eq!(res.unwrap(), 1);
+    }
+
+    #[test]
+    fn selected_spawner() {
+        Lua::new().context(|lua| {
+            let spawner = Arc::new(CountingSpawner::default());
+            lua.set_spawner(spawner.clone()).unwrap();
+            lua.set_async_fn("never", |_, ()| future::pending::<()>())
+                .unwrap();
+
+            let f = lua.load("never()").into_function().unwrap();
+            let res = executor::block_on(async {
+                f.async_call::<()>(lua)
+                    .timeout(Duration::from_millis(10))
+                    .await
+            });
+            assert!(res.is_err());
+            assert_eq!(spawner.0.load(Ordering::SeqCst), 1);
+        });
+    }
+}
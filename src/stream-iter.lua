@@ -0,0 +1,8 @@
+function(stream)
+    -- The iterator function of a generic `for`, that calls `stream:next()` without the control
+    -- variable
+    local next = stream.next
+    return function()
+        return next(stream)
+    end
+end
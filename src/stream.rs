@@ -0,0 +1,338 @@
+//! Consuming Lua coroutines as [`Stream`]s, and Rust [`Stream`]s and [`Sink`]s from Lua
+
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+};
+
+use futures::{
+    lock::Mutex,
+    stream::{BoxStream, Fuse},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use rlua::{
+    AnyUserData, Context, Error, FromLuaMulti, Function, MultiValue, Result, Thread, ThreadStatus,
+    ToLuaMulti, UserData, UserDataMethods, Value,
+};
+
+use crate::{depth::DepthGuard, is_pending_yield, IntoLuaResult, UserDataMethodsExt, FUTURE_CTX};
+
+static STREAM_ITER: &[u8] = include_bytes!("stream-iter.lua");
+static STREAM_ITER_REGISTRY_KEY: &str = "rlua-async stream iter";
+
+/// Values that can be turned into a Lua [`Thread`]
+pub trait IntoLuaThread<'lua> {
+    /// Perform the conversion, creating a new thread if need be
+    fn into_lua_thread(self, ctx: Context<'lua>) -> Result<Thread<'lua>>;
+}
+
+impl<'lua> IntoLuaThread<'lua> for Thread<'lua> {
+    fn into_lua_thread(self, _: Context<'lua>) -> Result<Thread<'lua>> {
+        Ok(self)
+    }
+}
+
+impl<'lua> IntoLuaThread<'lua> for Function<'lua> {
+    fn into_lua_thread(self, ctx: Context<'lua>) -> Result<Thread<'lua>> {
+        ctx.create_thread(self)
+    }
+}
+
+/// Adapt a Lua generator, ie. a coroutine that `coroutine.yield`s its values, into a [`Stream`]
+///
+/// Each value (or set of values) yielded by the coroutine becomes an item of the stream, and
+/// the stream terminates when the coroutine returns. Its return values are ignored. The
+/// coroutine can also call async functions, eg. created with
+/// [`ContextExt::create_async_function`](crate::ContextExt::create_async_function): the yields
+/// they generate are not seen as items.
+///
+/// If the coroutine raises an error, it is returned as the last item of the stream.
+pub fn coroutine_to_stream<'lua, T, C>(
+    ctx: Context<'lua>,
+    coroutine: C,
+) -> Result<CoroutineStream<'lua, T>>
+where
+    T: FromLuaMulti<'lua>,
+    C: IntoLuaThread<'lua>,
+{
+    Ok(CoroutineStream::new(ctx, coroutine.into_lua_thread(ctx)?))
+}
+
+/// The stream returned by [`coroutine_to_stream`]
+pub struct CoroutineStream<'lua, T> {
+    ctx: Context<'lua>,
+    thread: Thread<'lua>,
+    done: bool,
+    _phantom: PhantomData<T>,
+}
+
+// The stream is never structurally pinned
+impl<'lua, T> Unpin for CoroutineStream<'lua, T> {}
+
+impl<'lua, T> CoroutineStream<'lua, T> {
+    pub(crate) fn new(ctx: Context<'lua>, thread: Thread<'lua>) -> CoroutineStream<'lua, T> {
+        CoroutineStream {
+            ctx,
+            thread,
+            done: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The thread running the coroutine
+    pub fn thread(&self) -> &Thread<'lua> {
+        &self.thread
+    }
+}
+
+impl<'lua, T> Stream for CoroutineStream<'lua, T>
+where
+    T: FromLuaMulti<'lua>,
+{
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, fut_ctx: &mut task::Context) -> Poll<Option<Result<T>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let resume_ret = DepthGuard::enter().and_then(|_guard| {
+            FUTURE_CTX.set(&(fut_ctx as *mut _ as *mut ()), || {
+                this.thread.resume::<_, MultiValue>(())
+            })
+        });
+
+        match resume_ret {
+            Err(e) => {
+                this.done = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Ok(v) => match this.thread.status() {
+                ThreadStatus::Resumable if is_pending_yield(&v) => Poll::Pending,
+                ThreadStatus::Resumable => Poll::Ready(Some(T::from_lua_multi(v, this.ctx))),
+                ThreadStatus::Unresumable => {
+                    this.done = true;
+                    Poll::Ready(None)
+                }
+                // The `Error` case should be caught by the `Err(e)` match above
+                ThreadStatus::Error => unreachable!(),
+            },
+        }
+    }
+}
+
+/// An item of a [`LuaStream`], or its end, converted to the return values of `s:next()`
+struct Next<T>(Option<T>);
+
+impl<'lua, T: ToLuaMulti<'lua>> ToLuaMulti<'lua> for Next<T> {
+    fn to_lua_multi(self, ctx: Context<'lua>) -> Result<MultiValue<'lua>> {
+        match self.0 {
+            Some(item) => item.to_lua_multi(ctx),
+            None => Value::Nil.to_lua_multi(ctx),
+        }
+    }
+}
+
+/// A Rust stream, the userdata created by
+/// [`ContextExt::create_async_stream`](crate::ContextExt::create_async_stream)
+struct LuaStream<T>(Arc<Mutex<Fuse<BoxStream<'static, Result<T>>>>>);
+
+impl<T> UserData for LuaStream<T>
+where
+    T: 'static + Send + for<'all> ToLuaMulti<'all>,
+{
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("next", |_, this, ()| {
+            let stream = this.0.clone();
+            async move { Ok(Next(stream.lock().await.next().await.transpose()?)) }
+        });
+        methods.add_function("iter", |ctx, stream: AnyUserData| {
+            let iter =
+                match ctx.named_registry_value::<_, Option<Function>>(STREAM_ITER_REGISTRY_KEY)? {
+                    Some(iter) => iter,
+                    None => {
+                        let iter = ctx
+                            .load(STREAM_ITER)
+                            .set_name(b"rlua-async stream iter")?
+                            .eval::<Function>()?;
+                        ctx.set_named_registry_value(STREAM_ITER_REGISTRY_KEY, iter.clone())?;
+                        iter
+                    }
+                };
+            iter.call::<_, Function>(stream)
+        });
+    }
+}
+
+pub(crate) fn create<'lua, S, T>(ctx: Context<'lua>, stream: S) -> Result<AnyUserData<'lua>>
+where
+    S: 'static + Send + Stream,
+    S::Item: IntoLuaResult<T>,
+    T: 'static + Send + for<'all> ToLuaMulti<'all>,
+{
+    let stream = stream.map(IntoLuaResult::into_lua_result).boxed().fuse();
+    ctx.create_userdata(LuaStream(Arc::new(Mutex::new(stream))))
+}
+
+/// A Rust sink, the userdata created by
+/// [`ContextExt::create_async_sink`](crate::ContextExt::create_async_sink)
+struct LuaSink<T>(Arc<Mutex<BoxSink<T>>>);
+
+type BoxSink<T> = Pin<Box<dyn Sink<T, Error = Error> + Send>>;
+
+impl<T> UserData for LuaSink<T>
+where
+    T: 'static + Send + for<'all> FromLuaMulti<'all>,
+{
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("send", |_, this, item: T| {
+            let sink = this.0.clone();
+            async move { sink.lock().await.send(item).await }
+        });
+        methods.add_async_method("close", |_, this, ()| {
+            let sink = this.0.clone();
+            async move { sink.lock().await.close().await }
+        });
+    }
+}
+
+pub(crate) fn create_sink<'lua, S, T>(ctx: Context<'lua>, sink: S) -> Result<AnyUserData<'lua>>
+where
+    S: 'static + Send + Sink<T>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    T: 'static + Send + for<'all> FromLuaMulti<'all>,
+{
+    let sink = Box::pin(sink.sink_map_err(Error::external));
+    ctx.create_userdata(LuaSink(Arc::new(Mutex::new(sink))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{channel::mpsc, executor, future, stream, SinkExt, StreamExt};
+    use rlua::{Error, Lua};
+
+    use crate::{ChunkExt, ContextExt, FunctionExt};
+
+    #[test]
+    fn generator_stream() {
+        Lua::new().context(|lua| {
+            let gen = lua
+                .load(r#"function() for i = 1, 3 do coroutine.yield(i, i * 2) end end"#)
+                .eval::<Function>()
+                .unwrap();
+            let stream = coroutine_to_stream::<(usize, usize), _>(lua, gen).unwrap();
+            let items = executor::block_on(stream.collect::<Vec<_>>());
+            let items = items.into_iter().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(items, vec![(1, 2), (2, 4), (3, 6)]);
+        });
+    }
+
+    #[test]
+    fn awaiting_generator_stream() {
+        Lua::new().context(|lua| {
+            let f = lua
+                .create_async_function(|_, a: usize| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(a * 10)
+                })
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+
+            let thread = lua
+                .load(r#"coroutine.create(function() for i = 1, 3 do coroutine.yield(f(i)) end error("done") end)"#)
+                .eval::<Thread>()
+                .unwrap();
+            let mut stream = coroutine_to_stream::<usize, _>(lua, thread).unwrap();
+            executor::block_on(async {
+                assert_eq!(stream.next().await.unwrap().unwrap(), 10);
+                assert_eq!(stream.next().await.unwrap().unwrap(), 20);
+                assert_eq!(stream.next().await.unwrap().unwrap(), 30);
+                assert!(stream.next().await.unwrap().is_err());
+                assert!(stream.next().await.is_none());
+            });
+        });
+    }
+
+    #[test]
+    fn lua_iterated_stream() {
+        Lua::new().context(|lua| {
+            let (mut tx, rx) = mpsc::channel::<Result<(usize, String)>>(0);
+            lua.globals()
+                .set("lines", lua.create_async_stream(rx).unwrap())
+                .unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        local got = { lines:next() }
+                        for i, line in lines:iter() do
+                            got[#got + 1] = i .. "=" .. line
+                        end
+                        return table.concat(got, " ")
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let (consumed, ()) = executor::block_on(future::join(
+                f.call_async::<_, String>(lua, ()),
+                async move {
+                    tx.send(Ok((1, "one".to_string()))).await.unwrap();
+                    tx.send(Ok((2, "two".to_string()))).await.unwrap();
+                    tx.send(Ok((3, "three".to_string()))).await.unwrap();
+                },
+            ));
+            assert_eq!(consumed.expect("failed to iterate"), "1 one 2=two 3=three");
+
+            let failing = stream::iter(vec![Err(Error::RuntimeError("stream failed".to_string()))]);
+            lua.globals()
+                .set(
+                    "failing",
+                    lua.create_async_stream::<_, ()>(failing).unwrap(),
+                )
+                .unwrap();
+            let err = executor::block_on(lua.load("failing:next()").exec_async(lua)).unwrap_err();
+            assert!(err.to_string().contains("stream failed"), "{}", err);
+            let end = executor::block_on(lua.eval_async::<_, Value>("failing:next()"));
+            assert!(matches!(end, Ok(Value::Nil)));
+        });
+    }
+
+    #[test]
+    fn lua_fed_sink() {
+        Lua::new().context(|lua| {
+            let (tx, rx) = mpsc::channel::<(String, usize)>(0);
+            lua.globals()
+                .set("events", lua.create_async_sink(tx).unwrap())
+                .unwrap();
+            let f = lua
+                .load(
+                    r#"
+                        for i = 1, 3 do
+                            events:send("event", i)
+                        end
+                        events:close()
+                    "#,
+                )
+                .into_function()
+                .unwrap();
+            let (sent, received) = executor::block_on(future::join(
+                f.call_async::<_, ()>(lua, ()),
+                rx.collect::<Vec<_>>(),
+            ));
+            sent.expect("failed to send");
+            let expected = (1..=3)
+                .map(|i| ("event".to_string(), i))
+                .collect::<Vec<_>>();
+            assert_eq!(received, expected);
+
+            let closed = lua.load(r#"events:send("late", 4)"#).exec_async(lua);
+            assert!(executor::block_on(closed).is_err());
+        });
+    }
+}
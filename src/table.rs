@@ -0,0 +1,120 @@
+use std::future::Future;
+
+use rlua::{Context, FromLuaMulti, MultiValue, Result, Table, ToLuaMulti, Value};
+
+use crate::{call, CallAsyncFuture, ContextExt, IntoLuaResult};
+
+/// Extension trait for [`rlua::Table`]
+pub trait TableExt<'lua> {
+    /// Create an asynchronous function named `key`, and set it as the field `key` of the table.
+    /// See [`ContextExt::create_named_async_function`].
+    fn set_async_fn<Arg, Ret, RetFut, F>(
+        &self,
+        ctx: Context<'lua>,
+        key: &str,
+        func: F,
+    ) -> Result<()>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
+
+    /// Call the method `name` of the table in an async-compliant way, like `table:name(args)`
+    /// in Lua. See also [`FunctionExt::call_async`](crate::FunctionExt::call_async).
+    fn call_async_method<Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>;
+}
+
+impl<'lua> TableExt<'lua> for Table<'lua> {
+    fn set_async_fn<Arg, Ret, RetFut, F>(
+        &self,
+        ctx: Context<'lua>,
+        key: &str,
+        func: F,
+    ) -> Result<()>
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        self.set(key, ctx.create_named_async_function(key, func)?)
+    }
+
+    fn call_async_method<Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        call::call_async_method(ctx, Value::Table(self.clone()), name, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use futures::{executor, FutureExt};
+    use rlua::Lua;
+
+    use crate::ChunkExt;
+
+    #[test]
+    fn table_async_fns() {
+        Lua::new().context(|lua| {
+            let net = lua.create_table().unwrap();
+            net.set_async_fn(lua, "fetch", |_, url: String| async move {
+                futures_timer::Delay::new(Duration::from_millis(10)).await;
+                Ok(format!("contents of {}", url))
+            })
+            .unwrap();
+            lua.globals().set("net", net).unwrap();
+
+            let res = executor::block_on(
+                lua.load(r#"return net.fetch("example.org")"#)
+                    .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, "contents of example.org");
+        });
+    }
+
+    #[test]
+    fn async_method_calls() {
+        Lua::new().context(|lua| {
+            lua.set_async_fn("sleep", |_, ms: u64| {
+                futures_timer::Delay::new(Duration::from_millis(ms)).map(Ok)
+            })
+            .unwrap();
+            let obj = lua
+                .load(
+                    r#"{ n = 40, add = function(self, a, b) sleep(10) return self.n + a + b end }"#,
+                )
+                .eval::<Table>()
+                .unwrap();
+
+            let res = executor::block_on(obj.call_async_method::<_, usize>(lua, "add", (1, 1)));
+            assert_eq!(res.expect("failed to call"), 42);
+            let err =
+                executor::block_on(obj.call_async_method::<_, ()>(lua, "missing", ())).unwrap_err();
+            assert!(err.to_string().contains("no method `missing`"), "{}", err);
+        });
+    }
+}
@@ -0,0 +1,242 @@
+//! Conversions between Rust time types and Lua numbers
+//!
+//! Lua has no time types, so durations and instants are represented as numbers. This module
+//! provides wrappers implementing [`ToLua`] and [`FromLua`] for the Rust time types, so that
+//! scripts and their host agree on the representation:
+//!  * [`Seconds`] and [`Millis`] are durations, as (possibly fractional) seconds and as an
+//!    integer number of milliseconds respectively,
+//!  * [`LuaInstant`] is a monotonic instant, as the seconds elapsed since an arbitrary origin
+//!    fixed for the whole process, the one used by the `time.now()` function installed by
+//!    [`install`],
+//!  * [`UnixTime`] is a wall-clock time, as seconds since the Unix epoch, like `time.unix()`.
+//!
+//! The Lua functions of the crate taking or returning durations, eg. `async.sleep`, all use
+//! [`Millis`]. Negative or non-finite durations are refused when converting from Lua, and
+//! durations too long to be represented when converting to Lua.
+
+use std::{
+    convert::TryFrom,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rlua::{Context, Error, FromLua, Result, ToLua, Value};
+
+use crate::global_table;
+
+/// A [`Duration`] represented in Lua as a number of seconds, eg. `1.5`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Seconds(pub Duration);
+
+/// A [`Duration`] represented in Lua as an integer number of milliseconds, eg. `1500`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Millis(pub Duration);
+
+/// A monotonic [`Instant`] represented in Lua as the number of seconds since the origin of
+/// [`now`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaInstant(pub Instant);
+
+/// A [`SystemTime`] represented in Lua as the number of seconds since the Unix epoch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UnixTime(pub SystemTime);
+
+fn origin() -> Instant {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    *ORIGIN.get_or_init(Instant::now)
+}
+
+/// The current monotonic time, in seconds since an arbitrary origin fixed for the whole process
+pub fn now() -> f64 {
+    LuaInstant(Instant::now()).seconds()
+}
+
+impl LuaInstant {
+    fn seconds(&self) -> f64 {
+        let origin = origin();
+        if self.0 >= origin {
+            (self.0 - origin).as_secs_f64()
+        } else {
+            -(origin - self.0).as_secs_f64()
+        }
+    }
+}
+
+fn conversion_error(from: &'static str, to: &'static str, message: &str) -> Error {
+    Error::FromLuaConversionError {
+        from,
+        to,
+        message: Some(message.to_string()),
+    }
+}
+
+fn seconds_to_duration(secs: f64, to: &'static str) -> Result<Duration> {
+    if !secs.is_finite() || secs < 0. {
+        return Err(conversion_error(
+            "number",
+            to,
+            "durations must be finite and non-negative",
+        ));
+    }
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| conversion_error("number", to, "duration out of range"))
+}
+
+impl<'lua> ToLua<'lua> for Seconds {
+    fn to_lua(self, _: Context<'lua>) -> Result<Value<'lua>> {
+        Ok(Value::Number(self.0.as_secs_f64()))
+    }
+}
+
+impl<'lua> FromLua<'lua> for Seconds {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<Seconds> {
+        let secs = rlua::Number::from_lua(value, ctx)?;
+        seconds_to_duration(secs, "Duration").map(Seconds)
+    }
+}
+
+impl<'lua> ToLua<'lua> for Millis {
+    fn to_lua(self, _: Context<'lua>) -> Result<Value<'lua>> {
+        let millis = rlua::Integer::try_from(self.0.as_millis()).map_err(|_| {
+            Error::ToLuaConversionError {
+                from: "Duration",
+                to: "integer",
+                message: Some("duration out of range".to_string()),
+            }
+        })?;
+        Ok(Value::Integer(millis))
+    }
+}
+
+impl<'lua> FromLua<'lua> for Millis {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<Millis> {
+        let millis = rlua::Integer::from_lua(value, ctx)?;
+        if millis < 0 {
+            return Err(conversion_error(
+                "integer",
+                "Duration",
+                "durations must be non-negative",
+            ));
+        }
+        Ok(Millis(Duration::from_millis(millis as u64)))
+    }
+}
+
+impl<'lua> ToLua<'lua> for LuaInstant {
+    fn to_lua(self, _: Context<'lua>) -> Result<Value<'lua>> {
+        Ok(Value::Number(self.seconds()))
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaInstant {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<LuaInstant> {
+        let secs = rlua::Number::from_lua(value, ctx)?;
+        if !secs.is_finite() {
+            return Err(conversion_error(
+                "number",
+                "Instant",
+                "instants must be finite",
+            ));
+        }
+        let origin = origin();
+        let instant = if secs >= 0. {
+            origin.checked_add(seconds_to_duration(secs, "Instant")?)
+        } else {
+            origin.checked_sub(seconds_to_duration(-secs, "Instant")?)
+        };
+        instant
+            .map(LuaInstant)
+            .ok_or_else(|| conversion_error("number", "Instant", "instant out of range"))
+    }
+}
+
+impl<'lua> ToLua<'lua> for UnixTime {
+    fn to_lua(self, _: Context<'lua>) -> Result<Value<'lua>> {
+        Ok(Value::Number(match self.0.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        }))
+    }
+}
+
+impl<'lua> FromLua<'lua> for UnixTime {
+    fn from_lua(value: Value<'lua>, ctx: Context<'lua>) -> Result<UnixTime> {
+        let secs = rlua::Number::from_lua(value, ctx)?;
+        if !secs.is_finite() {
+            return Err(conversion_error(
+                "number",
+                "SystemTime",
+                "times must be finite",
+            ));
+        }
+        let time = if secs >= 0. {
+            UNIX_EPOCH.checked_add(seconds_to_duration(secs, "SystemTime")?)
+        } else {
+            UNIX_EPOCH.checked_sub(seconds_to_duration(-secs, "SystemTime")?)
+        };
+        time.map(UnixTime)
+            .ok_or_else(|| conversion_error("number", "SystemTime", "time out of range"))
+    }
+}
+
+/// Install `time.now()` and `time.unix()` in the globals, creating the `time` table if need be
+///
+/// `time.now()` returns the monotonic time as per [`now`], to measure durations, and
+/// `time.unix()` the wall-clock time in seconds since the Unix epoch.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let time = global_table(ctx, "time")?;
+    time.set("now", ctx.create_function(|_, ()| Ok(now()))?)?;
+    time.set(
+        "unix",
+        ctx.create_function(|_, ()| Ok(UnixTime(SystemTime::now())))?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rlua::Lua;
+
+    #[test]
+    fn time_conversions() {
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("d", Seconds(Duration::from_millis(1500)))
+                .unwrap();
+            lua.globals()
+                .set("start", LuaInstant(Instant::now()))
+                .unwrap();
+
+            let (secs, millis, elapsed, unix): (Seconds, Millis, Seconds, UnixTime) = lua
+                .load(r#"return d * 2, math.floor(d * 1000), time.now() - start, time.unix()"#)
+                .eval()
+                .unwrap();
+            assert_eq!(secs.0, Duration::from_secs(3));
+            assert_eq!(millis.0, Duration::from_millis(1500));
+            assert!(elapsed.0 < Duration::from_secs(10));
+            assert!(unix.0 > UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+
+            assert!(lua.load(r#"-1"#).eval::<Seconds>().is_err());
+            assert!(lua.load(r#"1e300"#).eval::<Seconds>().is_err());
+            assert!(lua.load(r#"-1e300"#).eval::<LuaInstant>().is_err());
+            assert!(lua.load(r#"1e300"#).eval::<UnixTime>().is_err());
+            assert!(lua
+                .load(r#"return ..."#)
+                .call::<_, Value>(Millis(Duration::MAX))
+                .is_err());
+            let instant = Instant::now();
+            let back = lua
+                .load(r#"return ..."#)
+                .call::<_, LuaInstant>(LuaInstant(instant))
+                .unwrap();
+            let diff = if back.0 > instant {
+                back.0 - instant
+            } else {
+                instant - back.0
+            };
+            assert!(diff < Duration::from_millis(1));
+        });
+    }
+}
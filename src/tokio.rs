@@ -0,0 +1,152 @@
+//! Running the crate on the [`tokio`](::tokio) runtime
+//!
+//! [`TokioSpawner`] is a [`Spawner`] that spawns the background futures of the crate as tokio
+//! tasks, runs its blocking functions on the blocking thread pool of the runtime, and uses the
+//! timers of the runtime, eg. for [`AsyncCall::timeout`](crate::AsyncCall::timeout) and the
+//! `async.sleep` of the `async` Lua library:
+//!
+//! ```no_run
+//! # use rlua::Lua;
+//! # use rlua_async::{tokio::TokioSpawner, ContextExt};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let rt = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+//! let lua = Lua::new();
+//! lua.context(|ctx| ctx.set_spawner(TokioSpawner::new(rt.handle().clone())))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! This module is only available with the `tokio` feature.
+
+use std::time::Duration;
+
+use ::tokio::{runtime::Handle, task};
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    task::SpawnError,
+};
+use rlua::{Error, Result};
+
+use crate::spawner::Spawner;
+
+/// A [`Spawner`] running on a tokio runtime
+///
+/// The futures, blocking functions and timers go to the runtime of the handle the spawner was
+/// created with, so that the spawner works from any thread, eg. the ones of
+/// [`ContextExt::create_blocking_function`](crate::ContextExt::create_blocking_function).
+///
+/// The local futures, that are not `Send`, are spawned with [`task::spawn_local`] on the
+/// [`LocalSet`](task::LocalSet) that is running on the current thread, eg. in
+/// [`LocalSet::run_until`](task::LocalSet::run_until). Spawning them fails outside of one.
+#[derive(Clone, Debug)]
+pub struct TokioSpawner {
+    handle: Handle,
+}
+
+impl TokioSpawner {
+    /// A spawner for the runtime of `handle`
+    pub fn new(handle: Handle) -> TokioSpawner {
+        TokioSpawner { handle }
+    }
+
+    /// A spawner for the runtime this is called from, failing outside of a runtime
+    pub fn current() -> Result<TokioSpawner> {
+        Handle::try_current()
+            .map(TokioSpawner::new)
+            .map_err(Error::external)
+    }
+}
+
+impl Spawner for TokioSpawner {
+    fn spawn(&self, fut: BoxFuture<'static, ()>) -> std::result::Result<(), SpawnError> {
+        drop(self.handle.spawn(fut));
+        Ok(())
+    }
+
+    fn spawn_local(&self, fut: LocalBoxFuture<'static, ()>) -> std::result::Result<(), SpawnError> {
+        // `spawn_local` panics outside of a `LocalSet`, and there is no way to check beforehand
+        // for one but to catch that panic
+        let _guard = self.handle.enter();
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| task::spawn_local(fut)))
+            .map(drop)
+            .map_err(|_| SpawnError::shutdown())
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        // The timer is registered with the runtime of the context it is created in
+        let _guard = self.handle.enter();
+        Box::pin(::tokio::time::sleep(duration))
+    }
+
+    fn spawn_blocking(&self, f: Box<dyn FnOnce() + Send>) -> std::result::Result<(), SpawnError> {
+        drop(self.handle.spawn_blocking(f));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::Arc, thread};
+
+    use ::tokio::{runtime::Builder, task::LocalSet};
+    use futures::{channel::oneshot, future};
+    use rlua::Lua;
+
+    use crate::{ChunkExt, ContextExt, FunctionExt};
+
+    #[test]
+    fn tokio_spawner() {
+        let rt = Builder::new_current_thread().enable_time().build().unwrap();
+        let spawner: Arc<dyn Spawner> = Arc::new(TokioSpawner::new(rt.handle().clone()));
+        let (tx, rx) = oneshot::channel();
+        spawner
+            .spawn_blocking(Box::new(move || {
+                let _ = tx.send(thread::current().name().map(String::from));
+            }))
+            .unwrap();
+        assert!(rt.block_on(rx).unwrap().unwrap().contains("tokio"));
+
+        let (tx, rx) = oneshot::channel();
+        let local = Box::pin(async move {
+            let _ = tx.send(42);
+        });
+        let res = LocalSet::new().block_on(&rt, async {
+            spawner.spawn_local(local).unwrap();
+            rx.await
+        });
+        assert_eq!(res, Ok(42));
+        assert!(spawner.spawn_local(Box::pin(future::ready(()))).is_err());
+    }
+
+    #[test]
+    fn lua_on_tokio() {
+        let rt = Builder::new_current_thread().enable_time().build().unwrap();
+        Lua::new().context(|lua| {
+            rt.block_on(async {
+                lua.set_spawner(TokioSpawner::current().unwrap()).unwrap();
+                lua.set_async_fn("sleep", |ctx, ms: u64| {
+                    let sleep = ctx.spawner().map(|s| s.sleep(Duration::from_millis(ms)));
+                    async move {
+                        sleep?.await;
+                        Ok(ms)
+                    }
+                })
+                .unwrap();
+                lua.set_async_fn("never", |_, ()| future::pending::<()>())
+                    .unwrap();
+
+                let slept = lua.load("return sleep(10) + sleep(10)");
+                assert_eq!(slept.call_async::<_, u64>(lua, ()).await.unwrap(), 20);
+
+                let f = lua.load("never()").into_function().unwrap();
+                let res = f
+                    .async_call::<()>(lua)
+                    .timeout(Duration::from_millis(10))
+                    .await;
+                assert!(res.is_err());
+            })
+        });
+    }
+}
@@ -0,0 +1,75 @@
+function(make_poller, pending, prefix)
+    local trampolines = setmetatable({}, { __mode = "k" })
+
+    local function trampoline(method, name)
+        local t = trampolines[method]
+        if t == nil then
+            t = make_poller(method, pending, name)
+            trampolines[method] = t
+        end
+        return t
+    end
+
+    -- The `__index` metamethod of the userdata with async methods
+    local function index(ud, key)
+        if type(key) ~= "string" or key:sub(1, #prefix) == prefix then
+            return nil
+        end
+        local method = ud[prefix .. key]
+        if method == nil then
+            return nil
+        end
+        return trampoline(method, key)
+    end
+
+    -- A table forwarding to the async metamethods of `ud`, as the ones of the userdata are Rust
+    -- functions that cannot yield
+    local function proxy(ud)
+        local async_index = ud[prefix .. "__index"]
+        local async_newindex = ud[prefix .. "__newindex"]
+        local async_call = ud[prefix .. "__call"]
+        local p = {}
+        -- The methods are called with `p` as `self`, so they are wrapped to pass `ud` instead
+        local methods = setmetatable({}, { __mode = "k" })
+        local meta = { __metatable = false }
+
+        function meta.__index(_, key)
+            local v = ud[key]
+            if v == nil and async_index ~= nil then
+                return trampoline(async_index, "__index")(ud, key)
+            elseif type(v) == "function" then
+                local m = methods[v]
+                if m == nil then
+                    m = function(self, ...)
+                        if rawequal(self, p) then
+                            self = ud
+                        end
+                        return v(self, ...)
+                    end
+                    methods[v] = m
+                end
+                return m
+            end
+            return v
+        end
+
+        function meta.__newindex(_, key, value)
+            if async_newindex == nil then
+                ud[key] = value
+            else
+                trampoline(async_newindex, "__newindex")(ud, key, value)
+            end
+        end
+
+        function meta.__call(_, ...)
+            if async_call == nil then
+                return ud(...)
+            end
+            return trampoline(async_call, "__call")(ud, ...)
+        end
+
+        return setmetatable(p, meta)
+    end
+
+    return { index = index, proxy = proxy }
+end
@@ -0,0 +1,429 @@
+//! Async methods and functions on userdata
+//!
+//! `rlua` only stores Rust callbacks in the metatables of userdata, and those cannot yield. The
+//! async methods are thus registered as regular methods under a hidden name, that return the
+//! polling function of their future, and an `__index` metamethod wraps them on access in the
+//! same trampoline as [`ContextExt::create_async_function`](crate::ContextExt).
+//!
+//! For the same reason, the async metamethods are only used through a proxy table, created by
+//! [`ContextExt::create_async_proxy`](crate::ContextExt::create_async_proxy), whose
+//! metamethods are Lua functions.
+
+use std::{future::Future, sync::Arc};
+
+use rlua::{
+    AnyUserData, Context, FromLuaMulti, Function, MetaMethod, MultiValue, Result, Table,
+    ToLuaMulti, UserData, UserDataMethods, Value,
+};
+
+use crate::{
+    call, middleware, pending_marker, poller_fn, CallAsyncFuture, IntoLuaResult, MAKE_POLLER,
+};
+
+static USERDATA_METHODS: &[u8] = include_bytes!("userdata-methods.lua");
+static HELPERS_REGISTRY_KEY: &str = "rlua-async userdata helpers";
+static HIDDEN_PREFIX: &str = "\0rlua-async ";
+
+fn hidden_name(name: &[u8]) -> Vec<u8> {
+    let mut hidden = HIDDEN_PREFIX.as_bytes().to_vec();
+    hidden.extend_from_slice(name);
+    hidden
+}
+
+/// The Lua helpers of the userdata with async methods
+fn helpers(ctx: Context) -> Result<Table> {
+    if let Some(helpers) = ctx.named_registry_value::<_, Option<Table>>(HELPERS_REGISTRY_KEY)? {
+        return Ok(helpers);
+    }
+    let make_poller = ctx
+        .load(MAKE_POLLER)
+        .set_name(b"coroutine yield helper")?
+        .eval::<Function>()?;
+    let helpers = ctx
+        .load(USERDATA_METHODS)
+        .set_name(b"rlua-async userdata methods")?
+        .eval::<Function>()?
+        .call::<_, Table>((make_poller, pending_marker(), HIDDEN_PREFIX))?;
+    ctx.set_named_registry_value(HELPERS_REGISTRY_KEY, helpers.clone())?;
+    Ok(helpers)
+}
+
+/// The `__index` metamethod of the userdata with async methods
+fn lookup<'lua>(
+    ctx: Context<'lua>,
+    (ud, key): (AnyUserData<'lua>, Value<'lua>),
+) -> Result<Value<'lua>> {
+    helpers(ctx)?.get::<_, Function>("index")?.call((ud, key))
+}
+
+/// Add the async method `name`, whose `method` returns the polling function of the call itself,
+/// for the calls that need the Lua context to make progress
+#[cfg(feature = "async-lib")]
+pub(crate) fn add_polled_method<'lua, T, U, M>(methods: &mut U, name: &str, method: M)
+where
+    T: UserData,
+    U: UserDataMethods<'lua, T>,
+    M: 'static + Send + Fn(Context<'lua>, &T, MultiValue<'lua>) -> Result<Function<'lua>>,
+{
+    methods.add_method(&hidden_name(name.as_bytes()), method);
+    methods.add_meta_function(MetaMethod::Index, lookup);
+}
+
+pub(crate) fn proxy<'lua>(ctx: Context<'lua>, ud: AnyUserData<'lua>) -> Result<Table<'lua>> {
+    helpers(ctx)?.get::<_, Function>("proxy")?.call(ud)
+}
+
+fn async_meta_method_name(meta: MetaMethod) -> &'static str {
+    match meta {
+        MetaMethod::Index => "__index",
+        MetaMethod::NewIndex => "__newindex",
+        MetaMethod::Call => "__call",
+        meta => panic!(
+            "only the `__index`, `__newindex` and `__call` metamethods can be async, not {:?}",
+            meta
+        ),
+    }
+}
+
+/// Extension trait for [`rlua::UserDataMethods`], to add async methods to userdata types
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rlua::{UserData, UserDataMethods};
+/// # use rlua_async::UserDataMethodsExt;
+/// # struct Conn;
+/// # impl Conn {
+/// #     async fn fetch(&self, key: String) -> rlua::Result<String> { Ok(key) }
+/// # }
+/// # struct Db { conn: Arc<Conn> }
+/// impl UserData for Db {
+///     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+///         methods.add_async_method("fetch", |_, this, key: String| {
+///             let conn = this.conn.clone();
+///             async move { conn.fetch(key).await }
+///         });
+///     }
+/// }
+/// ```
+///
+/// makes `db:fetch(key)` an async call in Lua. As with [`rlua::UserDataMethods::add_method`],
+/// the method only borrows the userdata while it is called, so the future it returns must not
+/// borrow it. The async methods are found through the `__index` metamethod, so the userdata
+/// types having some cannot define their own.
+pub trait UserDataMethodsExt<'lua, T: UserData> {
+    /// Add an async method, see [`rlua::UserDataMethods::add_method`] and
+    /// [`ContextExt::create_async_function`](crate::ContextExt::create_async_function)
+    fn add_async_method<S, Arg, Ret, RetFut, M>(&mut self, name: &S, method: M)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + Fn(Context<'lua>, &T, Arg) -> RetFut;
+
+    /// Add an async method that can mutate the userdata, see
+    /// [`UserDataMethodsExt::add_async_method`]
+    fn add_async_method_mut<S, Arg, Ret, RetFut, M>(&mut self, name: &S, method: M)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + FnMut(Context<'lua>, &mut T, Arg) -> RetFut;
+
+    /// Add an async metamethod, see [`rlua::UserDataMethods::add_meta_method`]
+    ///
+    /// Only `__index`, `__newindex` and `__call` can be async, the other metamethods panic. As the
+    /// metamethods of userdata can not yield, the async ones are only called when using the
+    /// userdata through the table returned by
+    /// [`ContextExt::create_async_proxy`](crate::ContextExt::create_async_proxy). Like for the
+    /// userdata, the async `__index` metamethod of the proxy is only called for the keys that
+    /// are not methods.
+    fn add_async_meta_method<Arg, Ret, RetFut, M>(&mut self, meta: MetaMethod, method: M)
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + Fn(Context<'lua>, &T, Arg) -> RetFut;
+
+    /// Add an async function that does not take the userdata as first argument, see
+    /// [`rlua::UserDataMethods::add_function`]
+    ///
+    /// Like regular functions, it is accessed through a value of the type: with eg. a `Db`
+    /// global set to a userdata of type `Db`, an async `connect` function is called with
+    /// `Db.connect(url)`.
+    fn add_async_function<S, Arg, Ret, RetFut, F>(&mut self, name: &S, func: F)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut;
+}
+
+impl<'lua, T: UserData, U: UserDataMethods<'lua, T>> UserDataMethodsExt<'lua, T> for U {
+    fn add_async_method<S, Arg, Ret, RetFut, M>(&mut self, name: &S, method: M)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + Fn(Context<'lua>, &T, Arg) -> RetFut,
+    {
+        let name = name.as_ref();
+        let fun_name: Arc<str> = String::from_utf8_lossy(name).into();
+        self.add_method(
+            &hidden_name(name),
+            move |ctx, this, args: MultiValue<'lua>| {
+                let fut = middleware::wrap(ctx, &fun_name, args, |args| {
+                    Ok(method(ctx, this, FromLuaMulti::from_lua_multi(args, ctx)?))
+                })?;
+                poller_fn(ctx, fut)
+            },
+        );
+        self.add_meta_function(MetaMethod::Index, lookup);
+    }
+
+    fn add_async_method_mut<S, Arg, Ret, RetFut, M>(&mut self, name: &S, mut method: M)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + FnMut(Context<'lua>, &mut T, Arg) -> RetFut,
+    {
+        let name = name.as_ref();
+        let fun_name: Arc<str> = String::from_utf8_lossy(name).into();
+        self.add_method_mut(
+            &hidden_name(name),
+            move |ctx, this, args: MultiValue<'lua>| {
+                let fut = middleware::wrap(ctx, &fun_name, args, |args| {
+                    Ok(method(ctx, this, FromLuaMulti::from_lua_multi(args, ctx)?))
+                })?;
+                poller_fn(ctx, fut)
+            },
+        );
+        self.add_meta_function(MetaMethod::Index, lookup);
+    }
+
+    fn add_async_meta_method<Arg, Ret, RetFut, M>(&mut self, meta: MetaMethod, method: M)
+    where
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        M: 'static + Send + Fn(Context<'lua>, &T, Arg) -> RetFut,
+    {
+        self.add_async_method(async_meta_method_name(meta), method);
+    }
+
+    fn add_async_function<S, Arg, Ret, RetFut, F>(&mut self, name: &S, func: F)
+    where
+        S: ?Sized + AsRef<[u8]>,
+        Arg: FromLuaMulti<'lua>,
+        Ret: 'static + Send + ToLuaMulti<'lua>,
+        RetFut: 'static + Send + Future,
+        RetFut::Output: IntoLuaResult<Ret>,
+        F: 'static + Send + Fn(Context<'lua>, Arg) -> RetFut,
+    {
+        let name = name.as_ref();
+        let fun_name: Arc<str> = String::from_utf8_lossy(name).into();
+        self.add_function(&hidden_name(name), move |ctx, args: MultiValue<'lua>| {
+            let fut = middleware::wrap(ctx, &fun_name, args, |args| {
+                Ok(func(ctx, FromLuaMulti::from_lua_multi(args, ctx)?))
+            })?;
+            poller_fn(ctx, fut)
+        });
+        self.add_meta_function(MetaMethod::Index, lookup);
+    }
+}
+
+/// Extension trait for [`rlua::AnyUserData`]
+pub trait AnyUserDataExt<'lua> {
+    /// Call the method `name` of the userdata in an async-compliant way, like `ud:name(args)` in
+    /// Lua, see [`TableExt::call_async_method`](crate::TableExt::call_async_method). This works
+    /// both for the async methods and the regular ones.
+    fn call_async_method<Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>;
+}
+
+impl<'lua> AnyUserDataExt<'lua> for AnyUserData<'lua> {
+    fn call_async_method<Arg, Ret>(
+        &self,
+        ctx: Context<'lua>,
+        name: &str,
+        args: Arg,
+    ) -> CallAsyncFuture<'lua, MultiValue<'lua>, Ret>
+    where
+        Arg: ToLuaMulti<'lua>,
+        Ret: FromLuaMulti<'lua>,
+    {
+        call::call_async_method(ctx, Value::UserData(self.clone()), name, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::{ChunkExt, ContextExt};
+
+    struct Counter(usize);
+
+    impl UserData for Counter {
+        fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+            methods.add_method("get", |_, this, ()| Ok(this.0));
+            methods.add_async_method("later", |_, this, delay: u64| {
+                let n = this.0;
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(delay)).await;
+                    Ok(n)
+                }
+            });
+            methods.add_async_function("new", |_, n: usize| async move {
+                futures_timer::Delay::new(Duration::from_millis(10)).await;
+                Ok(Counter(n))
+            });
+            methods.add_async_method_mut("add", |_, this, n: usize| {
+                this.0 += n;
+                let n = this.0;
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(n)
+                }
+            });
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Store(Arc<Mutex<HashMap<String, String>>>);
+
+    impl UserData for Store {
+        fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+            methods.add_method("len", |_, this, ()| Ok(this.0.lock().unwrap().len()));
+            methods.add_async_meta_method(MetaMethod::Index, |_, this, key: String| {
+                let store = this.clone();
+                async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(store.0.lock().unwrap().get(&key).cloned())
+                }
+            });
+            methods.add_async_meta_method(
+                MetaMethod::NewIndex,
+                |_, this, (key, value): (String, String)| {
+                    let store = this.clone();
+                    async move {
+                        futures_timer::Delay::new(Duration::from_millis(10)).await;
+                        store.0.lock().unwrap().insert(key, value);
+                        Ok(())
+                    }
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn async_meta_methods() {
+        Lua::new().context(|lua| {
+            let store = Store::default();
+            let proxy = lua
+                .create_async_proxy(lua.create_userdata(store.clone()).unwrap())
+                .unwrap();
+            lua.globals().set("store", proxy).unwrap();
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        store.answer = "42"
+                        store.other = "1"
+                        return store.answer, store.missing, store:len()
+                    "#,
+                )
+                .call_async::<_, (String, Option<String>, usize)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, ("42".to_string(), None, 2));
+            assert_eq!(store.0.lock().unwrap()["other"], "1");
+        });
+    }
+
+    struct Client;
+
+    impl UserData for Client {
+        fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+            methods.add_async_meta_method(
+                MetaMethod::Call,
+                |_, _, (method, path): (String, String)| async move {
+                    futures_timer::Delay::new(Duration::from_millis(10)).await;
+                    Ok(format!("{} {}", method, path))
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn async_call_meta_method() {
+        Lua::new().context(|lua| {
+            let client = lua
+                .create_async_proxy(lua.create_userdata(Client).unwrap())
+                .unwrap();
+            lua.globals().set("client", client).unwrap();
+            let res = executor::block_on(
+                lua.load(r#"return client("GET", "/")"#)
+                    .call_async::<_, String>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, "GET /");
+        });
+    }
+
+    #[test]
+    fn async_methods() {
+        Lua::new().context(|lua| {
+            lua.globals().set("counter", Counter(1)).unwrap();
+            let res = executor::block_on(
+                lua.load(
+                    r#"
+                        local a = counter:later(10)
+                        local b = counter:add(2)
+                        assert(counter.later == counter.later and counter.missing == nil)
+                        return a, b, counter:get(), counter:later(0)
+                    "#,
+                )
+                .call_async::<_, (usize, usize, usize, usize)>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, (1, 3, 3, 3));
+
+            let res = executor::block_on(
+                lua.load(r#"return counter.new(5):add(1)"#)
+                    .call_async::<_, usize>(lua, ()),
+            )
+            .expect("failed to call");
+            assert_eq!(res, 6);
+
+            let counter = lua.create_userdata(Counter(1)).unwrap();
+            let res = executor::block_on(counter.call_async_method::<_, usize>(lua, "add", 2));
+            assert_eq!(res.expect("failed to call"), 3);
+            let res = executor::block_on(counter.call_async_method::<_, usize>(lua, "get", ()));
+            assert_eq!(res.expect("failed to call"), 3);
+        });
+    }
+}
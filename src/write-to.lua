@@ -0,0 +1,5 @@
+function(builder, writer)
+    for chunk in builder:chunks() do
+        writer:write(chunk)
+    end
+end
@@ -0,0 +1,319 @@
+//! A WebSocket client for Lua scripts, without blocking the executor
+//!
+//! Each connection is driven by a thread of its own, running the blocking client of
+//! [`tungstenite`]: it alternates between sending the messages the scripts queued and reading
+//! the socket with a short timeout, so that reading and writing do not wait on each other.
+//!
+//! This module is only available with the `ws` feature.
+
+use std::{
+    io,
+    net::TcpStream,
+    sync::{mpsc as std_mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    lock::Mutex,
+    StreamExt,
+};
+use rlua::{Context, Error, MultiValue, Result, ToLuaMulti, UserData, UserDataMethods, Value};
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    stream::MaybeTlsStream,
+    Message,
+};
+
+use crate::{blocking, buffer::Buffer, global_table, ContextExt, UserDataMethodsExt};
+
+/// How long the connection threads wait for incoming messages before sending the queued ones
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+type Socket = tungstenite::WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// A request from a Lua handle to its connection thread, with the sender of its result
+enum Command {
+    Send(Message, oneshot::Sender<tungstenite::Result<()>>),
+    Close(Option<CloseFrame>, oneshot::Sender<tungstenite::Result<()>>),
+}
+
+/// Whether `e` is the timeout of a read
+fn timed_out(e: &tungstenite::Error) -> bool {
+    match e {
+        tungstenite::Error::Io(e) => {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Drive the connection, until it is closed or the Lua handle is dropped
+fn run(
+    mut socket: Socket,
+    commands: std_mpsc::Receiver<Command>,
+    incoming: mpsc::UnboundedSender<tungstenite::Result<Message>>,
+) {
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Send(message, done)) => {
+                    let _ = done.send(socket.send(message));
+                }
+                Ok(Command::Close(frame, done)) => {
+                    let _ = done.send(socket.close(frame));
+                }
+                Err(std_mpsc::TryRecvError::Empty) => break,
+                Err(std_mpsc::TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    return;
+                }
+            }
+        }
+        match socket.read() {
+            Ok(message) => {
+                if incoming.unbounded_send(Ok(message)).is_err() {
+                    return;
+                }
+            }
+            // Flush the replies to pings queued by the reads
+            Err(e) if timed_out(&e) => match socket.flush() {
+                Err(e) if !timed_out(&e) => {
+                    let _ = incoming.unbounded_send(Err(e));
+                    return;
+                }
+                _ => {}
+            },
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                return
+            }
+            Err(e) => {
+                let _ = incoming.unbounded_send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+/// Connect to `url`, and start the thread driving the connection
+fn connect(url: &str) -> io::Result<WebSocket> {
+    let (socket, _) = tungstenite::connect(url).map_err(io::Error::other)?;
+    let tcp = match socket.get_ref() {
+        MaybeTlsStream::Plain(tcp) => tcp,
+        MaybeTlsStream::Rustls(tls) => tls.get_ref(),
+        _ => unreachable!("only rustls is enabled"),
+    };
+    tcp.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let (commands, commands_rx) = std_mpsc::channel();
+    let (incoming_tx, incoming) = mpsc::unbounded();
+    thread::Builder::new()
+        .name("rlua-async ws".to_string())
+        .spawn(move || run(socket, commands_rx, incoming_tx))?;
+    Ok(WebSocket {
+        commands,
+        incoming: Arc::new(Mutex::new(incoming)),
+    })
+}
+
+/// A message received by `ws:recv()`, converted to its Lua return values
+enum Received {
+    Text(String),
+    Binary(Buffer),
+    Closed(Option<CloseFrame>),
+}
+
+impl<'lua> ToLuaMulti<'lua> for Received {
+    fn to_lua_multi(self, ctx: Context<'lua>) -> Result<MultiValue<'lua>> {
+        match self {
+            Received::Text(text) => (text, "text").to_lua_multi(ctx),
+            Received::Binary(data) => (data, "binary").to_lua_multi(ctx),
+            Received::Closed(None) => Value::Nil.to_lua_multi(ctx),
+            Received::Closed(Some(frame)) => {
+                let code = u16::from(frame.code);
+                (Value::Nil, code, frame.reason.as_str()).to_lua_multi(ctx)
+            }
+        }
+    }
+}
+
+/// A WebSocket connection, the Lua handle returned by `ws.connect`
+struct WebSocket {
+    commands: std_mpsc::Sender<Command>,
+    incoming: Arc<Mutex<mpsc::UnboundedReceiver<tungstenite::Result<Message>>>>,
+}
+
+impl WebSocket {
+    /// Have the connection thread run the command built by `command`, and wait for its result
+    async fn request(
+        commands: std_mpsc::Sender<Command>,
+        command: impl FnOnce(oneshot::Sender<tungstenite::Result<()>>) -> Command,
+    ) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        let closed = || Error::RuntimeError("the WebSocket connection is closed".to_string());
+        commands.send(command(done)).map_err(|_| closed())?;
+        match result.await {
+            Ok(res) => res.map_err(Error::external),
+            Err(_) => Err(closed()),
+        }
+    }
+}
+
+impl UserData for WebSocket {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("send", |_, this, message: Value| {
+            let message = match message {
+                Value::UserData(ud) => ud
+                    .borrow::<Buffer>()
+                    .map(|data| Message::Binary(data.clone().into_bytes())),
+                Value::String(s) => match s.to_str() {
+                    Ok(text) => Ok(Message::Text(text.into())),
+                    Err(_) => Err(Error::RuntimeError(
+                        "text messages must be valid UTF-8, send a buffer for binary messages"
+                            .to_string(),
+                    )),
+                },
+                _ => Err(Error::RuntimeError(
+                    "a WebSocket message must be a string or a buffer".to_string(),
+                )),
+            };
+            let commands = this.commands.clone();
+            async move {
+                let message = message?;
+                WebSocket::request(commands, |done| Command::Send(message, done)).await
+            }
+        });
+
+        methods.add_async_method("recv", |_, this, ()| {
+            let incoming = this.incoming.clone();
+            async move {
+                let mut incoming = incoming.lock().await;
+                loop {
+                    return Ok(match incoming.next().await {
+                        None => Received::Closed(None),
+                        Some(Err(e)) => return Err(Error::external(e)),
+                        Some(Ok(Message::Text(text))) => Received::Text(text.to_string()),
+                        Some(Ok(Message::Binary(data))) => Received::Binary(Buffer::from(data)),
+                        Some(Ok(Message::Close(frame))) => Received::Closed(frame),
+                        Some(Ok(_)) => continue,
+                    });
+                }
+            }
+        });
+
+        methods.add_async_method(
+            "close",
+            |_, this, (code, reason): (Option<u16>, Option<String>)| {
+                let frame = CloseFrame {
+                    code: CloseCode::from(code.unwrap_or(1000)),
+                    reason: reason.unwrap_or_default().into(),
+                };
+                let commands = this.commands.clone();
+                WebSocket::request(commands, |done| Command::Close(Some(frame), done))
+            },
+        );
+    }
+}
+
+/// Install the WebSocket client functions in the `ws` table of the globals, creating it if
+/// need be
+///
+/// The installed function is `ws.connect(url)`, that connects to the `ws://` or `wss://`
+/// `url`, and returns the connection `c`. It has the async methods:
+///  * `c:send(message)`, that sends a string as a text message, that must then be valid UTF-8,
+///    or a [`Buffer`] as a binary message,
+///  * `c:recv()`, that returns the next text message as a string and `"text"`, or the next
+///    binary message as a [`Buffer`] and `"binary"`. Once the connection is closed, it returns
+///    `nil`, followed by the close code and reason if the peer sent some.
+///  * `c:close(code, reason)`, that starts the closing handshake, with code 1000 (normal
+///    closure) by default. The messages received until the peer acknowledges it can still be
+///    read with `c:recv()`.
+///
+/// The pings of the peer are answered automatically. The messages are read as they arrive,
+/// even if the script does not call `c:recv()`; the connection is closed when its handle is
+/// garbage-collected.
+pub fn install<'lua>(ctx: Context<'lua>) -> Result<()> {
+    let ws = global_table(ctx, "ws")?;
+    ws.set(
+        "connect",
+        ctx.create_named_async_function("ws.connect", |ctx, url: String| {
+            blocking(ctx.spawner(), "ws", move || connect(&url))
+        })?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use futures::executor;
+    use rlua::Lua;
+
+    use crate::{ChunkExt, FunctionExt};
+
+    #[test]
+    fn websocket_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            socket.send(Message::Text("welcome".into())).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(message) if message.is_text() || message.is_binary() => {
+                        socket.send(message).unwrap()
+                    }
+                    Ok(_) => {}
+                    Err(tungstenite::Error::ConnectionClosed) => return,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        });
+
+        Lua::new().context(|lua| {
+            install(lua).unwrap();
+            lua.globals()
+                .set("binary", Buffer::from(vec![0, 255]))
+                .unwrap();
+            let talk = lua
+                .load(
+                    r#"
+                        return function(url)
+                            local c = ws.connect(url)
+                            local received = {}
+                            local welcome, kind = c:recv()
+                            received[1] = welcome .. ":" .. kind
+                            c:send("hello")
+                            c:send(binary)
+                            local echo, kind = c:recv()
+                            received[2] = echo .. ":" .. kind
+                            echo, kind = c:recv()
+                            received[3] = echo:byte(2) .. ":" .. kind
+                            c:close(4000, "bye")
+                            local closed, code, reason = c:recv()
+                            received[4] = tostring(closed) .. ":" .. code .. ":" .. reason
+                            conn = c
+                            return table.concat(received, " ")
+                        end
+                    "#,
+                )
+                .eval::<rlua::Function>()
+                .unwrap();
+            let url = format!("ws://127.0.0.1:{}", port);
+            let received = executor::block_on(talk.call_async::<_, String>(lua, url))
+                .expect("failed to talk to the server");
+            assert_eq!(received, "welcome:text hello:text 255:binary nil:4000:bye");
+
+            let invalid = lua.load(r#"conn:send("\xff")"#).exec_async(lua);
+            let err = executor::block_on(invalid).unwrap_err();
+            assert!(err.to_string().contains("valid UTF-8"), "{}", err);
+        });
+        server.join().unwrap();
+    }
+}